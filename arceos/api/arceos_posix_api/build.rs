@@ -52,6 +52,9 @@ typedef struct {{
             "iovec",
             "clockid_t",
             "rlimit",
+            "rusage",
+            "tms",
+            "cpu_set_t",
             "aibuf",
         ];
         let allow_vars = [
@@ -66,8 +69,10 @@ typedef struct {{
             "EPOLL_CTL_.*",
             "EPOLL.*",
             "RLIMIT_.*",
+            "RUSAGE_.*",
             "EAI_.*",
             "MAXADDRS",
+            "MADV_.*",
         ];
 
         #[derive(Debug)]
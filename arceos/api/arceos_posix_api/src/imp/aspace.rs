@@ -0,0 +1,50 @@
+//! The address space of the calling task, for the calls from user processes.
+
+use axerrno::{AxError, AxResult, LinuxError, LinuxResult};
+use axmm::AddrSpace;
+
+/// Runs a function on the address space of the current task.
+pub type WithAspaceFn = fn(&mut dyn FnMut(&mut AddrSpace) -> AxResult) -> AxResult;
+
+/// The address space of the calling task.
+///
+/// A kernel running user processes registers a [`WithAspaceFn`] here, which
+/// runs on the address space of the current process. The user pointers
+/// passed to the calls are then checked and accessed through it. Without it,
+/// the calls come from the application in the kernel address space.
+#[linkme::distributed_slice]
+pub static CURRENT_ASPACE: [WithAspaceFn];
+
+fn run_on<R>(
+    with_aspace: WithAspaceFn,
+    f: impl FnOnce(&mut AddrSpace) -> AxResult<R>,
+) -> LinuxResult<R> {
+    let mut f = Some(f);
+    let mut ret = None;
+    with_aspace(&mut |aspace| {
+        let f = f.take().ok_or(AxError::BadState)?;
+        ret = Some(f(aspace)?);
+        Ok(())
+    })?;
+    ret.ok_or(LinuxError::EFAULT)
+}
+
+/// Runs `f` on the address space of the calling user process, or returns
+/// `None` if the calls are not from user processes.
+#[cfg(feature = "uspace")]
+pub(crate) fn with_user_aspace<R>(
+    f: impl FnOnce(&mut AddrSpace) -> AxResult<R>,
+) -> Option<LinuxResult<R>> {
+    CURRENT_ASPACE
+        .first()
+        .map(|&with_aspace| run_on(with_aspace, f))
+}
+
+/// Runs `f` on the address space of the calling task: the one of the user
+/// process, or the kernel address space.
+pub(crate) fn with_current_aspace(f: impl FnOnce(&mut AddrSpace) -> AxResult) -> LinuxResult {
+    match CURRENT_ASPACE.first() {
+        Some(&with_aspace) => run_on(with_aspace, f),
+        None => f(&mut axmm::kernel_aspace().lock()).map_err(LinuxError::from),
+    }
+}
@@ -133,6 +133,14 @@ pub fn sys_lseek(fd: c_int, offset: ctypes::off_t, whence: c_int) -> ctypes::off
     })
 }
 
+/// Get a new handle to the file opened as `fd`, e.g., to map the file into
+/// an address space.
+///
+/// The handle stays valid after `fd` is closed.
+pub fn file_from_fd(fd: c_int) -> LinuxResult<axfs::fops::File> {
+    Ok(File::from_fd(fd)?.inner.lock().try_clone()?)
+}
+
 /// Get the file metadata by `path` and write into `buf`.
 ///
 /// Return 0 if success.
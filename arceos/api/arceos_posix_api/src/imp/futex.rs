@@ -0,0 +1,154 @@
+use core::ffi::c_int;
+use core::sync::atomic::AtomicU32;
+use core::time::Duration;
+
+use axerrno::{LinuxError, LinuxResult};
+use axtask::FutexError;
+#[cfg(feature = "uspace")]
+use {axhal::paging::MappingFlags, axmm::UserPtr, core::mem::size_of, memory_addr::VirtAddr};
+
+#[cfg(feature = "uspace")]
+use super::aspace::with_user_aspace;
+use crate::ctypes;
+
+pub const FUTEX_WAIT: c_int = 0;
+pub const FUTEX_WAKE: c_int = 1;
+pub const FUTEX_REQUEUE: c_int = 3;
+pub const FUTEX_CMP_REQUEUE: c_int = 4;
+pub const FUTEX_WAIT_BITSET: c_int = 9;
+pub const FUTEX_WAKE_BITSET: c_int = 10;
+
+pub const FUTEX_PRIVATE_FLAG: c_int = 128;
+pub const FUTEX_CLOCK_REALTIME: c_int = 256;
+
+/// Only the bitset that matches all waiters is supported.
+pub const FUTEX_BITSET_MATCH_ANY: u32 = u32::MAX;
+
+fn futex_err(e: FutexError) -> LinuxError {
+    match e {
+        FutexError::WouldBlock => LinuxError::EAGAIN,
+        FutexError::TimedOut => LinuxError::ETIMEDOUT,
+        FutexError::Interrupted => LinuxError::EINTR,
+    }
+}
+
+/// Converts a futex address from the caller to a reference.
+///
+/// The futex words are accessed by the kernel directly, where a page fault
+/// is fatal, so the words of a user process are faulted in first.
+fn futex_ref<'a>(uaddr: *mut u32) -> LinuxResult<&'a AtomicU32> {
+    if uaddr.is_null() {
+        return Err(LinuxError::EFAULT);
+    }
+    if !uaddr.is_aligned() {
+        return Err(LinuxError::EINVAL);
+    }
+    #[cfg(feature = "uspace")]
+    if let Some(res) = with_user_aspace(|aspace| {
+        let addr = VirtAddr::from(uaddr as usize);
+        aspace.fault_in_user(addr, size_of::<u32>(), MappingFlags::READ)
+    }) {
+        res?;
+    }
+    Ok(unsafe { AtomicU32::from_ptr(uaddr) })
+}
+
+/// Copies the timeout in from the caller.
+fn read_timespec(timeout: *const ctypes::timespec) -> LinuxResult<ctypes::timespec> {
+    #[cfg(feature = "uspace")]
+    if let Some(res) =
+        with_user_aspace(|aspace| UserPtr::<ctypes::timespec>::new(timeout as usize).read(aspace))
+    {
+        return res;
+    }
+    if !timeout.is_aligned() {
+        return Err(LinuxError::EFAULT);
+    }
+    Ok(unsafe { timeout.read() })
+}
+
+/// Reads the timeout of `FUTEX_WAIT` (relative) or `FUTEX_WAIT_BITSET`
+/// (absolute, on the clock selected by `FUTEX_CLOCK_REALTIME`).
+fn futex_timeout(
+    timeout: *const ctypes::timespec,
+    absolute: bool,
+    realtime: bool,
+) -> LinuxResult<Option<Duration>> {
+    if timeout.is_null() {
+        return Ok(None);
+    }
+    let ts = read_timespec(timeout)?;
+    if ts.tv_sec < 0 || ts.tv_nsec < 0 || ts.tv_nsec > 999_999_999 {
+        return Err(LinuxError::EINVAL);
+    }
+    let dur = Duration::from(ts);
+    if !absolute {
+        return Ok(Some(dur));
+    }
+    let now = if realtime {
+        axhal::time::wall_time()
+    } else {
+        axhal::time::monotonic_time()
+    };
+    Ok(Some(dur.saturating_sub(now)))
+}
+
+fn futex_wait(futex: &AtomicU32, val: u32, timeout: Option<Duration>) -> LinuxResult<c_int> {
+    match timeout {
+        None => axtask::futex_wait(futex, val).map_err(futex_err)?,
+        #[cfg(feature = "irq")]
+        Some(dur) => axtask::futex_wait_timeout(futex, val, dur).map_err(futex_err)?,
+        // Timed waits need the timer interrupts.
+        #[cfg(not(feature = "irq"))]
+        Some(_) => return Err(LinuxError::ENOSYS),
+    }
+    Ok(0)
+}
+
+/// Fast user-space locking.
+///
+/// Supports `FUTEX_WAIT`, `FUTEX_WAKE`, `FUTEX_REQUEUE`, `FUTEX_CMP_REQUEUE`,
+/// and the bitset variants with `FUTEX_BITSET_MATCH_ANY`. Private and shared
+/// futexes are treated the same, as they are keyed by the virtual address.
+///
+/// For the requeue operations, `timeout` is the maximum number of waiters to
+/// requeue instead.
+///
+/// The calls from user processes are checked against the address space
+/// registered in [`CURRENT_ASPACE`](crate::CURRENT_ASPACE), if any.
+pub unsafe fn sys_futex(
+    uaddr: *mut u32,
+    futex_op: c_int,
+    val: u32,
+    timeout: *const ctypes::timespec,
+    uaddr2: *mut u32,
+    val3: u32,
+) -> c_int {
+    debug!(
+        "sys_futex <= {:#x} op={} val={} val3={}",
+        uaddr as usize, futex_op, val, val3
+    );
+    syscall_body!(sys_futex, {
+        let futex = futex_ref(uaddr)?;
+        let realtime = futex_op & FUTEX_CLOCK_REALTIME != 0;
+        let cmd = futex_op & !(FUTEX_PRIVATE_FLAG | FUTEX_CLOCK_REALTIME);
+        match cmd {
+            FUTEX_WAIT => futex_wait(futex, val, futex_timeout(timeout, false, realtime)?),
+            FUTEX_WAIT_BITSET | FUTEX_WAKE_BITSET if val3 != FUTEX_BITSET_MATCH_ANY => {
+                Err(LinuxError::EINVAL)
+            }
+            FUTEX_WAIT_BITSET => futex_wait(futex, val, futex_timeout(timeout, true, realtime)?),
+            FUTEX_WAKE | FUTEX_WAKE_BITSET => Ok(axtask::futex_wake(futex, val as usize) as c_int),
+            FUTEX_REQUEUE | FUTEX_CMP_REQUEUE => {
+                let to = futex_ref(uaddr2)?;
+                let nr_requeue = timeout as usize as u32;
+                let expected = (cmd == FUTEX_CMP_REQUEUE).then_some(val3);
+                let n =
+                    axtask::futex_requeue(futex, to, val as usize, nr_requeue as usize, expected)
+                        .map_err(futex_err)?;
+                Ok(n as c_int)
+            }
+            _ => Err(LinuxError::ENOSYS),
+        }
+    })
+}
@@ -0,0 +1,73 @@
+//! Memory advice and locking on the address space of the calling task.
+
+use axerrno::{LinuxError, LinuxResult};
+use axmm::MemoryAdvice;
+use core::ffi::{c_int, c_void};
+use memory_addr::{is_aligned_4k, MemoryAddr, VirtAddr};
+
+use super::aspace::with_current_aspace;
+use crate::ctypes;
+
+/// Returns the pages covering `[addr, addr + len)`.
+fn page_range(addr: usize, len: usize) -> LinuxResult<(VirtAddr, usize)> {
+    let end = addr.checked_add(len).ok_or(LinuxError::ENOMEM)?;
+    let start = VirtAddr::from(addr).align_down_4k();
+    let end = VirtAddr::from(end).align_up_4k();
+    Ok((start, end - start))
+}
+
+/// Give advice about the use of memory
+///
+/// `MADV_DONTNEED` and `MADV_FREE` give the frames back to the kernel, which
+/// only works for the lazily allocated mappings, e.g., the anonymous mappings
+/// of a user process. `MADV_WILLNEED` faults in the pages in advance. The
+/// other advice is accepted and ignored.
+pub unsafe fn sys_madvise(addr: *mut c_void, len: ctypes::size_t, advice: c_int) -> c_int {
+    debug!("sys_madvise <= {:#x} {:#x} {}", addr as usize, len, advice);
+    syscall_body!(sys_madvise, {
+        if !is_aligned_4k(addr as usize) {
+            return Err(LinuxError::EINVAL);
+        }
+        let advice = match advice as u32 {
+            ctypes::MADV_NORMAL | ctypes::MADV_RANDOM | ctypes::MADV_SEQUENTIAL => {
+                MemoryAdvice::Normal
+            }
+            ctypes::MADV_WILLNEED => MemoryAdvice::WillNeed,
+            ctypes::MADV_DONTNEED => MemoryAdvice::DontNeed,
+            ctypes::MADV_FREE => MemoryAdvice::Free,
+            _ => return Err(LinuxError::EINVAL),
+        };
+        let (start, size) = page_range(addr as usize, len as usize)?;
+        if size == 0 {
+            return Ok(0);
+        }
+        with_current_aspace(|aspace| aspace.madvise(start, size, advice))?;
+        Ok(0)
+    })
+}
+
+/// Lock the pages in memory, so that they are never swapped out or dropped
+pub unsafe fn sys_mlock(addr: *const c_void, len: ctypes::size_t) -> c_int {
+    debug!("sys_mlock <= {:#x} {:#x}", addr as usize, len);
+    syscall_body!(sys_mlock, {
+        let (start, size) = page_range(addr as usize, len as usize)?;
+        if size == 0 {
+            return Ok(0);
+        }
+        with_current_aspace(|aspace| aspace.mlock(start, size))?;
+        Ok(0)
+    })
+}
+
+/// Unlock the pages locked by `mlock`
+pub unsafe fn sys_munlock(addr: *const c_void, len: ctypes::size_t) -> c_int {
+    debug!("sys_munlock <= {:#x} {:#x}", addr as usize, len);
+    syscall_body!(sys_munlock, {
+        let (start, size) = page_range(addr as usize, len as usize)?;
+        if size == 0 {
+            return Ok(0);
+        }
+        with_current_aspace(|aspace| aspace.munlock(start, size))?;
+        Ok(0)
+    })
+}
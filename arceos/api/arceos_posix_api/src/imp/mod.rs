@@ -6,12 +6,18 @@ pub mod sys;
 pub mod task;
 pub mod time;
 
+#[cfg(feature = "mman")]
+pub mod aspace;
 #[cfg(feature = "fd")]
 pub mod fd_ops;
 #[cfg(feature = "fs")]
 pub mod fs;
+#[cfg(feature = "multitask")]
+pub mod futex;
 #[cfg(any(feature = "select", feature = "epoll"))]
 pub mod io_mpx;
+#[cfg(feature = "mman")]
+pub mod mman;
 #[cfg(feature = "net")]
 pub mod net;
 #[cfg(feature = "pipe")]
@@ -1,6 +1,52 @@
 use crate::ctypes;
 use axerrno::LinuxError;
-use core::ffi::c_int;
+use core::ffi::{c_int, c_long};
+use core::time::Duration;
+
+/// The CPU time statistics of a thread or the whole process.
+#[derive(Default)]
+pub(crate) struct CpuTime {
+    pub utime: Duration,
+    pub stime: Duration,
+    pub nvcsw: u64,
+    pub nivcsw: u64,
+}
+
+impl CpuTime {
+    pub fn total(&self) -> Duration {
+        self.utime + self.stime
+    }
+}
+
+/// Gets the CPU time statistics of the current thread, or the sum of all
+/// threads if `thread_only` is `false`.
+///
+/// Without the `multitask` feature, all the time since boot is counted as
+/// system time of the only thread.
+pub(crate) fn cpu_time(thread_only: bool) -> CpuTime {
+    #[cfg(feature = "multitask")]
+    {
+        let t = if thread_only {
+            axtask::current().cpu_time()
+        } else {
+            axtask::total_cpu_time()
+        };
+        CpuTime {
+            utime: t.utime,
+            stime: t.stime,
+            nvcsw: t.nvcsw,
+            nivcsw: t.nivcsw,
+        }
+    }
+    #[cfg(not(feature = "multitask"))]
+    {
+        let _ = thread_only;
+        CpuTime {
+            stime: axhal::time::monotonic_time(),
+            ..Default::default()
+        }
+    }
+}
 
 /// Get resource limitations
 ///
@@ -49,3 +95,34 @@ pub unsafe fn sys_setrlimit(resource: c_int, rlimits: *mut crate::ctypes::rlimit
         Ok(0)
     })
 }
+
+/// Get resource usage of the current process or thread
+///
+/// `RUSAGE_SELF` counts all threads, and `RUSAGE_CHILDREN` always returns
+/// zeros as there are no child processes. Only the CPU time and context
+/// switch fields are filled.
+pub unsafe fn sys_getrusage(who: c_int, usage: *mut ctypes::rusage) -> c_int {
+    debug!("sys_getrusage <= {} {:#x}", who, usage as usize);
+    syscall_body!(sys_getrusage, {
+        if usage.is_null() {
+            return Err(LinuxError::EFAULT);
+        }
+        let time = if who == ctypes::RUSAGE_SELF as c_int {
+            cpu_time(false)
+        } else if who == ctypes::RUSAGE_THREAD as c_int {
+            cpu_time(true)
+        } else if who == ctypes::RUSAGE_CHILDREN as c_int {
+            CpuTime::default()
+        } else {
+            return Err(LinuxError::EINVAL);
+        };
+        unsafe {
+            core::ptr::write_bytes(usage, 0, 1);
+            (*usage).ru_utime = time.utime.into();
+            (*usage).ru_stime = time.stime.into();
+            (*usage).ru_nvcsw = time.nvcsw as c_long;
+            (*usage).ru_nivcsw = time.nivcsw as c_long;
+        }
+        Ok(0)
+    })
+}
@@ -11,6 +11,8 @@ pub fn sys_sysconf(name: c_int) -> c_long {
     debug!("sys_sysconf <= {}", name);
     syscall_body!(sys_sysconf, {
         match name as u32 {
+            // Clock ticks per second
+            ctypes::_SC_CLK_TCK => Ok(super::time::CLK_TCK as usize),
             // Page size
             ctypes::_SC_PAGE_SIZE => Ok(PAGE_SIZE_4K),
             // Total physical pages
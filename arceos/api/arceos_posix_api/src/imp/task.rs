@@ -1,5 +1,9 @@
 use core::ffi::c_int;
 
+use axerrno::LinuxError;
+
+use crate::ctypes;
+
 /// Relinquish the CPU, and switches to another task.
 ///
 /// For single-threaded configuration (`multitask` feature is disabled), we just
@@ -38,3 +42,84 @@ pub fn sys_exit(exit_code: c_int) -> ! {
     #[cfg(not(feature = "multitask"))]
     axhal::misc::terminate();
 }
+
+/// Checks that `pid` refers to the current thread, as only the affinity of
+/// the current thread can be accessed.
+fn check_current_pid(pid: c_int) -> Result<(), LinuxError> {
+    if pid == 0 || pid == sys_getpid() {
+        Ok(())
+    } else {
+        Err(LinuxError::ESRCH)
+    }
+}
+
+/// Set the CPU affinity mask of a thread.
+///
+/// Only the current thread (`pid` is 0 or the current thread ID) is supported.
+pub unsafe fn sys_sched_setaffinity(
+    pid: c_int,
+    cpusetsize: usize,
+    mask: *const ctypes::cpu_set_t,
+) -> c_int {
+    debug!(
+        "sys_sched_setaffinity <= {} {} {:#x}",
+        pid, cpusetsize, mask as usize
+    );
+    syscall_body!(sys_sched_setaffinity, {
+        if mask.is_null() {
+            return Err(LinuxError::EFAULT);
+        }
+        check_current_pid(pid)?;
+        let words = unsafe {
+            core::slice::from_raw_parts(
+                mask as *const usize,
+                cpusetsize.min(core::mem::size_of::<ctypes::cpu_set_t>())
+                    / core::mem::size_of::<usize>(),
+            )
+        };
+        #[cfg(feature = "multitask")]
+        if !axtask::set_affinity(axtask::AxCpuMask::from_raw_words(words)) {
+            return Err(LinuxError::EINVAL);
+        }
+        #[cfg(not(feature = "multitask"))]
+        if words.first().map_or(true, |w| w & 1 == 0) {
+            return Err(LinuxError::EINVAL); // only the boot CPU is in use
+        }
+        Ok(0)
+    })
+}
+
+/// Get the CPU affinity mask of a thread.
+///
+/// Only the current thread (`pid` is 0 or the current thread ID) is supported.
+pub unsafe fn sys_sched_getaffinity(
+    pid: c_int,
+    cpusetsize: usize,
+    mask: *mut ctypes::cpu_set_t,
+) -> c_int {
+    debug!(
+        "sys_sched_getaffinity <= {} {} {:#x}",
+        pid, cpusetsize, mask as usize
+    );
+    syscall_body!(sys_sched_getaffinity, {
+        if mask.is_null() {
+            return Err(LinuxError::EFAULT);
+        }
+        check_current_pid(pid)?;
+        let nr_words = axconfig::SMP.div_ceil(usize::BITS as usize);
+        if cpusetsize < nr_words * core::mem::size_of::<usize>() {
+            return Err(LinuxError::EINVAL);
+        }
+        let words = unsafe {
+            core::ptr::write_bytes(mask as *mut u8, 0, cpusetsize);
+            core::slice::from_raw_parts_mut(mask as *mut usize, nr_words)
+        };
+        #[cfg(feature = "multitask")]
+        words.copy_from_slice(axtask::current().cpumask().as_raw_words());
+        #[cfg(not(feature = "multitask"))]
+        {
+            words[0] = 1;
+        }
+        Ok(0)
+    })
+}
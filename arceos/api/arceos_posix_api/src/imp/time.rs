@@ -3,7 +3,12 @@ use core::ffi::{c_int, c_long};
 use core::time::Duration;
 
 use crate::ctypes;
-use crate::ctypes::{CLOCK_MONOTONIC, CLOCK_REALTIME};
+use crate::ctypes::{
+    CLOCK_MONOTONIC, CLOCK_PROCESS_CPUTIME_ID, CLOCK_REALTIME, CLOCK_THREAD_CPUTIME_ID,
+};
+
+/// The number of clock ticks per second, used by [`sys_times`].
+pub const CLK_TCK: u64 = 100;
 
 impl From<ctypes::timespec> for Duration {
     fn from(ts: ctypes::timespec) -> Self {
@@ -44,6 +49,8 @@ pub unsafe fn sys_clock_gettime(clk: ctypes::clockid_t, ts: *mut ctypes::timespe
         let now = match clk as u32 {
             CLOCK_REALTIME => axhal::time::wall_time().into(),
             CLOCK_MONOTONIC => axhal::time::monotonic_time().into(),
+            CLOCK_PROCESS_CPUTIME_ID => super::resources::cpu_time(false).total().into(),
+            CLOCK_THREAD_CPUTIME_ID => super::resources::cpu_time(true).total().into(),
             _ => {
                 warn!("Called sys_clock_gettime for unsupported clock {}", clk);
                 return Err(LinuxError::EINVAL);
@@ -90,3 +97,28 @@ pub unsafe fn sys_nanosleep(req: *const ctypes::timespec, rem: *mut ctypes::time
         Ok(0)
     })
 }
+
+/// Get process times
+///
+/// Returns the elapsed time since boot, all in clock ticks ([`CLK_TCK`] per
+/// second).
+pub unsafe fn sys_times(buf: *mut ctypes::tms) -> ctypes::clock_t {
+    syscall_body!(sys_times, {
+        if !buf.is_null() {
+            let time = super::resources::cpu_time(false);
+            unsafe {
+                *buf = ctypes::tms {
+                    tms_utime: to_clock_ticks(time.utime),
+                    tms_stime: to_clock_ticks(time.stime),
+                    tms_cutime: 0,
+                    tms_cstime: 0,
+                };
+            }
+        }
+        Ok(to_clock_ticks(axhal::time::monotonic_time()))
+    })
+}
+
+fn to_clock_ticks(d: Duration) -> ctypes::clock_t {
+    (d.as_nanos() * CLK_TCK as u128 / 1_000_000_000) as ctypes::clock_t
+}
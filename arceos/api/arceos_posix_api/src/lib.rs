@@ -31,19 +31,29 @@ pub mod config {
 pub mod ctypes;
 
 pub use imp::io::{sys_read, sys_write, sys_writev};
-pub use imp::resources::{sys_getrlimit, sys_setrlimit};
+pub use imp::resources::{sys_getrlimit, sys_getrusage, sys_setrlimit};
 pub use imp::sys::sys_sysconf;
-pub use imp::task::{sys_exit, sys_getpid, sys_sched_yield};
-pub use imp::time::{sys_clock_gettime, sys_nanosleep};
+pub use imp::task::{
+    sys_exit, sys_getpid, sys_sched_getaffinity, sys_sched_setaffinity, sys_sched_yield,
+};
+pub use imp::time::{sys_clock_gettime, sys_nanosleep, sys_times};
 
 #[cfg(feature = "fd")]
 pub use imp::fd_ops::{sys_close, sys_dup, sys_dup2, sys_fcntl, get_file_like};
 #[cfg(feature = "fs")]
-pub use imp::fs::{sys_fstat, sys_getcwd, sys_lseek, sys_lstat, sys_open, sys_rename, sys_stat};
+pub use imp::fs::{
+    file_from_fd, sys_fstat, sys_getcwd, sys_lseek, sys_lstat, sys_open, sys_rename, sys_stat,
+};
+#[cfg(feature = "multitask")]
+pub use imp::futex::sys_futex;
 #[cfg(feature = "select")]
 pub use imp::io_mpx::sys_select;
 #[cfg(feature = "epoll")]
 pub use imp::io_mpx::{sys_epoll_create, sys_epoll_ctl, sys_epoll_wait};
+#[cfg(feature = "mman")]
+pub use imp::aspace::{WithAspaceFn, CURRENT_ASPACE};
+#[cfg(feature = "mman")]
+pub use imp::mman::{sys_madvise, sys_mlock, sys_munlock};
 #[cfg(feature = "net")]
 pub use imp::net::{
     sys_accept, sys_bind, sys_connect, sys_freeaddrinfo, sys_getaddrinfo, sys_getpeername,
@@ -12,13 +12,21 @@
 //!     - `alloc-tlsf`: Use the TLSF allocator.
 //!     - `alloc-slab`: Use the slab allocator.
 //!     - `alloc-buddy`: Use the buddy system allocator.
+//!     - `alloc-hybrid`: Use the slab allocator for small blocks and TLSF for the others.
+//!     - `alloc-dynamic`: Carry all the allocators above, and select one at boot.
+//!     - `alloc-trace`: Keep allocation statistics and track leaks.
 //!     - `paging`: Enable page table manipulation.
+//!     - `swap`: Enable swapping anonymous pages out of memory.
+//!     - `aslr`: Enable the address space layout randomization for user programs.
 //!     - `tls`: Enable thread-local storage.
 //! - Task management
 //!     - `multitask`: Enable multi-threading support.
 //!     - `sched_fifo`: Use the FIFO cooperative scheduler.
 //!     - `sched_rr`: Use the Round-robin preemptive scheduler.
 //!     - `sched_cfs`: Use the Completely Fair Scheduler (CFS) preemptive scheduler.
+//!     - `sched_edf`: Add the earliest-deadline-first (EDF) class for real-time tasks.
+//!     - `watchdog`: Report hung tasks and soft lockups.
+//!     - `oom`: Notify memory pressure to tasks and kill a task on OOM.
 //! - Upperlayer stacks (fs, net, display)
 //!     - `fs`: Enable file system support.
 //!     - `myfs`: Allow users to define their custom filesystems to override the default.
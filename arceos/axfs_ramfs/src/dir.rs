@@ -100,6 +100,26 @@ impl DirNode {
         
     }
 
+    /// Gives back the spare capacity of the files in this directory and its
+    /// subdirectories, see [`RamFileSystem::trim`](crate::RamFileSystem::trim).
+    pub(super) fn trim(&self, min_spare: usize) -> usize {
+        let Some(children) = self.children.try_read() else {
+            return 0;
+        };
+        children
+            .values()
+            .map(|node| {
+                if let Some(file) = node.as_any().downcast_ref::<FileNode>() {
+                    file.trim(min_spare)
+                } else if let Some(dir) = node.as_any().downcast_ref::<DirNode>() {
+                    dir.trim(min_spare)
+                } else {
+                    0
+                }
+            })
+            .sum()
+    }
+
 
 
 }
@@ -17,6 +17,30 @@ impl FileNode {
     }
 }
 
+impl FileNode {
+    /// Gives back the spare capacity of the content, if it's at least
+    /// `min_spare` bytes. Returns the number of bytes freed.
+    ///
+    /// It does not block, and does nothing if the file is in use or the
+    /// smaller buffer cannot be allocated.
+    pub(super) fn trim(&self, min_spare: usize) -> usize {
+        let Some(mut content) = self.content.try_write() else {
+            return 0;
+        };
+        let spare = content.capacity() - content.len();
+        if spare < min_spare {
+            return 0;
+        }
+        let mut trimmed = Vec::new();
+        if trimmed.try_reserve_exact(content.len()).is_err() {
+            return 0;
+        }
+        trimmed.extend_from_slice(&content);
+        *content = trimmed;
+        spare
+    }
+}
+
 impl VfsNodeOps for FileNode {
     fn get_attr(&self) -> VfsResult<VfsNodeAttr> {
         Ok(VfsNodeAttr::new_file(self.content.read().len() as _, 0))
@@ -38,6 +38,16 @@ impl RamFileSystem {
     pub fn root_dir_node(&self) -> Arc<DirNode> {
         self.root.clone()
     }
+
+    /// Gives back the spare capacity of the file buffers, left by truncating
+    /// or growing the files, for the files with at least `min_spare` spare
+    /// bytes. Returns the number of bytes freed.
+    ///
+    /// It does not block, so it can be called when memory is short. The
+    /// files in use are skipped.
+    pub fn trim(&self, min_spare: usize) -> usize {
+        self.root.trim(min_spare)
+    }
 }
 
 impl VfsOps for RamFileSystem {
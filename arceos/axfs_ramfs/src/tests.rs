@@ -134,3 +134,30 @@ fn test_ramfs() {
     assert_eq!(root.remove("./foo"), Ok(()));
     assert!(ramfs.root_dir_node().get_entries().is_empty());
 }
+
+#[test]
+fn test_ramfs_trim() {
+    let ramfs = RamFileSystem::new();
+    let root = ramfs.root_dir();
+    root.create("foo", VfsNodeType::Dir).unwrap();
+    root.create("f1", VfsNodeType::File).unwrap();
+    root.create("foo/f2", VfsNodeType::File).unwrap();
+
+    let f1 = root.clone().lookup("f1").unwrap();
+    let f2 = root.lookup("foo/f2").unwrap();
+    f1.write_at(0, &[1; 0x1000]).unwrap();
+    f2.write_at(0, &[2; 0x1000]).unwrap();
+    f1.truncate(0x100).unwrap();
+    f2.truncate(0x100).unwrap();
+
+    // Too little to trim.
+    assert_eq!(ramfs.trim(0x1000), 0);
+    assert_eq!(ramfs.trim(0x100), 2 * 0xf00);
+    assert_eq!(ramfs.trim(1), 0);
+
+    let mut buf = [0; 0x200];
+    assert_eq!(f1.read_at(0, &mut buf).unwrap(), 0x100);
+    assert!(buf[..0x100].iter().all(|&b| b == 1));
+    assert_eq!(f2.read_at(0, &mut buf).unwrap(), 0x100);
+    assert!(buf[..0x100].iter().all(|&b| b == 2));
+}
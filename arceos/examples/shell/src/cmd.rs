@@ -17,6 +17,8 @@ macro_rules! print_err {
 type CmdHandler = fn(&str);
 
 const CMD_TABLE: &[(&str, CmdHandler)] = &[
+    #[cfg(feature = "alloc-trace")]
+    ("allocstat", do_allocstat),
     ("cat", do_cat),
     ("cd", do_cd),
     ("echo", do_echo),
@@ -267,6 +269,39 @@ fn do_help(_args: &str) {
     }
 }
 
+#[cfg(feature = "alloc-trace")]
+fn do_allocstat(args: &str) {
+    use std::os::arceos::modules::axalloc::trace;
+    use std::sync::Mutex;
+
+    static SNAPSHOT: Mutex<Option<trace::Snapshot>> = Mutex::new(None);
+
+    match args {
+        "" => print!("{}", trace::report()),
+        "reset" => trace::reset(),
+        "snapshot" => {
+            let snapshot = trace::snapshot();
+            println!("{} live allocations", snapshot.allocs.len());
+            *SNAPSHOT.lock() = Some(snapshot);
+        }
+        "diff" => {
+            let old = SNAPSHOT.lock();
+            let Some(old) = old.as_ref() else {
+                print_err!("allocstat", "no snapshot");
+                return;
+            };
+            let new = trace::snapshot();
+            let mut count = 0;
+            for alloc in old.diff(&new) {
+                println!("{}", alloc);
+                count += 1;
+            }
+            println!("{} allocations since the snapshot", count);
+        }
+        _ => println!("usage: allocstat [reset|snapshot|diff]"),
+    }
+}
+
 fn do_exit(_args: &str) {
     println!("Bye~");
     std::process::exit(0);
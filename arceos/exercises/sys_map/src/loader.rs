@@ -8,27 +8,59 @@ use axhal::paging::MappingFlags;
 use axhal::mem::{PAGE_SIZE_4K, VirtAddr, MemoryAddr};
 use axmm::AddrSpace;
 
-use elf::abi::{PT_INTERP, PT_LOAD};
+use elf::abi::{DT_JMPREL, DT_NULL, DT_PLTRELSZ, DT_REL, DT_RELA, DT_RELASZ, DT_RELSZ};
+use elf::abi::{ET_DYN, PT_DYNAMIC, PT_INTERP, PT_LOAD};
+use elf::dynamic::DynamicTable;
 use elf::endian::AnyEndian;
+use elf::file::FileHeader;
 use elf::parse::ParseAt;
+use elf::relocation::RelaIterator;
 use elf::segment::ProgramHeader;
 use elf::segment::SegmentTable;
 use elf::ElfBytes;
 
 const ELF_HEAD_BUF_SIZE: usize = 256;
 
+#[cfg(any(target_arch = "riscv32", target_arch = "riscv64"))]
+const R_RELATIVE: u32 = elf::abi::R_RISCV_RELATIVE;
+#[cfg(target_arch = "x86_64")]
+const R_RELATIVE: u32 = elf::abi::R_X86_64_RELATIVE;
+#[cfg(target_arch = "aarch64")]
+const R_RELATIVE: u32 = elf::abi::R_AARCH64_RELATIVE;
+
+/// The relocation that does nothing, the same on all architectures.
+const R_NONE: u32 = 0;
+
+/// Where to load a PIE, without ASLR.
+#[cfg(not(feature = "aslr"))]
+const PIE_BASE: usize = 0x40_0000;
+
 pub fn load_user_app(fname: &str, uspace: &mut AddrSpace) -> io::Result<usize> {
     let mut file = File::open(fname)?;
-    let (phdrs, entry, _, _) = load_elf_phdrs(&mut file)?;
+    let (ehdr, phdrs) = load_elf_phdrs(&mut file)?;
+    let is_pie = ehdr.e_type == ET_DYN;
+    let mut heap_base = VirtAddr::from(0);
+    let mut dynamic = None;
+    // The addresses of a PIE are relative to where it is loaded.
+    #[cfg(feature = "aslr")]
+    let pie_base = axmm::aslr::pie_base().as_usize();
+    #[cfg(not(feature = "aslr"))]
+    let pie_base = PIE_BASE;
+    let load_base = if is_pie { pie_base } else { 0 };
+    ax_println!("load base: {:#x}", load_base);
 
     for phdr in &phdrs {
+        if phdr.p_type == PT_DYNAMIC {
+            dynamic = Some(phdr); // in a loaded segment
+            continue;
+        }
         ax_println!(
             "phdr: offset: {:#X}=>{:#X} size: {:#X}=>{:#X}",
             phdr.p_offset, phdr.p_vaddr, phdr.p_filesz, phdr.p_memsz
         );
 
-        let vaddr = VirtAddr::from(phdr.p_vaddr as usize).align_down_4k();
-        let vaddr_end = VirtAddr::from((phdr.p_vaddr+phdr.p_memsz) as usize)
+        let vaddr = VirtAddr::from(load_base + phdr.p_vaddr as usize).align_down_4k();
+        let vaddr_end = VirtAddr::from(load_base + (phdr.p_vaddr+phdr.p_memsz) as usize)
             .align_up_4k();
 
         ax_println!("{:#x} - {:#x}", vaddr, vaddr_end);
@@ -44,13 +76,72 @@ pub fn load_user_app(fname: &str, uspace: &mut AddrSpace) -> io::Result<usize> {
             index += n;
         }
         assert_eq!(index, filesz);
-        uspace.write(VirtAddr::from(phdr.p_vaddr as usize), &data)?;
+        uspace.write(VirtAddr::from(load_base + phdr.p_vaddr as usize), &data)?;
+        heap_base = heap_base.max(vaddr_end);
+    }
+    if let (true, Some(dynamic)) = (is_pie, dynamic) {
+        relocate_pie(uspace, &ehdr, dynamic, load_base)?;
     }
+    // The heap for `brk` starts after the program data, with a random gap
+    // under ASLR.
+    #[cfg(feature = "aslr")]
+    let heap_base = axmm::aslr::heap_base(heap_base);
+    uspace.init_heap(heap_base);
 
-    Ok(entry)
+    Ok(load_base + ehdr.e_entry as usize)
+}
+
+/// Applies the relocations of a PIE loaded at `load_base`.
+///
+/// Only the relative relocations in the `DT_RELA` table are supported, which
+/// are all that a static PIE has, as there is no dynamic linker. The others
+/// are rejected, rather than leaving the addresses unrelocated.
+fn relocate_pie(
+    uspace: &mut AddrSpace,
+    ehdr: &FileHeader<AnyEndian>,
+    dynamic: &ProgramHeader,
+    load_base: usize,
+) -> io::Result<()> {
+    let mut buf = vec![0u8; dynamic.p_filesz as usize];
+    uspace.read(VirtAddr::from(load_base + dynamic.p_vaddr as usize), &mut buf)?;
+    let (mut rela, mut rela_size) = (None, 0);
+    for entry in DynamicTable::new(ehdr.endianness, ehdr.class, &buf).iter() {
+        match entry.d_tag {
+            DT_NULL => break,
+            DT_RELA => rela = Some(entry.d_ptr() as usize),
+            DT_RELASZ => rela_size = entry.d_val() as usize,
+            // The `DT_REL` tables and the PLT relocations need symbols.
+            DT_REL | DT_RELSZ | DT_JMPREL | DT_PLTRELSZ if entry.d_val() != 0 => {
+                warn!("unsupported dynamic relocation table {:#x} in PIE", entry.d_tag);
+                return Err(io::Error::Unsupported);
+            }
+            _ => {}
+        }
+    }
+    let Some(rela) = rela else {
+        return Ok(());
+    };
+    let mut buf = vec![0u8; rela_size];
+    uspace.read(VirtAddr::from(load_base + rela), &mut buf)?;
+    for rela in RelaIterator::new(ehdr.endianness, ehdr.class, &buf) {
+        match rela.r_type {
+            R_RELATIVE => {}
+            R_NONE => continue,
+            r_type => {
+                warn!("unsupported relocation type {} in PIE", r_type);
+                return Err(io::Error::Unsupported);
+            }
+        }
+        let value = load_base.wrapping_add(rela.r_addend as usize);
+        uspace.write(
+            VirtAddr::from(load_base + rela.r_offset as usize),
+            &value.to_ne_bytes(),
+        )?;
+    }
+    Ok(())
 }
 
-fn load_elf_phdrs(file: &mut File) -> io::Result<(Vec<ProgramHeader>, usize, usize, usize)> {
+fn load_elf_phdrs(file: &mut File) -> io::Result<(FileHeader<AnyEndian>, Vec<ProgramHeader>)> {
     let mut buf: [u8; ELF_HEAD_BUF_SIZE] = [0; ELF_HEAD_BUF_SIZE];
     file.read(&mut buf)?;
 
@@ -70,7 +161,7 @@ fn load_elf_phdrs(file: &mut File) -> io::Result<(Vec<ProgramHeader>, usize, usi
 
     let phdrs: Vec<ProgramHeader> = phdrs
         .iter()
-        .filter(|phdr| phdr.p_type == PT_LOAD || phdr.p_type == PT_INTERP)
+        .filter(|phdr| matches!(phdr.p_type, PT_LOAD | PT_INTERP | PT_DYNAMIC))
         .collect();
-    Ok((phdrs, ehdr.e_entry as usize, ehdr.e_phoff as usize, ehdr.e_phnum as usize))
+    Ok((ehdr, phdrs))
 }
@@ -16,18 +16,28 @@ use axstd::io;
 use axhal::paging::MappingFlags;
 use axhal::arch::UspaceContext;
 use axhal::mem::VirtAddr;
+use memory_addr::VirtAddrRange;
 use axsync::Mutex;
 use alloc::sync::Arc;
 use alloc::string::String;
 use alloc::collections::BTreeMap;
 use axmm::AddrSpace;
 use loader::load_user_app;
+use axtask::TaskExtRef;
+use axhal::trap::{register_trap_handler, PAGE_FAULT};
 
 const USER_STACK_SIZE: usize = 0x10000;
 const KERNEL_STACK_SIZE: usize = 0x40000; // 256 KiB
 
+const SWAP_FILE: &str = "/swapfile";
+const SWAP_SIZE: usize = 0x100_0000; // 16 MiB
+
 #[cfg_attr(feature = "axstd", no_mangle)]
 fn main() {
+    // The OOM reaper kills the process with the largest RSS.
+    axtask::set_oom_score_fn(task::oom_score);
+    init_swap();
+
     // A new address space for user app.
     let mut uspace = axmm::new_user_aspace().unwrap();
 
@@ -39,21 +49,53 @@ fn main() {
     ax_println!("entry: {:#x}", entry);
 
     // Init user stack.
-    let ustack_top = init_user_stack(&mut uspace, true).unwrap();
+    let (ustack_top, ustack) = init_user_stack(&mut uspace, true).unwrap();
     ax_println!("New user address space: {:#x?}", uspace);
 
     // Let's kick off the user process.
-    let user_task = task::spawn_user_task(
-        Arc::new(Mutex::new(uspace)),
-        UspaceContext::new(entry, ustack_top),
-    );
+    let uspace = Arc::new(Mutex::new(uspace));
+    axmm::swap::register_aspace(&uspace);
+    let user_task = task::spawn_user_task(uspace.clone(), UspaceContext::new(entry, ustack_top));
+
+    // Show it in /proc/<pid> and /proc/self.
+    let pid = user_task.id().as_u64();
+    axfs::procfs::set_current_pid_fn(|| axtask::current().id().as_u64());
+    let info = task::ProcessInfo::new("mapfile", uspace, ustack);
+    axfs::procfs::register_process(pid, Arc::new(info));
 
     // Wait for user process to exit ...
     let exit_code = user_task.join();
+    axfs::procfs::unregister_process(pid);
     ax_println!("monolithic kernel exit [{:?}] normally!", exit_code);
 }
 
-fn init_user_stack(uspace: &mut AddrSpace, populating: bool) -> io::Result<VirtAddr> {
+/// Swaps the pages of the user processes out to a file on the disk when
+/// memory runs out.
+fn init_swap() {
+    let mut opts = axfs::fops::OpenOptions::new();
+    opts.read(true);
+    opts.write(true);
+    opts.create(true);
+    opts.truncate(true);
+    let res = axfs::fops::File::open(SWAP_FILE, &opts).and_then(|file| {
+        let dev = axmm::swap::SwapFile::new(file, SWAP_SIZE);
+        axmm::swap::set_swap_device(alloc::boxed::Box::new(dev))
+    });
+    if let Err(e) = res {
+        warn!("no swap file {}: {:?}", SWAP_FILE, e);
+    }
+}
+
+/// Maps the user stack, and returns the initial stack pointer and the range
+/// of the stack.
+fn init_user_stack(
+    uspace: &mut AddrSpace,
+    populating: bool,
+) -> io::Result<(VirtAddr, VirtAddrRange)> {
+    // At the top of the address space, or below it by a random gap under ASLR.
+    #[cfg(feature = "aslr")]
+    let ustack_top = axmm::aslr::stack_top(uspace.end());
+    #[cfg(not(feature = "aslr"))]
     let ustack_top = uspace.end();
     let ustack_vaddr = ustack_top - crate::USER_STACK_SIZE;
     ax_println!(
@@ -78,5 +120,24 @@ fn init_user_stack(uspace: &mut AddrSpace, populating: bool) -> io::Result<VirtA
     );
     uspace.write(VirtAddr::from_usize(ustack_pointer), stack_data.as_slice())?;
 
-    Ok(ustack_pointer.into())
+    let ustack = VirtAddrRange::from_start_size(ustack_vaddr, crate::USER_STACK_SIZE);
+    Ok((ustack_pointer.into(), ustack))
+}
+
+#[register_trap_handler(PAGE_FAULT)]
+fn handle_page_fault(vaddr: VirtAddr, access_flags: MappingFlags, is_user: bool) -> bool {
+    if is_user {
+        if !axtask::current()
+            .task_ext()
+            .aspace
+            .lock()
+            .handle_page_fault(vaddr, access_flags)
+        {
+            ax_println!("{}: segmentation fault, exit!", axtask::current().id_name());
+            axtask::exit(-1);
+        }
+        true
+    } else {
+        false
+    }
 }
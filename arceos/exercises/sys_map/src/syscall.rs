@@ -4,21 +4,22 @@ use core::ffi::{c_void, c_char, c_int};
 // use core::task;
 use axhal::arch::TrapFrame;
 use axhal::trap::{register_trap_handler, SYSCALL};
-use axerrno::LinuxError;
-// use axmm::AddrSpace;
+use axerrno::{AxResult, LinuxError};
+use axmm::AddrSpace;
 use axtask::current;
 use axtask::TaskExtRef;
-use axhal::paging::MappingFlags;
+use axhal::paging::{MappingFlags, PageSize};
 use arceos_posix_api as api;
+use alloc::vec;
+use axmm::uaccess::{UserPtr, UserSlice};
+use axmm::{shm, SharedMemory};
 
 // Physical memory management.
 // use axhal::mem;
-use axhal::mem::{VirtAddr, phys_to_virt};
+use axhal::mem::VirtAddr;
 // Page table manipulation.
 // use axhal::paging;
-use memory_addr::{MemoryAddr, VirtAddrRange};
-
-use crate::std::vec::Vec;
+use memory_addr::{MemoryAddr, VirtAddrRange, PAGE_SIZE_4K};
 
 
 const SYS_IOCTL: usize = 29;
@@ -30,9 +31,35 @@ const SYS_WRITEV: usize = 66;
 const SYS_EXIT: usize = 93;
 const SYS_EXIT_GROUP: usize = 94;
 const SYS_SET_TID_ADDRESS: usize = 96;
+const SYS_FUTEX: usize = 98;
+const SYS_SHMGET: usize = 194;
+const SYS_SHMCTL: usize = 195;
+const SYS_SHMAT: usize = 196;
+const SYS_SHMDT: usize = 197;
+const SYS_BRK: usize = 214;
+const SYS_MUNMAP: usize = 215;
+const SYS_MREMAP: usize = 216;
 const SYS_MMAP: usize = 222;
+const SYS_MPROTECT: usize = 226;
+const SYS_MSYNC: usize = 227;
+const SYS_MLOCK: usize = 228;
+const SYS_MUNLOCK: usize = 229;
+const SYS_MADVISE: usize = 233;
 
 const AT_FDCWD: i32 = -100;
+const PATH_MAX: usize = 4096;
+const IOV_MAX: i32 = 1024;
+
+/// The maximum size of a kernel buffer for reading or writing files.
+const MAX_IO_SIZE: usize = 0x10000;
+
+const IPC_CREAT: i32 = 0o1000;
+const IPC_EXCL: i32 = 0o2000;
+const IPC_RMID: i32 = 0;
+const SHM_RDONLY: i32 = 0o10000;
+
+const MREMAP_MAYMOVE: i32 = 1;
+const MREMAP_FIXED: i32 = 2;
 
 /// Macro to generate syscall body
 ///
@@ -105,6 +132,10 @@ bitflags::bitflags! {
         const MAP_NORESERVE = 1 << 14;
         /// Allocation is for a stack.
         const MAP_STACK = 0x20000;
+        /// Create a huge page mapping.
+        const MAP_HUGETLB = 0x40000;
+        /// Like `MAP_FIXED`, but fail if the range is already mapped.
+        const MAP_FIXED_NOREPLACE = 0x100000;
     }
 }
 
@@ -135,6 +166,32 @@ fn handle_syscall(tf: &TrapFrame, syscall_num: usize) -> isize {
             tf.arg4() as _,
             tf.arg5() as _,
         ),
+        SYS_MUNMAP => sys_munmap(tf.arg0() as _, tf.arg1() as _),
+        SYS_MPROTECT => sys_mprotect(tf.arg0() as _, tf.arg1() as _, tf.arg2() as _),
+        SYS_MREMAP => sys_mremap(
+            tf.arg0() as _,
+            tf.arg1() as _,
+            tf.arg2() as _,
+            tf.arg3() as _,
+            tf.arg4() as _,
+        ),
+        SYS_BRK => sys_brk(tf.arg0() as _),
+        SYS_MSYNC => sys_msync(tf.arg0() as _, tf.arg1() as _, tf.arg2() as _),
+        SYS_MLOCK => sys_mlock(tf.arg0() as _, tf.arg1() as _),
+        SYS_MUNLOCK => sys_munlock(tf.arg0() as _, tf.arg1() as _),
+        SYS_MADVISE => sys_madvise(tf.arg0() as _, tf.arg1() as _, tf.arg2() as _),
+        SYS_SHMGET => sys_shmget(tf.arg0() as _, tf.arg1() as _, tf.arg2() as _),
+        SYS_SHMCTL => sys_shmctl(tf.arg0() as _, tf.arg1() as _, tf.arg2() as _),
+        SYS_SHMAT => sys_shmat(tf.arg0() as _, tf.arg1() as _, tf.arg2() as _),
+        SYS_SHMDT => sys_shmdt(tf.arg0() as _),
+        SYS_FUTEX => sys_futex(
+            tf.arg0() as _,
+            tf.arg1() as _,
+            tf.arg2() as _,
+            tf.arg3() as _,
+            tf.arg4() as _,
+            tf.arg5() as _,
+        ),
         _ => {
             ax_println!("Unimplemented syscall: {}", syscall_num);
             -LinuxError::ENOSYS.code() as _
@@ -150,7 +207,7 @@ fn sys_mmap(
     prot: i32,
     flags: i32,
     fd: i32,
-    _offset: isize,
+    offset: isize,
 ) -> isize {
     // unimplemented!("no sys_mmap!");
     // 实现sys_mmap
@@ -197,21 +254,30 @@ fn sys_mmap(
 
     let mmap_flags = MmapFlags::from_bits_truncate(flags);
     let is_anonymous = mmap_flags.contains(MmapFlags::MAP_ANONYMOUS);
-    let mut lazy = true;
+    let is_shared = mmap_flags.contains(MmapFlags::MAP_SHARED);
+    // Only private anonymous mappings are backed by huge pages.
+    let page_size = if mmap_flags.contains(MmapFlags::MAP_HUGETLB) && is_anonymous && !is_shared {
+        PageSize::Size2M
+    } else {
+        PageSize::Size4K
+    };
+    let length = length.align_up(page_size);
 
-    let mut buf = Vec::new();
-    if !is_anonymous {
+    // The file is mapped lazily, the pages are read on the first access.
+    let file = if !is_anonymous {
         if fd == -1 {
             return -LinuxError::EBADF.code() as isize;
         }
-        // Simplified file reading: read entire length
-        buf.resize(length, 0u8);
-        let size = api::sys_read(fd, buf.as_mut_ptr() as *mut c_void, length);
-        if size < 0 {
-            return -LinuxError::EIO.code() as isize;
+        if offset < 0 || offset as usize % PAGE_SIZE_4K != 0 {
+            return -LinuxError::EINVAL.code() as isize;
         }
-        lazy = false;
-    }
+        match api::file_from_fd(fd) {
+            Ok(file) => Some(file),
+            Err(e) => return -e.code() as isize,
+        }
+    } else {
+        None
+    };
 
     let mut mapping_flags = MappingFlags::USER;
     if prot & MmapProt::PROT_READ.bits() != 0 {
@@ -227,51 +293,226 @@ fn sys_mmap(
     let task = current();
     let mut uspace = task.task_ext().aspace.lock();
 
-    let va_start = if addr.is_null() {
+    let hint = VirtAddr::from(addr as usize);
+    let va_start = if mmap_flags.intersects(MmapFlags::MAP_FIXED | MmapFlags::MAP_FIXED_NOREPLACE) {
+        if !hint.is_aligned(page_size) {
+            return -LinuxError::EINVAL.code() as isize;
+        }
+        if mmap_flags.contains(MmapFlags::MAP_FIXED_NOREPLACE) {
+            let range = VirtAddrRange::from_start_size(hint, length);
+            if uspace.find_free_area(hint, length, range) != Some(hint) {
+                return -LinuxError::EEXIST.code() as isize;
+            }
+        } else if let Err(e) = uspace.unmap(hint, length) {
+            // Replace the old mappings.
+            return -LinuxError::from(e).code() as isize;
+        }
+        hint
+    } else {
         const USER_ASPACE_BASE: usize = 0x0000;
         const USER_ASPACE_SIZE: usize = 0x40_0000_0000;
         let limit = VirtAddrRange::new(USER_ASPACE_BASE.into(), USER_ASPACE_SIZE.into());
-        let vaddr = uspace
-            .find_free_area(0.into(), length, limit)
-            .ok_or(LinuxError::ENOMEM)
-            .unwrap();
-        vaddr
-    } else {
-        VirtAddr::from(addr as usize)
-    }.align_down_4k();
+        // Leave room to align the start to the page size.
+        let align = usize::from(page_size) - PAGE_SIZE_4K;
+        // The address is only a hint, search from there, or from the mmap
+        // base which may be randomized.
+        let hint = if addr.is_null() { uspace.mmap_base() } else { hint };
+        match uspace.find_free_area(hint.align_down(page_size), length + align, limit) {
+            Some(vaddr) => vaddr.align_up(page_size),
+            None => return -LinuxError::ENOMEM.code() as isize,
+        }
+    };
 
     let va_end = (va_start + length).align_up_4k();
     let size = va_end - va_start;
 
     // Map the virtual address range
-    uspace
-        .map_alloc(va_start, size, mapping_flags, !lazy)
-        .map_err(|_| LinuxError::ENOMEM)
-        .unwrap();
-
-    // Copy data for non-lazy file-backed mappings
-    if !is_anonymous && !lazy {
-        let (paddr, _, _) = uspace
-            .page_table()
-            .query(va_start.into())
-            .expect("Failed to query page table");
-        unsafe {
-            core::ptr::copy_nonoverlapping(
-                buf.as_ptr(),
-                phys_to_virt(paddr).as_mut_ptr(),
-                buf.len(),
-            );
+    let res = match file {
+        Some(file) => uspace.map_file(va_start, size, mapping_flags, file, offset as u64, is_shared),
+        // Shared anonymous memory stays shared with the cloned address spaces.
+        None if is_shared => {
+            uspace.map_shared(va_start, size, mapping_flags, SharedMemory::new(size), 0)
         }
+        None if page_size.is_huge() => {
+            uspace.map_alloc_huge(va_start, size, mapping_flags, page_size, false)
+        }
+        None => uspace.map_alloc(va_start, size, mapping_flags, false),
+    };
+    if let Err(e) = res {
+        return -LinuxError::from(e).code() as isize;
     }
 
     va_start.as_usize() as isize
 
 }
 
+fn sys_munmap(addr: *mut usize, length: usize) -> isize {
+    let va_start = VirtAddr::from(addr as usize);
+    if !va_start.is_aligned_4k() || length == 0 {
+        return -LinuxError::EINVAL.code() as isize;
+    }
+    let size = length.align_up_4k();
+    let task = current();
+    let mut uspace = task.task_ext().aspace.lock();
+    match uspace.unmap(va_start, size) {
+        Ok(()) => 0,
+        Err(e) => -LinuxError::from(e).code() as isize,
+    }
+}
+
+fn sys_mprotect(addr: *mut usize, length: usize, prot: i32) -> isize {
+    let va_start = VirtAddr::from(addr as usize);
+    if !va_start.is_aligned_4k() {
+        return -LinuxError::EINVAL.code() as isize;
+    }
+    let size = length.align_up_4k();
+    let flags = MappingFlags::from(MmapProt::from_bits_truncate(prot));
+    let task = current();
+    let mut uspace = task.task_ext().aspace.lock();
+    match uspace.protect(va_start, size, flags) {
+        Ok(()) => 0,
+        Err(e) => -LinuxError::from(e).code() as isize,
+    }
+}
+
+fn sys_mremap(
+    old_addr: *mut usize,
+    old_size: usize,
+    new_size: usize,
+    flags: i32,
+    new_addr: *mut usize,
+) -> isize {
+    let old_start = VirtAddr::from(old_addr as usize);
+    let may_move = flags & MREMAP_MAYMOVE != 0;
+    // `MREMAP_FIXED` must be used with `MREMAP_MAYMOVE`.
+    if !old_start.is_aligned_4k() || (flags & MREMAP_FIXED != 0 && !may_move) {
+        return -LinuxError::EINVAL.code() as isize;
+    }
+    let new_start = (flags & MREMAP_FIXED != 0).then(|| VirtAddr::from(new_addr as usize));
+    let task = current();
+    let mut uspace = task.task_ext().aspace.lock();
+    match uspace.mremap(
+        old_start,
+        old_size.align_up_4k(),
+        new_size.align_up_4k(),
+        may_move,
+        new_start,
+    ) {
+        Ok(vaddr) => vaddr.as_usize() as isize,
+        Err(e) => -LinuxError::from(e).code() as isize,
+    }
+}
+
+fn sys_brk(addr: *mut usize) -> isize {
+    let task = current();
+    let mut uspace = task.task_ext().aspace.lock();
+    // Returns the current break on failure, or for `brk(0)`.
+    uspace.brk(VirtAddr::from(addr as usize)).as_usize() as isize
+}
+
+fn sys_msync(addr: *mut usize, length: usize, _flags: i32) -> isize {
+    let va_start = VirtAddr::from(addr as usize);
+    if !va_start.is_aligned_4k() {
+        return -LinuxError::EINVAL.code() as isize;
+    }
+    let size = length.align_up_4k();
+    let task = current();
+    let mut uspace = task.task_ext().aspace.lock();
+    match uspace.msync(va_start, size) {
+        Ok(()) => 0,
+        Err(e) => -LinuxError::from(e).code() as isize,
+    }
+}
+
+/// Runs the calls of `arceos_posix_api` that access the memory (e.g., the
+/// futex words and the memory advice) on the address space of the current
+/// process.
+#[linkme::distributed_slice(api::CURRENT_ASPACE)]
+fn with_current_aspace(f: &mut dyn FnMut(&mut AddrSpace) -> AxResult) -> AxResult {
+    f(&mut current().task_ext().aspace.lock())
+}
+
+fn sys_mlock(addr: *const c_void, length: usize) -> isize {
+    unsafe { api::sys_mlock(addr, length as _) as isize }
+}
+
+fn sys_munlock(addr: *const c_void, length: usize) -> isize {
+    unsafe { api::sys_munlock(addr, length as _) as isize }
+}
+
+fn sys_madvise(addr: *mut c_void, length: usize, advice: c_int) -> isize {
+    unsafe { api::sys_madvise(addr, length as _, advice) as isize }
+}
+
+fn sys_shmget(key: i32, size: usize, shmflg: i32) -> isize {
+    let create = shmflg & IPC_CREAT != 0;
+    let exclusive = shmflg & IPC_EXCL != 0;
+    match shm::shmget(key, size, create, exclusive) {
+        Ok(id) => id as isize,
+        Err(e) => -LinuxError::from(e).code() as isize,
+    }
+}
+
+fn sys_shmat(shmid: usize, shmaddr: *mut usize, shmflg: i32) -> isize {
+    let mem = match shm::shm_by_id(shmid) {
+        Ok(mem) => mem,
+        Err(e) => return -LinuxError::from(e).code() as isize,
+    };
+    let size = mem.size();
+    let mut flags = MappingFlags::USER | MappingFlags::READ;
+    if shmflg & SHM_RDONLY == 0 {
+        flags |= MappingFlags::WRITE;
+    }
+
+    let task = current();
+    let mut uspace = task.task_ext().aspace.lock();
+    let va_start = if shmaddr.is_null() {
+        let limit = VirtAddrRange::from_start_size(uspace.base(), uspace.size());
+        match uspace.find_free_area(uspace.mmap_base(), size, limit) {
+            Some(vaddr) => vaddr,
+            None => return -LinuxError::ENOMEM.code() as isize,
+        }
+    } else {
+        VirtAddr::from(shmaddr as usize)
+    };
+    match uspace.map_shared(va_start, size, flags, mem, 0) {
+        Ok(()) => va_start.as_usize() as isize,
+        Err(e) => -LinuxError::from(e).code() as isize,
+    }
+}
+
+fn sys_shmdt(shmaddr: *mut usize) -> isize {
+    let task = current();
+    let mut uspace = task.task_ext().aspace.lock();
+    match uspace.unmap_shared(VirtAddr::from(shmaddr as usize)) {
+        Ok(_) => 0,
+        Err(e) => -LinuxError::from(e).code() as isize,
+    }
+}
+
+fn sys_shmctl(shmid: usize, cmd: i32, _buf: *mut c_void) -> isize {
+    if cmd != IPC_RMID {
+        ax_println!("Unsupported shmctl cmd: {}", cmd);
+        return -LinuxError::EINVAL.code() as isize;
+    }
+    match shm::shm_remove(shmid) {
+        Ok(()) => 0,
+        Err(e) => -LinuxError::from(e).code() as isize,
+    }
+}
 
 fn sys_openat(dfd: c_int, fname: *const c_char, flags: c_int, mode: api::ctypes::mode_t) -> isize {
     assert_eq!(dfd, AT_FDCWD);
-    api::sys_open(fname, flags, mode) as isize
+    let task = current();
+    let mut uspace = task.task_ext().aspace.lock();
+    let path = UserPtr::<u8>::new(fname as usize).read_cstr(&mut uspace, PATH_MAX);
+    drop(uspace);
+    let mut path = match path {
+        Ok(path) => path.into_bytes(),
+        Err(e) => return -LinuxError::from(e).code() as isize,
+    };
+    path.push(0);
+    api::sys_open(path.as_ptr() as _, flags, mode) as isize
 }
 
 fn sys_close(fd: i32) -> isize {
@@ -279,15 +520,80 @@ fn sys_close(fd: i32) -> isize {
 }
 
 fn sys_read(fd: i32, buf: *mut c_void, count: usize) -> isize {
-    api::sys_read(fd, buf, count)
+    let task = current();
+    let aspace = &task.task_ext().aspace;
+    let ubuf = UserSlice::new(buf as usize, count.min(MAX_IO_SIZE));
+    // Check the buffer first, not to lose the data read.
+    let res = aspace
+        .lock()
+        .fault_in_user(ubuf.addr(), ubuf.len(), MappingFlags::WRITE);
+    if let Err(e) = res {
+        return -LinuxError::from(e).code() as isize;
+    }
+    let mut kbuf = vec![0u8; ubuf.len()];
+    let n = api::sys_read(fd, kbuf.as_mut_ptr() as _, kbuf.len());
+    if n <= 0 {
+        return n;
+    }
+    match ubuf.write(&mut aspace.lock(), &kbuf[..n as usize]) {
+        Ok(()) => n,
+        Err(e) => -LinuxError::from(e).code() as isize,
+    }
+}
+
+/// Writes `count` bytes at the user address `buf` to the file, through a
+/// kernel buffer.
+fn write_user(fd: i32, buf: usize, count: usize) -> isize {
+    let task = current();
+    let mut kbuf = vec![0u8; count.min(MAX_IO_SIZE)];
+    let mut written = 0;
+    while written < count {
+        let chunk = (count - written).min(kbuf.len());
+        let ubuf = UserSlice::new(buf + written, chunk);
+        if let Err(e) = ubuf.read(&mut task.task_ext().aspace.lock(), &mut kbuf[..chunk]) {
+            return match written {
+                0 => -LinuxError::from(e).code() as isize,
+                _ => written as isize,
+            };
+        }
+        let n = api::sys_write(fd, kbuf.as_ptr() as _, chunk);
+        if n < 0 {
+            return if written == 0 { n } else { written as isize };
+        }
+        written += n as usize;
+        if (n as usize) < chunk {
+            break;
+        }
+    }
+    written as isize
 }
 
 fn sys_write(fd: i32, buf: *const c_void, count: usize) -> isize {
-    api::sys_write(fd, buf, count)
+    write_user(fd, buf as usize, count)
 }
 
 fn sys_writev(fd: i32, iov: *const api::ctypes::iovec, iocnt: i32) -> isize {
-    unsafe { api::sys_writev(fd, iov, iocnt) }
+    if !(0..=IOV_MAX).contains(&iocnt) {
+        return -LinuxError::EINVAL.code() as isize;
+    }
+    let task = current();
+    let iov = UserPtr::<api::ctypes::iovec>::new(iov as usize);
+    let mut written = 0;
+    for i in 0..iocnt as usize {
+        let ent = match iov.add(i).read(&mut task.task_ext().aspace.lock()) {
+            Ok(ent) => ent,
+            Err(e) => return -LinuxError::from(e).code() as isize,
+        };
+        let n = write_user(fd, ent.iov_base as usize, ent.iov_len);
+        if n < 0 {
+            return if written == 0 { n } else { written };
+        }
+        written += n;
+        if (n as usize) < ent.iov_len {
+            break;
+        }
+    }
+    written
 }
 
 fn sys_set_tid_address(tid_ptd: *const i32) -> isize {
@@ -301,4 +607,13 @@ fn sys_ioctl(_fd: i32, _op: usize, _argp: *mut c_void) -> i32 {
     0
 }
 
-
+fn sys_futex(
+    uaddr: *mut u32,
+    futex_op: c_int,
+    val: u32,
+    timeout: *const api::ctypes::timespec,
+    uaddr2: *mut u32,
+    val3: u32,
+) -> isize {
+    unsafe { api::sys_futex(uaddr, futex_op, val, timeout, uaddr2, val3) as isize }
+}
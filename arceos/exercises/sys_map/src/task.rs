@@ -2,12 +2,17 @@
 
 use core::sync::atomic::AtomicU64;
 
+use alloc::string::String;
 use alloc::sync::Arc;
+use alloc::vec::Vec;
 
+use axfs::procfs::{self, MapsEntry};
 use axhal::arch::UspaceContext;
+use axhal::paging::MappingFlags;
 use axmm::AddrSpace;
 use axsync::Mutex;
 use axtask::{AxTaskRef, TaskExtRef, TaskInner};
+use memory_addr::VirtAddrRange;
 
 /// Task extended data for the monolithic kernel.
 pub struct TaskExt {
@@ -48,6 +53,16 @@ impl TaskExt {
 
 axtask::def_task_ext!(TaskExt);
 
+/// The OOM score of a task: the RSS of its address space, and its kernel
+/// stack.
+pub fn oom_score(task: &TaskInner) -> usize {
+    let kstack = axtask::default_oom_score(task);
+    if unsafe { task.task_ext_ptr() }.is_null() {
+        return kstack;
+    }
+    kstack + task.task_ext().aspace.lock().rss()
+}
+
 pub fn spawn_user_task(aspace: Arc<Mutex<AddrSpace>>, uctx: UspaceContext) -> AxTaskRef {
     let mut task = TaskInner::new(
         || {
@@ -69,3 +84,60 @@ pub fn spawn_user_task(aspace: Arc<Mutex<AddrSpace>>, uctx: UspaceContext) -> Ax
     task.init_task_ext(TaskExt::new(uctx, aspace));
     axtask::spawn_task(task)
 }
+
+/// The process information shown in `/proc/<pid>`.
+pub struct ProcessInfo {
+    name: String,
+    aspace: Arc<Mutex<AddrSpace>>,
+    /// The range of the user stack, which is not always at the top of the
+    /// address space.
+    stack: VirtAddrRange,
+}
+
+impl ProcessInfo {
+    pub fn new(name: &str, aspace: Arc<Mutex<AddrSpace>>, stack: VirtAddrRange) -> Self {
+        Self {
+            name: name.into(),
+            aspace,
+            stack,
+        }
+    }
+}
+
+impl procfs::ProcessInfo for ProcessInfo {
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    fn maps(&self) -> Vec<MapsEntry> {
+        let aspace = self.aspace.lock();
+        let heap = aspace.heap();
+        aspace
+            .areas()
+            .map(|area| {
+                let name = if heap.is_some_and(|heap| heap.contains(area.va_range.start)) {
+                    "[heap]"
+                } else if area.va_range.contains(self.stack.start) {
+                    "[stack]"
+                } else {
+                    ""
+                };
+                MapsEntry {
+                    start: area.va_range.start.as_usize(),
+                    end: area.va_range.end.as_usize(),
+                    read: area.flags.contains(MappingFlags::READ),
+                    write: area.flags.contains(MappingFlags::WRITE),
+                    execute: area.flags.contains(MappingFlags::EXECUTE),
+                    shared: area.shared,
+                    offset: area.offset,
+                    name: name.into(),
+                }
+            })
+            .collect()
+    }
+
+    fn memory_usage(&self) -> (usize, usize) {
+        let aspace = self.aspace.lock();
+        (aspace.vsz(), aspace.rss())
+    }
+}
@@ -0,0 +1,198 @@
+//! Per-CPU caches of small memory blocks, in front of the byte allocator.
+//!
+//! Each CPU keeps a magazine of free blocks for each power-of-two size class,
+//! so that most of the small allocations and deallocations do not take the
+//! global lock. An empty magazine is refilled from the byte allocator, and a
+//! full one is drained back to it, [`BATCH`] blocks at a time under one lock.
+//!
+//! A request is served by the smallest class not less than its size. The
+//! blocks are only aligned to [`MIN_CLASS_SIZE`], so the requests with a
+//! larger alignment bypass the caches.
+//!
+//! The magazines of a CPU are locked by the CPU itself, which is never
+//! contended but by [`drain_all`], so that the blocks cached by the other CPUs
+//! can be reclaimed when memory is short.
+
+use core::alloc::Layout;
+use core::ptr::{self, NonNull};
+use core::sync::atomic::{AtomicBool, AtomicPtr, Ordering};
+
+use allocator::AllocResult;
+use kernel_guard::NoPreemptIrqSave;
+use kspin::SpinRaw;
+
+/// The smallest size class, which is also the alignment of all the blocks.
+const MIN_CLASS_SIZE: usize = 16;
+/// The largest size class. The larger blocks bypass the caches.
+const MAX_CLASS_SIZE: usize = 2048;
+const NUM_CLASSES: usize = (MAX_CLASS_SIZE / MIN_CLASS_SIZE).trailing_zeros() as usize + 1;
+
+/// The number of blocks a magazine can hold.
+const MAGAZINE_SIZE: usize = 32;
+/// The number of blocks moved between a magazine and the byte allocator at a
+/// time.
+pub(crate) const BATCH: usize = MAGAZINE_SIZE / 2;
+
+/// A stack of free blocks of the same size class.
+struct Magazine {
+    blocks: [usize; MAGAZINE_SIZE],
+    len: usize,
+}
+
+impl Magazine {
+    const EMPTY: Self = Self {
+        blocks: [0; MAGAZINE_SIZE],
+        len: 0,
+    };
+}
+
+/// The magazines of a CPU.
+struct CpuCache {
+    mags: SpinRaw<[Magazine; NUM_CLASSES]>,
+    /// Whether it's in the list of [`ALL_CACHES`].
+    linked: AtomicBool,
+    /// The next cache in the list.
+    next: AtomicPtr<CpuCache>,
+}
+
+impl CpuCache {
+    const fn new() -> Self {
+        Self {
+            mags: SpinRaw::new([Magazine::EMPTY; NUM_CLASSES]),
+            linked: AtomicBool::new(false),
+            next: AtomicPtr::new(ptr::null_mut()),
+        }
+    }
+
+    /// Adds the cache to the list of [`ALL_CACHES`] on its first use. It must
+    /// be called on the CPU it belongs to, with preemption and IRQs disabled.
+    fn link(&'static self) {
+        if self.linked.load(Ordering::Relaxed) {
+            return;
+        }
+        self.linked.store(true, Ordering::Relaxed);
+        let this = self as *const Self as *mut Self;
+        let mut head = ALL_CACHES.load(Ordering::Relaxed);
+        loop {
+            self.next.store(head, Ordering::Relaxed);
+            match ALL_CACHES.compare_exchange_weak(head, this, Ordering::Release, Ordering::Relaxed)
+            {
+                Ok(_) => break,
+                Err(h) => head = h,
+            }
+        }
+    }
+}
+
+#[percpu::def_percpu]
+static CACHE: CpuCache = CpuCache::new();
+
+/// The list of the caches of all the CPUs that have used theirs. The caches
+/// are never removed, as the per-CPU data lives forever.
+static ALL_CACHES: AtomicPtr<CpuCache> = AtomicPtr::new(ptr::null_mut());
+
+/// Returns the cache of the current CPU.
+///
+/// # Safety
+///
+/// Preemption and IRQs must be disabled while the cache is used.
+unsafe fn local_cache() -> &'static CpuCache {
+    let cache = unsafe { &*CACHE.current_ptr() };
+    cache.link();
+    cache
+}
+
+/// Returns the size class that serves `layout`, or `None` if it is too large
+/// or too aligned to be cached.
+pub(crate) fn size_class(layout: Layout) -> Option<usize> {
+    if layout.size() > MAX_CLASS_SIZE || layout.align() > MIN_CLASS_SIZE {
+        return None;
+    }
+    let size = layout.size().max(MIN_CLASS_SIZE).next_power_of_two();
+    Some((size / MIN_CLASS_SIZE).trailing_zeros() as usize)
+}
+
+/// Returns the layout of the blocks of the size class in the byte allocator.
+pub(crate) fn class_layout(class: usize) -> Layout {
+    Layout::from_size_align(MIN_CLASS_SIZE << class, MIN_CLASS_SIZE).unwrap()
+}
+
+/// Takes a block of the size class from the magazine of the current CPU.
+///
+/// If the magazine is empty, `refill` is called to fill the given slots
+/// (at most [`BATCH`]) from the byte allocator, and returns the number of
+/// slots filled.
+pub(crate) fn alloc(
+    class: usize,
+    refill: impl FnOnce(&mut [usize]) -> AllocResult<usize>,
+) -> AllocResult<NonNull<u8>> {
+    let _guard = NoPreemptIrqSave::new();
+    // Safety: preemption and IRQs are disabled.
+    let mut mags = unsafe { local_cache() }.mags.lock();
+    let mag = &mut mags[class];
+    if mag.len == 0 {
+        mag.len = refill(&mut mag.blocks[..BATCH])?;
+    }
+    mag.len -= 1;
+    Ok(unsafe { NonNull::new_unchecked(mag.blocks[mag.len] as *mut u8) })
+}
+
+/// Puts a block of the size class into the magazine of the current CPU.
+///
+/// If the magazine is full, the oldest [`BATCH`] blocks are given to `drain`
+/// to be returned to the byte allocator first.
+pub(crate) fn dealloc(class: usize, block: NonNull<u8>, drain: impl FnOnce(&[usize])) {
+    let _guard = NoPreemptIrqSave::new();
+    // Safety: preemption and IRQs are disabled.
+    let mut mags = unsafe { local_cache() }.mags.lock();
+    let mag = &mut mags[class];
+    if mag.len == MAGAZINE_SIZE {
+        // The recently freed blocks are kept, as they are likely hot in the
+        // CPU cache.
+        drain(&mag.blocks[..BATCH]);
+        mag.blocks.copy_within(BATCH.., 0);
+        mag.len -= BATCH;
+    }
+    mag.blocks[mag.len] = block.as_ptr() as usize;
+    mag.len += 1;
+}
+
+/// Empties all the magazines of all the CPUs, giving the blocks of each size
+/// class to `drain`. Returns the number of bytes drained.
+pub(crate) fn drain_all(mut drain: impl FnMut(usize, &[usize])) -> usize {
+    let mut bytes = 0;
+    let mut next = ALL_CACHES.load(Ordering::Acquire);
+    while !next.is_null() {
+        // Safety: the caches in the list live forever.
+        let cache = unsafe { &*next };
+        {
+            // An IRQ handler on this CPU may allocate from the cache of this
+            // CPU while it's locked here.
+            let _guard = NoPreemptIrqSave::new();
+            let mut mags = cache.mags.lock();
+            for (class, mag) in mags.iter_mut().enumerate() {
+                if mag.len > 0 {
+                    drain(class, &mag.blocks[..mag.len]);
+                    bytes += mag.len * (MIN_CLASS_SIZE << class);
+                    mag.len = 0;
+                }
+            }
+        }
+        next = cache.next.load(Ordering::Relaxed);
+    }
+    bytes
+}
+
+/// Puts the blocks of the size class into the cache of a new CPU, as if they
+/// were freed there.
+#[cfg(test)]
+pub(crate) fn cache_on_other_cpu(class: usize, blocks: &[usize]) {
+    use alloc::boxed::Box;
+
+    let cache: &'static CpuCache = Box::leak(Box::new(CpuCache::new()));
+    let mut mags = cache.mags.lock();
+    let mag = &mut mags[class];
+    mag.blocks[..blocks.len()].copy_from_slice(blocks);
+    mag.len = blocks.len();
+    cache.link();
+}
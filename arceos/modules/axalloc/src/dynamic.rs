@@ -0,0 +1,138 @@
+//! A byte allocator that carries all the strategies, one of which is selected
+//! at boot.
+
+use core::alloc::Layout;
+use core::ptr::NonNull;
+
+use allocator::{
+    AllocResult, BaseAllocator, BuddyByteAllocator, ByteAllocator, SlabByteAllocator,
+    TlsfByteAllocator,
+};
+
+use crate::hybrid::HybridByteAllocator;
+
+/// The byte allocation strategies of [`DynamicByteAllocator`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ByteAllocatorKind {
+    /// The [`TlsfByteAllocator`], it's the default.
+    Tlsf,
+    /// The [`SlabByteAllocator`].
+    Slab,
+    /// The [`BuddyByteAllocator`].
+    Buddy,
+    /// The [`HybridByteAllocator`].
+    Hybrid,
+}
+
+impl ByteAllocatorKind {
+    /// All the strategies.
+    pub const ALL: [Self; 4] = [Self::Tlsf, Self::Slab, Self::Buddy, Self::Hybrid];
+
+    /// Returns the name of the strategy.
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::Tlsf => "TLSF",
+            Self::Slab => "slab",
+            Self::Buddy => "buddy",
+            Self::Hybrid => "hybrid",
+        }
+    }
+
+    /// Finds the strategy by its name, ignoring the case.
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|kind| kind.name().eq_ignore_ascii_case(name))
+    }
+}
+
+/// A byte allocator that dispatches to the strategy selected by
+/// [`select`](Self::select), through a trait object.
+pub struct DynamicByteAllocator {
+    kind: ByteAllocatorKind,
+    inited: bool,
+    tlsf: TlsfByteAllocator,
+    slab: SlabByteAllocator,
+    buddy: BuddyByteAllocator,
+    hybrid: HybridByteAllocator,
+}
+
+impl DynamicByteAllocator {
+    /// Creates an empty [`DynamicByteAllocator`], with the default strategy.
+    pub const fn new() -> Self {
+        Self {
+            kind: ByteAllocatorKind::Tlsf,
+            inited: false,
+            tlsf: TlsfByteAllocator::new(),
+            slab: SlabByteAllocator::new(),
+            buddy: BuddyByteAllocator::new(),
+            hybrid: HybridByteAllocator::new(),
+        }
+    }
+
+    /// Returns the selected strategy.
+    pub const fn kind(&self) -> ByteAllocatorKind {
+        self.kind
+    }
+
+    /// Selects the strategy. Returns `false` if the allocator is already
+    /// initialized, then the strategy cannot be changed.
+    pub fn select(&mut self, kind: ByteAllocatorKind) -> bool {
+        if self.inited && kind != self.kind {
+            return false;
+        }
+        self.kind = kind;
+        true
+    }
+
+    fn inner(&self) -> &dyn ByteAllocator {
+        match self.kind {
+            ByteAllocatorKind::Tlsf => &self.tlsf,
+            ByteAllocatorKind::Slab => &self.slab,
+            ByteAllocatorKind::Buddy => &self.buddy,
+            ByteAllocatorKind::Hybrid => &self.hybrid,
+        }
+    }
+
+    fn inner_mut(&mut self) -> &mut dyn ByteAllocator {
+        match self.kind {
+            ByteAllocatorKind::Tlsf => &mut self.tlsf,
+            ByteAllocatorKind::Slab => &mut self.slab,
+            ByteAllocatorKind::Buddy => &mut self.buddy,
+            ByteAllocatorKind::Hybrid => &mut self.hybrid,
+        }
+    }
+}
+
+impl BaseAllocator for DynamicByteAllocator {
+    fn init(&mut self, start: usize, size: usize) {
+        self.inited = true;
+        self.inner_mut().init(start, size)
+    }
+
+    fn add_memory(&mut self, start: usize, size: usize) -> AllocResult {
+        self.inner_mut().add_memory(start, size)
+    }
+}
+
+impl ByteAllocator for DynamicByteAllocator {
+    fn alloc(&mut self, layout: Layout) -> AllocResult<NonNull<u8>> {
+        self.inner_mut().alloc(layout)
+    }
+
+    fn dealloc(&mut self, pos: NonNull<u8>, layout: Layout) {
+        self.inner_mut().dealloc(pos, layout)
+    }
+
+    fn total_bytes(&self) -> usize {
+        self.inner().total_bytes()
+    }
+
+    fn used_bytes(&self) -> usize {
+        self.inner().used_bytes()
+    }
+
+    fn available_bytes(&self) -> usize {
+        self.inner().available_bytes()
+    }
+}
@@ -0,0 +1,209 @@
+//! The boot-time allocator, used before the global allocator is initialized.
+//!
+//! It's an [`EarlyAllocator`] on one region, which allocates bytes forward
+//! from the start and pages backward from the end:
+//!
+//! ```text
+//! [ bytes | free | pages ]
+//! ```
+//!
+//! At the handoff, the free space in the middle is given to the global
+//! allocator, and the two areas of the early allocations that are still live
+//! are left to it. Their pages are given to the byte allocator once all the
+//! blocks on them are freed, so that the memory is not lost forever.
+//!
+//! To tell when that happens, the number of the live byte blocks on each page
+//! of the region is counted from the start, in an array of [`PageState`]s
+//! allocated from the end of the region (2 bytes per page). The whole pages
+//! freed at once are given together.
+
+use core::alloc::Layout;
+use core::ptr::NonNull;
+use core::sync::atomic::{AtomicBool, AtomicU16, AtomicUsize, Ordering};
+
+use allocator::{AllocResult, BaseAllocator, ByteAllocator, PageAllocator};
+use bump_allocator::EarlyAllocator;
+use kspin::SpinNoIrq;
+use memory_addr::{align_down_4k, align_up_4k};
+
+use crate::PAGE_SIZE;
+
+/// The state of a page in the early region: the number of the live byte
+/// blocks on it, and whether it has been given to the global allocator.
+///
+/// A page holds at most [`PAGE_SIZE`] blocks, so the count never reaches the
+/// [`DONATED`](PageState::DONATED) bit.
+#[repr(transparent)]
+struct PageState(AtomicU16);
+
+impl PageState {
+    const DONATED: u16 = 1 << 15;
+
+    /// Counts a new block on the page.
+    fn get(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Drops a block on the page, and returns whether it was the last one.
+    fn put(&self) -> bool {
+        self.0.fetch_sub(1, Ordering::AcqRel) == 1
+    }
+
+    fn is_donated(&self) -> bool {
+        self.0.load(Ordering::Acquire) & Self::DONATED != 0
+    }
+
+    fn set_donated(&self) {
+        self.0.fetch_or(Self::DONATED, Ordering::AcqRel);
+    }
+}
+
+pub(crate) struct EarlyHeap {
+    inner: SpinNoIrq<EarlyAllocator<PAGE_SIZE>>,
+    active: AtomicBool,
+    // The byte area `[start, bytes_end)` and the page area `[pages_start,
+    // end)`, which cover the whole region until the handoff.
+    start: AtomicUsize,
+    bytes_end: AtomicUsize,
+    pages_start: AtomicUsize,
+    end: AtomicUsize,
+    /// The address of the [`PageState`] array, for the pages from
+    /// `align_down_4k(start)`.
+    states: AtomicUsize,
+}
+
+impl EarlyHeap {
+    pub const fn new() -> Self {
+        Self {
+            inner: SpinNoIrq::new(EarlyAllocator::new()),
+            active: AtomicBool::new(false),
+            start: AtomicUsize::new(0),
+            bytes_end: AtomicUsize::new(0),
+            pages_start: AtomicUsize::new(0),
+            end: AtomicUsize::new(0),
+            states: AtomicUsize::new(0),
+        }
+    }
+
+    /// Starts the early phase with the given region.
+    pub fn init(&self, start: usize, size: usize) {
+        let mut inner = self.inner.lock();
+        inner.init(start, size);
+        let nr_pages = (align_up_4k(start + size) - align_down_4k(start)) / PAGE_SIZE;
+        let states_size = nr_pages * core::mem::size_of::<PageState>();
+        let states = inner
+            .alloc_pages(align_up_4k(states_size) / PAGE_SIZE, PAGE_SIZE)
+            .expect("early region too small");
+        // Safety: the pages are just allocated, and all-zero is a valid state.
+        unsafe { core::ptr::write_bytes(states as *mut u8, 0, states_size) };
+        self.states.store(states, Ordering::Relaxed);
+        self.start.store(start, Ordering::Relaxed);
+        self.bytes_end.store(start + size, Ordering::Relaxed);
+        self.pages_start.store(start + size, Ordering::Relaxed);
+        self.end.store(start + size, Ordering::Relaxed);
+        self.active.store(true, Ordering::Release);
+    }
+
+    /// Whether the allocations are served by the early allocator.
+    pub fn is_active(&self) -> bool {
+        self.active.load(Ordering::Acquire)
+    }
+
+    /// Whether `pos` is allocated by the early allocator, and not given to
+    /// the global allocator yet.
+    pub fn owns(&self, pos: usize) -> bool {
+        let in_bytes = self.start.load(Ordering::Relaxed) <= pos
+            && pos < self.bytes_end.load(Ordering::Relaxed);
+        let in_pages = self.pages_start.load(Ordering::Relaxed) <= pos
+            && pos < self.end.load(Ordering::Relaxed);
+        (in_bytes || in_pages) && !self.state(pos).is_donated()
+    }
+
+    /// Returns the state of the page that `pos` is in.
+    fn state(&self, pos: usize) -> &PageState {
+        let states = self.states.load(Ordering::Relaxed) as *const PageState;
+        let index = (pos - align_down_4k(self.start.load(Ordering::Relaxed))) / PAGE_SIZE;
+        // Safety: the array covers the whole region, and lives forever.
+        unsafe { &*states.add(index) }
+    }
+
+    /// Returns the pages that the byte block `[pos, pos + size)` is on.
+    fn block_pages(pos: usize, size: usize) -> impl Iterator<Item = usize> {
+        (align_down_4k(pos)..align_up_4k(pos + size.max(1))).step_by(PAGE_SIZE)
+    }
+
+    pub fn alloc(&self, layout: Layout) -> AllocResult<NonNull<u8>> {
+        let ptr = self.inner.lock().alloc(layout)?;
+        for page in Self::block_pages(ptr.as_ptr() as usize, layout.size()) {
+            self.state(page).get();
+        }
+        Ok(ptr)
+    }
+
+    pub fn dealloc(&self, pos: NonNull<u8>, layout: Layout) {
+        for page in Self::block_pages(pos.as_ptr() as usize, layout.size()) {
+            self.state(page).put();
+        }
+        self.inner.lock().dealloc(pos, layout)
+    }
+
+    pub fn alloc_pages(&self, num_pages: usize, align_pow2: usize) -> AllocResult<usize> {
+        self.inner.lock().alloc_pages(num_pages, align_pow2)
+    }
+
+    pub fn dealloc_pages(&self, pos: usize, num_pages: usize) {
+        self.inner.lock().dealloc_pages(pos, num_pages)
+    }
+
+    /// Returns the memory usage as `(used_bytes, available_bytes,
+    /// used_pages, available_pages)`.
+    pub fn usage(&self) -> (usize, usize, usize, usize) {
+        let inner = self.inner.lock();
+        (
+            inner.used_bytes(),
+            inner.available_bytes(),
+            inner.used_pages(),
+            inner.available_pages(),
+        )
+    }
+
+    /// Ends the early phase. Returns the free region between the byte and
+    /// page areas, as `(start, size)`.
+    pub fn finish(&self) -> (usize, usize) {
+        let inner = self.inner.lock();
+        let bytes_end = align_up_4k(inner.bytes_end());
+        let pages_start = align_down_4k(inner.pages_start()).max(bytes_end);
+        self.bytes_end.store(bytes_end, Ordering::Relaxed);
+        self.pages_start.store(pages_start, Ordering::Relaxed);
+        self.active.store(false, Ordering::Release);
+        (bytes_end, pages_start - bytes_end)
+    }
+
+    /// Takes the pages freed with the block `[pos, pos + size)` after the
+    /// handoff, to be given to the global allocator. Returns them as `(start,
+    /// size)`, or `None` if other blocks are still live on all of them.
+    ///
+    /// A page block frees all its pages. A byte block frees the pages that no
+    /// other blocks are on, which are always contiguous, as only the first
+    /// and the last pages can be shared.
+    pub fn take_freed(&self, pos: usize, size: usize) -> Option<(usize, usize)> {
+        if pos >= self.pages_start.load(Ordering::Relaxed) {
+            for page in (pos..pos + size).step_by(PAGE_SIZE) {
+                self.state(page).set_donated();
+            }
+            return Some((pos, size));
+        }
+        // Only the pages in the byte area are ours.
+        let area_start = align_up_4k(self.start.load(Ordering::Relaxed));
+        let area_end = self.bytes_end.load(Ordering::Relaxed);
+        let mut freed: Option<(usize, usize)> = None;
+        for page in Self::block_pages(pos, size) {
+            if self.state(page).put() && area_start <= page && page < area_end {
+                self.state(page).set_donated();
+                let start = freed.map_or(page, |(start, _)| start);
+                freed = Some((start, page + PAGE_SIZE));
+            }
+        }
+        freed.map(|(start, end)| (start, end - start))
+    }
+}
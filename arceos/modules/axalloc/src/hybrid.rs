@@ -0,0 +1,123 @@
+//! A size-segregated byte allocator, which serves the small blocks with a
+//! slab allocator and the others with a TLSF allocator.
+
+use core::alloc::Layout;
+use core::ptr::NonNull;
+
+use allocator::{
+    AllocError, AllocResult, BaseAllocator, ByteAllocator, SlabByteAllocator, TlsfByteAllocator,
+};
+
+/// The largest block served by the slab allocator, which is the size of its
+/// largest slab.
+const MAX_SMALL_SIZE: usize = 4096;
+/// The smallest region the slab allocator can be initialized with.
+const MIN_SLAB_HEAP_SIZE: usize = 0x8000; // 32 K
+
+/// A byte allocator that serves the blocks up to 4 KB (in size and alignment)
+/// with a [`SlabByteAllocator`], and the larger ones with a
+/// [`TlsfByteAllocator`].
+///
+/// The small blocks of the same size are packed in slabs without headers,
+/// and do not fragment the memory for the large blocks. It suits the
+/// workloads with many small objects and some large buffers.
+///
+/// The memory is given to the TLSF allocator at first. The next region added
+/// by [`add_memory`](BaseAllocator::add_memory) after an allocation fails is
+/// given to the allocator that failed, so that the expansion of the heap
+/// goes to the one that asked for it.
+pub struct HybridByteAllocator {
+    slab: SlabByteAllocator,
+    tlsf: TlsfByteAllocator,
+    slab_inited: bool,
+    /// The last allocation that failed was a small one, so the slab allocator
+    /// gets the next region.
+    slab_starving: bool,
+}
+
+impl HybridByteAllocator {
+    /// Creates an empty [`HybridByteAllocator`].
+    pub const fn new() -> Self {
+        Self {
+            slab: SlabByteAllocator::new(),
+            tlsf: TlsfByteAllocator::new(),
+            slab_inited: false,
+            slab_starving: false,
+        }
+    }
+
+    fn is_small(layout: &Layout) -> bool {
+        layout.size().max(layout.align()) <= MAX_SMALL_SIZE
+    }
+}
+
+impl BaseAllocator for HybridByteAllocator {
+    fn init(&mut self, start: usize, size: usize) {
+        // The slab allocator is initialized with the first region it asks for.
+        self.tlsf.init(start, size);
+    }
+
+    fn add_memory(&mut self, start: usize, size: usize) -> AllocResult {
+        if !self.slab_starving || (!self.slab_inited && size < MIN_SLAB_HEAP_SIZE) {
+            return self.tlsf.add_memory(start, size);
+        }
+        self.slab_starving = false;
+        if self.slab_inited {
+            self.slab.add_memory(start, size)
+        } else {
+            self.slab.init(start, size);
+            self.slab_inited = true;
+            Ok(())
+        }
+    }
+}
+
+impl ByteAllocator for HybridByteAllocator {
+    fn alloc(&mut self, layout: Layout) -> AllocResult<NonNull<u8>> {
+        let small = Self::is_small(&layout);
+        let res = match (small, self.slab_inited) {
+            (true, true) => self.slab.alloc(layout),
+            (true, false) => Err(AllocError::NoMemory),
+            (false, _) => self.tlsf.alloc(layout),
+        };
+        if res.is_err() {
+            self.slab_starving = small;
+        }
+        res
+    }
+
+    fn dealloc(&mut self, pos: NonNull<u8>, layout: Layout) {
+        if Self::is_small(&layout) {
+            self.slab.dealloc(pos, layout)
+        } else {
+            self.tlsf.dealloc(pos, layout)
+        }
+    }
+
+    fn total_bytes(&self) -> usize {
+        let slab = if self.slab_inited {
+            self.slab.total_bytes()
+        } else {
+            0
+        };
+        self.tlsf.total_bytes() + slab
+    }
+
+    fn used_bytes(&self) -> usize {
+        let slab = if self.slab_inited {
+            self.slab.used_bytes()
+        } else {
+            0
+        };
+        self.tlsf.used_bytes() + slab
+    }
+
+    fn available_bytes(&self) -> usize {
+        let slab = if self.slab_inited {
+            self.slab.available_bytes()
+        } else {
+            0
+        };
+        self.tlsf.available_bytes() + slab
+    }
+}
@@ -4,27 +4,78 @@
 //! [`core::alloc::GlobalAlloc`]. A static global variable of type
 //! [`GlobalAllocator`] is defined with the `#[global_allocator]` attribute, to
 //! be registered as the standard library’s default allocator.
+//!
+//! # Cargo Features
+//!
+//! - `tlsf`, `slab`, `buddy`: Use the TLSF, slab or buddy byte allocator.
+//! - `hybrid`: Use the [`HybridByteAllocator`], which serves the small blocks
+//!   with the slab allocator and the others with the TLSF allocator.
+//! - `dynamic`: Carry all the byte allocators above, and select one at boot
+//!   with [`GlobalAllocator::select_byte_allocator`]. It overrides the above.
+//! - `smp`: Cache small blocks per CPU in front of the byte allocator, see
+//!   [`GlobalAllocator`].
+//! - `alloc-trace`: Keep the allocation statistics and track the live
+//!   allocations, see [`trace`].
+//!
+//! # Out of memory
+//!
+//! When an allocation fails, the registered [`Shrinker`]s are asked to free
+//! memory before it's retried, and then the [`OomPolicy`] is applied. The
+//! memory pressure is also tracked with watermarks. See [`oom`] for details.
+//! The allocations that can fall back use [`GlobalAllocator::try_alloc_pages`]
+//! to fail at once instead.
 
-#![no_std]
+#![cfg_attr(not(test), no_std)]
 
 #[macro_use]
 extern crate log;
 extern crate alloc;
 
+#[cfg(feature = "smp")]
+mod cpu_cache;
+#[cfg(feature = "dynamic")]
+mod dynamic;
+mod early;
+#[cfg(any(feature = "hybrid", feature = "dynamic"))]
+mod hybrid;
+pub mod oom;
 mod page;
+#[cfg(feature = "alloc-trace")]
+pub mod trace;
+
+#[cfg(test)]
+mod tests;
 
-use allocator::{AllocResult, BaseAllocator, BitmapPageAllocator, ByteAllocator, PageAllocator};
+use allocator::{
+    AllocError, AllocResult, BaseAllocator, BitmapPageAllocator, ByteAllocator, PageAllocator,
+};
 use core::alloc::{GlobalAlloc, Layout};
 use core::ptr::NonNull;
+use early::EarlyHeap;
 use kspin::SpinNoIrq;
 
 const PAGE_SIZE: usize = 0x1000;
 const MIN_HEAP_SIZE: usize = 0x8000; // 32 K
+/// How many times a failed allocation is retried after the shrinkers free
+/// some memory.
+const RECLAIM_RETRIES: usize = 3;
 
+pub use oom::{MemoryPressure, OomPolicy, Shrinker};
 pub use page::GlobalPage;
 
+#[cfg(feature = "dynamic")]
+pub use dynamic::{ByteAllocatorKind, DynamicByteAllocator};
+#[cfg(any(feature = "hybrid", feature = "dynamic"))]
+pub use hybrid::HybridByteAllocator;
+
 cfg_if::cfg_if! {
-    if #[cfg(feature = "slab")] {
+    if #[cfg(feature = "dynamic")] {
+        /// The default byte allocator.
+        pub type DefaultByteAllocator = DynamicByteAllocator;
+    } else if #[cfg(feature = "hybrid")] {
+        /// The default byte allocator.
+        pub type DefaultByteAllocator = HybridByteAllocator;
+    } else if #[cfg(feature = "slab")] {
         /// The default byte allocator.
         pub type DefaultByteAllocator = allocator::SlabByteAllocator;
     } else if #[cfg(feature = "buddy")] {
@@ -43,13 +94,36 @@ cfg_if::cfg_if! {
 /// there is no memory, asks the page allocator for more memory and adds it to
 /// the byte allocator.
 ///
-/// Currently, [`TlsfByteAllocator`] is used as the byte allocator, while
-/// [`BitmapPageAllocator`] is used as the page allocator.
+/// By default, [`TlsfByteAllocator`] is used as the byte allocator, while
+/// [`BitmapPageAllocator`] is used as the page allocator. The byte allocator
+/// can be changed by the cargo features, or at boot with the `dynamic`
+/// feature, see [`select_byte_allocator`].
+///
+/// With the `smp` feature, the small blocks (up to 2 KB, aligned to at most
+/// 16 bytes) are allocated from and freed to per-CPU caches first, which are
+/// refilled from and drained to the byte allocator in batches, so that the
+/// CPUs rarely contend for its lock. The cached blocks are counted as used in
+/// [`used_bytes`].
 ///
+/// If an allocation (of bytes or pages) fails, the memory cached by all the
+/// CPUs and the registered [`Shrinker`]s is reclaimed, and the allocation is
+/// retried. If it still fails, the [`OomPolicy`] is applied.
+///
+/// It can be booted in two phases: the allocations are served by an early
+/// bump allocator from [`init_early`] until [`handoff`], which initializes
+/// the allocator with the free space left by the early allocator. The early
+/// allocations that are still live are kept, and their pages are added to the
+/// byte allocator once all the blocks on them are freed.
+///
+/// [`init_early`]: GlobalAllocator::init_early
+/// [`handoff`]: GlobalAllocator::handoff
+/// [`used_bytes`]: GlobalAllocator::used_bytes
+/// [`select_byte_allocator`]: GlobalAllocator::select_byte_allocator
 /// [`TlsfByteAllocator`]: allocator::TlsfByteAllocator
 pub struct GlobalAllocator {
     balloc: SpinNoIrq<DefaultByteAllocator>,
     palloc: SpinNoIrq<BitmapPageAllocator<PAGE_SIZE>>,
+    early: EarlyHeap,
 }
 
 impl GlobalAllocator {
@@ -58,13 +132,18 @@ impl GlobalAllocator {
         Self {
             balloc: SpinNoIrq::new(DefaultByteAllocator::new()),
             palloc: SpinNoIrq::new(BitmapPageAllocator::new()),
+            early: EarlyHeap::new(),
         }
     }
 
     /// Returns the name of the allocator.
-    pub const fn name(&self) -> &'static str {
+    pub fn name(&self) -> &'static str {
         cfg_if::cfg_if! {
-            if #[cfg(feature = "slab")] {
+            if #[cfg(feature = "dynamic")] {
+                self.balloc.lock().kind().name()
+            } else if #[cfg(feature = "hybrid")] {
+                "hybrid"
+            } else if #[cfg(feature = "slab")] {
                 "slab"
             } else if #[cfg(feature = "buddy")] {
                 "buddy"
@@ -74,6 +153,24 @@ impl GlobalAllocator {
         }
     }
 
+    /// Selects the byte allocator by its name, ignoring the case. It must be
+    /// called before [`init`](Self::init).
+    ///
+    /// With the `dynamic` feature, the name can be `tlsf`, `slab`, `buddy` or
+    /// `hybrid`. Otherwise, only the name of the built-in one is accepted.
+    pub fn select_byte_allocator(&self, name: &str) -> AllocResult {
+        #[cfg(feature = "dynamic")]
+        let selected =
+            ByteAllocatorKind::from_name(name).is_some_and(|kind| self.balloc.lock().select(kind));
+        #[cfg(not(feature = "dynamic"))]
+        let selected = name.eq_ignore_ascii_case(self.name());
+        if selected {
+            Ok(())
+        } else {
+            Err(AllocError::InvalidParam)
+        }
+    }
+
     /// Initializes the allocator with the given region.
     ///
     /// It firstly adds the whole region to the page allocator, then allocates
@@ -84,11 +181,52 @@ impl GlobalAllocator {
         let init_heap_size = MIN_HEAP_SIZE;
         self.palloc.lock().init(start_vaddr, size);
         let heap_ptr = self
-            .alloc_pages(init_heap_size / PAGE_SIZE, PAGE_SIZE)
+            .try_alloc_pages(init_heap_size / PAGE_SIZE, PAGE_SIZE)
             .unwrap();
         self.balloc.lock().init(heap_ptr, init_heap_size);
     }
 
+    /// Starts the early phase with the given region, where the allocations
+    /// are served by a bump allocator until [`handoff`](Self::handoff).
+    ///
+    /// The bump allocator allocates bytes from the start of the region, and
+    /// pages from the end. It only reuses the memory when all the allocations
+    /// of bytes (or pages) are freed.
+    pub fn init_early(&self, start_vaddr: usize, size: usize) {
+        self.early.init(start_vaddr, size);
+    }
+
+    /// Ends the early phase started by [`init_early`], and initializes the
+    /// allocator with the free space between the byte and page allocations
+    /// of the early phase, which must be larger than 32 KB.
+    ///
+    /// The early allocations can still be freed afterwards, and their pages
+    /// are added to the byte allocator once all the blocks on them are freed.
+    ///
+    /// It must be called before the other CPUs and interrupts are started.
+    ///
+    /// [`init_early`]: GlobalAllocator::init_early
+    pub fn handoff(&self) {
+        let (start_vaddr, size) = self.early.finish();
+        debug!(
+            "hand off early allocator, free region: [{:#x}, {:#x})",
+            start_vaddr,
+            start_vaddr + size
+        );
+        self.init(start_vaddr, size);
+    }
+
+    /// Gives the pages freed with the block `[pos, pos + size)`, allocated in
+    /// the early phase and freed after the handoff, to the byte allocator.
+    fn dealloc_early(&self, pos: usize, size: usize) {
+        if let Some((start, size)) = self.early.take_freed(pos, size) {
+            debug!("reclaim early memory: [{:#x}, {:#x})", start, start + size);
+            if let Err(e) = self.add_memory(start, size) {
+                warn!("failed to reclaim early memory: {:?}", e);
+            }
+        }
+    }
+
     /// Add the given region to the allocator.
     ///
     /// It will add the whole region to the byte allocator.
@@ -103,8 +241,27 @@ impl GlobalAllocator {
     /// memory, it asks the page allocator for more memory and adds it to the
     /// byte allocator.
     pub fn alloc(&self, layout: Layout) -> AllocResult<NonNull<u8>> {
-        // simple two-level allocator: if no heap memory, allocate from the page allocator.
+        self.reclaim_retry(layout.size(), || self.try_alloc(layout))
+    }
+
+    fn try_alloc(&self, layout: Layout) -> AllocResult<NonNull<u8>> {
+        if self.early.is_active() {
+            return self.early.alloc(layout);
+        }
+        #[cfg(feature = "smp")]
+        if let Some(class) = cpu_cache::size_class(layout) {
+            return cpu_cache::alloc(class, |blocks| self.refill(class, blocks));
+        }
         let mut balloc = self.balloc.lock();
+        self.alloc_in(&mut balloc, layout)
+    }
+
+    fn alloc_in(
+        &self,
+        balloc: &mut DefaultByteAllocator,
+        layout: Layout,
+    ) -> AllocResult<NonNull<u8>> {
+        // simple two-level allocator: if no heap memory, allocate from the page allocator.
         loop {
             if let Ok(ptr) = balloc.alloc(layout) {
                 return Ok(ptr);
@@ -114,7 +271,7 @@ impl GlobalAllocator {
                     .max(layout.size())
                     .next_power_of_two()
                     .max(PAGE_SIZE);
-                let heap_ptr = self.alloc_pages(expand_size / PAGE_SIZE, PAGE_SIZE)?;
+                let heap_ptr = self.try_alloc_pages(expand_size / PAGE_SIZE, PAGE_SIZE)?;
                 debug!(
                     "expand heap memory: [{:#x}, {:#x})",
                     heap_ptr,
@@ -133,9 +290,90 @@ impl GlobalAllocator {
     ///
     /// [`alloc`]: GlobalAllocator::alloc
     pub fn dealloc(&self, pos: NonNull<u8>, layout: Layout) {
+        if self.early.owns(pos.as_ptr() as usize) {
+            if self.early.is_active() {
+                return self.early.dealloc(pos, layout);
+            }
+            return self.dealloc_early(pos.as_ptr() as usize, layout.size());
+        }
+        #[cfg(feature = "smp")]
+        if let Some(class) = cpu_cache::size_class(layout) {
+            return cpu_cache::dealloc(class, pos, |blocks| self.drain(class, blocks));
+        }
         self.balloc.lock().dealloc(pos, layout)
     }
 
+    /// Fills `blocks` with the blocks of the size class from the byte
+    /// allocator. Returns the number of blocks filled, at least one.
+    #[cfg(feature = "smp")]
+    fn refill(&self, class: usize, blocks: &mut [usize]) -> AllocResult<usize> {
+        let layout = cpu_cache::class_layout(class);
+        let mut balloc = self.balloc.lock();
+        for (i, block) in blocks.iter_mut().enumerate() {
+            match self.alloc_in(&mut balloc, layout) {
+                Ok(ptr) => *block = ptr.as_ptr() as usize,
+                Err(e) if i == 0 => return Err(e),
+                Err(_) => return Ok(i),
+            }
+        }
+        Ok(blocks.len())
+    }
+
+    /// Gives the blocks of the size class back to the byte allocator.
+    #[cfg(feature = "smp")]
+    fn drain(&self, class: usize, blocks: &[usize]) {
+        let layout = cpu_cache::class_layout(class);
+        let mut balloc = self.balloc.lock();
+        for &block in blocks {
+            balloc.dealloc(NonNull::new(block as *mut u8).unwrap(), layout);
+        }
+    }
+
+    /// Gives the blocks cached by all the CPUs back to the byte allocator,
+    /// e.g., when memory is short. Returns the number of bytes given back.
+    #[cfg(feature = "smp")]
+    pub fn drain_cpu_caches(&self) -> usize {
+        cpu_cache::drain_all(|class, blocks| self.drain(class, blocks))
+    }
+
+    /// Retries the allocation `f` of `size` bytes after reclaiming memory, if
+    /// it fails for no memory, and applies the OOM policy if it still fails.
+    ///
+    /// It must not be called with the allocator locks held, as the reclaimed
+    /// memory is freed to the allocator.
+    fn reclaim_retry<T>(&self, size: usize, f: impl Fn() -> AllocResult<T>) -> AllocResult<T> {
+        for _ in 0..RECLAIM_RETRIES {
+            match f() {
+                Err(AllocError::NoMemory) => {}
+                res => return res,
+            }
+            if self.reclaim(size) == 0 {
+                break;
+            }
+        }
+        let res = f();
+        // The allocations of the shrinkers just fail.
+        if matches!(res, Err(AllocError::NoMemory)) && !oom::is_shrinking() {
+            #[cfg(feature = "alloc-trace")]
+            error!("out of memory:\n{}", trace::report());
+            oom::out_of_memory(size, self.available_pages());
+        }
+        res
+    }
+
+    /// Frees the memory cached by all the CPUs and the shrinkers, trying to
+    /// free at least `size` bytes. Returns the number of bytes freed.
+    fn reclaim(&self, size: usize) -> usize {
+        #[cfg(feature = "smp")]
+        let cached = self.drain_cpu_caches();
+        #[cfg(not(feature = "smp"))]
+        let cached = 0;
+        if cached >= size {
+            return cached;
+        }
+        cached + oom::shrink(size - cached)
+    }
+
     /// Allocates contiguous pages.
     ///
     /// It allocates `num_pages` pages from the page allocator.
@@ -143,7 +381,30 @@ impl GlobalAllocator {
     /// `align_pow2` must be a power of 2, and the returned region bound will be
     /// aligned to it.
     pub fn alloc_pages(&self, num_pages: usize, align_pow2: usize) -> AllocResult<usize> {
-        self.palloc.lock().alloc_pages(num_pages, align_pow2)
+        self.reclaim_retry(num_pages * PAGE_SIZE, || {
+            self.try_alloc_pages(num_pages, align_pow2)
+        })
+    }
+
+    /// Allocates contiguous pages like [`alloc_pages`], but neither reclaims
+    /// memory nor applies the [`OomPolicy`] if it fails.
+    ///
+    /// It's for the opportunistic allocations that can fall back, e.g., to
+    /// smaller pages.
+    ///
+    /// [`alloc_pages`]: GlobalAllocator::alloc_pages
+    pub fn try_alloc_pages(&self, num_pages: usize, align_pow2: usize) -> AllocResult<usize> {
+        if self.early.is_active() {
+            return self.early.alloc_pages(num_pages, align_pow2);
+        }
+        let mut palloc = self.palloc.lock();
+        let res = palloc.alloc_pages(num_pages, align_pow2);
+        #[cfg(feature = "alloc-trace")]
+        if res.is_ok() {
+            trace::record_pages(palloc.used_pages());
+        }
+        oom::update_pressure(palloc.available_pages());
+        res
     }
 
     /// Gives back the allocated pages starts from `pos` to the page allocator.
@@ -154,40 +415,101 @@ impl GlobalAllocator {
     ///
     /// [`alloc_pages`]: GlobalAllocator::alloc_pages
     pub fn dealloc_pages(&self, pos: usize, num_pages: usize) {
-        self.palloc.lock().dealloc_pages(pos, num_pages)
+        if self.early.owns(pos) {
+            if self.early.is_active() {
+                return self.early.dealloc_pages(pos, num_pages);
+            }
+            return self.dealloc_early(pos, num_pages * PAGE_SIZE);
+        }
+        let mut palloc = self.palloc.lock();
+        palloc.dealloc_pages(pos, num_pages);
+        oom::update_pressure(palloc.available_pages());
     }
 
     /// Returns the number of allocated bytes in the byte allocator.
     pub fn used_bytes(&self) -> usize {
+        if self.early.is_active() {
+            return self.early.usage().0;
+        }
         self.balloc.lock().used_bytes()
     }
 
     /// Returns the number of available bytes in the byte allocator.
     pub fn available_bytes(&self) -> usize {
+        if self.early.is_active() {
+            return self.early.usage().1;
+        }
         self.balloc.lock().available_bytes()
     }
 
     /// Returns the number of allocated pages in the page allocator.
     pub fn used_pages(&self) -> usize {
+        if self.early.is_active() {
+            return self.early.usage().2;
+        }
         self.palloc.lock().used_pages()
     }
 
     /// Returns the number of available pages in the page allocator.
     pub fn available_pages(&self) -> usize {
+        if self.early.is_active() {
+            return self.early.usage().3;
+        }
         self.palloc.lock().available_pages()
     }
 }
 
-unsafe impl GlobalAlloc for GlobalAllocator {
-    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+impl GlobalAllocator {
+    /// Allocates for the [`GlobalAlloc`] interface, and records the allocation
+    /// made by `caller` with `alloc-trace`.
+    #[cfg_attr(not(feature = "alloc-trace"), allow(unused_variables))]
+    #[inline(always)]
+    fn global_alloc(&self, layout: Layout, caller: usize) -> *mut u8 {
         if let Ok(ptr) = GlobalAllocator::alloc(self, layout) {
+            #[cfg(feature = "alloc-trace")]
+            trace::record_alloc(ptr.as_ptr() as usize, layout, caller);
             ptr.as_ptr()
         } else {
             alloc::alloc::handle_alloc_error(layout)
         }
     }
+}
+
+unsafe impl GlobalAlloc for GlobalAllocator {
+    // Not inlined with `alloc-trace`, so that the walk to the caller starts
+    // from its own frame.
+    #[cfg_attr(feature = "alloc-trace", inline(never))]
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        #[cfg(feature = "alloc-trace")]
+        let caller = trace::caller(trace::Entry::Alloc);
+        #[cfg(not(feature = "alloc-trace"))]
+        let caller = 0;
+        self.global_alloc(layout, caller)
+    }
+
+    // The default `alloc_zeroed` and `realloc` call `alloc` from another
+    // shim, so they are traced from their own frames.
+    #[cfg(feature = "alloc-trace")]
+    #[inline(never)]
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let ptr = self.global_alloc(layout, trace::caller(trace::Entry::AllocZeroed));
+        ptr.write_bytes(0, layout.size());
+        ptr
+    }
+
+    #[cfg(feature = "alloc-trace")]
+    #[inline(never)]
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+        let new_ptr = self.global_alloc(new_layout, trace::caller(trace::Entry::Realloc));
+        core::ptr::copy_nonoverlapping(ptr, new_ptr, layout.size().min(new_size));
+        GlobalAlloc::dealloc(self, ptr, layout);
+        new_ptr
+    }
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        #[cfg(feature = "alloc-trace")]
+        trace::record_dealloc(ptr as usize, layout);
         GlobalAllocator::dealloc(self, NonNull::new(ptr).expect("dealloc null ptr"), layout)
     }
 }
@@ -215,6 +537,30 @@ pub fn global_init(start_vaddr: usize, size: usize) {
         start_vaddr + size
     );
     GLOBAL_ALLOCATOR.init(start_vaddr, size);
+    #[cfg(all(feature = "alloc-trace", target_os = "none"))]
+    trace::calibrate();
+}
+
+/// Starts the early phase of the global allocator with the given memory
+/// region, see [`GlobalAllocator::init_early`].
+///
+/// It's an alternative to [`global_init`], which should be followed by
+/// [`global_handoff`] once the boot-time allocations are done.
+pub fn global_init_early(start_vaddr: usize, size: usize) {
+    debug!(
+        "initialize early allocator at: [{:#x}, {:#x})",
+        start_vaddr,
+        start_vaddr + size
+    );
+    GLOBAL_ALLOCATOR.init_early(start_vaddr, size);
+    #[cfg(all(feature = "alloc-trace", target_os = "none"))]
+    trace::calibrate();
+}
+
+/// Ends the early phase of the global allocator started by
+/// [`global_init_early`], see [`GlobalAllocator::handoff`].
+pub fn global_handoff() {
+    GLOBAL_ALLOCATOR.handoff();
 }
 
 /// Add the given memory region to the global allocator.
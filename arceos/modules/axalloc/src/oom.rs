@@ -0,0 +1,218 @@
+//! Out-of-memory (OOM) handling and memory pressure.
+//!
+//! - **Shrinkers**: caches (e.g., a block cache) can register a [`Shrinker`]
+//!   with [`register_shrinker`]. When an allocation fails, the shrinkers are
+//!   asked to free memory, and the allocation is retried.
+//! - **Watermarks**: the memory pressure becomes [`MemoryPressure::Low`] when
+//!   the available pages drop below the low watermark, and goes back to
+//!   [`MemoryPressure::Normal`] when they reach the high watermark, see
+//!   [`set_watermarks`]. Each change is counted in [`pressure_events`], on
+//!   which the tasks can wait (with the `oom` feature of `axtask`).
+//! - **OOM policy**: if an allocation still fails after the shrinkers are
+//!   run, the [`OomPolicy`] decides what to do, see [`set_oom_policy`].
+//!
+//! All of these are done without allocating memory, and the shrinkers are
+//! called with no allocator lock held.
+
+use core::sync::atomic::{AtomicBool, AtomicU8, AtomicUsize, Ordering};
+
+use kspin::SpinNoIrq;
+
+/// The maximum number of shrinkers that can be registered.
+pub const MAX_SHRINKERS: usize = 16;
+
+/// Something that holds memory it can give back when memory is short, such as
+/// a cache.
+pub trait Shrinker: Sync {
+    /// The name of the shrinker, for logging.
+    fn name(&self) -> &str;
+
+    /// Frees some memory, trying to free at least `bytes` bytes. Returns the
+    /// number of bytes freed.
+    ///
+    /// It's called when an allocation fails, possibly in an interrupt
+    /// context or with other locks held, so it must not block. It may
+    /// allocate memory, but the shrinkers are not called again for the
+    /// nested allocations, nor for those failing on the other CPUs meanwhile,
+    /// and the OOM policy is not applied to them.
+    fn shrink(&self, bytes: usize) -> usize;
+}
+
+/// What to do when an allocation fails even after the shrinkers are run.
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OomPolicy {
+    /// Fails the allocation, it's the default.
+    Fail = 0,
+    /// Panics with the memory usage.
+    Panic = 1,
+    /// Fails the allocation, and requests the largest task to be killed so
+    /// that the later allocations can succeed. The kill is done by the OOM
+    /// reaper of `axtask` (with its `oom` feature), which takes the request
+    /// with [`take_kill_request`].
+    KillLargest = 2,
+}
+
+/// The memory pressure, decided by the watermarks.
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryPressure {
+    /// There is enough memory.
+    Normal = 0,
+    /// The available pages drop below the low watermark.
+    Low = 1,
+}
+
+static SHRINKERS: SpinNoIrq<[Option<&'static dyn Shrinker>; MAX_SHRINKERS]> =
+    SpinNoIrq::new([None; MAX_SHRINKERS]);
+/// The shrinkers are running, so that they are not run again before they
+/// return.
+static SHRINKING: AtomicBool = AtomicBool::new(false);
+
+static POLICY: AtomicU8 = AtomicU8::new(OomPolicy::Fail as u8);
+static KILL_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+static LOW_WATERMARK: AtomicUsize = AtomicUsize::new(0);
+static HIGH_WATERMARK: AtomicUsize = AtomicUsize::new(0);
+static PRESSURE: AtomicU8 = AtomicU8::new(MemoryPressure::Normal as u8);
+static PRESSURE_EVENTS: AtomicUsize = AtomicUsize::new(0);
+
+/// Registers a shrinker to be called when memory is short.
+///
+/// Returns `false` if [`MAX_SHRINKERS`] shrinkers are already registered.
+pub fn register_shrinker(shrinker: &'static dyn Shrinker) -> bool {
+    let mut shrinkers = SHRINKERS.lock();
+    match shrinkers.iter_mut().find(|s| s.is_none()) {
+        Some(slot) => {
+            *slot = Some(shrinker);
+            true
+        }
+        None => false,
+    }
+}
+
+/// Unregisters a shrinker registered by [`register_shrinker`].
+pub fn unregister_shrinker(shrinker: &'static dyn Shrinker) {
+    for slot in SHRINKERS.lock().iter_mut() {
+        if slot.is_some_and(|s| core::ptr::addr_eq(s, shrinker)) {
+            *slot = None;
+        }
+    }
+}
+
+/// Asks the shrinkers to free `bytes` bytes. Returns the number of bytes
+/// freed.
+pub(crate) fn shrink(bytes: usize) -> usize {
+    if SHRINKING.swap(true, Ordering::Acquire) {
+        return 0;
+    }
+    // Copied out, so that the shrinkers can be (un)registered by a shrinker.
+    let shrinkers = *SHRINKERS.lock();
+    let mut freed = 0;
+    for shrinker in shrinkers.iter().flatten() {
+        let n = shrinker.shrink(bytes - freed);
+        debug!("shrinker {:?} freed {} bytes", shrinker.name(), n);
+        freed += n;
+        if freed >= bytes {
+            break;
+        }
+    }
+    SHRINKING.store(false, Ordering::Release);
+    freed
+}
+
+/// Whether the shrinkers are running.
+pub(crate) fn is_shrinking() -> bool {
+    SHRINKING.load(Ordering::Acquire)
+}
+
+/// Sets the OOM policy.
+pub fn set_oom_policy(policy: OomPolicy) {
+    POLICY.store(policy as u8, Ordering::Relaxed);
+}
+
+/// Returns the OOM policy.
+pub fn oom_policy() -> OomPolicy {
+    match POLICY.load(Ordering::Relaxed) {
+        1 => OomPolicy::Panic,
+        2 => OomPolicy::KillLargest,
+        _ => OomPolicy::Fail,
+    }
+}
+
+/// Takes the request to kill the largest task, made by
+/// [`OomPolicy::KillLargest`]. Returns whether there was one.
+pub fn take_kill_request() -> bool {
+    KILL_REQUESTED.swap(false, Ordering::Acquire)
+}
+
+/// Applies the OOM policy, after an allocation of `size` bytes fails even
+/// though the shrinkers are run.
+pub(crate) fn out_of_memory(size: usize, available_pages: usize) {
+    match oom_policy() {
+        OomPolicy::Fail => {}
+        OomPolicy::Panic => panic!(
+            "out of memory: failed to allocate {} bytes, {} pages available",
+            size, available_pages
+        ),
+        OomPolicy::KillLargest => {
+            warn!(
+                "out of memory: failed to allocate {} bytes, killing the largest task",
+                size
+            );
+            KILL_REQUESTED.store(true, Ordering::Release);
+        }
+    }
+}
+
+/// Sets the watermarks in pages: the memory pressure becomes low when the
+/// available pages drop below `low`, and goes back to normal when they reach
+/// `high`. Both are zero by default, which never reports low memory.
+///
+/// # Panics
+///
+/// Panics if `low` is greater than `high`.
+pub fn set_watermarks(low: usize, high: usize) {
+    assert!(low <= high);
+    LOW_WATERMARK.store(low, Ordering::Relaxed);
+    HIGH_WATERMARK.store(high, Ordering::Relaxed);
+}
+
+/// Returns the watermarks in pages, as `(low, high)`.
+pub fn watermarks() -> (usize, usize) {
+    (
+        LOW_WATERMARK.load(Ordering::Relaxed),
+        HIGH_WATERMARK.load(Ordering::Relaxed),
+    )
+}
+
+/// Returns the current memory pressure.
+pub fn memory_pressure() -> MemoryPressure {
+    match PRESSURE.load(Ordering::Acquire) {
+        1 => MemoryPressure::Low,
+        _ => MemoryPressure::Normal,
+    }
+}
+
+/// Returns the number of the changes of the memory pressure so far.
+///
+/// A waiter can compare it with the value it saw before, without missing a
+/// change that comes back soon.
+pub fn pressure_events() -> usize {
+    PRESSURE_EVENTS.load(Ordering::Acquire)
+}
+
+/// Updates the memory pressure with the available pages of the page
+/// allocator.
+pub(crate) fn update_pressure(available_pages: usize) {
+    let level = if available_pages < LOW_WATERMARK.load(Ordering::Relaxed) {
+        MemoryPressure::Low
+    } else if available_pages >= HIGH_WATERMARK.load(Ordering::Relaxed) {
+        MemoryPressure::Normal
+    } else {
+        return;
+    };
+    if PRESSURE.swap(level as u8, Ordering::AcqRel) != level as u8 {
+        PRESSURE_EVENTS.fetch_add(1, Ordering::Release);
+    }
+}
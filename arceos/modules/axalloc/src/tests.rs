@@ -0,0 +1,403 @@
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::vec::Vec;
+
+use allocator::AllocError;
+use core::alloc::Layout;
+use core::ptr::NonNull;
+
+use crate::oom::{
+    memory_pressure, pressure_events, register_shrinker, set_oom_policy, set_watermarks,
+    unregister_shrinker,
+};
+use crate::{GlobalAllocator, MemoryPressure, OomPolicy, Shrinker, PAGE_SIZE};
+
+/// The OOM policy, the shrinkers and the watermarks are global.
+static SERIAL: Mutex<()> = Mutex::new(());
+
+const HEAP_SIZE: usize = 0x10_0000;
+
+/// Allocates a page-aligned region from the host.
+fn region(size: usize) -> usize {
+    let layout = Layout::from_size_align(size, PAGE_SIZE).unwrap();
+    unsafe { std::alloc::alloc(layout) as usize }
+}
+
+fn init(alloc: &GlobalAllocator) {
+    alloc.init(region(HEAP_SIZE), HEAP_SIZE);
+}
+
+/// Allocates all the free pages.
+fn exhaust(alloc: &GlobalAllocator) -> Vec<usize> {
+    let mut pages = Vec::new();
+    while let Ok(page) = alloc.try_alloc_pages(1, PAGE_SIZE) {
+        pages.push(page);
+    }
+    assert_eq!(alloc.available_pages(), 0);
+    pages
+}
+
+/// A shrinker that frees the pages it holds.
+struct PageHolder {
+    alloc: &'static GlobalAllocator,
+    pages: Mutex<Vec<usize>>,
+}
+
+impl Shrinker for PageHolder {
+    fn name(&self) -> &str {
+        "page holder"
+    }
+
+    fn shrink(&self, bytes: usize) -> usize {
+        let mut pages = self.pages.lock().unwrap();
+        let mut freed = 0;
+        while freed < bytes {
+            let Some(page) = pages.pop() else {
+                break;
+            };
+            self.alloc.dealloc_pages(page, 1);
+            freed += PAGE_SIZE;
+        }
+        freed
+    }
+}
+
+#[test]
+fn test_shrink_and_retry() {
+    static ALLOC: GlobalAllocator = GlobalAllocator::new();
+    static HOLDER: PageHolder = PageHolder {
+        alloc: &ALLOC,
+        pages: Mutex::new(Vec::new()),
+    };
+    let _lock = SERIAL.lock();
+    init(&ALLOC);
+
+    let mut pages = exhaust(&ALLOC);
+    HOLDER.pages.lock().unwrap().extend(pages.drain(..2));
+    assert!(register_shrinker(&HOLDER));
+
+    // One page is reclaimed for the retry.
+    let page = ALLOC.alloc_pages(1, PAGE_SIZE).unwrap();
+    assert_eq!(HOLDER.pages.lock().unwrap().len(), 1);
+    pages.push(page);
+
+    // Not enough even after the shrinker gives all.
+    assert!(matches!(
+        ALLOC.alloc_pages(2, PAGE_SIZE),
+        Err(AllocError::NoMemory)
+    ));
+    assert!(HOLDER.pages.lock().unwrap().is_empty());
+    assert_eq!(ALLOC.available_pages(), 1);
+    unregister_shrinker(&HOLDER);
+}
+
+#[test]
+fn test_try_alloc_no_oom() {
+    static ALLOC: GlobalAllocator = GlobalAllocator::new();
+    static NESTED_FAILED: AtomicBool = AtomicBool::new(false);
+
+    /// A shrinker that only tries to allocate.
+    struct Allocating;
+    static ALLOCATING: Allocating = Allocating;
+
+    impl Shrinker for Allocating {
+        fn name(&self) -> &str {
+            "allocating"
+        }
+
+        fn shrink(&self, _bytes: usize) -> usize {
+            let res = ALLOC.alloc_pages(1, PAGE_SIZE);
+            NESTED_FAILED.store(res.is_err(), Ordering::Relaxed);
+            0
+        }
+    }
+
+    let _lock = SERIAL.lock();
+    init(&ALLOC);
+    let _pages = exhaust(&ALLOC);
+
+    set_oom_policy(OomPolicy::Panic);
+    // Fails at once.
+    assert!(matches!(
+        ALLOC.try_alloc_pages(1, PAGE_SIZE),
+        Err(AllocError::NoMemory)
+    ));
+    // The allocation of a shrinker fails without the OOM policy, then the
+    // one that called the shrinker panics.
+    assert!(register_shrinker(&ALLOCATING));
+    let res = catch_unwind(AssertUnwindSafe(|| ALLOC.alloc_pages(1, PAGE_SIZE)));
+    assert!(res.is_err());
+    assert!(NESTED_FAILED.load(Ordering::Relaxed));
+    unregister_shrinker(&ALLOCATING);
+
+    set_oom_policy(OomPolicy::Fail);
+    assert!(matches!(
+        ALLOC.alloc_pages(1, PAGE_SIZE),
+        Err(AllocError::NoMemory)
+    ));
+}
+
+#[test]
+fn test_watermarks() {
+    static ALLOC: GlobalAllocator = GlobalAllocator::new();
+    let _lock = SERIAL.lock();
+    init(&ALLOC);
+
+    let total = ALLOC.available_pages();
+    set_watermarks(total / 2, total * 3 / 4);
+    let events = pressure_events();
+    assert_eq!(memory_pressure(), MemoryPressure::Normal);
+
+    let mut pages: Vec<_> = (0..total - total / 2 + 1)
+        .map(|_| ALLOC.alloc_pages(1, PAGE_SIZE).unwrap())
+        .collect();
+    assert_eq!(memory_pressure(), MemoryPressure::Low);
+    assert_eq!(pressure_events(), events + 1);
+
+    // Still low until the high watermark is reached.
+    ALLOC.dealloc_pages(pages.pop().unwrap(), 1);
+    assert_eq!(memory_pressure(), MemoryPressure::Low);
+    assert_eq!(pressure_events(), events + 1);
+
+    for page in pages {
+        ALLOC.dealloc_pages(page, 1);
+    }
+    assert_eq!(memory_pressure(), MemoryPressure::Normal);
+    assert_eq!(pressure_events(), events + 2);
+    set_watermarks(0, 0);
+}
+
+#[test]
+fn test_early_reclaim() {
+    static ALLOC: GlobalAllocator = GlobalAllocator::new();
+    let _lock = SERIAL.lock();
+
+    let heap = region(HEAP_SIZE);
+    ALLOC.init_early(heap, HEAP_SIZE);
+
+    // Two small blocks on the first page with a padding between them, then a
+    // block on the first 4 pages.
+    let small = Layout::from_size_align(8, 8).unwrap();
+    let aligned = Layout::from_size_align(64, 64).unwrap();
+    let large = Layout::from_size_align(3 * PAGE_SIZE, 8).unwrap();
+    let a = ALLOC.alloc(small).unwrap();
+    let b = ALLOC.alloc(aligned).unwrap();
+    let c = ALLOC.alloc(large).unwrap();
+    assert_eq!(a.as_ptr() as usize, heap);
+    assert_eq!(b.as_ptr() as usize, heap + 64);
+    assert_eq!(c.as_ptr() as usize, heap + 128);
+    let pages = ALLOC.alloc_pages(2, PAGE_SIZE).unwrap();
+    let singles: Vec<_> = (0..150)
+        .map(|_| ALLOC.alloc_pages(1, PAGE_SIZE).unwrap())
+        .collect();
+    ALLOC.handoff();
+
+    let early = &ALLOC.early;
+    let avail = ALLOC.available_bytes();
+    // The first page is still shared with `a` and `b`, the other 3 are freed.
+    ALLOC.dealloc(c, large);
+    assert_eq!(ALLOC.available_bytes(), avail + 3 * PAGE_SIZE);
+    assert!(early.owns(heap));
+    assert!(!early.owns(heap + PAGE_SIZE) && !early.owns(heap + 3 * PAGE_SIZE));
+
+    // The padding does not keep the page.
+    ALLOC.dealloc(a, small);
+    assert!(early.owns(heap));
+    ALLOC.dealloc(b, aligned);
+    assert_eq!(ALLOC.available_bytes(), avail + 4 * PAGE_SIZE);
+    assert!(!early.owns(heap));
+
+    // The page blocks are freed as a whole.
+    ALLOC.dealloc_pages(pages, 2);
+    assert_eq!(ALLOC.available_bytes(), avail + 6 * PAGE_SIZE);
+    assert!(!early.owns(pages) && !early.owns(pages + PAGE_SIZE));
+
+    // No limit on the number of the separate ranges freed.
+    for &page in singles.iter().step_by(2) {
+        ALLOC.dealloc_pages(page, 1);
+    }
+    let avail = avail + (6 + 75) * PAGE_SIZE;
+    assert_eq!(ALLOC.available_bytes(), avail);
+    assert!(singles
+        .iter()
+        .skip(1)
+        .step_by(2)
+        .all(|&page| early.owns(page)));
+
+    // The blocks allocated from the reclaimed memory go to the byte allocator.
+    let ptr = ALLOC.alloc(large).unwrap();
+    ALLOC.dealloc(ptr, large);
+    assert_eq!(ALLOC.available_bytes(), avail);
+}
+
+#[cfg(any(feature = "hybrid", feature = "dynamic"))]
+#[test]
+fn test_hybrid_expand() {
+    use crate::HybridByteAllocator;
+    use allocator::{BaseAllocator, ByteAllocator};
+
+    let small = Layout::from_size_align(64, 8).unwrap();
+    let large = Layout::from_size_align(0x20000, 8).unwrap();
+    let mut hybrid = HybridByteAllocator::new();
+    hybrid.init(region(0x10000), 0x10000);
+
+    // The slab allocator is not initialized until it asks for memory.
+    assert!(hybrid.alloc(small).is_err());
+    // But a large allocation fails later, the next region is for it.
+    assert!(hybrid.alloc(large).is_err());
+    hybrid.add_memory(region(0x40000), 0x40000).unwrap();
+    let ptr = hybrid.alloc(large).unwrap();
+    assert!(hybrid.alloc(small).is_err());
+    hybrid.add_memory(region(0x8000), 0x8000).unwrap();
+    let small_ptr = hybrid.alloc(small).unwrap();
+
+    let used = hybrid.used_bytes();
+    assert!(used >= large.size() + small.size());
+    hybrid.dealloc(ptr, large);
+    hybrid.dealloc(small_ptr, small);
+    assert!(hybrid.used_bytes() < used);
+}
+
+#[cfg(feature = "dynamic")]
+#[test]
+fn test_dynamic_select() {
+    use crate::{ByteAllocatorKind, DynamicByteAllocator};
+    use allocator::{BaseAllocator, ByteAllocator};
+
+    const SIZE: usize = 0x40000;
+    let layouts = [(8, 8), (100, 16), (4096, 4096), (0x10000, 8)]
+        .map(|(size, align)| Layout::from_size_align(size, align).unwrap());
+
+    for kind in ByteAllocatorKind::ALL {
+        assert_eq!(
+            ByteAllocatorKind::from_name(&kind.name().to_uppercase()),
+            Some(kind)
+        );
+        let mut dynamic = DynamicByteAllocator::new();
+        assert!(dynamic.select(kind));
+        dynamic.init(region(SIZE), SIZE);
+        assert_eq!(dynamic.kind(), kind);
+        // Cannot be changed once initialized.
+        let other = ByteAllocatorKind::ALL
+            .into_iter()
+            .find(|&k| k != kind)
+            .unwrap();
+        assert!(!dynamic.select(other));
+
+        let used = dynamic.used_bytes();
+        let ptrs: Vec<_> = layouts
+            .iter()
+            .map(|&layout| {
+                // The hybrid one gives the small blocks their own region.
+                let ptr = dynamic.alloc(layout).or_else(|_| {
+                    dynamic.add_memory(region(SIZE), SIZE)?;
+                    dynamic.alloc(layout)
+                });
+                let ptr = ptr.unwrap_or_else(|e| panic!("{}: {:?}: {:?}", kind.name(), layout, e));
+                assert_eq!(ptr.as_ptr() as usize % layout.align(), 0);
+                ptr
+            })
+            .collect();
+        for (ptr, layout) in ptrs.into_iter().zip(layouts) {
+            dynamic.dealloc(ptr, layout);
+        }
+        assert_eq!(dynamic.used_bytes(), used, "{}", kind.name());
+    }
+}
+
+#[cfg(feature = "smp")]
+#[test]
+fn test_cpu_cache_reclaim() {
+    use crate::cpu_cache::{self, BATCH};
+
+    static ALLOC: GlobalAllocator = GlobalAllocator::new();
+    let _lock = SERIAL.lock();
+    init(&ALLOC);
+
+    let layout = Layout::from_size_align(64, 8).unwrap();
+    let class = cpu_cache::size_class(layout).unwrap();
+    let used = ALLOC.used_bytes();
+    // Two refills, the second one leaves `BATCH - 4` blocks cached.
+    let ptrs: Vec<_> = (0..BATCH + 4)
+        .map(|_| ALLOC.alloc(layout).unwrap().as_ptr() as usize)
+        .collect();
+    assert_eq!(ALLOC.used_bytes(), used + 2 * BATCH * 64);
+
+    // Half of the blocks are freed on this CPU, the others on another one.
+    let (local, remote) = ptrs.split_at(ptrs.len() / 2);
+    for &ptr in local {
+        ALLOC.dealloc(NonNull::new(ptr as *mut u8).unwrap(), layout);
+    }
+    cpu_cache::cache_on_other_cpu(class, remote);
+    assert_eq!(ALLOC.used_bytes(), used + 2 * BATCH * 64);
+
+    // The caches of all the CPUs are reclaimed.
+    assert_eq!(ALLOC.reclaim(1), 2 * BATCH * 64);
+    assert_eq!(ALLOC.used_bytes(), used);
+    assert_eq!(ALLOC.drain_cpu_caches(), 0);
+}
+
+#[cfg(feature = "smp")]
+#[test]
+fn test_cpu_cache_align() {
+    use crate::cpu_cache;
+
+    static ALLOC: GlobalAllocator = GlobalAllocator::new();
+    let _lock = SERIAL.lock();
+    init(&ALLOC);
+
+    // The blocks are aligned to 16 bytes, not to their size.
+    let small = Layout::from_size_align(1024, 16).unwrap();
+    let class = cpu_cache::size_class(small).unwrap();
+    assert_eq!(cpu_cache::class_layout(class).align(), 16);
+    let used = ALLOC.used_bytes();
+    let ptr = ALLOC.alloc(small).unwrap();
+    assert_eq!(ptr.as_ptr() as usize % 16, 0);
+    ALLOC.dealloc(ptr, small);
+
+    // A larger alignment bypasses the caches.
+    let aligned = Layout::from_size_align(64, 64).unwrap();
+    assert!(cpu_cache::size_class(aligned).is_none());
+    let ptr = ALLOC.alloc(aligned).unwrap();
+    assert_eq!(ptr.as_ptr() as usize % 64, 0);
+    ALLOC.dealloc(ptr, aligned);
+
+    assert_eq!(ALLOC.drain_cpu_caches(), cpu_cache::BATCH * 1024);
+    assert_eq!(ALLOC.used_bytes(), used);
+}
+
+/// Compares the cost of the small allocations with and without the per-CPU
+/// cache. Run it with `cargo test -p axalloc --features smp --release --
+/// --ignored --nocapture bench_cpu_cache`.
+#[cfg(feature = "smp")]
+#[test]
+#[ignore]
+fn bench_cpu_cache() {
+    use allocator::ByteAllocator;
+    use std::time::Instant;
+
+    const ROUNDS: usize = 100_000;
+    const LIVE: usize = 16;
+    static ALLOC: GlobalAllocator = GlobalAllocator::new();
+    let _lock = SERIAL.lock();
+    init(&ALLOC);
+
+    let layout = Layout::from_size_align(64, 8).unwrap();
+    let run = |alloc: &dyn Fn() -> NonNull<u8>, dealloc: &dyn Fn(NonNull<u8>)| {
+        let start = Instant::now();
+        for _ in 0..ROUNDS {
+            let ptrs: [_; LIVE] = core::array::from_fn(|_| alloc());
+            ptrs.into_iter().for_each(dealloc);
+        }
+        start.elapsed().as_nanos() / (ROUNDS * LIVE) as u128
+    };
+    let cached = run(&|| ALLOC.alloc(layout).unwrap(), &|ptr| {
+        ALLOC.dealloc(ptr, layout)
+    });
+    let uncached = run(&|| ALLOC.balloc.lock().alloc(layout).unwrap(), &|ptr| {
+        ALLOC.balloc.lock().dealloc(ptr, layout)
+    });
+    println!("alloc + dealloc: {cached} ns cached, {uncached} ns uncached");
+    ALLOC.drain_cpu_caches();
+}
@@ -0,0 +1,617 @@
+//! Allocation profiling and leak tracking.
+//!
+//! The allocations through the [`GlobalAlloc`] interface, i.e., the Rust and
+//! C heap, are recorded in:
+//!
+//! - a histogram of the sizes, in power-of-two classes;
+//! - the high-water marks of the heap bytes in use and of the used pages;
+//! - the allocation counts grouped by the caller return address;
+//! - the table of live allocations, which can be [`snapshot`]ted, and two
+//!   snapshots can be [diffed](Snapshot::diff) to find the leaks.
+//!
+//! The records are kept in fixed-size tables, so that recording never
+//! allocates. The live allocations beyond the capacity are counted but not
+//! tracked one by one.
+//!
+//! The callers are found by walking the frame pointer chain past the
+//! allocation shims, so the kernel must be built with
+//! `-C force-frame-pointers=yes`, which the build scripts add when the
+//! `alloc-trace` feature is enabled. The number of shim frames is measured
+//! once the allocator is initialized.
+//!
+//! [`GlobalAlloc`]: core::alloc::GlobalAlloc
+
+use alloc::vec::Vec;
+use core::alloc::Layout;
+use core::fmt;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use kspin::SpinNoIrq;
+
+/// The number of size classes, from 8 bytes to 1 MB, and a class for the
+/// larger sizes.
+const NUM_SIZE_CLASSES: usize = 19;
+const MIN_CLASS_SHIFT: usize = 3;
+/// The number of distinct callers to count. The others are counted together.
+const MAX_CALLERS: usize = 256;
+/// The capacity of the live allocation table, a power of 2.
+const MAX_LIVE: usize = 4096;
+/// The number of callers shown in the report.
+const REPORT_CALLERS: usize = 16;
+
+/// The most frames walked from [`GlobalAlloc`] methods up to the caller.
+///
+/// [`GlobalAlloc`]: core::alloc::GlobalAlloc
+const MAX_SHIM_DEPTH: usize = 8;
+const UNKNOWN_DEPTH: usize = usize::MAX;
+
+/// The entry points of the [`GlobalAlloc`] interface, which are called from
+/// the allocation functions through different shims.
+///
+/// [`GlobalAlloc`]: core::alloc::GlobalAlloc
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Entry {
+    Alloc = 0,
+    AllocZeroed = 1,
+    Realloc = 2,
+}
+
+/// The number of shim frames between each [`Entry`] and the caller, found by
+/// [`calibrate`].
+static SHIM_DEPTH: [AtomicUsize; 3] = [
+    AtomicUsize::new(UNKNOWN_DEPTH),
+    AtomicUsize::new(UNKNOWN_DEPTH),
+    AtomicUsize::new(UNKNOWN_DEPTH),
+];
+/// The frame pointer of [`calibrate`] while it runs.
+static PROBE_FP: AtomicUsize = AtomicUsize::new(0);
+
+/// Returns the frame pointer of the calling function.
+#[inline(always)]
+fn frame_pointer() -> usize {
+    let fp: usize;
+    cfg_if::cfg_if! {
+        if #[cfg(target_arch = "x86_64")] {
+            unsafe { core::arch::asm!("mov {}, rbp", out(reg) fp) };
+        } else if #[cfg(target_arch = "riscv64")] {
+            unsafe { core::arch::asm!("mv {}, s0", out(reg) fp) };
+        } else if #[cfg(target_arch = "aarch64")] {
+            unsafe { core::arch::asm!("mov {}, x29", out(reg) fp) };
+        } else {
+            fp = 0;
+        }
+    }
+    fp
+}
+
+/// Returns the saved frame pointer of the parent and the return address in
+/// the frame record at `fp`, or `None` if `fp` is not a valid frame pointer.
+fn frame_record(fp: usize) -> Option<(usize, usize)> {
+    if fp == 0 || fp % core::mem::align_of::<usize>() != 0 {
+        return None;
+    }
+    let fp = fp as *const usize;
+    // The record is `[fp - 16]: parent fp, [fp - 8]: ra` on riscv64, and
+    // `[fp]: parent fp, [fp + 8]: ra` on the others.
+    #[cfg(target_arch = "riscv64")]
+    let fp = fp.wrapping_sub(2);
+    unsafe { Some((fp.read(), fp.add(1).read())) }
+}
+
+/// Returns the return address of the allocation call, in the caller of the
+/// allocation shims like `__rust_alloc`.
+///
+/// It must be inlined into the [`Entry`] method, whose frame is the start of
+/// the walk up the frame pointer chain.
+#[inline(always)]
+pub(crate) fn caller(entry: Entry) -> usize {
+    caller_from(frame_pointer(), entry)
+}
+
+fn caller_from(mut fp: usize, entry: Entry) -> usize {
+    let depth = &SHIM_DEPTH[entry as usize];
+    let mut num_shims = depth.load(Ordering::Relaxed);
+    if num_shims == UNKNOWN_DEPTH {
+        let probe = PROBE_FP.load(Ordering::Relaxed);
+        if probe == 0 {
+            // Not calibrated yet, report the shim.
+            num_shims = 0;
+        } else {
+            num_shims = count_shims(fp, probe);
+            depth.store(num_shims, Ordering::Relaxed);
+        }
+    }
+    for _ in 0..num_shims {
+        match frame_record(fp) {
+            Some((parent, _)) => fp = parent,
+            None => return 0,
+        }
+    }
+    frame_record(fp).map_or(0, |(_, ra)| ra)
+}
+
+/// Counts the frames above `fp` until the one called by the function whose
+/// frame pointer is `probe`.
+fn count_shims(mut fp: usize, probe: usize) -> usize {
+    for n in 0..MAX_SHIM_DEPTH {
+        match frame_record(fp) {
+            Some((parent, _)) if parent == probe => return n,
+            Some((parent, _)) => fp = parent,
+            None => break,
+        }
+    }
+    warn!("alloc-trace: allocation shims not found, are the frame pointers enabled?");
+    0
+}
+
+/// Finds the number of shim frames of each [`Entry`] by allocating from a
+/// known frame. It must be called after the allocator is initialized, before
+/// the other CPUs are started.
+#[inline(never)]
+pub(crate) fn calibrate() {
+    use alloc::alloc::{alloc, alloc_zeroed, dealloc, realloc};
+    PROBE_FP.store(frame_pointer(), Ordering::Relaxed);
+    let layout = Layout::new::<usize>();
+    let layout2 = Layout::new::<[usize; 2]>();
+    unsafe {
+        let ptr = alloc(layout);
+        if !ptr.is_null() {
+            let ptr = realloc(ptr, layout, layout2.size());
+            if !ptr.is_null() {
+                dealloc(ptr, layout2);
+            }
+        }
+        let ptr = alloc_zeroed(layout);
+        if !ptr.is_null() {
+            dealloc(ptr, layout);
+        }
+    }
+    PROBE_FP.store(0, Ordering::Relaxed);
+}
+
+fn size_class(size: usize) -> usize {
+    let shift = size.max(1).next_power_of_two().trailing_zeros() as usize;
+    shift
+        .saturating_sub(MIN_CLASS_SHIFT)
+        .min(NUM_SIZE_CLASSES - 1)
+}
+
+/// The allocation counts of a size class.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SizeClassStat {
+    /// The number of allocations since the last [`reset`].
+    pub total: usize,
+    /// The number of live allocations.
+    pub live: usize,
+}
+
+/// The allocations made by a caller since the last [`reset`].
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CallerStat {
+    /// The return address of the allocation call.
+    pub caller: usize,
+    /// The number of allocations.
+    pub count: usize,
+    /// The number of bytes allocated.
+    pub bytes: usize,
+}
+
+/// A live allocation.
+#[derive(Debug, Clone, Copy)]
+pub struct LiveAlloc {
+    /// The address of the allocated block.
+    pub ptr: usize,
+    /// The requested size.
+    pub size: usize,
+    /// The return address of the allocation call.
+    pub caller: usize,
+    /// The sequence number, in the order of allocation.
+    pub id: u64,
+}
+
+impl LiveAlloc {
+    const EMPTY: Self = Self {
+        ptr: 0,
+        size: 0,
+        caller: 0,
+        id: 0,
+    };
+}
+
+impl fmt::Display for LiveAlloc {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "#{} {:#x}: {} bytes from {:#x}",
+            self.id, self.ptr, self.size, self.caller
+        )
+    }
+}
+
+struct TraceState {
+    classes: [SizeClassStat; NUM_SIZE_CLASSES],
+    /// Hash table keyed by the caller, without deletion.
+    callers: [CallerStat; MAX_CALLERS],
+    num_callers: usize,
+    other_callers: CallerStat,
+    /// Hash table keyed by the pointer, with linear probing.
+    live: [LiveAlloc; MAX_LIVE],
+    num_live: usize,
+    untracked: usize,
+    next_id: u64,
+    total_allocs: u64,
+    total_deallocs: u64,
+    live_bytes: usize,
+    peak_bytes: usize,
+    peak_pages: usize,
+}
+
+static STATE: SpinNoIrq<TraceState> = SpinNoIrq::new(TraceState::new());
+
+fn hash(key: usize) -> usize {
+    let h = (key >> 3).wrapping_mul(0x9e37_79b9_7f4a_7c15_u64 as usize);
+    h ^ (h >> 32)
+}
+
+impl TraceState {
+    const fn new() -> Self {
+        Self {
+            classes: [SizeClassStat { total: 0, live: 0 }; NUM_SIZE_CLASSES],
+            callers: [CallerStat {
+                caller: 0,
+                count: 0,
+                bytes: 0,
+            }; MAX_CALLERS],
+            num_callers: 0,
+            other_callers: CallerStat {
+                caller: 0,
+                count: 0,
+                bytes: 0,
+            },
+            live: [LiveAlloc::EMPTY; MAX_LIVE],
+            num_live: 0,
+            untracked: 0,
+            next_id: 0,
+            total_allocs: 0,
+            total_deallocs: 0,
+            live_bytes: 0,
+            peak_bytes: 0,
+            peak_pages: 0,
+        }
+    }
+
+    fn count_caller(&mut self, caller: usize, size: usize) {
+        let mut idx = hash(caller) % MAX_CALLERS;
+        for _ in 0..MAX_CALLERS {
+            let stat = &mut self.callers[idx];
+            if stat.count == 0 {
+                // Keep some room, or the probing gets long.
+                if self.num_callers >= MAX_CALLERS * 3 / 4 {
+                    break;
+                }
+                stat.caller = caller;
+                self.num_callers += 1;
+            }
+            if stat.caller == caller {
+                stat.count += 1;
+                stat.bytes += size;
+                return;
+            }
+            idx = (idx + 1) % MAX_CALLERS;
+        }
+        self.other_callers.count += 1;
+        self.other_callers.bytes += size;
+    }
+
+    fn insert_live(&mut self, record: LiveAlloc) {
+        if self.num_live >= MAX_LIVE * 3 / 4 {
+            self.untracked += 1;
+            return;
+        }
+        let mut idx = hash(record.ptr) % MAX_LIVE;
+        while self.live[idx].ptr != 0 {
+            idx = (idx + 1) % MAX_LIVE;
+        }
+        self.live[idx] = record;
+        self.num_live += 1;
+    }
+
+    fn remove_live(&mut self, ptr: usize) {
+        let mut idx = hash(ptr) % MAX_LIVE;
+        loop {
+            match self.live[idx].ptr {
+                0 => return, // not tracked
+                p if p == ptr => break,
+                _ => idx = (idx + 1) % MAX_LIVE,
+            }
+        }
+        self.num_live -= 1;
+        // Shift the following entries back to fill the hole, unless they are
+        // already at or after their home slots.
+        let mut hole = idx;
+        let mut next = idx;
+        loop {
+            next = (next + 1) % MAX_LIVE;
+            if self.live[next].ptr == 0 {
+                break;
+            }
+            let home = hash(self.live[next].ptr) % MAX_LIVE;
+            let stays = if hole <= next {
+                hole < home && home <= next
+            } else {
+                hole < home || home <= next
+            };
+            if !stays {
+                self.live[hole] = self.live[next];
+                hole = next;
+            }
+        }
+        self.live[hole] = LiveAlloc::EMPTY;
+    }
+}
+
+/// Records an allocation.
+pub(crate) fn record_alloc(ptr: usize, layout: Layout, caller: usize) {
+    let mut state = STATE.lock();
+    let size = layout.size();
+    let class = &mut state.classes[size_class(size)];
+    class.total += 1;
+    class.live += 1;
+    state.count_caller(caller, size);
+    let id = state.next_id;
+    state.next_id += 1;
+    state.insert_live(LiveAlloc {
+        ptr,
+        size,
+        caller,
+        id,
+    });
+    state.total_allocs += 1;
+    state.live_bytes += size;
+    state.peak_bytes = state.peak_bytes.max(state.live_bytes);
+}
+
+/// Records a deallocation.
+pub(crate) fn record_dealloc(ptr: usize, layout: Layout) {
+    let mut state = STATE.lock();
+    let size = layout.size();
+    let class = &mut state.classes[size_class(size)];
+    class.live = class.live.saturating_sub(1);
+    state.remove_live(ptr);
+    state.total_deallocs += 1;
+    state.live_bytes = state.live_bytes.saturating_sub(size);
+}
+
+/// Records the number of used pages after a page allocation.
+pub(crate) fn record_pages(used_pages: usize) {
+    let mut state = STATE.lock();
+    state.peak_pages = state.peak_pages.max(used_pages);
+}
+
+/// Clears the histogram totals and the caller counts, and resets the
+/// high-water marks to the current usage. The live allocations are kept.
+pub fn reset() {
+    let used_pages = crate::global_allocator().used_pages();
+    let mut state = STATE.lock();
+    for class in state.classes.iter_mut() {
+        class.total = 0;
+    }
+    state.callers = [CallerStat::default(); MAX_CALLERS];
+    state.num_callers = 0;
+    state.other_callers = CallerStat::default();
+    state.total_allocs = 0;
+    state.total_deallocs = 0;
+    state.peak_bytes = state.live_bytes;
+    state.peak_pages = used_pages;
+}
+
+/// The statistics of the allocations, printed by its [`Display`] impl.
+///
+/// [`Display`]: fmt::Display
+#[derive(Debug, Clone)]
+pub struct Report {
+    /// The allocation counts of each size class, the `i`-th class for the
+    /// sizes in `(4 << i, 8 << i]`, and the last one for all larger sizes.
+    pub classes: [SizeClassStat; NUM_SIZE_CLASSES],
+    /// The callers with the most allocations, in descending order.
+    pub top_callers: [CallerStat; REPORT_CALLERS],
+    /// The allocations of the callers not counted one by one.
+    pub other_callers: CallerStat,
+    /// The number of allocations since the last [`reset`].
+    pub total_allocs: u64,
+    /// The number of deallocations since the last [`reset`].
+    pub total_deallocs: u64,
+    /// The number of live allocations.
+    pub live_allocs: usize,
+    /// The number of live allocations not tracked one by one.
+    pub untracked_allocs: usize,
+    /// The number of requested bytes of the live allocations.
+    pub live_bytes: usize,
+    /// The high-water mark of `live_bytes`.
+    pub peak_bytes: usize,
+    /// The high-water mark of the used pages in the page allocator.
+    pub peak_pages: usize,
+}
+
+/// Returns the statistics of the allocations.
+///
+/// It does not allocate, so it can be used when memory runs out.
+pub fn report() -> Report {
+    let state = STATE.lock();
+    let mut callers = state.callers;
+    callers.sort_unstable_by(|a, b| b.count.cmp(&a.count));
+    Report {
+        classes: state.classes,
+        top_callers: callers[..REPORT_CALLERS].try_into().unwrap(),
+        other_callers: state.other_callers,
+        total_allocs: state.total_allocs,
+        total_deallocs: state.total_deallocs,
+        live_allocs: state.classes.iter().map(|c| c.live).sum(),
+        untracked_allocs: state.untracked,
+        live_bytes: state.live_bytes,
+        peak_bytes: state.peak_bytes,
+        peak_pages: state.peak_pages,
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "heap: {} bytes in {} allocations ({} untracked), peak {} bytes",
+            self.live_bytes, self.live_allocs, self.untracked_allocs, self.peak_bytes
+        )?;
+        writeln!(
+            f,
+            "allocs: {}, deallocs: {}, peak pages: {}",
+            self.total_allocs, self.total_deallocs, self.peak_pages
+        )?;
+        writeln!(f, "size classes (total / live):")?;
+        for (i, class) in self.classes.iter().enumerate() {
+            if class.total == 0 && class.live == 0 {
+                continue;
+            }
+            if i == NUM_SIZE_CLASSES - 1 {
+                write!(f, "  > {:<8}", 1usize << (i - 1 + MIN_CLASS_SHIFT))?;
+            } else {
+                write!(f, "  <= {:<7}", 1usize << (i + MIN_CLASS_SHIFT))?;
+            }
+            writeln!(f, " {} / {}", class.total, class.live)?;
+        }
+        writeln!(f, "top callers (allocs / bytes):")?;
+        for caller in self.top_callers.iter().filter(|c| c.count > 0) {
+            writeln!(
+                f,
+                "  {:#018x} {} / {}",
+                caller.caller, caller.count, caller.bytes
+            )?;
+        }
+        if self.other_callers.count > 0 {
+            writeln!(
+                f,
+                "  (others)           {} / {}",
+                self.other_callers.count, self.other_callers.bytes
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// The live allocations at a time, see [`snapshot`].
+pub struct Snapshot {
+    /// The tracked live allocations, sorted by the sequence number.
+    pub allocs: Vec<LiveAlloc>,
+    /// The sequence number of the next allocation at the time.
+    pub next_id: u64,
+}
+
+impl Snapshot {
+    /// Returns the allocations live in the `later` snapshot that were made
+    /// after this one, i.e., the leaks if everything allocated in between
+    /// should have been freed.
+    pub fn diff<'a>(&self, later: &'a Snapshot) -> impl Iterator<Item = &'a LiveAlloc> {
+        let next_id = self.next_id;
+        later.allocs.iter().filter(move |a| a.id >= next_id)
+    }
+}
+
+/// Takes a snapshot of the tracked live allocations, excluding the snapshot
+/// itself.
+pub fn snapshot() -> Snapshot {
+    // Allocate before taking the lock, as the allocation is recorded.
+    let capacity = STATE.lock().num_live + 64;
+    let mut allocs = Vec::with_capacity(capacity);
+    let buf = allocs.as_ptr() as usize;
+    let next_id = {
+        let state = STATE.lock();
+        for record in state.live.iter() {
+            if allocs.len() == capacity {
+                break;
+            }
+            if record.ptr != 0 && record.ptr != buf {
+                allocs.push(*record);
+            }
+        }
+        state.next_id
+    };
+    allocs.sort_unstable_by_key(|a: &LiveAlloc| a.id);
+    Snapshot { allocs, next_id }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns `n` distinct pointers whose home slot is `home`.
+    fn ptrs_at(home: usize, n: usize) -> Vec<usize> {
+        (1..)
+            .map(|i| i * 8)
+            .filter(|&p| hash(p) % MAX_LIVE == home)
+            .take(n)
+            .collect()
+    }
+
+    fn record(ptr: usize) -> LiveAlloc {
+        LiveAlloc {
+            ptr,
+            size: 8,
+            caller: 0,
+            id: ptr as u64,
+        }
+    }
+
+    fn contains(state: &TraceState, ptr: usize) -> bool {
+        let mut idx = hash(ptr) % MAX_LIVE;
+        while state.live[idx].ptr != 0 {
+            if state.live[idx].ptr == ptr {
+                return true;
+            }
+            idx = (idx + 1) % MAX_LIVE;
+        }
+        false
+    }
+
+    fn check_remove_live(home: usize) {
+        let mut state = alloc::boxed::Box::new(TraceState::new());
+        // A cluster at `home`, followed by an entry pushed out of its own
+        // home slot right after `home`.
+        let cluster = ptrs_at(home, 3);
+        let next = ptrs_at((home + 1) % MAX_LIVE, 1)[0];
+        for &ptr in cluster.iter().chain([next].iter()) {
+            state.insert_live(record(ptr));
+        }
+        assert_eq!(state.live[(home + 3) % MAX_LIVE].ptr, next);
+
+        // Shifted back to fill the hole.
+        state.remove_live(cluster[0]);
+        assert_eq!(state.num_live, 3);
+        assert!(!contains(&state, cluster[0]));
+        for &ptr in cluster[1..].iter().chain([next].iter()) {
+            assert!(contains(&state, ptr));
+        }
+        assert_eq!(state.live[home].ptr, cluster[1]);
+        assert_eq!(state.live[(home + 2) % MAX_LIVE].ptr, next);
+        assert_eq!(state.live[(home + 3) % MAX_LIVE].ptr, 0);
+
+        // Removing an untracked pointer changes nothing.
+        state.remove_live(ptrs_at(home, 4)[3]);
+        assert_eq!(state.num_live, 3);
+
+        for &ptr in cluster[1..].iter().chain([next].iter()) {
+            state.remove_live(ptr);
+            assert!(!contains(&state, ptr));
+        }
+        assert_eq!(state.num_live, 0);
+        assert!(state.live.iter().all(|r| r.ptr == 0));
+    }
+
+    #[test]
+    fn test_remove_live_shift() {
+        check_remove_live(100);
+    }
+
+    #[test]
+    fn test_remove_live_wrap_around() {
+        check_remove_live(MAX_LIVE - 2);
+    }
+}
@@ -1,6 +1,99 @@
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use axalloc::Shrinker;
 use axdriver::prelude::*;
+use axsync::spin::SpinNoIrq;
 
 const BLOCK_SIZE: usize = 512;
+/// The number of blocks kept in the [`BlockCache`].
+const CACHE_BLOCKS: usize = 256;
+
+/// A write-through cache of the recently used blocks of the disk.
+///
+/// It's registered as a [`Shrinker`], which drops the least recently used
+/// blocks when memory is short.
+pub(crate) struct BlockCache {
+    inner: SpinNoIrq<CacheInner>,
+}
+
+struct CacheInner {
+    /// The cached blocks by their IDs, with the time they were last used.
+    blocks: BTreeMap<u64, (u64, Box<[u8; BLOCK_SIZE]>)>,
+    clock: u64,
+}
+
+/// The cache of the disk, as there is only one.
+pub(crate) static BLOCK_CACHE: BlockCache = BlockCache {
+    inner: SpinNoIrq::new(CacheInner {
+        blocks: BTreeMap::new(),
+        clock: 0,
+    }),
+};
+
+impl CacheInner {
+    fn tick(&mut self) -> u64 {
+        self.clock += 1;
+        self.clock
+    }
+
+    /// Drops the least recently used block. Returns whether there was one.
+    fn evict_lru(&mut self) -> bool {
+        let lru = self
+            .blocks
+            .iter()
+            .min_by_key(|(_, (used, _))| *used)
+            .map(|(&block_id, _)| block_id);
+        lru.is_some_and(|block_id| self.blocks.remove(&block_id).is_some())
+    }
+}
+
+impl BlockCache {
+    /// Copies the block to `buf` if it's cached. Returns whether it is.
+    fn read(&self, block_id: u64, buf: &mut [u8]) -> bool {
+        let mut inner = self.inner.lock();
+        let now = inner.tick();
+        match inner.blocks.get_mut(&block_id) {
+            Some((used, data)) => {
+                *used = now;
+                buf.copy_from_slice(&data[..]);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Caches the content of the block, replacing the least recently used one
+    /// if the cache is full.
+    fn insert(&self, block_id: u64, buf: &[u8]) {
+        // Allocated before taking the lock, which the shrinker needs.
+        let mut data = Box::new([0; BLOCK_SIZE]);
+        data.copy_from_slice(buf);
+        let mut inner = self.inner.lock();
+        let now = inner.tick();
+        if inner.blocks.len() >= CACHE_BLOCKS && !inner.blocks.contains_key(&block_id) {
+            inner.evict_lru();
+        }
+        inner.blocks.insert(block_id, (now, data));
+    }
+}
+
+impl Shrinker for BlockCache {
+    fn name(&self) -> &str {
+        "block cache"
+    }
+
+    fn shrink(&self, bytes: usize) -> usize {
+        // Skipped if the allocation is made with the lock held.
+        let Some(mut inner) = self.inner.try_lock() else {
+            return 0;
+        };
+        let mut freed = 0;
+        while freed < bytes && inner.evict_lru() {
+            freed += BLOCK_SIZE;
+        }
+        freed
+    }
+}
 
 /// A disk device with a cursor.
 pub struct Disk {
@@ -36,12 +129,27 @@ impl Disk {
         self.offset = pos as usize % BLOCK_SIZE;
     }
 
+    /// Reads a whole block, from the [`BlockCache`] if it's cached.
+    fn read_block(&mut self, block_id: u64, buf: &mut [u8]) -> DevResult {
+        if !BLOCK_CACHE.read(block_id, buf) {
+            self.dev.read_block(block_id, buf)?;
+            BLOCK_CACHE.insert(block_id, buf);
+        }
+        Ok(())
+    }
+
+    /// Writes a whole block, through the [`BlockCache`].
+    fn write_block(&mut self, block_id: u64, buf: &[u8]) -> DevResult {
+        self.dev.write_block(block_id, buf)?;
+        BLOCK_CACHE.insert(block_id, buf);
+        Ok(())
+    }
+
     /// Read within one block, returns the number of bytes read.
     pub fn read_one(&mut self, buf: &mut [u8]) -> DevResult<usize> {
         let read_size = if self.offset == 0 && buf.len() >= BLOCK_SIZE {
             // whole block
-            self.dev
-                .read_block(self.block_id, &mut buf[0..BLOCK_SIZE])?;
+            self.read_block(self.block_id, &mut buf[0..BLOCK_SIZE])?;
             self.block_id += 1;
             BLOCK_SIZE
         } else {
@@ -50,7 +158,7 @@ impl Disk {
             let start = self.offset;
             let count = buf.len().min(BLOCK_SIZE - self.offset);
 
-            self.dev.read_block(self.block_id, &mut data)?;
+            self.read_block(self.block_id, &mut data)?;
             buf[..count].copy_from_slice(&data[start..start + count]);
 
             self.offset += count;
@@ -67,7 +175,7 @@ impl Disk {
     pub fn write_one(&mut self, buf: &[u8]) -> DevResult<usize> {
         let write_size = if self.offset == 0 && buf.len() >= BLOCK_SIZE {
             // whole block
-            self.dev.write_block(self.block_id, &buf[0..BLOCK_SIZE])?;
+            self.write_block(self.block_id, &buf[0..BLOCK_SIZE])?;
             self.block_id += 1;
             BLOCK_SIZE
         } else {
@@ -76,9 +184,9 @@ impl Disk {
             let start = self.offset;
             let count = buf.len().min(BLOCK_SIZE - self.offset);
 
-            self.dev.read_block(self.block_id, &mut data)?;
+            self.read_block(self.block_id, &mut data)?;
             data[start..start + count].copy_from_slice(&buf[..count]);
-            self.dev.write_block(self.block_id, &data)?;
+            self.write_block(self.block_id, &data)?;
 
             self.offset += count;
             if self.offset >= BLOCK_SIZE {
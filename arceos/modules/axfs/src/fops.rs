@@ -1,5 +1,6 @@
 //! Low-level filesystem operations.
 
+use alloc::sync::Arc;
 use axerrno::{ax_err, ax_err_type, AxError, AxResult};
 use axfs_vfs::{VfsError, VfsNodeRef};
 use axio::SeekFrom;
@@ -169,6 +170,22 @@ impl File {
         Self::_open_at(None, path, opts)
     }
 
+    /// Creates a new handle of the same file, with the same permissions and
+    /// cursor.
+    ///
+    /// The two handles are independent, e.g., seeking one does not affect the
+    /// other.
+    pub fn try_clone(&self) -> AxResult<Self> {
+        let cap = self.node.cap();
+        let node = self.access_node(cap)?.clone();
+        node.open()?;
+        Ok(Self {
+            node: WithCap::new(node, cap),
+            is_append: self.is_append,
+            offset: self.offset,
+        })
+    }
+
     /// Truncates the file to the specified size.
     pub fn truncate(&self, size: u64) -> AxResult {
         self.access_node(Cap::WRITE)?.truncate(size)?;
@@ -246,6 +263,14 @@ impl File {
     pub fn get_attr(&self) -> AxResult<FileAttr> {
         self.access_node(Cap::empty())?.get_attr()
     }
+
+    /// Returns an identifier of the underlying node, which is the same for
+    /// all the files opened on it while any of them is alive.
+    pub fn node_id(&self) -> usize {
+        // `Cap::empty()` is always allowed.
+        let node = self.access_node(Cap::empty()).unwrap();
+        Arc::as_ptr(node) as *const () as usize
+    }
 }
 
 impl Directory {
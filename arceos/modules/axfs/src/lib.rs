@@ -10,6 +10,9 @@
 //!    **enabled** by default.
 //! - `ramfs`: Mount [`axfs_ramfs::RamFileSystem`] on `/tmp`. This feature is
 //!    **enabled** by default.
+//! - `procfs`: Mount a RAM filesystem on `/proc`, with the process information
+//!    registered by [`procfs::register_process`]. This feature is **enabled**
+//!    by default.
 //! - `myfs`: Allow users to define their custom filesystems to override the
 //!    default. In this case, [`MyFileSystemIf`] is required to be implemented
 //!    to create and initialize other filesystems. This feature is **disabled** by
@@ -33,6 +36,8 @@ mod root;
 
 pub mod api;
 pub mod fops;
+#[cfg(feature = "procfs")]
+pub mod procfs;
 
 use axdriver::{prelude::*, AxDeviceContainer};
 
@@ -43,4 +48,8 @@ pub fn init_filesystems(mut blk_devs: AxDeviceContainer<AxBlockDevice>) {
     let dev = blk_devs.take_one().expect("No block device found!");
     info!("  use block device 0: {:?}", dev.device_name());
     self::root::init_rootfs(self::dev::Disk::new(dev));
+
+    axalloc::oom::register_shrinker(&self::dev::BLOCK_CACHE);
+    #[cfg(feature = "ramfs")]
+    axalloc::oom::register_shrinker(&self::mounts::TmpFsShrinker);
 }
@@ -1,5 +1,9 @@
 use alloc::sync::Arc;
+#[cfg(feature = "ramfs")]
+use axalloc::Shrinker;
 use axfs_vfs::{VfsNodeType, VfsOps, VfsResult};
+#[cfg(feature = "ramfs")]
+use lazyinit::LazyInit;
 
 use crate::fs;
 
@@ -16,14 +20,44 @@ pub(crate) fn devfs() -> Arc<fs::devfs::DeviceFileSystem> {
     Arc::new(devfs)
 }
 
+/// The RAM filesystem mounted on `/tmp`.
+#[cfg(feature = "ramfs")]
+static TMP_FS: LazyInit<Arc<fs::ramfs::RamFileSystem>> = LazyInit::new();
+
+/// The spare capacity of a file in `/tmp` that is worth giving back when
+/// memory is short.
+#[cfg(feature = "ramfs")]
+const TMP_FS_TRIM_SPARE: usize = 0x1000;
+
 #[cfg(feature = "ramfs")]
 pub(crate) fn ramfs() -> Arc<fs::ramfs::RamFileSystem> {
-    Arc::new(fs::ramfs::RamFileSystem::new())
+    let ramfs = Arc::new(fs::ramfs::RamFileSystem::new());
+    TMP_FS.init_once(ramfs.clone());
+    ramfs
+}
+
+/// Gives back the spare capacity of the files in `/tmp` when memory is short.
+#[cfg(feature = "ramfs")]
+pub(crate) struct TmpFsShrinker;
+
+#[cfg(feature = "ramfs")]
+impl Shrinker for TmpFsShrinker {
+    fn name(&self) -> &str {
+        "ramfs"
+    }
+
+    fn shrink(&self, _bytes: usize) -> usize {
+        if TMP_FS.is_inited() {
+            TMP_FS.trim(TMP_FS_TRIM_SPARE)
+        } else {
+            0
+        }
+    }
 }
 
 #[cfg(feature = "procfs")]
-pub(crate) fn procfs() -> VfsResult<Arc<fs::ramfs::RamFileSystem>> {
-    let procfs = fs::ramfs::RamFileSystem::new();
+pub(crate) fn procfs() -> VfsResult<Arc<crate::procfs::ProcFileSystem>> {
+    let procfs = crate::procfs::ProcFileSystem::new();
     let proc_root = procfs.root_dir();
 
     // Create /proc/sys/net/core/somaxconn
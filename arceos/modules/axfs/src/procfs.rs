@@ -0,0 +1,398 @@
+//! The process information in `/proc/<pid>`.
+//!
+//! The kernel registers its processes with [`register_process`], and the
+//! files in `/proc/<pid>` are generated from the [`ProcessInfo`] when opened:
+//!
+//! - `maps`: the mapped memory areas, in the format of Linux.
+//! - `status`: the name, pid, and memory usage.
+//!
+//! `/proc/self` refers to the current process, if the way to get its pid is
+//! set by [`set_current_pid_fn`]. The other entries of `/proc` are static
+//! files in a RAM filesystem.
+
+use alloc::collections::BTreeMap;
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt::Write;
+
+use axfs_ramfs::{DirNode, RamFileSystem};
+use axfs_vfs::{VfsDirEntry, VfsNodeAttr, VfsNodeOps, VfsNodeRef, VfsNodeType, VfsOps};
+use axfs_vfs::{VfsError, VfsResult};
+use axsync::Mutex;
+use lazyinit::LazyInit;
+
+/// A mapped memory area, a line of `/proc/<pid>/maps`.
+pub struct MapsEntry {
+    /// The start address.
+    pub start: usize,
+    /// The end address (exclusive).
+    pub end: usize,
+    /// Whether the area is readable.
+    pub read: bool,
+    /// Whether the area is writable.
+    pub write: bool,
+    /// Whether the area is executable.
+    pub execute: bool,
+    /// Whether the changes are shared with other mappings.
+    pub shared: bool,
+    /// The offset in the mapped file or shared memory.
+    pub offset: u64,
+    /// The path of the mapped file, or a pseudo name like `[heap]`. Empty for
+    /// anonymous areas.
+    pub name: String,
+}
+
+/// The information of a process shown in `/proc/<pid>`.
+pub trait ProcessInfo: Send + Sync {
+    /// Returns the name of the process.
+    fn name(&self) -> String;
+
+    /// Returns the mapped memory areas, sorted by the start address.
+    fn maps(&self) -> Vec<MapsEntry>;
+
+    /// Returns the virtual memory size and the resident set size in bytes.
+    fn memory_usage(&self) -> (usize, usize);
+}
+
+static PROCESSES: Mutex<BTreeMap<u64, Arc<dyn ProcessInfo>>> = Mutex::new(BTreeMap::new());
+static CURRENT_PID: LazyInit<fn() -> u64> = LazyInit::new();
+
+/// Adds `/proc/<pid>` for the process, replacing the old one with the same
+/// pid.
+pub fn register_process(pid: u64, info: Arc<dyn ProcessInfo>) {
+    PROCESSES.lock().insert(pid, info);
+}
+
+/// Removes `/proc/<pid>`, usually when the process exits.
+pub fn unregister_process(pid: u64) {
+    PROCESSES.lock().remove(&pid);
+}
+
+/// Sets the function to get the pid of the current process, for
+/// `/proc/self`. It can only be set once.
+pub fn set_current_pid_fn(f: fn() -> u64) {
+    CURRENT_PID.init_once(f);
+}
+
+fn process_info(pid: u64) -> Option<Arc<dyn ProcessInfo>> {
+    PROCESSES.lock().get(&pid).cloned()
+}
+
+fn gen_maps(info: &dyn ProcessInfo) -> String {
+    let mut buf = String::new();
+    for area in info.maps() {
+        let start = buf.len();
+        let _ = write!(
+            buf,
+            "{:08x}-{:08x} {}{}{}{} {:08x} 00:00 0",
+            area.start,
+            area.end,
+            if area.read { 'r' } else { '-' },
+            if area.write { 'w' } else { '-' },
+            if area.execute { 'x' } else { '-' },
+            if area.shared { 's' } else { 'p' },
+            area.offset,
+        );
+        if !area.name.is_empty() {
+            // Align the names at column 73 as Linux does.
+            let width = 73usize.saturating_sub(buf.len() - start).max(1);
+            let _ = write!(buf, "{:width$}{}", "", area.name);
+        }
+        buf.push('\n');
+    }
+    buf
+}
+
+fn gen_status(pid: u64, info: &dyn ProcessInfo) -> String {
+    let (vsz, rss) = info.memory_usage();
+    let mut buf = String::new();
+    let _ = writeln!(buf, "Name:\t{}", info.name());
+    let _ = writeln!(buf, "State:\tR (running)");
+    let _ = writeln!(buf, "Pid:\t{}", pid);
+    let _ = writeln!(buf, "VmSize:\t{:8} kB", vsz / 1024);
+    let _ = writeln!(buf, "VmRSS:\t{:8} kB", rss / 1024);
+    buf
+}
+
+fn split_path(path: &str) -> (&str, Option<&str>) {
+    let trimmed_path = path.trim_start_matches('/');
+    trimmed_path.find('/').map_or((trimmed_path, None), |n| {
+        (&trimmed_path[..n], Some(&trimmed_path[n + 1..]))
+    })
+}
+
+/// The `/proc` filesystem.
+pub(crate) struct ProcFileSystem {
+    ramfs: RamFileSystem,
+    root: Arc<ProcRootDir>,
+}
+
+impl ProcFileSystem {
+    pub fn new() -> Self {
+        let ramfs = RamFileSystem::new();
+        let root = Arc::new(ProcRootDir {
+            ramfs: ramfs.root_dir_node(),
+        });
+        Self { ramfs, root }
+    }
+}
+
+impl VfsOps for ProcFileSystem {
+    fn mount(&self, path: &str, mount_point: VfsNodeRef) -> VfsResult {
+        self.ramfs.mount(path, mount_point)
+    }
+
+    fn root_dir(&self) -> VfsNodeRef {
+        self.root.clone()
+    }
+}
+
+/// The root directory of `/proc`, with the process directories besides the
+/// static entries.
+struct ProcRootDir {
+    ramfs: Arc<DirNode>,
+}
+
+impl ProcRootDir {
+    fn pid_of(&self, name: &str) -> Option<u64> {
+        let pid = match name {
+            "self" => {
+                let current_pid = CURRENT_PID.get()?;
+                current_pid()
+            }
+            _ => name.parse().ok()?,
+        };
+        PROCESSES.lock().contains_key(&pid).then_some(pid)
+    }
+}
+
+impl VfsNodeOps for ProcRootDir {
+    axfs_vfs::impl_vfs_dir_default! {}
+
+    fn get_attr(&self) -> VfsResult<VfsNodeAttr> {
+        self.ramfs.get_attr()
+    }
+
+    fn parent(&self) -> Option<VfsNodeRef> {
+        self.ramfs.parent()
+    }
+
+    fn lookup(self: Arc<Self>, path: &str) -> VfsResult<VfsNodeRef> {
+        let (name, rest) = split_path(path);
+        match (name, rest) {
+            ("" | ".", None) => return Ok(self),
+            ("" | ".", Some(rest)) => return self.lookup(rest),
+            _ => {}
+        }
+        if let Some(pid) = self.pid_of(name) {
+            let dir: VfsNodeRef = Arc::new(ProcPidDir {
+                root: self,
+                name: name.into(),
+                pid,
+            });
+            return match rest {
+                Some(rest) => dir.lookup(rest),
+                None => Ok(dir),
+            };
+        }
+        self.ramfs.clone().lookup(path)
+    }
+
+    fn read_dir(&self, start_idx: usize, dirents: &mut [VfsDirEntry]) -> VfsResult<usize> {
+        // The static entries (with "." and "..") first, then the processes.
+        let num_static = self.ramfs.get_entries().len() + 2;
+        let mut count = 0;
+        if start_idx < num_static {
+            count = self.ramfs.read_dir(start_idx, dirents)?;
+        }
+        let skip = (start_idx + count).saturating_sub(num_static);
+        let pids: Vec<u64> = PROCESSES.lock().keys().copied().collect();
+        for (ent, pid) in dirents[count..].iter_mut().zip(pids.iter().skip(skip)) {
+            *ent = VfsDirEntry::new(&pid.to_string(), VfsNodeType::Dir);
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    fn create(&self, path: &str, ty: VfsNodeType) -> VfsResult {
+        self.ramfs.create(path, ty)
+    }
+
+    fn remove(&self, path: &str) -> VfsResult {
+        self.ramfs.remove(path)
+    }
+}
+
+/// The directory `/proc/<pid>`, or `/proc/self`.
+struct ProcPidDir {
+    root: Arc<ProcRootDir>,
+    /// The name in `/proc`, the pid or `self`.
+    name: String,
+    pid: u64,
+}
+
+const PID_FILES: [(&str, ProcFileKind); 2] = [
+    ("maps", ProcFileKind::Maps),
+    ("status", ProcFileKind::Status),
+];
+
+impl VfsNodeOps for ProcPidDir {
+    axfs_vfs::impl_vfs_dir_default! {}
+
+    fn get_attr(&self) -> VfsResult<VfsNodeAttr> {
+        Ok(VfsNodeAttr::new_dir(0, 0))
+    }
+
+    fn parent(&self) -> Option<VfsNodeRef> {
+        Some(self.root.clone())
+    }
+
+    fn lookup(self: Arc<Self>, path: &str) -> VfsResult<VfsNodeRef> {
+        let (name, rest) = split_path(path);
+        let node: VfsNodeRef = match name {
+            "" | "." => self.clone(),
+            ".." => self.root.clone(),
+            _ => match PID_FILES.iter().find(|(file, _)| *file == name) {
+                Some(&(_, kind)) => Arc::new(ProcFile::new(self.pid, kind)?),
+                // The static files under the same name, e.g., `/proc/self/stat`.
+                None => {
+                    let path = alloc::format!("{}/{}", self.name, path.trim_start_matches('/'));
+                    return self.root.ramfs.clone().lookup(&path);
+                }
+            },
+        };
+        match rest {
+            Some(rest) => node.lookup(rest),
+            None => Ok(node),
+        }
+    }
+
+    fn read_dir(&self, start_idx: usize, dirents: &mut [VfsDirEntry]) -> VfsResult<usize> {
+        let mut count = 0;
+        for (i, ent) in dirents.iter_mut().enumerate() {
+            *ent = match i + start_idx {
+                0 => VfsDirEntry::new(".", VfsNodeType::Dir),
+                1 => VfsDirEntry::new("..", VfsNodeType::Dir),
+                idx => match PID_FILES.get(idx - 2) {
+                    Some((name, _)) => VfsDirEntry::new(name, VfsNodeType::File),
+                    None => break,
+                },
+            };
+            count += 1;
+        }
+        Ok(count)
+    }
+}
+
+#[derive(Clone, Copy)]
+enum ProcFileKind {
+    Maps,
+    Status,
+}
+
+/// A file in `/proc/<pid>`, generated once it is looked up, so that the reads
+/// of an opened file are consistent.
+struct ProcFile {
+    content: String,
+}
+
+impl ProcFile {
+    fn new(pid: u64, kind: ProcFileKind) -> VfsResult<Self> {
+        // The process may have exited.
+        let info = process_info(pid).ok_or(VfsError::NotFound)?;
+        let content = match kind {
+            ProcFileKind::Maps => gen_maps(info.as_ref()),
+            ProcFileKind::Status => gen_status(pid, info.as_ref()),
+        };
+        Ok(Self { content })
+    }
+}
+
+impl VfsNodeOps for ProcFile {
+    axfs_vfs::impl_vfs_non_dir_default! {}
+
+    fn get_attr(&self) -> VfsResult<VfsNodeAttr> {
+        Ok(VfsNodeAttr::new_file(self.content.len() as u64, 0))
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> VfsResult<usize> {
+        let content = self.content.as_bytes();
+        let start = content.len().min(offset as usize);
+        let end = content.len().min(offset as usize + buf.len());
+        let src = &content[start..end];
+        buf[..src.len()].copy_from_slice(src);
+        Ok(src.len())
+    }
+
+    fn write_at(&self, _offset: u64, _buf: &[u8]) -> VfsResult<usize> {
+        Err(VfsError::PermissionDenied)
+    }
+
+    fn truncate(&self, _size: u64) -> VfsResult {
+        Err(VfsError::PermissionDenied)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct FakeProcess;
+
+    impl ProcessInfo for FakeProcess {
+        fn name(&self) -> String {
+            "fake".into()
+        }
+
+        fn maps(&self) -> Vec<MapsEntry> {
+            let entry = |start, end, write, shared, offset, name: &str| MapsEntry {
+                start,
+                end,
+                read: true,
+                write,
+                execute: !write,
+                shared,
+                offset,
+                name: name.into(),
+            };
+            alloc::vec![
+                entry(0x1000, 0x3000, false, false, 0, "/sbin/mapfile"),
+                entry(0x4000, 0x5000, true, true, 0x2000, "/tmp/test"),
+                entry(0x10_0000, 0x10_1000, true, false, 0, ""),
+                entry(0x3f_ffff_0000, 0x40_0000_0000, true, false, 0, "[stack]"),
+            ]
+        }
+
+        fn memory_usage(&self) -> (usize, usize) {
+            (0x8000, 0x2000)
+        }
+    }
+
+    #[test]
+    fn test_gen_maps() {
+        let maps = gen_maps(&FakeProcess);
+        let lines: Vec<&str> = maps.lines().collect();
+        assert_eq!(
+            lines,
+            [
+                "00001000-00003000 r-xp 00000000 00:00 0                                  /sbin/mapfile",
+                "00004000-00005000 rw-s 00002000 00:00 0                                  /tmp/test",
+                "00100000-00101000 rw-p 00000000 00:00 0",
+                "3fffff0000-4000000000 rw-p 00000000 00:00 0                              [stack]",
+            ]
+        );
+        // The names are aligned at column 73.
+        assert_eq!(lines[0].find('/'), Some(73));
+        assert!(maps.ends_with('\n'));
+    }
+
+    #[test]
+    fn test_gen_status() {
+        let status = gen_status(42, &FakeProcess);
+        assert_eq!(
+            status,
+            "Name:\tfake\nState:\tR (running)\nPid:\t42\nVmSize:\t      32 kB\nVmRSS:\t       8 kB\n"
+        );
+    }
+}
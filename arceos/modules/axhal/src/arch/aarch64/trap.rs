@@ -37,8 +37,16 @@ fn invalid_exception(tf: &TrapFrame, kind: TrapKind, source: TrapSource) {
 }
 
 #[no_mangle]
-fn handle_irq_exception(_tf: &TrapFrame) {
+fn handle_irq_exception(tf: &TrapFrame) {
+    #[cfg(feature = "uspace")]
+    if tf.is_user() {
+        crate::trap::notify_user_kernel_switch(true);
+    }
     handle_trap!(IRQ, 0);
+    #[cfg(feature = "uspace")]
+    if tf.is_user() {
+        crate::trap::notify_user_kernel_switch(false);
+    }
 }
 
 fn handle_instruction_abort(tf: &TrapFrame, iss: u64, is_user: bool) {
@@ -64,7 +72,7 @@ fn handle_instruction_abort(tf: &TrapFrame, iss: u64, is_user: bool) {
     }
 }
 
-fn handle_data_abort(tf: &TrapFrame, iss: u64, is_user: bool) {
+fn handle_data_abort(tf: &mut TrapFrame, iss: u64, is_user: bool) {
     let wnr = (iss & (1 << 6)) != 0; // WnR: Write not Read
     let cm = (iss & (1 << 8)) != 0; // CM: Cache maintenance
     let mut access_flags = if wnr & !cm {
@@ -81,6 +89,14 @@ fn handle_data_abort(tf: &TrapFrame, iss: u64, is_user: bool) {
     if !matches!(iss & 0b111100, 0b0100 | 0b1100) // IFSC or DFSC bits
         || !handle_trap!(PAGE_FAULT, vaddr, access_flags, is_user)
     {
+        #[cfg(feature = "uspace")]
+        if !is_user {
+            // Recover from the fault in accessing the user memory.
+            if let Some(fixup) = crate::uaccess::fixup_exception(tf.elr as _) {
+                tf.elr = fixup as _;
+                return;
+            }
+        }
         panic!(
             "Unhandled {} Data Abort @ {:#x}, fault_vaddr={:#x}, ISS=0b{:08b} ({:?}):\n{:#x?}",
             if is_user { "EL0" } else { "EL1" },
@@ -95,6 +111,10 @@ fn handle_data_abort(tf: &TrapFrame, iss: u64, is_user: bool) {
 
 #[no_mangle]
 fn handle_sync_exception(tf: &mut TrapFrame) {
+    #[cfg(feature = "uspace")]
+    if tf.is_user() {
+        crate::trap::notify_user_kernel_switch(true);
+    }
     let esr = ESR_EL1.extract();
     let iss = esr.read(ESR_EL1::ISS);
     match esr.read_as_enum(ESR_EL1::EC) {
@@ -119,4 +139,8 @@ fn handle_sync_exception(tf: &mut TrapFrame) {
             );
         }
     }
+    #[cfg(feature = "uspace")]
+    if tf.is_user() {
+        crate::trap::notify_user_kernel_switch(false);
+    }
 }
@@ -256,6 +256,7 @@ impl UspaceContext {
         use riscv::register::{sepc, sscratch};
 
         super::disable_irqs();
+        crate::trap::notify_user_kernel_switch(false);
         sscratch::write(kstack_top.as_usize());
         sepc::write(self.0.sepc);
         // Address of the top of the kernel stack after saving the trap frame.
@@ -16,12 +16,20 @@ fn handle_breakpoint(sepc: &mut usize) {
     *sepc += 2
 }
 
-fn handle_page_fault(tf: &TrapFrame, mut access_flags: MappingFlags, is_user: bool) {
+fn handle_page_fault(tf: &mut TrapFrame, mut access_flags: MappingFlags, is_user: bool) {
     if is_user {
         access_flags |= MappingFlags::USER;
     }
     let vaddr = va!(stval::read());
     if !handle_trap!(PAGE_FAULT, vaddr, access_flags, is_user) {
+        #[cfg(feature = "uspace")]
+        if !is_user {
+            // Recover from the fault in accessing the user memory.
+            if let Some(fixup) = crate::uaccess::fixup_exception(tf.sepc) {
+                tf.sepc = fixup;
+                return;
+            }
+        }
         panic!(
             "Unhandled {} Page Fault @ {:#x}, fault_vaddr={:#x} ({:?}):\n{:#x?}",
             if is_user { "User" } else { "Supervisor" },
@@ -35,6 +43,10 @@ fn handle_page_fault(tf: &TrapFrame, mut access_flags: MappingFlags, is_user: bo
 
 #[no_mangle]
 fn riscv_trap_handler(tf: &mut TrapFrame, from_user: bool) {
+    #[cfg(feature = "uspace")]
+    if from_user {
+        crate::trap::notify_user_kernel_switch(true);
+    }
     let scause = scause::read();
     match scause.cause() {
         #[cfg(feature = "uspace")]
@@ -60,4 +72,8 @@ fn riscv_trap_handler(tf: &mut TrapFrame, from_user: bool) {
             );
         }
     }
+    #[cfg(feature = "uspace")]
+    if from_user {
+        crate::trap::notify_user_kernel_switch(false);
+    }
 }
@@ -41,6 +41,107 @@ impl TrapFrame {
     }
 }
 
+/// Context to enter user space.
+#[cfg(feature = "uspace")]
+pub struct UspaceContext(TrapFrame);
+
+#[cfg(feature = "uspace")]
+impl UspaceContext {
+    /// Creates an empty context with all registers set to zero.
+    pub const fn empty() -> Self {
+        unsafe { core::mem::MaybeUninit::zeroed().assume_init() }
+    }
+
+    /// Creates a new context with the given entry point, user stack pointer,
+    /// and the argument.
+    pub fn new(entry: usize, ustack_top: VirtAddr) -> Self {
+        use super::GdtStruct;
+        use x86_64::registers::rflags::RFlags;
+        Self(TrapFrame {
+            rip: entry as _,
+            cs: GdtStruct::UCODE64_SELECTOR.0 as _,
+            rflags: RFlags::INTERRUPT_FLAG.bits(),
+            rsp: ustack_top.as_usize() as _,
+            ss: GdtStruct::UDATA_SELECTOR.0 as _,
+            ..Default::default()
+        })
+    }
+
+    /// Creates a new context from the given [`TrapFrame`].
+    pub fn from(trap_frame: &TrapFrame) -> Self {
+        Self(trap_frame.clone())
+    }
+
+    /// Gets the instruction pointer.
+    pub const fn get_ip(&self) -> usize {
+        self.0.rip as _
+    }
+
+    /// Gets the stack pointer.
+    pub const fn get_sp(&self) -> usize {
+        self.0.rsp as _
+    }
+
+    /// Sets the instruction pointer.
+    pub const fn set_ip(&mut self, rip: usize) {
+        self.0.rip = rip as _;
+    }
+
+    /// Sets the stack pointer.
+    pub const fn set_sp(&mut self, rsp: usize) {
+        self.0.rsp = rsp as _;
+    }
+
+    /// Sets the return value register.
+    pub const fn set_retval(&mut self, rax: usize) {
+        self.0.rax = rax as _;
+    }
+
+    /// Enters user space.
+    ///
+    /// It restores the user registers and jumps to the user entry point
+    /// (saved in `rip`).
+    /// When an exception or syscall occurs, the kernel stack pointer is
+    /// switched to `kstack_top`.
+    ///
+    /// # Safety
+    ///
+    /// This function is unsafe because it changes processor mode and the stack.
+    #[inline(never)]
+    #[no_mangle]
+    pub unsafe fn enter_uspace(&self, kstack_top: VirtAddr) -> ! {
+        super::disable_irqs();
+        crate::trap::notify_user_kernel_switch(false);
+        #[cfg(target_os = "none")]
+        crate::platform::set_kernel_stack(kstack_top);
+        #[cfg(not(target_os = "none"))]
+        let _ = kstack_top;
+        asm!("
+            mov     rsp, {tf}
+            pop     rax
+            pop     rcx
+            pop     rdx
+            pop     rbx
+            pop     rbp
+            pop     rsi
+            pop     rdi
+            pop     r8
+            pop     r9
+            pop     r10
+            pop     r11
+            pop     r12
+            pop     r13
+            pop     r14
+            pop     r15
+            add     rsp, 16                     // skip vector, error_code
+            swapgs
+            iretq",
+            tf = in(reg) &self.0,
+            options(noreturn),
+        )
+    }
+}
+
 #[repr(C)]
 #[derive(Debug, Default)]
 struct ContextSwitchFrame {
@@ -192,6 +293,11 @@ impl TaskContext {
             self.fs_base = super::read_thread_pointer();
             unsafe { super::write_thread_pointer(next_ctx.fs_base) };
         }
+        // The traps from user space switch to the kernel stack of the task.
+        #[cfg(all(feature = "uspace", target_os = "none"))]
+        unsafe {
+            crate::platform::set_kernel_stack(next_ctx.kstack_top)
+        };
         unsafe { context_switch(&mut self.rsp, &next_ctx.rsp) }
     }
 }
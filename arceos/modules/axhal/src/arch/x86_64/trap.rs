@@ -9,11 +9,19 @@ core::arch::global_asm!(include_str!("trap.S"));
 const IRQ_VECTOR_START: u8 = 0x20;
 const IRQ_VECTOR_END: u8 = 0xff;
 
-fn handle_page_fault(tf: &TrapFrame) {
+fn handle_page_fault(tf: &mut TrapFrame) {
     let access_flags = err_code_to_flags(tf.error_code)
         .unwrap_or_else(|e| panic!("Invalid #PF error code: {:#x}", e));
     let vaddr = va!(unsafe { cr2() });
     if !handle_trap!(PAGE_FAULT, vaddr, access_flags, tf.is_user()) {
+        #[cfg(feature = "uspace")]
+        if !tf.is_user() {
+            // Recover from the fault in accessing the user memory.
+            if let Some(fixup) = crate::uaccess::fixup_exception(tf.rip as _) {
+                tf.rip = fixup as _;
+                return;
+            }
+        }
         panic!(
             "Unhandled {} #PF @ {:#x}, fault_vaddr={:#x}, error_code={:#x} ({:?}):\n{:#x?}",
             if tf.is_user() { "user" } else { "kernel" },
@@ -27,7 +35,11 @@ fn handle_page_fault(tf: &TrapFrame) {
 }
 
 #[no_mangle]
-fn x86_trap_handler(tf: &TrapFrame) {
+fn x86_trap_handler(tf: &mut TrapFrame) {
+    #[cfg(feature = "uspace")]
+    if tf.is_user() {
+        crate::trap::notify_user_kernel_switch(true);
+    }
     match tf.vector as u8 {
         PAGE_FAULT_VECTOR => handle_page_fault(tf),
         BREAKPOINT_VECTOR => debug!("#BP @ {:#x} ", tf.rip),
@@ -51,6 +63,10 @@ fn x86_trap_handler(tf: &TrapFrame) {
             );
         }
     }
+    #[cfg(feature = "uspace")]
+    if tf.is_user() {
+        crate::trap::notify_user_kernel_switch(false);
+    }
 }
 
 fn vec_to_str(vec: u64) -> &'static str {
@@ -81,6 +81,8 @@ pub(crate) fn init_primary(cpu_id: usize) {
         CPU_ID.write_current_raw(cpu_id);
         IS_BSP.write_current_raw(true);
     }
+    #[cfg(feature = "uspace")]
+    crate::uaccess::init();
 }
 
 #[allow(dead_code)]
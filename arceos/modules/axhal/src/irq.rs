@@ -5,7 +5,7 @@ use handler_table::HandlerTable;
 use crate::platform::irq::{dispatch_irq, MAX_IRQ_COUNT};
 use crate::trap::{register_trap_handler, IRQ};
 
-pub use crate::platform::irq::{register_handler, set_enable};
+pub use crate::platform::irq::{register_handler, send_ipi, set_enable, IPI_IRQ_NUM};
 
 /// The type if an IRQ handler.
 pub type IrqHandler = handler_table::Handler;
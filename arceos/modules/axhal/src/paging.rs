@@ -2,6 +2,7 @@
 
 use axalloc::global_allocator;
 use lazyinit::LazyInit;
+use page_table_entry::GenericPTE;
 use page_table_multiarch::PagingHandler;
 
 use crate::mem::{phys_to_virt, virt_to_phys, MemRegionFlags, PhysAddr, VirtAddr, PAGE_SIZE_4K};
@@ -57,15 +58,69 @@ cfg_if::cfg_if! {
     if #[cfg(target_arch = "x86_64")] {
         /// The architecture-specific page table.
         pub type PageTable = page_table_multiarch::x86_64::X64PageTable<PagingHandlerImpl>;
+        type PageTableEntry = page_table_entry::x86_64::X64PTE;
+        const PAGE_TABLE_LEVELS: usize = 4;
     } else if #[cfg(any(target_arch = "riscv32", target_arch = "riscv64"))] {
         /// The architecture-specific page table.
         pub type PageTable = page_table_multiarch::riscv::Sv39PageTable<PagingHandlerImpl>;
+        type PageTableEntry = page_table_entry::riscv::Rv64PTE;
+        const PAGE_TABLE_LEVELS: usize = 3;
     } else if #[cfg(target_arch = "aarch64")]{
         /// The architecture-specific page table.
         pub type PageTable = page_table_multiarch::aarch64::A64PageTable<PagingHandlerImpl>;
+        type PageTableEntry = page_table_entry::aarch64::A64PTE;
+        const PAGE_TABLE_LEVELS: usize = 4;
     }
 }
 
+const ENTRY_COUNT: usize = 512;
+
+/// Splits the huge page mapped at `vaddr` into the pages of the next smaller
+/// size, which map the same frames with the same flags. Returns the size of
+/// the huge page, or [`PagingError::NotMapped`] if no huge page is mapped
+/// there.
+///
+/// The table of the smaller pages is filled first, and then replaces the huge
+/// page entry in one store, so that the pages stay mapped all along, even for
+/// the other CPUs accessing them meanwhile.
+pub fn split_huge_page(pt: &mut PageTable, vaddr: VirtAddr) -> PagingResult<PageSize> {
+    let (_, _, page_size) = pt.query(vaddr)?;
+    let sub_size = match page_size {
+        PageSize::Size1G => PageSize::Size2M,
+        PageSize::Size2M => PageSize::Size4K,
+        PageSize::Size4K => return Err(PagingError::NotMapped),
+    };
+    let size: usize = page_size.into();
+    let sub_size: usize = sub_size.into();
+    let entry_shift = size.trailing_zeros() as usize;
+
+    // Walk down to the huge page entry.
+    let mut table = pt.root_paddr();
+    let mut shift = 12 + 9 * (PAGE_TABLE_LEVELS - 1);
+    let entry = loop {
+        let index = (vaddr.as_usize() >> shift) % ENTRY_COUNT;
+        let entries = phys_to_virt(table).as_mut_ptr() as *mut PageTableEntry;
+        let entry = unsafe { &mut *entries.add(index) };
+        if shift == entry_shift {
+            break entry;
+        }
+        table = entry.paddr();
+        shift -= 9;
+    };
+
+    let new_table = PagingHandlerImpl::alloc_frame().ok_or(PagingError::NoMemory)?;
+    let frame = entry.paddr();
+    let flags = entry.flags();
+    let entries = phys_to_virt(new_table).as_mut_ptr() as *mut PageTableEntry;
+    for i in 0..ENTRY_COUNT {
+        let pte = PageTableEntry::new_page(frame + i * sub_size, flags, sub_size > PAGE_SIZE_4K);
+        unsafe { entries.add(i).write(pte) };
+    }
+    unsafe { (entry as *mut PageTableEntry).write_volatile(PageTableEntry::new_table(new_table)) };
+    crate::arch::flush_tlb(None);
+    Ok(page_size)
+}
+
 static KERNEL_PAGE_TABLE_ROOT: LazyInit<PhysAddr> = LazyInit::new();
 
 /// Saves the root physical address of the kernel page table, which may be used
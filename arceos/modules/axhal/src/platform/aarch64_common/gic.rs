@@ -9,12 +9,18 @@ pub const MAX_IRQ_COUNT: usize = 1024;
 /// The timer IRQ number.
 pub const TIMER_IRQ_NUM: usize = translate_irq(14, InterruptType::PPI).unwrap();
 
+/// The IRQ number of the inter-processor interrupts (SGI 1).
+pub const IPI_IRQ_NUM: usize = translate_irq(1, InterruptType::SGI).unwrap();
+
 /// The UART IRQ number.
 pub const UART_IRQ_NUM: usize = translate_irq(axconfig::UART_IRQ, InterruptType::SPI).unwrap();
 
 const GICD_BASE: PhysAddr = pa!(axconfig::GICD_PADDR);
 const GICC_BASE: PhysAddr = pa!(axconfig::GICC_PADDR);
 
+/// Software Generated Interrupt Register of GICD.
+const GICD_SGIR: usize = 0xf00;
+
 static GICD: SpinNoIrq<GicDistributor> =
     SpinNoIrq::new(GicDistributor::new(phys_to_virt(GICD_BASE).as_mut_ptr()));
 
@@ -45,6 +51,16 @@ pub fn dispatch_irq(_unused: usize) {
     GICC.handle_irq(|irq_num| crate::irq::dispatch_irq_common(irq_num as _));
 }
 
+/// Sends an inter-processor interrupt to the given CPU.
+pub fn send_ipi(cpu_id: usize) {
+    // Hold the lock of GICD, as the register is shared by all CPUs.
+    let _gicd = GICD.lock();
+    let sgir = phys_to_virt(GICD_BASE + GICD_SGIR).as_mut_ptr() as *mut u32;
+    // Sent to the CPU interfaces in the target list (bits 23:16).
+    let value = (1 << (16 + cpu_id)) | IPI_IRQ_NUM as u32;
+    unsafe { sgir.write_volatile(value) };
+}
+
 /// Initializes GICD, GICC on the primary CPU.
 pub(crate) fn init_primary() {
     info!("Initialize GICv2...");
@@ -56,4 +72,6 @@ pub(crate) fn init_primary() {
 #[cfg(feature = "smp")]
 pub(crate) fn init_secondary() {
     GICC.init();
+    // The enable bits of SGIs are banked per CPU.
+    GICD.lock().set_enable(IPI_IRQ_NUM as _, true);
 }
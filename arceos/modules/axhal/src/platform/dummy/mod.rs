@@ -68,6 +68,9 @@ pub mod irq {
     /// The timer IRQ number.
     pub const TIMER_IRQ_NUM: usize = 0;
 
+    /// The IRQ number of the inter-processor interrupts.
+    pub const IPI_IRQ_NUM: usize = 1;
+
     /// Enables or disables the given IRQ.
     pub fn set_enable(irq_num: usize, enabled: bool) {}
 
@@ -76,6 +79,9 @@ pub mod irq {
         false
     }
 
+    /// Sends an inter-processor interrupt to the given CPU.
+    pub fn send_ipi(cpu_id: usize) {}
+
     /// Dispatches the IRQ.
     ///
     /// This function is called by the common interrupt handler. It looks
@@ -2,6 +2,8 @@
 
 use crate::arch::{GdtStruct, IdtStruct, TaskStateSegment};
 use lazyinit::LazyInit;
+#[cfg(feature = "uspace")]
+use memory_addr::VirtAddr;
 
 static IDT: LazyInit<IdtStruct> = LazyInit::new();
 
@@ -35,3 +37,15 @@ pub(super) fn init_primary() {
 pub(super) fn init_secondary() {
     init_percpu();
 }
+
+/// Sets the kernel stack that the current CPU switches to on the traps from
+/// user space (`RSP0` in the TSS).
+///
+/// # Safety
+///
+/// It must be called with IRQs and preemption disabled.
+#[cfg(feature = "uspace")]
+pub(crate) unsafe fn set_kernel_stack(kstack_top: VirtAddr) {
+    let tss = TSS.current_ref_mut_raw();
+    tss.privilege_stack_table[0] = x86_64::VirtAddr::new(kstack_top.as_usize() as u64);
+}
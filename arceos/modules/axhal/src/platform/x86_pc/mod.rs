@@ -3,6 +3,9 @@ mod boot;
 mod dtables;
 mod uart16550;
 
+#[cfg(feature = "uspace")]
+pub(crate) use self::dtables::set_kernel_stack;
+
 pub mod mem;
 pub mod misc;
 pub mod time;
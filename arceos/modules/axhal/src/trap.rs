@@ -22,6 +22,18 @@ pub static PAGE_FAULT: [fn(VirtAddr, MappingFlags, bool) -> bool];
 #[def_trap_handler]
 pub static SYSCALL: [fn(&TrapFrame, usize) -> isize];
 
+/// A slice of functions called on the transitions between user mode and
+/// kernel mode, the argument is `true` when entering the kernel.
+///
+/// They are called on the entry and exit of the traps from user mode on all
+/// architectures, and before entering user mode the first time by
+/// `UspaceContext::enter_uspace`.
+///
+/// All registered functions are called, and they are called with IRQs
+/// disabled.
+#[def_trap_handler]
+pub static USER_KERNEL_SWITCH: [fn(bool)];
+
 #[allow(unused_macros)]
 macro_rules! handle_trap {
     ($trap:ident, $($args:tt)*) => {{
@@ -43,3 +55,11 @@ macro_rules! handle_trap {
 pub(crate) fn handle_syscall(tf: &TrapFrame, syscall_num: usize) -> isize {
     SYSCALL[0](tf, syscall_num)
 }
+
+/// Notify the transition between user mode and kernel mode.
+#[cfg(feature = "uspace")]
+pub(crate) fn notify_user_kernel_switch(to_kernel: bool) {
+    for func in USER_KERNEL_SWITCH {
+        func(to_kernel);
+    }
+}
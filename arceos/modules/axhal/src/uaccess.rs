@@ -0,0 +1,129 @@
+//! Fault-tolerant access to the user memory.
+//!
+//! The instructions that access the user memory are recorded in the
+//! exception table, along with the addresses to continue at if they fault.
+//! When the page fault in the kernel mode is not handled by the registered
+//! [`PAGE_FAULT`](crate::trap::PAGE_FAULT) handler, the trap handler looks up
+//! the faulting instruction in the table and jumps to the fixup address,
+//! instead of panicking.
+//!
+//! The entries are emitted in the link order, so the table is sorted once at
+//! boot by [`init`], and then looked up by binary search.
+
+/// An entry of the exception table, in the `__ex_table` section.
+#[repr(C)]
+struct ExceptionTableEntry {
+    /// The address of the instruction that may fault.
+    insn: usize,
+    /// The address to continue at if it faults.
+    fixup: usize,
+}
+
+extern "C" {
+    fn _ex_table_start();
+    fn _ex_table_end();
+}
+
+fn exception_table() -> &'static [ExceptionTableEntry] {
+    let start = _ex_table_start as usize;
+    let len = (_ex_table_end as usize - start) / core::mem::size_of::<ExceptionTableEntry>();
+    unsafe { core::slice::from_raw_parts(start as *const ExceptionTableEntry, len) }
+}
+
+/// Sorts the exception table by the instruction addresses.
+///
+/// It's called on the primary CPU at boot, before any trap is taken. The
+/// table is in the `.data` section to be sorted in place.
+pub(crate) fn init() {
+    let start = _ex_table_start as usize;
+    let len = exception_table().len();
+    let table = unsafe { core::slice::from_raw_parts_mut(start as *mut ExceptionTableEntry, len) };
+    table.sort_unstable_by_key(|ent| ent.insn);
+}
+
+/// Returns the address to continue at if the instruction at `pc` faults, or
+/// `None` if the fault is not expected.
+pub(crate) fn fixup_exception(pc: usize) -> Option<usize> {
+    let table = exception_table();
+    let idx = table.binary_search_by_key(&pc, |ent| ent.insn).ok()?;
+    let ent = &table[idx];
+    debug!("fixup exception @ {:#x} -> {:#x}", ent.insn, ent.fixup);
+    Some(ent.fixup)
+}
+
+/// Copies `len` bytes from `src` to `dst`, either of which is in the user
+/// memory of the current address space.
+///
+/// Returns the number of bytes not copied, which is non-zero if a page fault
+/// is not handled in the middle.
+///
+/// # Safety
+///
+/// The kernel memory of `src` and `dst` must be valid for the copy. The user
+/// memory is allowed to be invalid.
+pub unsafe fn copy_user(dst: *mut u8, src: *const u8, len: usize) -> usize {
+    let mut remaining = len;
+    cfg_if::cfg_if! {
+        if #[cfg(target_arch = "x86_64")] {
+            core::arch::asm!(
+                "2: test {len}, {len}",
+                "   jz 5f",
+                "3: mov {tmp}, byte ptr [{src}]",
+                "4: mov byte ptr [{dst}], {tmp}",
+                "   inc {src}",
+                "   inc {dst}",
+                "   dec {len}",
+                "   jmp 2b",
+                "5:",
+                ".pushsection __ex_table, \"a\"",
+                ".balign 8",
+                ".quad 3b, 5b",
+                ".quad 4b, 5b",
+                ".popsection",
+                src = inout(reg) src => _,
+                dst = inout(reg) dst => _,
+                len = inout(reg) remaining,
+                tmp = out(reg_byte) _,
+            );
+        } else if #[cfg(target_arch = "riscv64")] {
+            core::arch::asm!(
+                "2: beqz {len}, 5f",
+                "3: lb {tmp}, 0({src})",
+                "4: sb {tmp}, 0({dst})",
+                "   addi {src}, {src}, 1",
+                "   addi {dst}, {dst}, 1",
+                "   addi {len}, {len}, -1",
+                "   j 2b",
+                "5:",
+                ".pushsection __ex_table, \"a\"",
+                ".balign 8",
+                ".quad 3b, 5b",
+                ".quad 4b, 5b",
+                ".popsection",
+                src = inout(reg) src => _,
+                dst = inout(reg) dst => _,
+                len = inout(reg) remaining,
+                tmp = out(reg) _,
+            );
+        } else if #[cfg(target_arch = "aarch64")] {
+            core::arch::asm!(
+                "2: cbz {len}, 5f",
+                "3: ldrb {tmp:w}, [{src}], #1",
+                "4: strb {tmp:w}, [{dst}], #1",
+                "   sub {len}, {len}, #1",
+                "   b 2b",
+                "5:",
+                ".pushsection __ex_table, \"a\"",
+                ".balign 8",
+                ".quad 3b, 5b",
+                ".quad 4b, 5b",
+                ".popsection",
+                src = inout(reg) src => _,
+                dst = inout(reg) dst => _,
+                len = inout(reg) remaining,
+                tmp = out(reg) _,
+            );
+        }
+    }
+    remaining
+}
@@ -0,0 +1,128 @@
+//! Address space layout randomization (ASLR) for user programs.
+//!
+//! The loaders ask this module where to put the user stack, the heap and the
+//! position-independent executables (PIE), and [`new_user_aspace`] sets a
+//! random [`mmap_base`](crate::AddrSpace::mmap_base) for the mappings
+//! without a fixed address. Each of them is moved by a random page-aligned
+//! offset from its fixed place.
+//!
+//! The random numbers come from [`axhal::misc::random`]. For reproducible
+//! runs, a seed can be given by the `AX_ASLR_SEED` environment variable at
+//! build time, or by [`set_seed`] at runtime, and the offsets are then
+//! generated by a pseudo-random generator from it. The randomization can also
+//! be turned off with [`set_enabled`], which puts everything at the fixed
+//! places.
+//!
+//! [`new_user_aspace`]: crate::new_user_aspace
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use kspin::SpinNoIrq;
+use memory_addr::{VirtAddr, PAGE_SIZE_4K};
+
+/// The range of the random offset below the top of the user address space,
+/// for the user stack.
+pub const STACK_RANDOM_RANGE: usize = 0x4000_0000; // 1G
+/// The range of the random offset above the base of the user address space,
+/// for the mappings without a fixed address.
+pub const MMAP_RANDOM_RANGE: usize = 0x10_0000_0000; // 64G
+/// The range of the random offset after the end of the program data, for the
+/// heap.
+pub const HEAP_RANDOM_RANGE: usize = 0x200_0000; // 32M
+/// The range of the random offset above [`PIE_BASE`], for the PIE.
+pub const PIE_RANDOM_RANGE: usize = 0x4000_0000; // 1G
+/// Where to load the PIE without randomization.
+pub const PIE_BASE: usize = 0x40_0000;
+
+/// The alignment of the PIE load base, for the segments aligned to more than
+/// 4K.
+const PIE_ALIGN: usize = 0x1_0000;
+
+static ENABLED: AtomicBool = AtomicBool::new(true);
+
+/// The state of the pseudo-random generator, if a seed is given.
+static SEEDED_STATE: SpinNoIrq<Option<u64>> = SpinNoIrq::new(None);
+
+/// Whether the user address space layout is randomized.
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Enables or disables the randomization, for the address spaces created
+/// afterwards.
+pub fn set_enabled(enabled: bool) {
+    ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+/// Generates the offsets from `seed` from now on, instead of the kernel
+/// entropy source, so that the same layouts are generated in each run.
+pub fn set_seed(seed: u64) {
+    *SEEDED_STATE.lock() = Some(seed);
+}
+
+/// Parses the seed given at build time, in decimal or `0x`-prefixed hex.
+fn build_seed() -> Option<u64> {
+    let seed = option_env!("AX_ASLR_SEED")?.trim();
+    match seed.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => seed.parse().ok(),
+    }
+}
+
+/// Initializes the seed from the `AX_ASLR_SEED` environment variable at build
+/// time, if it is set. It's called in [`init_memory_management`].
+///
+/// [`init_memory_management`]: crate::init_memory_management
+pub(crate) fn init() {
+    if let Some(seed) = build_seed() {
+        info!("ASLR seed: {:#x}", seed);
+        set_seed(seed);
+    }
+}
+
+fn next_random() -> u64 {
+    let mut state = SEEDED_STATE.lock();
+    match state.as_mut() {
+        Some(state) => {
+            // SplitMix64
+            *state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+            let mut z = *state;
+            z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+            z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+            z ^ (z >> 31)
+        }
+        None => axhal::misc::random() as u64,
+    }
+}
+
+/// Returns a random multiple of `align` less than `range`, or 0 if the
+/// randomization is disabled.
+pub fn random_offset(range: usize, align: usize) -> usize {
+    let slots = range / align;
+    if !is_enabled() || slots == 0 {
+        return 0;
+    }
+    (next_random() % slots as u64) as usize * align
+}
+
+/// Returns the top of the user stack, below the `end` of the user address
+/// space.
+pub fn stack_top(end: VirtAddr) -> VirtAddr {
+    end - random_offset(STACK_RANDOM_RANGE, PAGE_SIZE_4K)
+}
+
+/// Returns where to start searching for the free areas for the mappings
+/// without a fixed address, above the `base` of the user address space.
+pub fn mmap_base(base: VirtAddr) -> VirtAddr {
+    base + random_offset(MMAP_RANDOM_RANGE, PAGE_SIZE_4K)
+}
+
+/// Returns the heap base, after the end of the program data `data_end`.
+pub fn heap_base(data_end: VirtAddr) -> VirtAddr {
+    data_end + random_offset(HEAP_RANDOM_RANGE, PAGE_SIZE_4K)
+}
+
+/// Returns the load base of the PIE, added to all of its addresses.
+pub fn pie_base() -> VirtAddr {
+    VirtAddr::from(PIE_BASE + random_offset(PIE_RANDOM_RANGE, PIE_ALIGN))
+}
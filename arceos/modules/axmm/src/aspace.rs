@@ -3,22 +3,70 @@ use core::fmt;
 use axerrno::{ax_err, AxError, AxResult};
 use axhal::{
     mem::phys_to_virt,
-    paging::{MappingFlags, PageTable},
+    paging::{MappingFlags, PageSize, PageTable},
 };
 use memory_addr::{
-    is_aligned_4k, pa, MemoryAddr, PageIter4K, PhysAddr, VirtAddr, VirtAddrRange, PAGE_SIZE_4K,
+    is_aligned, is_aligned_4k, pa, va, MemoryAddr, PageIter4K, PhysAddr, VirtAddr, VirtAddrRange,
+    PAGE_SIZE_4K,
 };
-use memory_set::{MemoryArea, MemorySet};
-use crate::backend::Backend;
+use memory_set::{MappingBackend, MemoryArea, MemorySet};
+use crate::backend::{query_present, split_huge_at, Backend, BackendKind};
+use crate::frame::{alloc_frame, frame_ref_count, get_frame, put_frame};
+use crate::mlock::LockedRanges;
+use crate::shm::SharedMemory;
+#[cfg(feature = "swap")]
+use crate::swap::{swap_write, SwapState};
 use crate::paging_err_to_ax_err;
 use crate::mapping_err_to_ax_err;
+use alloc::sync::Arc;
 use alloc::vec::Vec;
 
+/// The information of a mapped area, see [`AddrSpace::areas`].
+#[derive(Debug, Clone)]
+pub struct AreaInfo {
+    /// The address range of the area.
+    pub va_range: VirtAddrRange,
+    /// The mapping flags.
+    pub flags: MappingFlags,
+    /// The kind of the backend.
+    pub kind: BackendKind,
+    /// Whether the changes are shared with the other mappings of the same
+    /// memory.
+    pub shared: bool,
+    /// The offset of the area start in the mapped file or shared memory.
+    pub offset: u64,
+    /// The number of 4K pages in physical memory.
+    pub resident_pages: usize,
+}
+
+/// The advice about the use of a memory range, see [`AddrSpace::madvise`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryAdvice {
+    /// No special treatment (`MADV_NORMAL`).
+    Normal,
+    /// The range will be accessed soon, so fault it in now (`MADV_WILLNEED`).
+    WillNeed,
+    /// The range will not be accessed soon, so drop its pages. It reads as
+    /// zeros, or the file content, on the next access (`MADV_DONTNEED`).
+    DontNeed,
+    /// The contents of the range are no longer needed, so its frames can be
+    /// freed (`MADV_FREE`). Only for the allocation mappings.
+    Free,
+}
+
 /// The virtual memory address space.
 pub struct AddrSpace {
     va_range: VirtAddrRange,
     areas: MemorySet<Backend>,
     pt: PageTable,
+    /// The heap range, from the heap base to the program break.
+    heap: Option<VirtAddrRange>,
+    /// Where to start searching for the free areas by default.
+    mmap_base: VirtAddr,
+    /// The ranges locked in memory by [`mlock`](Self::mlock).
+    locked: LockedRanges,
+    #[cfg(feature = "swap")]
+    swap: SwapState,
 }
 
 impl AddrSpace {
@@ -53,12 +101,103 @@ impl AddrSpace {
             .contains_range(VirtAddrRange::from_start_size(start, size))
     }
 
+    /// Checks if the given address range is fully covered by the mapped areas.
+    pub fn is_mapped(&self, start: VirtAddr, size: usize) -> bool {
+        let end = start + size;
+        let mut addr = start;
+        for area in self.areas.iter() {
+            if addr >= end {
+                break;
+            }
+            if area.end() <= addr {
+                continue;
+            }
+            if area.start() > addr {
+                return false;
+            }
+            addr = area.end();
+        }
+        addr >= end
+    }
+
+    /// Returns where to start searching for the free areas for the mappings
+    /// without a fixed address. It's the address space base by default.
+    pub const fn mmap_base(&self) -> VirtAddr {
+        self.mmap_base
+    }
+
+    /// Sets where to start searching for the free areas for the mappings
+    /// without a fixed address, e.g., a random one for ASLR.
+    pub fn set_mmap_base(&mut self, mmap_base: VirtAddr) {
+        self.mmap_base = mmap_base;
+    }
+
+    /// Returns the heap range, from the heap base to the program break, if it
+    /// is set by [`init_heap`](Self::init_heap).
+    pub const fn heap(&self) -> Option<VirtAddrRange> {
+        self.heap
+    }
+
+    /// Returns an iterator over the mapped areas, sorted by the start address.
+    ///
+    /// Counting the resident pages walks the page table, so it is slow for
+    /// large areas.
+    pub fn areas(&self) -> impl Iterator<Item = AreaInfo> + '_ {
+        self.areas.iter().map(|area| AreaInfo {
+            va_range: area.va_range(),
+            flags: area.flags(),
+            kind: area.backend().kind(),
+            shared: area.backend().is_shared(),
+            offset: area.backend().offset_of(area.start()),
+            resident_pages: self.resident_pages(area.va_range()),
+        })
+    }
+
+    /// Returns the total size of the mapped areas in bytes (VSZ).
+    pub fn vsz(&self) -> usize {
+        self.areas.iter().map(|area| area.size()).sum()
+    }
+
+    /// Returns the size of the mapped areas in physical memory in bytes
+    /// (RSS), excluding the pages not allocated yet or swapped out.
+    pub fn rss(&self) -> usize {
+        self.areas
+            .iter()
+            .map(|area| self.resident_pages(area.va_range()))
+            .sum::<usize>()
+            * PAGE_SIZE_4K
+    }
+
+    /// Counts the 4K pages in the range that are in physical memory.
+    fn resident_pages(&self, range: VirtAddrRange) -> usize {
+        let mut count = 0;
+        let mut vaddr = range.start;
+        while vaddr < range.end {
+            match self.pt.query(vaddr) {
+                // Either present, or inactive and still in the frame.
+                Ok((frame, flags, page_size)) if !flags.is_empty() || frame.as_usize() != 0 => {
+                    let size: usize = page_size.into();
+                    let end = (vaddr.align_down(size) + size).min(range.end);
+                    count += (end - vaddr) / PAGE_SIZE_4K;
+                    vaddr = end;
+                }
+                _ => vaddr += PAGE_SIZE_4K,
+            }
+        }
+        count
+    }
+
     /// Creates a new empty address space.
     pub fn new_empty(base: VirtAddr, size: usize) -> AxResult<Self> {
         Ok(Self {
             va_range: VirtAddrRange::from_start_size(base, size),
             areas: MemorySet::new(),
             pt: PageTable::try_new().map_err(|_| AxError::NoMemory)?,
+            heap: None,
+            mmap_base: base,
+            locked: LockedRanges::new(),
+            #[cfg(feature = "swap")]
+            swap: SwapState::new(),
         })
     }
 
@@ -94,7 +233,8 @@ impl AddrSpace {
     /// Add a new linear mapping.
     ///
     /// The mapping is linear, i.e., `start_vaddr` is mapped to `start_paddr`,
-    /// and `start_vaddr + size` is mapped to `start_paddr + size`.
+    /// and `start_vaddr + size` is mapped to `start_paddr + size`. Huge pages
+    /// are used where the alignment and size permit.
     ///
     /// The `flags` parameter indicates the mapping permissions and attributes.
     ///
@@ -121,7 +261,7 @@ impl AddrSpace {
                 |va| pa!(va.as_usize() - offset),
                 size,
                 flags,
-                false, // allow_huge
+                true,  // allow_huge
                 false, // flush_tlb_by_page
             )
             .map_err(paging_err_to_ax_err)?
@@ -158,6 +298,38 @@ impl AddrSpace {
         Ok(())
     }
 
+    /// Add a new allocation mapping with huge pages of `page_size`, like
+    /// `MAP_HUGETLB`.
+    ///
+    /// The pages are allocated on demand (or all at once if `populate` is
+    /// `true`), as huge pages if the physical memory permits, or as 4K pages
+    /// otherwise.
+    ///
+    /// Returns an error if the address range is out of the address space or not
+    /// aligned to `page_size`.
+    pub fn map_alloc_huge(
+        &mut self,
+        start: VirtAddr,
+        size: usize,
+        flags: MappingFlags,
+        page_size: PageSize,
+        populate: bool,
+    ) -> AxResult {
+        if !self.contains_range(start, size) {
+            return ax_err!(InvalidInput, "address out of range");
+        }
+        if !start.is_aligned(page_size) || !is_aligned(size, page_size.into()) {
+            return ax_err!(InvalidInput, "address not aligned");
+        }
+
+        let backend = Backend::new_alloc_huge(populate, page_size);
+        let area = MemoryArea::new(start, size, flags, backend);
+        self.areas
+            .map(area, &mut self.pt, false)
+            .map_err(mapping_err_to_ax_err)?;
+        Ok(())
+    }
+
     /// Removes mappings within the specified virtual address range.
     ///
     /// Returns an error if the address range is out of the address space or not
@@ -170,10 +342,129 @@ impl AddrSpace {
             return ax_err!(InvalidInput, "address not aligned");
         }
 
-        self.pt
-            .unmap_region(start, size, true)
-            .map_err(paging_err_to_ax_err)?
-            .ignore();
+        self.areas
+            .unmap(start, size, &mut self.pt)
+            .map_err(mapping_err_to_ax_err)?;
+        #[cfg(feature = "swap")]
+        self.swap.discard(start, start + size);
+        self.locked.remove(start, start + size);
+        Ok(())
+    }
+
+    /// Add a new shared memory mapping, which maps `mem` from `offset` to
+    /// `start`.
+    ///
+    /// The writes are visible to all the address spaces that map the same
+    /// pages of `mem`, and are kept after [`clone_cow`](Self::clone_cow).
+    ///
+    /// Returns an error if the address range is out of the address space or
+    /// `mem`, or not aligned.
+    pub fn map_shared(
+        &mut self,
+        start: VirtAddr,
+        size: usize,
+        flags: MappingFlags,
+        mem: Arc<SharedMemory>,
+        offset: usize,
+    ) -> AxResult {
+        if !self.contains_range(start, size) {
+            return ax_err!(InvalidInput, "address out of range");
+        }
+        if !start.is_aligned_4k() || !is_aligned_4k(size) || !is_aligned_4k(offset) {
+            return ax_err!(InvalidInput, "address not aligned");
+        }
+        if offset + size > mem.size() {
+            return ax_err!(InvalidInput, "out of the shared memory");
+        }
+
+        let area = MemoryArea::new(start, size, flags, Backend::new_shared(mem, start, offset));
+        self.areas
+            .map(area, &mut self.pt, false)
+            .map_err(mapping_err_to_ax_err)?;
+        Ok(())
+    }
+
+    /// Removes the whole shared memory mapping that starts at `start`, e.g.,
+    /// to detach System V shared memory.
+    ///
+    /// Returns the size of the removed mapping, or an error if there is no
+    /// shared memory mapping at `start`.
+    pub fn unmap_shared(&mut self, start: VirtAddr) -> AxResult<usize> {
+        let size = match self.areas.find(start) {
+            Some(area) if area.start() == start => match area.backend() {
+                Backend::Shared { .. } => area.size(),
+                _ => return ax_err!(InvalidInput, "not a shared memory mapping"),
+            },
+            _ => return ax_err!(InvalidInput, "no mapping starts at the address"),
+        };
+        self.unmap(start, size)?;
+        Ok(size)
+    }
+
+    /// Add a new file mapping, which maps the file from `offset` to `start`.
+    ///
+    /// The pages are read from the file on the first access. If `shared` is
+    /// `true`, the written pages are written back to the file by
+    /// [`msync`](Self::msync) or [`unmap`](Self::unmap), otherwise they are
+    /// private to this address space.
+    ///
+    /// Returns an error if the address range is out of the address space or not
+    /// aligned.
+    #[cfg(feature = "fs")]
+    pub fn map_file(
+        &mut self,
+        start: VirtAddr,
+        size: usize,
+        flags: MappingFlags,
+        file: axfs::fops::File,
+        offset: u64,
+        shared: bool,
+    ) -> AxResult {
+        if !self.contains_range(start, size) {
+            return ax_err!(InvalidInput, "address out of range");
+        }
+        if !start.is_aligned_4k() || !is_aligned_4k(size) || !is_aligned_4k(offset as usize) {
+            return ax_err!(InvalidInput, "address not aligned");
+        }
+
+        let backend = Backend::new_file(file, start, offset, shared);
+        let area = MemoryArea::new(start, size, flags, backend);
+        self.areas
+            .map(area, &mut self.pt, false)
+            .map_err(mapping_err_to_ax_err)?;
+        Ok(())
+    }
+
+    /// Writes the written pages of the shared file mappings within the
+    /// specified range back to the files.
+    ///
+    /// Returns an error if the address range is out of the address space or not
+    /// aligned.
+    #[cfg(feature = "fs")]
+    pub fn msync(&mut self, start: VirtAddr, size: usize) -> AxResult {
+        if !self.contains_range(start, size) {
+            return ax_err!(InvalidInput, "address out of range");
+        }
+        if !start.is_aligned_4k() || !is_aligned_4k(size) {
+            return ax_err!(InvalidInput, "address not aligned");
+        }
+
+        let end = start + size;
+        for area in self.areas.iter() {
+            if let Backend::File(mapping) = area.backend() {
+                let sync_start = area.start().max(start);
+                let sync_end = area.end().min(end);
+                if sync_start < sync_end {
+                    area.backend().sync_file(
+                        mapping,
+                        sync_start,
+                        sync_end - sync_start,
+                        area.flags(),
+                        &mut self.pt,
+                    )?;
+                }
+            }
+        }
         Ok(())
     }
 
@@ -229,7 +520,15 @@ impl AddrSpace {
     ///
     /// * `start_vaddr` - The start virtual address to write.
     /// * `buf` - The buffer to write to the address space.
-    pub fn write(&self, start: VirtAddr, buf: &[u8]) -> AxResult {
+    ///
+    /// The pages shared copy-on-write are copied before written. The kernel
+    /// writes through the linear mapping, which ignores the read-only entries,
+    /// so it takes `&mut self` to unshare them in the page table first.
+    pub fn write(&mut self, start: VirtAddr, buf: &[u8]) -> AxResult {
+        if !self.contains_range(start, buf.len()) {
+            return ax_err!(InvalidInput, "address out of range");
+        }
+        self.prepare_write(start, buf.len())?;
         self.process_area_data(start, buf.len(), |dst, offset, write_size| unsafe {
             core::ptr::copy_nonoverlapping(buf.as_ptr().add(offset), dst.as_mut_ptr(), write_size);
         })
@@ -237,20 +536,568 @@ impl AddrSpace {
 
     /// Updates mapping within the specified virtual address range.
     ///
+    /// The areas partially in the range are split, and the pages not present
+    /// get the new flags on the next page fault. The split areas are not
+    /// merged back even if they get the same flags again, as [`MemorySet`]
+    /// cannot merge areas, but they still behave as one mapping.
+    ///
     /// Returns an error if the address range is out of the address space or not
-    /// aligned.
+    /// aligned, or [`AxError::NoMemory`] if some of the range is not mapped
+    /// (`ENOMEM` of `mprotect`).
     pub fn protect(&mut self, start: VirtAddr, size: usize, flags: MappingFlags) -> AxResult {
+        self.check_mapped(start, size)?;
+        self.areas
+            .protect(start, size, |_| Some(flags), &mut self.pt)
+            .map_err(mapping_err_to_ax_err)?;
+        if flags.contains(MappingFlags::WRITE) {
+            // Some pages must stay read-only to catch the writes.
+            for vaddr in PageIter4K::new(start, start + size).unwrap() {
+                let Some(area) = self.areas.find(vaddr) else {
+                    continue;
+                };
+                if let Some((frame, _, PageSize::Size4K)) = query_present(&self.pt, vaddr) {
+                    if area.backend().needs_write_fault(vaddr, frame) {
+                        self.pt
+                            .protect(vaddr, flags - MappingFlags::WRITE)
+                            .map(|(_, tlb)| tlb.flush())
+                            .map_err(paging_err_to_ax_err)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Gives advice about the use of the memory in the range, like `madvise`.
+    ///
+    /// [`MemoryAdvice::DontNeed`] frees the frames of the lazy allocation
+    /// mappings, which are allocated and zeroed again on the next access, and
+    /// drops the pages of the shared and file mappings, which are mapped or
+    /// read again. The pages of the populated mappings are zeroed in place.
+    /// [`MemoryAdvice::Free`] does the same for the allocation mappings right
+    /// away, rather than when memory is short.
+    ///
+    /// Returns an error if the address range is out of the address space or not
+    /// aligned, [`AxError::NoMemory`] if some of the range is not mapped, or
+    /// [`AxError::InvalidInput`] if the pages cannot be dropped, e.g., they
+    /// are locked or linearly mapped.
+    pub fn madvise(&mut self, start: VirtAddr, size: usize, advice: MemoryAdvice) -> AxResult {
+        self.check_mapped(start, size)?;
+        let end = start + size;
+        match advice {
+            MemoryAdvice::Normal => Ok(()),
+            MemoryAdvice::WillNeed => {
+                // It's only a hint, so the failures are ignored.
+                let _ = self.populate(start, end);
+                Ok(())
+            }
+            MemoryAdvice::DontNeed => self.drop_pages(start, end, false),
+            MemoryAdvice::Free => self.drop_pages(start, end, true),
+        }
+    }
+
+    /// Locks the pages in the range in memory, like `mlock`.
+    ///
+    /// The pages are faulted in, and then they are never swapped out or
+    /// dropped by [`madvise`](Self::madvise), until they are unlocked or
+    /// unmapped. The locks are not inherited by [`clone_cow`](Self::clone_cow).
+    ///
+    /// Returns an error if the address range is out of the address space or not
+    /// aligned, or [`AxError::NoMemory`] if some of the range is not mapped or
+    /// cannot be faulted in.
+    pub fn mlock(&mut self, start: VirtAddr, size: usize) -> AxResult {
+        self.check_mapped(start, size)?;
+        self.populate(start, start + size)?;
+        self.locked.insert(start, start + size);
+        Ok(())
+    }
+
+    /// Unlocks the pages in the range locked by [`mlock`](Self::mlock), like
+    /// `munlock`.
+    ///
+    /// Returns an error if the address range is out of the address space or not
+    /// aligned, or [`AxError::NoMemory`] if some of the range is not mapped.
+    pub fn munlock(&mut self, start: VirtAddr, size: usize) -> AxResult {
+        self.check_mapped(start, size)?;
+        self.locked.remove(start, start + size);
+        Ok(())
+    }
+
+    /// Returns the size of the pages locked by [`mlock`](Self::mlock) in
+    /// bytes.
+    pub fn locked_size(&self) -> usize {
+        self.locked.size()
+    }
+
+    /// Checks that the range is aligned and fully mapped.
+    fn check_mapped(&self, start: VirtAddr, size: usize) -> AxResult {
         if !self.contains_range(start, size) {
             return ax_err!(InvalidInput, "address out of range");
         }
         if !start.is_aligned_4k() || !is_aligned_4k(size) {
             return ax_err!(InvalidInput, "address not aligned");
         }
+        if !self.is_mapped(start, size) {
+            return ax_err!(NoMemory, "address not mapped");
+        }
+        Ok(())
+    }
 
-        self.pt
-            .protect_region(start, size, flags, true)
-            .map_err(paging_err_to_ax_err)?
-            .ignore();
+    /// Faults in the pages in `[start, end)` that are not present. The
+    /// copy-on-write sharing of the writable allocation mappings is broken
+    /// as well, and the inaccessible areas are skipped.
+    fn populate(&mut self, start: VirtAddr, end: VirtAddr) -> AxResult {
+        for vaddr in PageIter4K::new(start, end).unwrap() {
+            let Some(area) = self.areas.find(vaddr) else {
+                continue;
+            };
+            let flags = area.flags();
+            if flags.is_empty() {
+                continue;
+            }
+            let access_flags = match area.backend() {
+                Backend::Alloc { .. } if flags.contains(MappingFlags::WRITE) => MappingFlags::WRITE,
+                _ => MappingFlags::empty(),
+            };
+            let present = match query_present(&self.pt, vaddr) {
+                Some((_, pte_flags, _)) => pte_flags.contains(access_flags),
+                None => false,
+            };
+            if !present && !self.handle_page_fault(vaddr, access_flags) {
+                return ax_err!(NoMemory);
+            }
+        }
+        Ok(())
+    }
+
+    /// Drops the pages in `[start, end)`, for [`MemoryAdvice::DontNeed`], or
+    /// [`MemoryAdvice::Free`] if `alloc_only` is `true`.
+    fn drop_pages(&mut self, start: VirtAddr, end: VirtAddr, alloc_only: bool) -> AxResult {
+        if self.locked.overlaps(start, end) {
+            return ax_err!(InvalidInput, "pages locked");
+        }
+        let ranges: Vec<_> = self
+            .areas
+            .iter()
+            .filter(|area| area.end() > start && area.start() < end)
+            .map(|area| {
+                let range = VirtAddrRange::new(area.start().max(start), area.end().min(end));
+                (range, area.flags(), area.backend().clone())
+            })
+            .collect();
+        // Check all the areas before dropping anything.
+        for (_, _, backend) in &ranges {
+            let droppable = match backend {
+                Backend::Linear { .. } => false,
+                Backend::Alloc { .. } => true,
+                _ => !alloc_only,
+            };
+            if !droppable {
+                return ax_err!(InvalidInput, "cannot drop the pages");
+            }
+        }
+        for (range, flags, backend) in ranges {
+            if let Backend::Alloc { populate: true, .. } = backend {
+                self.zero_pages(range, flags)?;
+                continue;
+            }
+            // The frames are freed, or the shared pages are written back.
+            if !backend.unmap(range.start, range.size(), &mut self.pt) {
+                return ax_err!(NoMemory);
+            }
+            #[cfg(feature = "swap")]
+            self.swap.discard(range.start, range.end);
+        }
+        Ok(())
+    }
+
+    /// Zeroes the present pages in the range of a populated allocation
+    /// mapping in place, or replaces them with zeroed frames if they are
+    /// shared copy-on-write.
+    fn zero_pages(&mut self, range: VirtAddrRange, flags: MappingFlags) -> AxResult {
+        if !split_huge_at(&mut self.pt, range.start) || !split_huge_at(&mut self.pt, range.end) {
+            return ax_err!(NoMemory);
+        }
+        let mut vaddr = range.start;
+        while vaddr < range.end {
+            let Some((frame, _, page_size)) = query_present(&self.pt, vaddr) else {
+                vaddr += PAGE_SIZE_4K;
+                continue;
+            };
+            if page_size == PageSize::Size4K && frame_ref_count(frame) > 1 {
+                let Some(new_frame) = alloc_frame(true) else {
+                    return ax_err!(NoMemory);
+                };
+                let (_, tlb) = self
+                    .pt
+                    .remap(vaddr, new_frame, flags)
+                    .map_err(paging_err_to_ax_err)?;
+                tlb.flush();
+                put_frame(frame);
+            } else {
+                unsafe {
+                    core::ptr::write_bytes(phys_to_virt(frame).as_mut_ptr(), 0, page_size.into())
+                };
+            }
+            vaddr += usize::from(page_size);
+        }
+        Ok(())
+    }
+
+    /// Resizes and/or moves the mapping at `old_start` of `old_size` bytes to
+    /// `new_size` bytes, like `mremap`. Returns the new start address.
+    ///
+    /// The range must be within one area. It is shrunk or grown in place if
+    /// possible. Otherwise, it is moved to a free range if `may_move` is
+    /// `true`, or to `new_start` replacing the old mappings there if it is
+    /// given. The pages are moved without copying.
+    ///
+    /// Returns [`AxError::BadAddress`] if the range is not mapped, and
+    /// [`AxError::NoMemory`] if it cannot be grown in place and not allowed
+    /// to move. Linear and file mappings cannot be moved
+    /// ([`AxError::Unsupported`]). Nothing is unmapped if it fails.
+    pub fn mremap(
+        &mut self,
+        old_start: VirtAddr,
+        old_size: usize,
+        new_size: usize,
+        may_move: bool,
+        new_start: Option<VirtAddr>,
+    ) -> AxResult<VirtAddr> {
+        if !old_start.is_aligned_4k() || !is_aligned_4k(old_size) || !is_aligned_4k(new_size) {
+            return ax_err!(InvalidInput, "address not aligned");
+        }
+        if new_size == 0 {
+            return ax_err!(InvalidInput, "zero size");
+        }
+        let old_end = old_start + old_size;
+        let (flags, backend) = match self.areas.find(old_start) {
+            Some(area) if old_end <= area.end() => (area.flags(), area.backend().clone()),
+            _ => return ax_err!(BadAddress, "address not mapped"),
+        };
+
+        if let Some(new_start) = new_start {
+            if !new_start.is_aligned_4k() {
+                return ax_err!(InvalidInput, "address not aligned");
+            }
+            if !self.contains_range(new_start, new_size) {
+                return ax_err!(InvalidInput, "address out of range");
+            }
+            let new_range = VirtAddrRange::from_start_size(new_start, new_size);
+            if new_range.overlaps(VirtAddrRange::new(old_start, old_end)) {
+                return ax_err!(InvalidInput, "overlapping ranges");
+            }
+            // Check everything before replacing the mappings at `new_start`.
+            let Some(new_backend) = backend.relocate(old_start, new_start) else {
+                return ax_err!(Unsupported, "cannot move the mapping");
+            };
+            self.unmap(new_start, new_size)?;
+            self.move_range(old_start, old_size, new_start, new_size, flags, new_backend)?;
+            return Ok(new_start);
+        }
+
+        if new_size <= old_size {
+            self.unmap(old_start + new_size, old_size - new_size)?;
+            return Ok(old_start);
+        }
+        // Grow in place with the same backend, so the extension behaves as a
+        // part of the same mapping.
+        let grow = VirtAddrRange::new(old_end, old_start + new_size);
+        if self.contains_range(grow.start, grow.size()) && !self.areas.overlaps(grow) {
+            let area = MemoryArea::new(grow.start, grow.size(), flags, backend);
+            self.areas
+                .map(area, &mut self.pt, false)
+                .map_err(mapping_err_to_ax_err)?;
+            return Ok(old_start);
+        }
+        if !may_move {
+            return ax_err!(NoMemory, "cannot grow in place");
+        }
+        let new_start = self
+            .find_free_area(self.mmap_base, new_size, self.va_range)
+            .ok_or(AxError::NoMemory)?;
+        let Some(new_backend) = backend.relocate(old_start, new_start) else {
+            return ax_err!(Unsupported, "cannot move the mapping");
+        };
+        self.move_range(old_start, old_size, new_start, new_size, flags, new_backend)?;
+        Ok(new_start)
+    }
+
+    /// Moves the pages in `[old_start, old_start + old_size)` to a new area of
+    /// `new_size` bytes with `new_backend` at the free range `new_start`, and
+    /// unmaps the old range.
+    ///
+    /// The old range is kept until all the pages are moved, so it is left as
+    /// it was if the move fails.
+    fn move_range(
+        &mut self,
+        old_start: VirtAddr,
+        old_size: usize,
+        new_start: VirtAddr,
+        new_size: usize,
+        flags: MappingFlags,
+        new_backend: Backend,
+    ) -> AxResult {
+        let area = MemoryArea::new(new_start, new_size, flags, new_backend);
+        self.areas
+            .map(area, &mut self.pt, false)
+            .map_err(mapping_err_to_ax_err)?;
+
+        let moved = old_size.min(new_size);
+        if let Err(e) = self.move_pages(old_start, new_start, moved) {
+            // Undo the move: only the swap slots are taken from the old range.
+            #[cfg(feature = "swap")]
+            for offset in (0..moved).step_by(PAGE_SIZE_4K) {
+                if let Some(slot) = self.swap.slots.remove(&(new_start + offset)) {
+                    self.swap.slots.insert(old_start + offset, slot);
+                }
+            }
+            self.unmap(new_start, new_size)?;
+            return Err(e);
+        }
+        // The locks of the old range are removed by the unmapping.
+        for (start, end) in self.locked.ranges_in(old_start, old_start + moved) {
+            self.locked.insert(
+                new_start + (start - old_start),
+                new_start + (end - old_start),
+            );
+        }
+        self.unmap(old_start, old_size)
+    }
+
+    /// Maps the frames of the `size` bytes at `src` to `dst` as well, taking
+    /// a reference of each frame, and moves the swap slots.
+    fn move_pages(&mut self, src: VirtAddr, dst: VirtAddr, size: usize) -> AxResult {
+        for offset in (0..size).step_by(PAGE_SIZE_4K) {
+            let (src, dst) = (src + offset, dst + offset);
+            // Split until `src` and `dst` are the start of a 4K page.
+            if !split_huge_at(&mut self.pt, src + PAGE_SIZE_4K)
+                || !split_huge_at(&mut self.pt, dst + PAGE_SIZE_4K)
+            {
+                return ax_err!(NoMemory);
+            }
+            #[cfg(feature = "swap")]
+            if let Some(slot) = self.swap.slots.remove(&src) {
+                self.swap.slots.insert(dst, slot);
+                continue;
+            }
+            // Including the inactive pages that are not present.
+            let Ok((frame, pte_flags, _)) = self.pt.query(src) else {
+                continue;
+            };
+            if frame.as_usize() == 0 {
+                continue; // not populated yet
+            }
+            // Replaces the frame allocated by a populated area.
+            let populated = query_present(&self.pt, dst).map(|(populated, _, _)| populated);
+            match self.pt.remap(dst, frame, pte_flags) {
+                Ok((_, tlb)) => tlb.flush(),
+                // The entries of lazy huge page areas do not exist.
+                Err(_) => self
+                    .pt
+                    .map(dst, frame, PageSize::Size4K, pte_flags)
+                    .map_err(paging_err_to_ax_err)?
+                    .flush(),
+            }
+            if let Some(populated) = populated {
+                put_frame(populated);
+            }
+            // Either mapping drops its reference when it is unmapped.
+            get_frame(frame);
+        }
+        Ok(())
+    }
+
+    /// Sets the heap to start at `base`, usually the end of the program data.
+    /// The program break is `base` at first.
+    pub fn init_heap(&mut self, base: VirtAddr) {
+        self.heap = Some(VirtAddrRange::new(base, base));
+    }
+
+    /// Sets the program break to `new_brk`, like `brk`.
+    ///
+    /// The heap is grown with lazy allocation mappings, and shrunk by
+    /// unmapping. Returns the new program break, or the current one if it
+    /// cannot be changed, e.g., `new_brk` is below the heap base, or the heap
+    /// would overlap other mappings. Returns 0 if the heap is not set by
+    /// [`init_heap`](Self::init_heap).
+    pub fn brk(&mut self, new_brk: VirtAddr) -> VirtAddr {
+        let Some(heap) = self.heap else {
+            return va!(0);
+        };
+        if new_brk < heap.start || !self.va_range.contains(new_brk) {
+            return heap.end;
+        }
+        let old_top = heap.end.align_up_4k();
+        let new_top = new_brk.align_up_4k();
+        let res = if new_top > old_top {
+            let flags = MappingFlags::READ | MappingFlags::WRITE | MappingFlags::USER;
+            self.map_alloc(old_top, new_top - old_top, flags, false)
+        } else if new_top < old_top {
+            self.unmap(new_top, old_top - new_top)
+        } else {
+            Ok(())
+        };
+        if res.is_err() {
+            return heap.end;
+        }
+        self.heap = Some(VirtAddrRange::new(heap.start, new_brk));
+        new_brk
+    }
+
+    /// Creates a copy of the address space, sharing the physical frames
+    /// copy-on-write.
+    ///
+    /// The populated pages of [`Backend::Alloc`] areas are mapped read-only in
+    /// both address spaces, and the first write to one of them copies the
+    /// frame (see [`handle_page_fault`](Self::handle_page_fault)). The copied
+    /// areas are always lazy, the pages not populated yet are allocated on
+    /// demand. So are the private file mappings, while the shared ones keep
+    /// sharing the pages. Linear areas are mapped to the same physical memory,
+    /// and shared memory areas to the same frames, writable as before.
+    ///
+    /// The huge pages are split into 4K pages to be shared. The kernel mappings
+    /// copied by [`copy_mappings_from`](Self::copy_mappings_from) are copied as
+    /// well.
+    pub fn clone_cow(&mut self) -> AxResult<Self> {
+        let mut child = Self::new_empty(self.base(), self.size())?;
+        let kernel_range = VirtAddrRange::from_start_size(
+            va!(axconfig::KERNEL_ASPACE_BASE),
+            axconfig::KERNEL_ASPACE_SIZE,
+        );
+        if !self.va_range.overlaps(kernel_range) {
+            child
+                .pt
+                .copy_from(&self.pt, kernel_range.start, kernel_range.size());
+        }
+
+        for area in self.areas.iter() {
+            let (start, size, flags) = (area.start(), area.size(), area.flags());
+            let backend = match area.backend() {
+                Backend::Alloc { .. } => Backend::new_alloc(false),
+                // The file mapping (and its page cache) is shared, private
+                // pages are copied on write like the above.
+                _ => area.backend().clone(),
+            };
+            child
+                .areas
+                .map(
+                    MemoryArea::new(start, size, flags, backend),
+                    &mut child.pt,
+                    false,
+                )
+                .map_err(mapping_err_to_ax_err)?;
+            if matches!(area.backend(), Backend::Linear { .. }) {
+                continue;
+            }
+            let shared = area.backend().is_shared();
+            for vaddr in PageIter4K::new(start, start + size).unwrap() {
+                // Bring the inactive and swapped out pages back to share them.
+                #[cfg(feature = "swap")]
+                if matches!(area.backend(), Backend::Alloc { .. })
+                    && self.swap.fault_in(&mut self.pt, vaddr, flags) == Some(false)
+                {
+                    return ax_err!(NoMemory);
+                }
+                // Split until `vaddr` is the start of a 4K page.
+                if !split_huge_at(&mut self.pt, vaddr + PAGE_SIZE_4K) {
+                    return ax_err!(NoMemory);
+                }
+                let Some((frame, pte_flags, _)) = query_present(&self.pt, vaddr) else {
+                    continue; // not populated yet
+                };
+                let child_flags = if shared {
+                    pte_flags
+                } else {
+                    pte_flags - MappingFlags::WRITE
+                };
+                if child_flags != pte_flags {
+                    self.pt
+                        .protect(vaddr, child_flags)
+                        .map_err(paging_err_to_ax_err)?
+                        .1
+                        .flush();
+                }
+                child
+                    .pt
+                    .remap(vaddr, frame, child_flags)
+                    .map_err(paging_err_to_ax_err)?
+                    .1
+                    .ignore();
+                get_frame(frame);
+            }
+        }
+        child.heap = self.heap;
+        child.mmap_base = self.mmap_base;
+        Ok(child)
+    }
+
+    /// Resolves the pages in the given range that are kept read-only to catch
+    /// writes (see [`Backend::needs_write_fault`]), as if they are written by
+    /// the user, before the kernel writes to them directly.
+    fn prepare_write(&mut self, start: VirtAddr, size: usize) -> AxResult {
+        let end = (start + size).align_up_4k();
+        for vaddr in PageIter4K::new(start.align_down_4k(), end).unwrap() {
+            let Some(area) = self.areas.find(vaddr) else {
+                continue;
+            };
+            let orig_flags = area.flags();
+            if !orig_flags.contains(MappingFlags::WRITE) {
+                continue;
+            }
+            #[cfg(feature = "swap")]
+            if matches!(area.backend(), Backend::Alloc { .. })
+                && self.swap.fault_in(&mut self.pt, vaddr, orig_flags) == Some(false)
+            {
+                return ax_err!(NoMemory);
+            }
+            if let Some((_, flags, _)) = query_present(&self.pt, vaddr) {
+                if !flags.contains(MappingFlags::WRITE)
+                    && !area.backend().handle_page_fault(
+                        vaddr,
+                        MappingFlags::WRITE,
+                        orig_flags,
+                        area.va_range(),
+                        &mut self.pt,
+                    )
+                {
+                    return ax_err!(NoMemory);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks that the user can access `[start, start + size)` with
+    /// `access_flags`, and handles the page faults in advance, so that the
+    /// kernel can access the range directly without faults.
+    ///
+    /// Returns [`AxError::BadAddress`] if some of the range is not mapped or
+    /// not accessible.
+    pub fn fault_in_user(
+        &mut self,
+        start: VirtAddr,
+        size: usize,
+        access_flags: MappingFlags,
+    ) -> AxResult {
+        if size == 0 {
+            return Ok(());
+        }
+        if !self.contains_range(start, size) {
+            return ax_err!(BadAddress, "address out of range");
+        }
+        let access_flags = access_flags | MappingFlags::USER;
+        let end = (start + size).align_up_4k();
+        for vaddr in PageIter4K::new(start.align_down_4k(), end).unwrap() {
+            let present = match query_present(&self.pt, vaddr) {
+                // Copy-on-write pages are present but not writable.
+                Some((_, flags, _)) => flags.contains(access_flags),
+                None => false,
+            };
+            if !present && !self.handle_page_fault(vaddr, access_flags) {
+                return ax_err!(BadAddress, "bad user address");
+            }
+        }
         Ok(())
     }
 
@@ -260,19 +1107,120 @@ impl AddrSpace {
     ///
     /// Returns `true` if the page fault is handled successfully (not a real
     /// fault).
+    ///
+    /// With the `swap` feature, the swapped out pages are read back here, and
+    /// some pages are swapped out to retry if it fails for lack of memory.
     pub fn handle_page_fault(&mut self, vaddr: VirtAddr, access_flags: MappingFlags) -> bool {
+        match self.try_handle_page_fault(vaddr, access_flags) {
+            #[cfg(feature = "swap")]
+            Some(false) if self.swap_out(crate::swap::SWAP_CLUSTER) > 0 => {
+                self.try_handle_page_fault(vaddr, access_flags) == Some(true)
+            }
+            res => res == Some(true),
+        }
+    }
+
+    /// Handles a page fault, returns `None` if the access is not allowed.
+    fn try_handle_page_fault(
+        &mut self,
+        vaddr: VirtAddr,
+        access_flags: MappingFlags,
+    ) -> Option<bool> {
         if !self.va_range.contains(vaddr) {
-            return false;
+            return None;
         }
-        if let Some(area) = self.areas.find(vaddr) {
-            let orig_flags = area.flags();
-            if orig_flags.contains(access_flags) {
-                return area
-                    .backend()
-                    .handle_page_fault(vaddr, orig_flags, &mut self.pt);
+        let area = self.areas.find(vaddr)?;
+        let orig_flags = area.flags();
+        if !orig_flags.contains(access_flags) {
+            return None;
+        }
+        #[cfg(feature = "swap")]
+        if matches!(area.backend(), Backend::Alloc { .. }) {
+            if let Some(res) = self.swap.fault_in(&mut self.pt, vaddr, orig_flags) {
+                return Some(res);
+            }
+        }
+        Some(area.backend().handle_page_fault(
+            vaddr,
+            access_flags,
+            orig_flags,
+            area.va_range(),
+            &mut self.pt,
+        ))
+    }
+
+    /// Swaps out at most `nr_pages` pages of this address space to the swap
+    /// device, see [`crate::swap`] for details.
+    ///
+    /// Returns the number of pages swapped out.
+    #[cfg(feature = "swap")]
+    pub fn swap_out(&mut self, nr_pages: usize) -> usize {
+        let (used, total) = crate::swap::swap_usage();
+        if used == total {
+            return 0; // no swap device, or it is full
+        }
+        let ranges: Vec<_> = self
+            .areas
+            .iter()
+            .filter(|area| {
+                matches!(
+                    area.backend(),
+                    Backend::Alloc {
+                        populate: false,
+                        ..
+                    }
+                )
+            })
+            .map(|area| (area.start(), area.end()))
+            .collect();
+        let hand = self.swap.hand;
+        // Start from the clock hand, and go around twice, as the pages are
+        // made inactive in the first round.
+        let after = ranges
+            .iter()
+            .filter(|r| r.1 > hand)
+            .map(|r| (r.0.max(hand), r.1));
+        let before = ranges
+            .iter()
+            .filter(|r| r.0 < hand)
+            .map(|r| (r.0, r.1.min(hand)));
+        let round: Vec<_> = after.chain(before).collect();
+
+        let mut count = 0;
+        for &(start, end) in round.iter().chain(round.iter()) {
+            for vaddr in PageIter4K::new(start, end).unwrap() {
+                let Ok((frame, flags, PageSize::Size4K)) = self.pt.query(vaddr) else {
+                    continue;
+                };
+                if frame.as_usize() == 0 || self.locked.contains(vaddr) {
+                    continue; // not populated yet, or locked
+                }
+                if !flags.is_empty() {
+                    // Active, make it inactive unless it is shared.
+                    if frame_ref_count(frame) == 1 {
+                        if let Ok((_, tlb)) = self.pt.remap(vaddr, frame, MappingFlags::empty()) {
+                            tlb.flush();
+                        }
+                    }
+                    continue;
+                }
+                // Still inactive since the last round, swap it out.
+                let Ok(slot) = swap_write(frame) else {
+                    return count; // no swap space
+                };
+                if let Ok((_, tlb)) = self.pt.remap(vaddr, 0.into(), MappingFlags::empty()) {
+                    tlb.flush();
+                }
+                put_frame(frame);
+                self.swap.slots.insert(vaddr, slot);
+                count += 1;
+                if count == nr_pages {
+                    self.swap.hand = vaddr + PAGE_SIZE_4K;
+                    return count;
+                }
             }
         }
-        false
+        count
     }
 
     pub fn translated_byte_buffer(
@@ -1,28 +1,35 @@
-use axalloc::global_allocator;
-use axhal::mem::{phys_to_virt, virt_to_phys};
 use axhal::paging::{MappingFlags, PageSize, PageTable};
-use memory_addr::{PageIter4K, PhysAddr, VirtAddr, PAGE_SIZE_4K};
+use memory_addr::{MemoryAddr, VirtAddr, VirtAddrRange, PAGE_SIZE_4K};
 
-use super::Backend;
+use super::{query_present, Backend};
+use crate::frame::{alloc_frame, alloc_huge_frame, free_huge_frame, put_frame, unshare_frame};
 
-fn alloc_frame(zeroed: bool) -> Option<PhysAddr> {
-    let vaddr = VirtAddr::from(global_allocator().alloc_pages(1, PAGE_SIZE_4K).ok()?);
-    if zeroed {
-        unsafe { core::ptr::write_bytes(vaddr.as_mut_ptr(), 0, PAGE_SIZE_4K) };
-    }
-    let paddr = virt_to_phys(vaddr);
-    Some(paddr)
-}
+/// The huge page sizes to try, from the largest.
+const HUGE_PAGE_SIZES: [PageSize; 2] = [PageSize::Size1G, PageSize::Size2M];
 
-fn dealloc_frame(frame: PhysAddr) {
-    let vaddr = phys_to_virt(frame);
-    global_allocator().dealloc_pages(vaddr.as_usize(), 1);
+/// Returns whether a page of `page_size` at the aligned-down `vaddr` lies
+/// within `[start, end)`.
+fn fits_in(vaddr: VirtAddr, page_size: PageSize, start: VirtAddr, end: VirtAddr) -> bool {
+    let base = vaddr.align_down(page_size);
+    base >= start && end.as_usize() - base.as_usize() >= page_size.into()
 }
 
 impl Backend {
     /// Creates a new allocation mapping backend.
     pub const fn new_alloc(populate: bool) -> Self {
-        Self::Alloc { populate }
+        Self::Alloc {
+            populate,
+            page_size: PageSize::Size4K,
+        }
+    }
+
+    /// Creates a new allocation mapping backend that allocates huge pages of
+    /// `page_size` on demand where possible.
+    pub const fn new_alloc_huge(populate: bool, page_size: PageSize) -> Self {
+        Self::Alloc {
+            populate,
+            page_size,
+        }
     }
 
     pub(crate) fn map_alloc(
@@ -32,17 +39,40 @@ impl Backend {
         flags: MappingFlags,
         pt: &mut PageTable,
         populate: bool,
+        page_size: PageSize,
     ) -> bool {
         debug!(
-            "map_alloc: [{:#x}, {:#x}) {:?} (populate={})",
+            "map_alloc: [{:#x}, {:#x}) {:?} (populate={}, page_size={:?})",
             start,
             start + size,
             flags,
-            populate
+            populate,
+            page_size
         );
+        let end = start + size;
         if populate {
             // allocate all possible physical frames for populated mapping.
-            for addr in PageIter4K::new(start, start + size).unwrap() {
+            let mut addr = start;
+            while addr < end {
+                // Try the largest huge page that fits, and fall back to 4K
+                // pages if the huge frame cannot be allocated.
+                let huge = HUGE_PAGE_SIZES.into_iter().find_map(|page_size| {
+                    if !addr.is_aligned(page_size) || !fits_in(addr, page_size, start, end) {
+                        return None;
+                    }
+                    let frame = alloc_huge_frame(page_size, true)?;
+                    if let Ok(tlb) = pt.map(addr, frame, page_size, flags) {
+                        tlb.ignore();
+                        Some(page_size)
+                    } else {
+                        free_huge_frame(frame, page_size);
+                        None
+                    }
+                });
+                if let Some(page_size) = huge {
+                    addr += page_size.into();
+                    continue;
+                }
                 if let Some(frame) = alloc_frame(true) {
                     if let Ok(tlb) = pt.map(addr, frame, PageSize::Size4K, flags) {
                         tlb.ignore(); // TLB flush on map is unnecessary, as there are no outdated mappings.
@@ -50,8 +80,13 @@ impl Backend {
                         return false;
                     }
                 }
+                addr += PAGE_SIZE_4K;
             }
             true
+        } else if page_size.is_huge() {
+            // Leave the entries unused, so that huge pages can be mapped on
+            // demand.
+            true
         } else {
             // Map to a empty entry for on-demand mapping.
             let flags = MappingFlags::empty();
@@ -69,40 +104,100 @@ impl Backend {
         _populate: bool,
     ) -> bool {
         debug!("unmap_alloc: [{:#x}, {:#x})", start, start + size);
-        for addr in PageIter4K::new(start, start + size).unwrap() {
+        // The huge pages across the boundaries have been split.
+        let end = start + size;
+        let mut addr = start;
+        while addr < end {
+            // The frame of an inactive page is not present.
+            #[cfg(feature = "swap")]
+            if let Ok((frame, flags, PageSize::Size4K)) = pt.query(addr) {
+                if flags.is_empty() && frame.as_usize() != 0 {
+                    put_frame(frame);
+                }
+            }
             if let Ok((frame, page_size, tlb)) = pt.unmap(addr) {
                 // Deallocate the physical frame if there is a mapping in the
                 // page table.
+                tlb.flush();
                 if page_size.is_huge() {
-                    return false;
+                    free_huge_frame(frame, page_size);
+                    addr += page_size.into();
+                    continue;
                 }
-                tlb.flush();
-                dealloc_frame(frame);
+                put_frame(frame);
             } else {
                 // Deallocation is needn't if the page is not mapped.
             }
+            addr += PAGE_SIZE_4K;
         }
         true
     }
 
+    #[allow(clippy::too_many_arguments)]
     pub(crate) fn handle_page_fault_alloc(
         &self,
         vaddr: VirtAddr,
+        access_flags: MappingFlags,
         orig_flags: MappingFlags,
+        area: VirtAddrRange,
         pt: &mut PageTable,
         populate: bool,
+        page_size: PageSize,
     ) -> bool {
-        if populate {
+        if let Some((frame, flags, page_size)) = query_present(pt, vaddr) {
+            // The page is present, so it must be a write to a copy-on-write
+            // page (see `AddrSpace::clone_cow`).
+            if !access_flags.contains(MappingFlags::WRITE)
+                || flags.contains(MappingFlags::WRITE)
+                || page_size.is_huge()
+            {
+                return false;
+            }
+            match unshare_frame(frame.align_down_4k()) {
+                Some(new_frame) => pt
+                    .remap(vaddr, new_frame, orig_flags)
+                    .map(|(_, tlb)| tlb.flush())
+                    .is_ok(),
+                None => false,
+            }
+        } else if populate {
             false // Populated mappings should not trigger page faults.
-        } else if let Some(frame) = alloc_frame(true) {
+        } else {
+            if page_size.is_huge() && fits_in(vaddr, page_size, area.start, area.end) {
+                // The whole huge page is not mapped yet, as it is mapped all at
+                // once. Fall back to 4K pages if no huge frame is available,
+                // or some of the 4K pages has been mapped.
+                if let Some(frame) = alloc_huge_frame(page_size, true) {
+                    match pt.map(vaddr.align_down(page_size), frame, page_size, orig_flags) {
+                        Ok(tlb) => {
+                            tlb.flush();
+                            return true;
+                        }
+                        Err(_) => free_huge_frame(frame, page_size),
+                    }
+                }
+            }
+            let Some(frame) = alloc_frame(true) else {
+                return false;
+            };
             // Allocate a physical frame lazily and map it to the fault address.
             // `vaddr` does not need to be aligned. It will be automatically
-            // aligned during `pt.remap` regardless of the page size.
-            pt.remap(vaddr, frame, orig_flags)
-                .map(|(_, tlb)| tlb.flush())
-                .is_ok()
-        } else {
-            false
+            // aligned during `pt.remap` regardless of the page size. The entry
+            // does not exist if the area allocates huge pages.
+            let res = match pt.remap(vaddr, frame, orig_flags) {
+                Ok((_, tlb)) => Ok(tlb),
+                Err(_) => pt.map(vaddr.align_down_4k(), frame, PageSize::Size4K, orig_flags),
+            };
+            match res {
+                Ok(tlb) => {
+                    tlb.flush();
+                    true
+                }
+                Err(_) => {
+                    put_frame(frame);
+                    false
+                }
+            }
         }
     }
 }
@@ -0,0 +1,275 @@
+use alloc::sync::Arc;
+
+use axerrno::AxResult;
+use axfs::fops::File;
+use axhal::mem::phys_to_virt;
+use axhal::paging::{MappingFlags, PageSize, PageTable};
+use memory_addr::{MemoryAddr, PageIter4K, PhysAddr, VirtAddr, PAGE_SIZE_4K};
+
+use super::{query_present, Backend};
+use crate::frame::{put_frame, unshare_frame};
+use crate::page_cache::PageCache;
+
+/// The file and the states of a file-backed mapping, shared by all the areas
+/// split from the mapping.
+pub struct FileMapping {
+    file: File,
+    /// The start address of the mapping, where `offset` is mapped to.
+    start: VirtAddr,
+    /// The offset in the file.
+    offset: u64,
+    /// Whether the changes are written back to the file (`MAP_SHARED`).
+    shared: bool,
+    /// The cached pages of the file, shared by all its mappings.
+    cache: Arc<PageCache>,
+}
+
+impl FileMapping {
+    /// Returns whether the changes are written back to the file.
+    pub const fn is_shared(&self) -> bool {
+        self.shared
+    }
+
+    /// Whether the page at `vaddr` has been written through a shared mapping
+    /// of the file since the last writeback.
+    pub(crate) fn is_dirty(&self, vaddr: VirtAddr) -> bool {
+        self.cache.is_dirty(self.page_index(vaddr))
+    }
+
+    pub(crate) fn file_offset(&self, vaddr: VirtAddr) -> u64 {
+        self.offset + (vaddr - self.start) as u64
+    }
+
+    /// Returns the index of the page at `vaddr` in the file.
+    fn page_index(&self, vaddr: VirtAddr) -> u64 {
+        self.file_offset(vaddr.align_down_4k()) / PAGE_SIZE_4K as u64
+    }
+
+    /// Fills the frame with the file content of the page at `vaddr`. The part
+    /// beyond the end of the file is left as it is.
+    fn read_page(&self, vaddr: VirtAddr, frame: PhysAddr) -> AxResult {
+        let buf = unsafe {
+            core::slice::from_raw_parts_mut(phys_to_virt(frame).as_mut_ptr(), PAGE_SIZE_4K)
+        };
+        let offset = self.file_offset(vaddr);
+        let mut pos = 0;
+        while pos < PAGE_SIZE_4K {
+            match self.file.read_at(offset + pos as u64, &mut buf[pos..])? {
+                0 => break,
+                n => pos += n,
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes the frame back to the page at `vaddr` of the file, without
+    /// extending the file.
+    fn write_page(&self, vaddr: VirtAddr, frame: PhysAddr, file_size: u64) -> AxResult {
+        let offset = self.file_offset(vaddr);
+        if offset >= file_size {
+            return Ok(());
+        }
+        let len = (file_size - offset).min(PAGE_SIZE_4K as u64) as usize;
+        let buf = unsafe { core::slice::from_raw_parts(phys_to_virt(frame).as_ptr(), len) };
+        let mut pos = 0;
+        while pos < len {
+            match self.file.write_at(offset + pos as u64, &buf[pos..])? {
+                0 => break,
+                n => pos += n,
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes the dirty pages mapped in the given range back to the file.
+    ///
+    /// A page is dirty if it is marked in the page cache, or writable in `pt`,
+    /// as another address space may have cleaned the cache since it was
+    /// written through `pt`. If `keep_mapped` is `true`, the writable pages
+    /// are made read-only again to catch the next write.
+    fn writeback(
+        &self,
+        start: VirtAddr,
+        size: usize,
+        flags: MappingFlags,
+        pt: &mut PageTable,
+        keep_mapped: bool,
+    ) -> AxResult {
+        if !self.shared {
+            return Ok(());
+        }
+        let mut file_size = None;
+        for vaddr in PageIter4K::new(start, start + size).unwrap() {
+            let Some((frame, pte_flags, _)) = query_present(pt, vaddr) else {
+                continue;
+            };
+            let index = self.page_index(vaddr);
+            let writable = pte_flags.contains(MappingFlags::WRITE);
+            if !self.cache.take_dirty(index) && !writable {
+                continue;
+            }
+            let size = match file_size {
+                Some(size) => size,
+                None => *file_size.insert(self.file.get_attr()?.size()),
+            };
+            if let Err(e) = self.write_page(vaddr, frame, size) {
+                self.cache.set_dirty(index);
+                return Err(e);
+            }
+            if keep_mapped && writable {
+                if let Ok((_, tlb)) = pt.protect(vaddr, flags - MappingFlags::WRITE) {
+                    tlb.flush();
+                }
+            }
+        }
+        if file_size.is_some() {
+            self.file.flush()?;
+        }
+        Ok(())
+    }
+}
+
+impl Backend {
+    /// Creates a new file mapping backend, which maps the file from `offset`
+    /// to the address `start`.
+    ///
+    /// The pages are shared with the other mappings of the file through its
+    /// page cache. If `shared` is `true`, the written pages are written back
+    /// to the file on [`AddrSpace::msync`](crate::AddrSpace::msync) and
+    /// unmapping. Otherwise, they are copied on write and the changes are
+    /// private to the address space.
+    pub fn new_file(file: File, start: VirtAddr, offset: u64, shared: bool) -> Self {
+        let cache = PageCache::of(&file);
+        Self::File(Arc::new(FileMapping {
+            file,
+            start,
+            offset,
+            shared,
+            cache,
+        }))
+    }
+
+    pub(crate) fn map_file(
+        &self,
+        start: VirtAddr,
+        size: usize,
+        flags: MappingFlags,
+        pt: &mut PageTable,
+    ) -> bool {
+        debug!("map_file: [{:#x}, {:#x}) {:?}", start, start + size, flags);
+        // Map to a empty entry, the pages are read from the file on demand.
+        pt.map_region(
+            start,
+            |_| 0.into(),
+            size,
+            MappingFlags::empty(),
+            false,
+            false,
+        )
+        .map(|tlb| tlb.ignore())
+        .is_ok()
+    }
+
+    pub(crate) fn unmap_file(
+        &self,
+        mapping: &FileMapping,
+        start: VirtAddr,
+        size: usize,
+        pt: &mut PageTable,
+    ) -> bool {
+        debug!("unmap_file: [{:#x}, {:#x})", start, start + size);
+        if let Err(e) = mapping.writeback(start, size, MappingFlags::empty(), pt, false) {
+            warn!("failed to write back the file mapping: {:?}", e);
+        }
+        for addr in PageIter4K::new(start, start + size).unwrap() {
+            if let Ok((frame, page_size, tlb)) = pt.unmap(addr) {
+                if page_size.is_huge() {
+                    return false;
+                }
+                tlb.flush();
+                put_frame(frame);
+            }
+        }
+        true
+    }
+
+    pub(crate) fn handle_page_fault_file(
+        &self,
+        mapping: &FileMapping,
+        vaddr: VirtAddr,
+        access_flags: MappingFlags,
+        orig_flags: MappingFlags,
+        pt: &mut PageTable,
+    ) -> bool {
+        let page = vaddr.align_down_4k();
+        let index = mapping.page_index(vaddr);
+        let is_write = access_flags.contains(MappingFlags::WRITE);
+        if let Some((frame, flags, page_size)) = query_present(pt, vaddr) {
+            // The page is present, so it must be a write to a read-only page:
+            // a clean page of a shared mapping, or a copy-on-write page of a
+            // private mapping.
+            if !is_write || flags.contains(MappingFlags::WRITE) || page_size != PageSize::Size4K {
+                return false;
+            }
+            if mapping.shared {
+                mapping.cache.set_dirty(index);
+                pt.protect(vaddr, orig_flags)
+                    .map(|(_, tlb)| tlb.flush())
+                    .is_ok()
+            } else {
+                match unshare_frame(frame.align_down_4k()) {
+                    Some(new_frame) => pt
+                        .remap(vaddr, new_frame, orig_flags)
+                        .map(|(_, tlb)| tlb.flush())
+                        .is_ok(),
+                    None => false,
+                }
+            }
+        } else if let Some(frame) = mapping
+            .cache
+            .get_page(index, |frame| mapping.read_page(page, frame))
+        {
+            let (frame, flags) = if mapping.shared {
+                // Map clean pages read-only, so that we know when they are
+                // written.
+                if is_write {
+                    mapping.cache.set_dirty(index);
+                }
+                if mapping.cache.is_dirty(index) {
+                    (frame, orig_flags)
+                } else {
+                    (frame, orig_flags - MappingFlags::WRITE)
+                }
+            } else if is_write {
+                match unshare_frame(frame) {
+                    Some(new_frame) => (new_frame, orig_flags),
+                    None => {
+                        put_frame(frame);
+                        return false;
+                    }
+                }
+            } else {
+                // Copy on write.
+                (frame, orig_flags - MappingFlags::WRITE)
+            };
+            pt.remap(vaddr, frame, flags)
+                .map(|(_, tlb)| tlb.flush())
+                .is_ok()
+        } else {
+            false
+        }
+    }
+
+    /// Writes the dirty pages of a shared file mapping in the given range
+    /// back to the file.
+    pub(crate) fn sync_file(
+        &self,
+        mapping: &FileMapping,
+        start: VirtAddr,
+        size: usize,
+        flags: MappingFlags,
+        pt: &mut PageTable,
+    ) -> AxResult {
+        mapping.writeback(start, size, flags, pt, true)
+    }
+}
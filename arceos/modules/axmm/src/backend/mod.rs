@@ -1,21 +1,39 @@
 //! Memory mapping backends.
 #![allow(dead_code)]
 
-use axhal::paging::{MappingFlags, PageTable};
-use memory_addr::VirtAddr;
+use ::alloc::sync::Arc;
+use axhal::paging::{MappingFlags, PageSize, PageTable};
+use memory_addr::{MemoryAddr, PhysAddr, VirtAddr, VirtAddrRange, PAGE_SIZE_4K};
 use memory_set::MappingBackend;
 
+use crate::shm::SharedMemory;
+
 mod alloc;
+#[cfg(feature = "fs")]
+mod file;
 mod linear;
+mod shared;
+
+#[cfg(feature = "fs")]
+pub use self::file::FileMapping;
 
 /// A unified enum type for different memory mapping backends.
 ///
-/// Currently, two backends are implemented:
+/// Currently, the following backends are implemented:
 ///
 /// - **Linear**: used for linear mappings. The target physical frames are
 ///   contiguous and their addresses should be known when creating the mapping.
 /// - **Allocation**: used in general, or for lazy mappings. The target physical
-///   frames are obtained from the global allocator.
+///   frames are obtained from the global allocator, and can be shared
+///   copy-on-write between address spaces. Huge pages are used if possible.
+///
+/// Huge pages are split into smaller ones when a part of them is unmapped or
+/// protected.
+/// - **Shared**: used for shared memory. The target physical frames belong to
+///   a [`SharedMemory`] object, and can be mapped in several address spaces.
+/// - **File**: used for file mappings (requires the `fs` feature). The pages
+///   are read from the file on demand, and written back if the mapping is
+///   shared.
 #[derive(Clone)]
 pub enum Backend {
     /// Linear mapping backend.
@@ -33,10 +51,48 @@ pub enum Backend {
     /// mapping is created, and no page faults are triggered during the memory
     /// access. Otherwise, the physical frames are allocated on demand (by
     /// handling page faults).
+    ///
+    /// Populated mappings use the largest pages that the alignment and size
+    /// permit. Lazy mappings allocate pages of `page_size` on demand, if the
+    /// aligned huge page lies within the area, or 4K pages otherwise.
     Alloc {
         /// Whether to populate the physical frames when creating the mapping.
         populate: bool,
+        /// The page size to allocate on demand.
+        page_size: PageSize,
+    },
+    /// Shared memory mapping backend.
+    ///
+    /// The page at `vaddr` is mapped to the page at the offset
+    /// `vaddr - start + offset` of `mem` on the first access.
+    Shared {
+        /// The shared memory object.
+        mem: Arc<SharedMemory>,
+        /// The start address of the mapping, where `offset` is mapped to.
+        start: VirtAddr,
+        /// The offset in the shared memory object.
+        offset: usize,
     },
+    /// File mapping backend.
+    ///
+    /// The page at `vaddr` is filled with the file content at the offset
+    /// `vaddr - start + offset` on the first access. The areas split from the
+    /// same mapping share the [`FileMapping`].
+    #[cfg(feature = "fs")]
+    File(Arc<FileMapping>),
+}
+
+/// The kind of a [`Backend`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BackendKind {
+    /// [`Backend::Linear`].
+    Linear,
+    /// [`Backend::Alloc`].
+    Alloc,
+    /// [`Backend::Shared`].
+    Shared,
+    /// `Backend::File`, with the `fs` feature.
+    File,
 }
 
 impl MappingBackend for Backend {
@@ -46,14 +102,26 @@ impl MappingBackend for Backend {
     fn map(&self, start: VirtAddr, size: usize, flags: MappingFlags, pt: &mut PageTable) -> bool {
         match *self {
             Self::Linear { pa_va_offset } => self.map_linear(start, size, flags, pt, pa_va_offset),
-            Self::Alloc { populate } => self.map_alloc(start, size, flags, pt, populate),
+            Self::Alloc {
+                populate,
+                page_size,
+            } => self.map_alloc(start, size, flags, pt, populate, page_size),
+            Self::Shared { .. } => self.map_shared(start, size, flags, pt),
+            #[cfg(feature = "fs")]
+            Self::File(_) => self.map_file(start, size, flags, pt),
         }
     }
 
     fn unmap(&self, start: VirtAddr, size: usize, pt: &mut PageTable) -> bool {
+        if !split_huge_at(pt, start) || !split_huge_at(pt, start + size) {
+            return false;
+        }
         match *self {
             Self::Linear { pa_va_offset } => self.unmap_linear(start, size, pt, pa_va_offset),
-            Self::Alloc { populate } => self.unmap_alloc(start, size, pt, populate),
+            Self::Alloc { populate, .. } => self.unmap_alloc(start, size, pt, populate),
+            Self::Shared { .. } => self.unmap_shared(start, size, pt),
+            #[cfg(feature = "fs")]
+            Self::File(ref mapping) => self.unmap_file(mapping, start, size, pt),
         }
     }
 
@@ -64,25 +132,171 @@ impl MappingBackend for Backend {
         new_flags: Self::Flags,
         page_table: &mut Self::PageTable,
     ) -> bool {
-        page_table
-            .protect_region(start, size, new_flags, true)
-            .map(|tlb| tlb.ignore())
-            .is_ok()
+        if !split_huge_at(page_table, start) || !split_huge_at(page_table, start + size) {
+            return false;
+        }
+        // Only the present pages, the empty entries of lazy mappings get the
+        // new flags on the page faults.
+        let end = start + size;
+        let mut vaddr = start;
+        while vaddr < end {
+            let step = match query_present(page_table, vaddr) {
+                Some((_, _, page_size)) => {
+                    if let Ok((_, tlb)) = page_table.protect(vaddr, new_flags) {
+                        tlb.flush();
+                    }
+                    page_size.into()
+                }
+                None => PAGE_SIZE_4K,
+            };
+            vaddr += step;
+        }
+        true
+    }
+}
+
+/// Queries the page mapped at `vaddr`.
+///
+/// Unlike [`PageTable::query`], the empty entries of lazy mappings, which are
+/// not present yet, are reported as not mapped.
+pub(crate) fn query_present(
+    pt: &PageTable,
+    vaddr: VirtAddr,
+) -> Option<(PhysAddr, MappingFlags, PageSize)> {
+    pt.query(vaddr)
+        .ok()
+        .filter(|(_, flags, _)| !flags.is_empty())
+}
+
+/// Splits the huge page mapped at `vaddr` into the pages of the next smaller
+/// size, with the same flags.
+fn split_huge_page(pt: &mut PageTable, vaddr: VirtAddr) -> bool {
+    let Some((_, _, page_size)) = query_present(pt, vaddr) else {
+        return true;
+    };
+    if !page_size.is_huge() {
+        return true;
     }
+    let size: usize = page_size.into();
+    let base = vaddr.align_down(size);
+    debug!("split huge page: [{:#x}, {:#x})", base, base + size);
+    // Never unmapped in between, as the other CPUs may be accessing it.
+    axhal::paging::split_huge_page(pt, vaddr).is_ok()
+}
+
+/// Splits the huge pages mapped across `vaddr` until `vaddr` is the boundary
+/// of two pages, so that the pages on either side can be changed alone.
+pub(crate) fn split_huge_at(pt: &mut PageTable, vaddr: VirtAddr) -> bool {
+    while let Some((_, _, page_size)) = query_present(pt, vaddr) {
+        if !page_size.is_huge() || vaddr.is_aligned(page_size) {
+            break;
+        }
+        if !split_huge_page(pt, vaddr) {
+            return false;
+        }
+    }
+    true
 }
 
 impl Backend {
+    /// Handles a page fault at `vaddr` in the area of the range `area`.
     pub(crate) fn handle_page_fault(
         &self,
         vaddr: VirtAddr,
+        access_flags: MappingFlags,
         orig_flags: MappingFlags,
+        area: VirtAddrRange,
         page_table: &mut PageTable,
     ) -> bool {
         match *self {
             Self::Linear { .. } => false, // Linear mappings should not trigger page faults.
-            Self::Alloc { populate } => {
-                self.handle_page_fault_alloc(vaddr, orig_flags, page_table, populate)
+            Self::Alloc {
+                populate,
+                page_size,
+            } => self.handle_page_fault_alloc(
+                vaddr,
+                access_flags,
+                orig_flags,
+                area,
+                page_table,
+                populate,
+                page_size,
+            ),
+            Self::Shared {
+                ref mem,
+                start,
+                offset,
+            } => self.handle_page_fault_shared(mem, vaddr, orig_flags, page_table, start, offset),
+            #[cfg(feature = "fs")]
+            Self::File(ref mapping) => {
+                self.handle_page_fault_file(mapping, vaddr, access_flags, orig_flags, page_table)
             }
         }
     }
+
+    /// Whether a present page at `vaddr` must be kept read-only to catch the
+    /// writes, even if the area is writable.
+    #[cfg_attr(not(feature = "fs"), allow(unused_variables))]
+    pub(crate) fn needs_write_fault(&self, vaddr: VirtAddr, frame: PhysAddr) -> bool {
+        match *self {
+            // The frames are written in place by all the mappings.
+            Self::Shared { .. } => false,
+            #[cfg(feature = "fs")]
+            Self::File(ref mapping) if mapping.is_shared() => !mapping.is_dirty(vaddr),
+            // Shared copy-on-write.
+            _ => crate::frame::frame_ref_count(frame) > 1,
+        }
+    }
+
+    /// Returns the kind of the backend.
+    pub const fn kind(&self) -> BackendKind {
+        match self {
+            Self::Linear { .. } => BackendKind::Linear,
+            Self::Alloc { .. } => BackendKind::Alloc,
+            Self::Shared { .. } => BackendKind::Shared,
+            #[cfg(feature = "fs")]
+            Self::File(_) => BackendKind::File,
+        }
+    }
+
+    /// Whether the changes are shared with the other mappings of the same
+    /// memory, like `MAP_SHARED`.
+    pub fn is_shared(&self) -> bool {
+        match self {
+            Self::Shared { .. } => true,
+            #[cfg(feature = "fs")]
+            Self::File(mapping) => mapping.is_shared(),
+            _ => false,
+        }
+    }
+
+    /// Returns the offset of `vaddr` in the mapped file or shared memory, or
+    /// 0 for the other backends.
+    pub(crate) fn offset_of(&self, vaddr: VirtAddr) -> u64 {
+        match *self {
+            Self::Shared { start, offset, .. } => (offset + (vaddr - start)) as u64,
+            #[cfg(feature = "fs")]
+            Self::File(ref mapping) => mapping.file_offset(vaddr),
+            _ => 0,
+        }
+    }
+
+    /// Returns the backend for the pages at `old_start` moved to `new_start`,
+    /// or `None` if they cannot be moved.
+    pub(crate) fn relocate(&self, old_start: VirtAddr, new_start: VirtAddr) -> Option<Self> {
+        match *self {
+            Self::Alloc { .. } => Some(self.clone()),
+            Self::Shared {
+                ref mem,
+                start,
+                offset,
+            } => Some(Self::new_shared(
+                mem.clone(),
+                new_start,
+                offset + (old_start - start),
+            )),
+            // The linear frames and the file pages are bound to the addresses.
+            _ => None,
+        }
+    }
 }
@@ -0,0 +1,84 @@
+use alloc::sync::Arc;
+
+use axhal::paging::{MappingFlags, PageTable};
+use memory_addr::{PageIter4K, VirtAddr, PAGE_SIZE_4K};
+
+use super::{query_present, Backend};
+use crate::frame::put_frame;
+use crate::shm::SharedMemory;
+
+impl Backend {
+    /// Creates a new shared memory mapping backend, which maps `mem` from
+    /// `offset` to the address `start`.
+    pub fn new_shared(mem: Arc<SharedMemory>, start: VirtAddr, offset: usize) -> Self {
+        Self::Shared { mem, start, offset }
+    }
+
+    pub(crate) fn map_shared(
+        &self,
+        start: VirtAddr,
+        size: usize,
+        flags: MappingFlags,
+        pt: &mut PageTable,
+    ) -> bool {
+        debug!(
+            "map_shared: [{:#x}, {:#x}) {:?}",
+            start,
+            start + size,
+            flags
+        );
+        // Map to a empty entry, the frames are looked up on demand.
+        pt.map_region(
+            start,
+            |_| 0.into(),
+            size,
+            MappingFlags::empty(),
+            false,
+            false,
+        )
+        .map(|tlb| tlb.ignore())
+        .is_ok()
+    }
+
+    pub(crate) fn unmap_shared(&self, start: VirtAddr, size: usize, pt: &mut PageTable) -> bool {
+        debug!("unmap_shared: [{:#x}, {:#x})", start, start + size);
+        for addr in PageIter4K::new(start, start + size).unwrap() {
+            if let Ok((frame, page_size, tlb)) = pt.unmap(addr) {
+                if page_size.is_huge() {
+                    return false;
+                }
+                tlb.flush();
+                // Drop the reference of this mapping, the frame is kept by
+                // the shared memory object.
+                put_frame(frame);
+            }
+        }
+        true
+    }
+
+    pub(crate) fn handle_page_fault_shared(
+        &self,
+        mem: &SharedMemory,
+        vaddr: VirtAddr,
+        orig_flags: MappingFlags,
+        pt: &mut PageTable,
+        start: VirtAddr,
+        offset: usize,
+    ) -> bool {
+        if query_present(pt, vaddr).is_some() {
+            // The pages are always mapped with the flags of the area.
+            return false;
+        }
+        let index = (vaddr - start + offset) / PAGE_SIZE_4K;
+        let Some(frame) = mem.get_page(index) else {
+            return false;
+        };
+        if let Ok((_, tlb)) = pt.remap(vaddr, frame, orig_flags) {
+            tlb.flush();
+            true
+        } else {
+            put_frame(frame);
+            false
+        }
+    }
+}
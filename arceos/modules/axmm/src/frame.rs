@@ -0,0 +1,120 @@
+//! Physical frame allocation with reference counting.
+//!
+//! A frame can be mapped in several address spaces, e.g., after
+//! [`AddrSpace::clone_cow`](crate::AddrSpace::clone_cow). It is only freed
+//! when the last mapping is removed.
+//!
+//! Huge frames are not reference counted, as they are split into 4K frames
+//! before shared.
+
+use alloc::collections::BTreeMap;
+
+use axalloc::global_allocator;
+use axhal::mem::{phys_to_virt, virt_to_phys};
+use axhal::paging::PageSize;
+use kspin::SpinNoIrq;
+use memory_addr::{PhysAddr, VirtAddr, PAGE_SIZE_4K};
+
+/// The reference counts of frames that are shared. A frame not in the table
+/// has only one reference, so that the common case costs nothing.
+static FRAME_REFS: SpinNoIrq<BTreeMap<PhysAddr, usize>> = SpinNoIrq::new(BTreeMap::new());
+
+/// Allocates a frame with one reference.
+pub(crate) fn alloc_frame(zeroed: bool) -> Option<PhysAddr> {
+    let vaddr = VirtAddr::from(global_allocator().alloc_pages(1, PAGE_SIZE_4K).ok()?);
+    if zeroed {
+        unsafe { core::ptr::write_bytes(vaddr.as_mut_ptr(), 0, PAGE_SIZE_4K) };
+    }
+    let paddr = virt_to_phys(vaddr);
+    Some(paddr)
+}
+
+/// Allocates a contiguous huge frame of `page_size`, aligned to its size.
+///
+/// The callers fall back to 4K frames if it fails, so memory is neither
+/// reclaimed for it nor reported out.
+pub(crate) fn alloc_huge_frame(page_size: PageSize, zeroed: bool) -> Option<PhysAddr> {
+    let size: usize = page_size.into();
+    let vaddr = VirtAddr::from(
+        global_allocator()
+            .try_alloc_pages(size / PAGE_SIZE_4K, size)
+            .ok()?,
+    );
+    if zeroed {
+        unsafe { core::ptr::write_bytes(vaddr.as_mut_ptr(), 0, size) };
+    }
+    Some(virt_to_phys(vaddr))
+}
+
+/// Frees a huge frame allocated by [`alloc_huge_frame`].
+pub(crate) fn free_huge_frame(frame: PhysAddr, page_size: PageSize) {
+    let size: usize = page_size.into();
+    global_allocator().dealloc_pages(phys_to_virt(frame).as_usize(), size / PAGE_SIZE_4K);
+}
+
+fn dealloc_frame(frame: PhysAddr) {
+    let vaddr = phys_to_virt(frame);
+    global_allocator().dealloc_pages(vaddr.as_usize(), 1);
+}
+
+/// Adds a reference to the frame.
+pub(crate) fn get_frame(frame: PhysAddr) {
+    *FRAME_REFS.lock().entry(frame).or_insert(1) += 1;
+}
+
+/// Drops a reference to the frame, and frees it if it is the last one.
+pub(crate) fn put_frame(frame: PhysAddr) {
+    let mut refs = FRAME_REFS.lock();
+    match refs.get_mut(&frame) {
+        Some(count) if *count > 2 => *count -= 1,
+        Some(_) => {
+            refs.remove(&frame);
+        }
+        None => {
+            drop(refs);
+            dealloc_frame(frame);
+        }
+    }
+}
+
+/// Returns the number of references to the frame.
+pub(crate) fn frame_ref_count(frame: PhysAddr) -> usize {
+    FRAME_REFS.lock().get(&frame).copied().unwrap_or(1)
+}
+
+/// Gets a frame with the same content as `frame` that the caller owns
+/// exclusively, for a write to a copy-on-write page.
+///
+/// If the caller holds the only reference, `frame` itself is returned.
+/// Otherwise, the content is copied to a new frame, and the reference to
+/// `frame` is dropped.
+pub(crate) fn unshare_frame(frame: PhysAddr) -> Option<PhysAddr> {
+    if !FRAME_REFS.lock().contains_key(&frame) {
+        return Some(frame);
+    }
+    // Allocate without the lock held, as the allocator may reclaim memory,
+    // which puts frames.
+    let new_frame = alloc_frame(false)?;
+    // Hold the lock while copying, so that the other owners cannot take the
+    // frame over and write to it in the meantime.
+    let mut refs = FRAME_REFS.lock();
+    let Some(count) = refs.get_mut(&frame) else {
+        // The others have dropped their references in the meantime.
+        drop(refs);
+        dealloc_frame(new_frame);
+        return Some(frame);
+    };
+    unsafe {
+        core::ptr::copy_nonoverlapping(
+            phys_to_virt(frame).as_ptr(),
+            phys_to_virt(new_frame).as_mut_ptr(),
+            PAGE_SIZE_4K,
+        )
+    };
+    if *count > 2 {
+        *count -= 1;
+    } else {
+        refs.remove(&frame);
+    }
+    Some(new_frame)
+}
@@ -1,15 +1,44 @@
 //! [ArceOS](https://github.com/arceos-org/arceos) memory management module.
-
-#![no_std]
+//!
+//! # Cargo Features
+//!
+//! - `aslr`: Enable the address space layout randomization for user programs,
+//!   see [`aslr`].
+//! - `fs`: Enable file mappings backed by [`axfs`] files, see
+//!   [`AddrSpace::map_file`].
+//! - `swap`: Enable swapping anonymous pages out to a block device (or a file
+//!   with `fs`), see [`swap`].
+//! - `uspace`: Enable the fault-tolerant access to the user memory, see
+//!   [`uaccess`].
+
+#![cfg_attr(not(test), no_std)]
 
 #[macro_use]
 extern crate log;
 extern crate alloc;
 
+#[cfg(feature = "aslr")]
+pub mod aslr;
 mod aspace;
 mod backend;
-
-pub use self::aspace::AddrSpace;
+mod frame;
+mod mlock;
+#[cfg(feature = "fs")]
+mod page_cache;
+pub mod shm;
+#[cfg(feature = "swap")]
+pub mod swap;
+#[cfg(feature = "uspace")]
+pub mod uaccess;
+
+#[cfg(test)]
+mod tests;
+
+pub use self::aspace::{AddrSpace, AreaInfo, MemoryAdvice};
+pub use self::backend::BackendKind;
+#[cfg(feature = "fs")]
+pub use self::backend::FileMapping;
+pub use self::shm::SharedMemory;
 
 use axerrno::{AxError, AxResult};
 use axhal::mem::phys_to_virt;
@@ -48,6 +77,8 @@ fn paging_err_to_ax_err(err: PagingError) -> AxError {
 pub fn new_user_aspace() -> AxResult<AddrSpace> {
     let mut aspace = AddrSpace::new_empty(VirtAddr::from(USER_ASPACE_BASE), USER_ASPACE_SIZE)?;
     aspace.copy_mappings_from(&kernel_aspace().lock())?;
+    #[cfg(feature = "aslr")]
+    aspace.set_mmap_base(aslr::mmap_base(aspace.base()));
     Ok(aspace)
 }
 
@@ -79,6 +110,8 @@ pub fn kernel_page_table_root() -> PhysAddr {
 /// fine-grained kernel page table.
 pub fn init_memory_management() {
     info!("Initialize virtual memory management...");
+    #[cfg(feature = "aslr")]
+    aslr::init();
 
     let kernel_aspace = new_kernel_aspace().expect("failed to initialize kernel address space");
     debug!("kernel address space init OK: {:#x?}", kernel_aspace);
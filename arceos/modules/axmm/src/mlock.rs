@@ -0,0 +1,89 @@
+//! The ranges locked in memory by [`AddrSpace::mlock`].
+//!
+//! [`AddrSpace::mlock`]: crate::AddrSpace::mlock
+
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+
+use memory_addr::VirtAddr;
+
+/// Disjoint and non-adjacent ranges, keyed by the start address.
+pub(crate) struct LockedRanges(BTreeMap<VirtAddr, VirtAddr>);
+
+impl LockedRanges {
+    pub const fn new() -> Self {
+        Self(BTreeMap::new())
+    }
+
+    /// Returns the ranges overlapping `[start, end)`, clipped to it.
+    pub fn ranges_in(&self, start: VirtAddr, end: VirtAddr) -> Vec<(VirtAddr, VirtAddr)> {
+        let mut ranges: Vec<_> = self
+            .0
+            .range(..end)
+            .rev()
+            .take_while(|(_, &e)| e > start)
+            .map(|(&s, &e)| (s.max(start), e.min(end)))
+            .collect();
+        ranges.reverse();
+        ranges
+    }
+
+    /// Whether the page at `vaddr` is locked.
+    pub fn contains(&self, vaddr: VirtAddr) -> bool {
+        self.0
+            .range(..=vaddr)
+            .next_back()
+            .is_some_and(|(_, &end)| vaddr < end)
+    }
+
+    /// Whether some of `[start, end)` is locked.
+    pub fn overlaps(&self, start: VirtAddr, end: VirtAddr) -> bool {
+        self.0
+            .range(..end)
+            .next_back()
+            .is_some_and(|(_, &e)| e > start)
+    }
+
+    /// Locks `[start, end)`, merging with the overlapping or adjacent ranges.
+    pub fn insert(&mut self, start: VirtAddr, end: VirtAddr) {
+        let merged: Vec<_> = self
+            .0
+            .range(..=end)
+            .rev()
+            .take_while(|(_, &e)| e >= start)
+            .map(|(&s, &e)| (s, e))
+            .collect();
+        let (mut start, mut end) = (start, end);
+        for (s, e) in merged {
+            self.0.remove(&s);
+            start = start.min(s);
+            end = end.max(e);
+        }
+        self.0.insert(start, end);
+    }
+
+    /// Unlocks `[start, end)`, splitting the ranges across the boundaries.
+    pub fn remove(&mut self, start: VirtAddr, end: VirtAddr) {
+        let overlapping: Vec<_> = self
+            .0
+            .range(..end)
+            .rev()
+            .take_while(|(_, &e)| e > start)
+            .map(|(&s, &e)| (s, e))
+            .collect();
+        for (s, e) in overlapping {
+            self.0.remove(&s);
+            if s < start {
+                self.0.insert(s, start);
+            }
+            if e > end {
+                self.0.insert(end, e);
+            }
+        }
+    }
+
+    /// Returns the total size of the locked ranges in bytes.
+    pub fn size(&self) -> usize {
+        self.0.iter().map(|(&s, &e)| e - s).sum()
+    }
+}
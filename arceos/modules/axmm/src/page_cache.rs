@@ -0,0 +1,202 @@
+//! Page caches of the mapped files.
+//!
+//! All the file mappings of the same file share a [`PageCache`], so that the
+//! writes through a `MAP_SHARED` mapping are seen by the other mappings (in
+//! any address space) at once. Private mappings map the cached pages as well,
+//! and copy them on the first write.
+//!
+//! Each cached page holds a reference to its frame, and a dirty flag set when
+//! it is written through a shared mapping. The flag is cleared when the page
+//! is written back to the file. A page can also be written through the
+//! writable mappings of the address spaces that faulted it in, which write it
+//! back on [`AddrSpace::msync`] and unmapping.
+//!
+//! The clean pages that are no longer mapped are dropped when memory runs
+//! out, see [`axalloc::Shrinker`].
+//!
+//! [`AddrSpace::msync`]: crate::AddrSpace::msync
+
+use alloc::collections::BTreeMap;
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use axalloc::Shrinker;
+use axerrno::AxResult;
+use axfs::fops::File;
+use kspin::SpinNoIrq;
+use memory_addr::{PhysAddr, PAGE_SIZE_4K};
+
+use crate::frame::{alloc_frame, frame_ref_count, get_frame, put_frame};
+
+struct CachedPage {
+    frame: PhysAddr,
+    dirty: bool,
+}
+
+/// The cached pages of a file, indexed by the page number in the file.
+pub(crate) struct PageCache {
+    pages: SpinNoIrq<BTreeMap<u64, CachedPage>>,
+}
+
+/// The page caches of the mapped files, by [`File::node_id`].
+static PAGE_CACHES: SpinNoIrq<BTreeMap<usize, Weak<PageCache>>> = SpinNoIrq::new(BTreeMap::new());
+
+static SHRINKER_REGISTERED: AtomicBool = AtomicBool::new(false);
+
+impl PageCache {
+    pub(crate) const fn new() -> Self {
+        Self {
+            pages: SpinNoIrq::new(BTreeMap::new()),
+        }
+    }
+
+    /// Returns the page cache of `file`, which is created if the file is not
+    /// mapped yet.
+    pub(crate) fn of(file: &File) -> Arc<Self> {
+        if !SHRINKER_REGISTERED.swap(true, Ordering::AcqRel)
+            && !axalloc::oom::register_shrinker(&PAGE_CACHE_SHRINKER)
+        {
+            warn!("too many shrinkers, the page caches are not shrunk");
+        }
+        let mut caches = PAGE_CACHES.lock();
+        let id = file.node_id();
+        if let Some(cache) = caches.get(&id).and_then(Weak::upgrade) {
+            return cache;
+        }
+        let cache = Arc::new(Self::new());
+        caches.insert(id, Arc::downgrade(&cache));
+        cache
+    }
+
+    /// Returns the frame of the page `index`, and takes a reference to it for
+    /// the caller.
+    ///
+    /// If the page is not cached, a zeroed frame is filled by `fill` and
+    /// cached. Returns `None` if no memory is available or `fill` fails.
+    pub(crate) fn get_page(
+        &self,
+        index: u64,
+        fill: impl FnOnce(PhysAddr) -> AxResult,
+    ) -> Option<PhysAddr> {
+        if let Some(page) = self.pages.lock().get(&index) {
+            get_frame(page.frame);
+            return Some(page.frame);
+        }
+        // Fill the page without the lock held, as the file I/O may sleep.
+        let frame = alloc_frame(true)?;
+        if let Err(e) = fill(frame) {
+            warn!("failed to read the page {} of the file: {:?}", index, e);
+            put_frame(frame);
+            return None;
+        }
+        let mut pages = self.pages.lock();
+        let page = pages.entry(index).or_insert(CachedPage {
+            frame,
+            dirty: false,
+        });
+        if page.frame != frame {
+            // Filled by someone else in the meantime.
+            put_frame(frame);
+        }
+        get_frame(page.frame);
+        Some(page.frame)
+    }
+
+    /// Whether the page `index` has been written through a shared mapping
+    /// since the last writeback.
+    pub(crate) fn is_dirty(&self, index: u64) -> bool {
+        self.pages.lock().get(&index).is_some_and(|page| page.dirty)
+    }
+
+    /// Marks the page `index` written.
+    pub(crate) fn set_dirty(&self, index: u64) {
+        if let Some(page) = self.pages.lock().get_mut(&index) {
+            page.dirty = true;
+        }
+    }
+
+    /// Clears the dirty flag of the page `index`, and returns whether it was
+    /// set.
+    pub(crate) fn take_dirty(&self, index: u64) -> bool {
+        self.pages
+            .lock()
+            .get_mut(&index)
+            .is_some_and(|page| core::mem::replace(&mut page.dirty, false))
+    }
+
+    /// Drops at most `nr_pages` clean pages that are not mapped, and returns
+    /// the number of pages dropped.
+    ///
+    /// The cache is skipped if it is locked, e.g., by the allocation that
+    /// runs out of memory.
+    pub(crate) fn shrink(&self, nr_pages: usize) -> usize {
+        let Some(mut pages) = self.pages.try_lock() else {
+            return 0;
+        };
+        let unused: Vec<u64> = pages
+            .iter()
+            .filter(|(_, page)| !page.dirty && frame_ref_count(page.frame) == 1)
+            .map(|(&index, _)| index)
+            .take(nr_pages)
+            .collect();
+        for index in &unused {
+            if let Some(page) = pages.remove(index) {
+                put_frame(page.frame);
+            }
+        }
+        unused.len()
+    }
+
+    /// Returns the number of cached pages.
+    #[cfg(test)]
+    pub(crate) fn len(&self) -> usize {
+        self.pages.lock().len()
+    }
+}
+
+impl Drop for PageCache {
+    fn drop(&mut self) {
+        for page in core::mem::take(self.pages.get_mut()).into_values() {
+            put_frame(page.frame);
+        }
+        PAGE_CACHES
+            .lock()
+            .retain(|_, cache| cache.strong_count() > 0);
+    }
+}
+
+/// Drops the clean pages of the page caches that are not mapped.
+struct PageCacheShrinker;
+
+static PAGE_CACHE_SHRINKER: PageCacheShrinker = PageCacheShrinker;
+
+impl Shrinker for PageCacheShrinker {
+    fn name(&self) -> &str {
+        "page cache"
+    }
+
+    fn shrink(&self, bytes: usize) -> usize {
+        // The frame reference counts are locked with IRQs disabled, skip if
+        // the allocation is made with them (or any other spinlock) held.
+        if !axhal::arch::irqs_enabled() {
+            return 0;
+        }
+        // Drop the lock before shrinking, as a cache may be dropped with it.
+        let caches: Vec<_> = match PAGE_CACHES.try_lock() {
+            Some(caches) => caches.values().filter_map(Weak::upgrade).collect(),
+            None => return 0,
+        };
+        let mut nr_pages = bytes.div_ceil(PAGE_SIZE_4K);
+        let mut freed = 0;
+        for cache in caches {
+            let n = cache.shrink(nr_pages);
+            freed += n;
+            nr_pages -= n;
+            if nr_pages == 0 {
+                break;
+            }
+        }
+        freed * PAGE_SIZE_4K
+    }
+}
@@ -0,0 +1,183 @@
+//! Shared memory objects.
+//!
+//! A [`SharedMemory`] is a set of physical frames that can be mapped into
+//! several address spaces by [`AddrSpace::map_shared`], e.g., for
+//! `MAP_SHARED | MAP_ANONYMOUS` mappings. Named objects can be looked up in
+//! two namespaces:
+//!
+//! - POSIX shared memory ([`shm_open`], [`shm_unlink`]), named by strings.
+//! - System V shared memory ([`shmget`], [`shm_by_id`], [`shm_remove`]),
+//!   named by integer keys.
+//!
+//! Removing an object from a namespace does not free it, the frames are
+//! freed when the object is dropped and no longer mapped.
+//!
+//! [`AddrSpace::map_shared`]: crate::AddrSpace::map_shared
+
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use axerrno::{ax_err, AxResult};
+use kspin::SpinNoIrq;
+use memory_addr::{align_up_4k, PhysAddr, PAGE_SIZE_4K};
+
+use crate::frame::{alloc_frame, get_frame, put_frame};
+
+/// The key of System V shared memory that always creates a new object.
+pub const IPC_PRIVATE: i32 = 0;
+
+/// Physical frames that can be mapped into several address spaces.
+///
+/// The frames are allocated (and zeroed) on the first access from any of the
+/// mappings.
+pub struct SharedMemory {
+    frames: SpinNoIrq<Vec<Option<PhysAddr>>>,
+}
+
+impl SharedMemory {
+    /// Creates a new shared memory object of `size` bytes, rounded up to the
+    /// page size.
+    pub fn new(size: usize) -> Arc<Self> {
+        let mut frames = Vec::new();
+        frames.resize(align_up_4k(size) / PAGE_SIZE_4K, None);
+        Arc::new(Self {
+            frames: SpinNoIrq::new(frames),
+        })
+    }
+
+    /// Returns the size in bytes.
+    pub fn size(&self) -> usize {
+        self.frames.lock().len() * PAGE_SIZE_4K
+    }
+
+    /// Changes the size to `size` bytes, rounded up to the page size.
+    ///
+    /// The pages cut off are not unmapped from the address spaces, they are
+    /// freed when the last of them is unmapped.
+    pub fn resize(&self, size: usize) {
+        let mut frames = self.frames.lock();
+        let new_len = align_up_4k(size) / PAGE_SIZE_4K;
+        for frame in frames.drain(new_len.min(frames.len())..).flatten() {
+            put_frame(frame);
+        }
+        frames.resize(new_len, None);
+    }
+
+    /// Returns the frame of the page `index`, and takes a reference to it for
+    /// the caller.
+    ///
+    /// Returns `None` if the page is out of range, or no memory is available.
+    pub(crate) fn get_page(&self, index: usize) -> Option<PhysAddr> {
+        let mut frames = self.frames.lock();
+        let slot = frames.get_mut(index)?;
+        let frame = match *slot {
+            Some(frame) => frame,
+            None => *slot.insert(alloc_frame(true)?),
+        };
+        get_frame(frame);
+        Some(frame)
+    }
+}
+
+impl Drop for SharedMemory {
+    fn drop(&mut self) {
+        for frame in self.frames.get_mut().drain(..).flatten() {
+            put_frame(frame);
+        }
+    }
+}
+
+static SHM_NAMES: SpinNoIrq<BTreeMap<String, Arc<SharedMemory>>> = SpinNoIrq::new(BTreeMap::new());
+
+/// Opens the POSIX shared memory object named `name`.
+///
+/// If it does not exist and `create` is `true`, an empty object is created.
+/// If `exclusive` is `true` as well, it is an error if the object exists.
+pub fn shm_open(name: &str, create: bool, exclusive: bool) -> AxResult<Arc<SharedMemory>> {
+    let mut names = SHM_NAMES.lock();
+    match names.get(name) {
+        Some(_) if create && exclusive => ax_err!(AlreadyExists),
+        Some(mem) => Ok(mem.clone()),
+        None if create => {
+            let mem = SharedMemory::new(0);
+            names.insert(String::from(name), mem.clone());
+            Ok(mem)
+        }
+        None => ax_err!(NotFound),
+    }
+}
+
+/// Removes the POSIX shared memory object named `name`.
+pub fn shm_unlink(name: &str) -> AxResult {
+    match SHM_NAMES.lock().remove(name) {
+        Some(_) => Ok(()),
+        None => ax_err!(NotFound),
+    }
+}
+
+struct SysvTable {
+    next_id: usize,
+    keys: BTreeMap<i32, usize>,
+    segments: BTreeMap<usize, Arc<SharedMemory>>,
+}
+
+static SYSV_SHM: SpinNoIrq<SysvTable> = SpinNoIrq::new(SysvTable {
+    next_id: 1,
+    keys: BTreeMap::new(),
+    segments: BTreeMap::new(),
+});
+
+/// Gets the identifier of the System V shared memory segment with `key`.
+///
+/// If the segment does not exist and `create` is `true`, a segment of `size`
+/// bytes is created. If `exclusive` is `true` as well, it is an error if the
+/// segment exists. [`IPC_PRIVATE`] always creates a new segment.
+pub fn shmget(key: i32, size: usize, create: bool, exclusive: bool) -> AxResult<usize> {
+    let mut table = SYSV_SHM.lock();
+    if key != IPC_PRIVATE {
+        if let Some(&id) = table.keys.get(&key) {
+            if create && exclusive {
+                return ax_err!(AlreadyExists);
+            }
+            if size > table.segments[&id].size() {
+                return ax_err!(InvalidInput, "segment too small");
+            }
+            return Ok(id);
+        }
+        if !create {
+            return ax_err!(NotFound);
+        }
+    }
+    if size == 0 {
+        return ax_err!(InvalidInput, "zero-sized segment");
+    }
+    let id = table.next_id;
+    table.next_id += 1;
+    table.segments.insert(id, SharedMemory::new(size));
+    if key != IPC_PRIVATE {
+        table.keys.insert(key, id);
+    }
+    Ok(id)
+}
+
+/// Returns the System V shared memory segment with the identifier `id`.
+pub fn shm_by_id(id: usize) -> AxResult<Arc<SharedMemory>> {
+    match SYSV_SHM.lock().segments.get(&id) {
+        Some(mem) => Ok(mem.clone()),
+        None => ax_err!(InvalidInput, "invalid segment id"),
+    }
+}
+
+/// Removes the System V shared memory segment with the identifier `id`
+/// (`IPC_RMID`). The memory lives until it is detached from all the address
+/// spaces.
+pub fn shm_remove(id: usize) -> AxResult {
+    let mut table = SYSV_SHM.lock();
+    if table.segments.remove(&id).is_none() {
+        return ax_err!(InvalidInput, "invalid segment id");
+    }
+    table.keys.retain(|_, &mut v| v != id);
+    Ok(())
+}
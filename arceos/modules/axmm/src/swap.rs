@@ -0,0 +1,394 @@
+//! Swapping anonymous pages out to a swap device.
+//!
+//! When memory is short, [`AddrSpace::swap_out`] picks cold pages of the lazy
+//! [`Backend::Alloc`] areas with a clock scan, writes them to the swap device
+//! set by [`set_swap_device`], and frees the frames. The pages are read back
+//! on the next access, in the page fault handler.
+//!
+//! It's done for the faulting address space in its page fault handler, and
+//! for all address spaces registered by [`register_aspace`] by a shrinker of
+//! [`axalloc`], which is registered with the swap device. See [`reclaim`].
+//!
+//! As the accessed bit of the page table entries is not available, a page gets
+//! a second chance in the clock scan by being made inactive first: it stays
+//! in its frame, but the entry is made not present. An access to an inactive
+//! page makes it present again, or it is swapped out by the next scan.
+//!
+//! Populated areas are never swapped out, nor are the frames shared
+//! copy-on-write.
+//!
+//! The device I/O may sleep, so the slots are reserved and released with the
+//! swap area locked, but read and written with it unlocked.
+//!
+//! [`AddrSpace::swap_out`]: crate::AddrSpace::swap_out
+//! [`Backend::Alloc`]: crate::backend::Backend::Alloc
+
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use axalloc::Shrinker;
+use axdriver::prelude::BlockDriverOps;
+use axerrno::{ax_err, AxResult};
+use axhal::mem::phys_to_virt;
+use axhal::paging::{MappingFlags, PageSize, PageTable};
+use axsync::Mutex;
+use kspin::SpinNoIrq;
+use memory_addr::{MemoryAddr, PhysAddr, VirtAddr, PAGE_SIZE_4K};
+
+use crate::frame::{alloc_frame, put_frame};
+use crate::AddrSpace;
+
+/// The number of pages to swap out when memory runs out in the page fault
+/// handler.
+pub(crate) const SWAP_CLUSTER: usize = 32;
+
+/// A device to store the swapped out pages, in slots of 4K bytes.
+pub trait SwapDevice: Send + Sync {
+    /// Returns the number of slots.
+    fn num_slots(&self) -> usize;
+
+    /// Reads the slot `slot` into `buf` of 4K bytes.
+    fn read_slot(&self, slot: usize, buf: &mut [u8]) -> AxResult;
+
+    /// Writes `buf` of 4K bytes to the slot `slot`.
+    fn write_slot(&self, slot: usize, buf: &[u8]) -> AxResult;
+}
+
+/// A swap partition on a block device.
+pub struct SwapPartition {
+    dev: Mutex<axdriver::AxBlockDevice>,
+    start_block: u64,
+    num_slots: usize,
+}
+
+impl SwapPartition {
+    /// Uses `num_blocks` blocks of the block device from `start_block` as the
+    /// swap partition.
+    pub fn new(dev: axdriver::AxBlockDevice, start_block: u64, num_blocks: u64) -> Self {
+        let blocks_per_slot = (PAGE_SIZE_4K / dev.block_size()) as u64;
+        Self {
+            dev: Mutex::new(dev),
+            start_block,
+            num_slots: (num_blocks / blocks_per_slot) as usize,
+        }
+    }
+
+    fn slot_block(&self, slot: usize) -> u64 {
+        let blocks_per_slot = PAGE_SIZE_4K / self.dev.lock().block_size();
+        self.start_block + (slot * blocks_per_slot) as u64
+    }
+}
+
+impl SwapDevice for SwapPartition {
+    fn num_slots(&self) -> usize {
+        self.num_slots
+    }
+
+    fn read_slot(&self, slot: usize, buf: &mut [u8]) -> AxResult {
+        let block_id = self.slot_block(slot);
+        self.dev
+            .lock()
+            .read_block(block_id, buf)
+            .or_else(|_| ax_err!(Io, "failed to read the swap partition"))
+    }
+
+    fn write_slot(&self, slot: usize, buf: &[u8]) -> AxResult {
+        let block_id = self.slot_block(slot);
+        self.dev
+            .lock()
+            .write_block(block_id, buf)
+            .or_else(|_| ax_err!(Io, "failed to write the swap partition"))
+    }
+}
+
+/// A swap file on the file system.
+#[cfg(feature = "fs")]
+pub struct SwapFile {
+    file: axfs::fops::File,
+    num_slots: usize,
+}
+
+#[cfg(feature = "fs")]
+impl SwapFile {
+    /// Uses the file of `size` bytes as the swap file. The file is extended
+    /// as the slots are written.
+    pub fn new(file: axfs::fops::File, size: usize) -> Self {
+        Self {
+            file,
+            num_slots: size / PAGE_SIZE_4K,
+        }
+    }
+}
+
+#[cfg(feature = "fs")]
+impl SwapDevice for SwapFile {
+    fn num_slots(&self) -> usize {
+        self.num_slots
+    }
+
+    fn read_slot(&self, slot: usize, buf: &mut [u8]) -> AxResult {
+        let offset = (slot * PAGE_SIZE_4K) as u64;
+        let mut pos = 0;
+        while pos < buf.len() {
+            match self.file.read_at(offset + pos as u64, &mut buf[pos..])? {
+                0 => return ax_err!(UnexpectedEof, "swap file truncated"),
+                n => pos += n,
+            }
+        }
+        Ok(())
+    }
+
+    fn write_slot(&self, slot: usize, buf: &[u8]) -> AxResult {
+        let offset = (slot * PAGE_SIZE_4K) as u64;
+        let mut pos = 0;
+        while pos < buf.len() {
+            match self.file.write_at(offset + pos as u64, &buf[pos..])? {
+                0 => return ax_err!(WriteZero),
+                n => pos += n,
+            }
+        }
+        Ok(())
+    }
+}
+
+struct SwapArea {
+    dev: Arc<dyn SwapDevice>,
+    /// One bit for each slot, set if the slot is in use.
+    bitmap: Vec<u64>,
+    used: usize,
+    /// Where to start searching for a free slot.
+    next: usize,
+}
+
+static SWAP_AREA: SpinNoIrq<Option<SwapArea>> = SpinNoIrq::new(None);
+
+/// The address spaces whose pages can be swapped out by [`reclaim`].
+static SWAPPABLE: SpinNoIrq<Vec<Weak<Mutex<AddrSpace>>>> = SpinNoIrq::new(Vec::new());
+
+/// Where the next [`reclaim`] starts in [`SWAPPABLE`].
+static RECLAIM_CURSOR: AtomicUsize = AtomicUsize::new(0);
+
+/// Reclaims memory for [`axalloc`] by swapping pages out.
+struct SwapShrinker;
+
+static SWAP_SHRINKER: SwapShrinker = SwapShrinker;
+
+impl Shrinker for SwapShrinker {
+    fn name(&self) -> &str {
+        "swap"
+    }
+
+    fn shrink(&self, bytes: usize) -> usize {
+        // The device I/O may sleep, which is not allowed with IRQs disabled,
+        // e.g., in an interrupt handler or with a spinlock held.
+        if !axhal::arch::irqs_enabled() {
+            return 0;
+        }
+        reclaim(bytes.div_ceil(PAGE_SIZE_4K)) * PAGE_SIZE_4K
+    }
+}
+
+/// Sets the device to store the swapped out pages, like `swapon`.
+///
+/// It also registers a shrinker to [`axalloc`], which swaps out the pages of
+/// the registered address spaces when an allocation fails, see [`reclaim`].
+///
+/// Returns an error if a swap device has been set.
+pub fn set_swap_device(dev: Box<dyn SwapDevice>) -> AxResult {
+    let mut area = SWAP_AREA.lock();
+    if area.is_some() {
+        return ax_err!(AlreadyExists, "swap device already set");
+    }
+    info!("swap on: {} pages", dev.num_slots());
+    let bitmap = alloc::vec![0; dev.num_slots().div_ceil(64)];
+    *area = Some(SwapArea {
+        dev: Arc::from(dev),
+        bitmap,
+        used: 0,
+        next: 0,
+    });
+    drop(area);
+    if !axalloc::oom::register_shrinker(&SWAP_SHRINKER) {
+        warn!("too many shrinkers, swap is only done in the page fault handler");
+    }
+    Ok(())
+}
+
+/// Lets the pages of `aspace` be swapped out by [`reclaim`], i.e., when other
+/// address spaces or the kernel run out of memory.
+///
+/// It is unregistered once dropped.
+pub fn register_aspace(aspace: &Arc<Mutex<AddrSpace>>) {
+    let mut spaces = SWAPPABLE.lock();
+    spaces.retain(|s| s.strong_count() > 0);
+    spaces.push(Arc::downgrade(aspace));
+}
+
+/// Swaps out at most `nr_pages` pages of the address spaces registered by
+/// [`register_aspace`], starting from a different one each time.
+///
+/// The address spaces locked by others are skipped, e.g., the one whose page
+/// fault handler runs out of memory, which swaps its own pages out instead.
+///
+/// Returns the number of pages swapped out.
+pub fn reclaim(nr_pages: usize) -> usize {
+    let num = SWAPPABLE.lock().len();
+    let start = RECLAIM_CURSOR.fetch_add(1, Ordering::Relaxed);
+    let mut count = 0;
+    for i in 0..num {
+        if count >= nr_pages {
+            break;
+        }
+        let Some(aspace) = SWAPPABLE
+            .lock()
+            .get((start + i) % num)
+            .and_then(Weak::upgrade)
+        else {
+            continue;
+        };
+        if let Some(mut aspace) = aspace.try_lock() {
+            count += aspace.swap_out(nr_pages - count);
+        }
+    }
+    count
+}
+
+/// Returns the number of used and total slots of the swap device.
+pub fn swap_usage() -> (usize, usize) {
+    match SWAP_AREA.lock().as_ref() {
+        Some(area) => (area.used, area.dev.num_slots()),
+        None => (0, 0),
+    }
+}
+
+/// Reserves a free slot. Returns the device and the slot.
+fn reserve_slot() -> AxResult<(Arc<dyn SwapDevice>, usize)> {
+    let mut guard = SWAP_AREA.lock();
+    let Some(area) = guard.as_mut() else {
+        return ax_err!(NoMemory, "no swap device");
+    };
+    let num_slots = area.dev.num_slots();
+    let Some(slot) = (0..num_slots)
+        .map(|i| (area.next + i) % num_slots)
+        .find(|&slot| area.bitmap[slot / 64] & (1 << (slot % 64)) == 0)
+    else {
+        return ax_err!(NoMemory, "swap device full");
+    };
+    area.bitmap[slot / 64] |= 1 << (slot % 64);
+    area.used += 1;
+    area.next = (slot + 1) % num_slots;
+    Ok((area.dev.clone(), slot))
+}
+
+/// Returns the swap device, which is set as a slot is in use.
+fn swap_device() -> Arc<dyn SwapDevice> {
+    SWAP_AREA
+        .lock()
+        .as_ref()
+        .expect("no swap device")
+        .dev
+        .clone()
+}
+
+/// Allocates a slot and writes the frame to it.
+pub(crate) fn swap_write(frame: PhysAddr) -> AxResult<usize> {
+    let (dev, slot) = reserve_slot()?;
+    let buf = unsafe { core::slice::from_raw_parts(phys_to_virt(frame).as_ptr(), PAGE_SIZE_4K) };
+    if let Err(e) = dev.write_slot(slot, buf) {
+        swap_free(slot);
+        return Err(e);
+    }
+    Ok(slot)
+}
+
+/// Reads the slot into the frame, and frees the slot.
+pub(crate) fn swap_read(slot: usize, frame: PhysAddr) -> AxResult {
+    let buf =
+        unsafe { core::slice::from_raw_parts_mut(phys_to_virt(frame).as_mut_ptr(), PAGE_SIZE_4K) };
+    swap_device().read_slot(slot, buf)?;
+    swap_free(slot);
+    Ok(())
+}
+
+/// Frees the slot without reading it.
+pub(crate) fn swap_free(slot: usize) {
+    let mut guard = SWAP_AREA.lock();
+    let area = guard.as_mut().expect("no swap device");
+    area.bitmap[slot / 64] &= !(1 << (slot % 64));
+    area.used -= 1;
+}
+
+/// The swap states of an address space.
+pub(crate) struct SwapState {
+    /// The slots of the swapped out pages.
+    pub slots: BTreeMap<VirtAddr, usize>,
+    /// The clock hand, where the next scan starts.
+    pub hand: VirtAddr,
+}
+
+impl SwapState {
+    pub const fn new() -> Self {
+        Self {
+            slots: BTreeMap::new(),
+            hand: VirtAddr::from_usize(0),
+        }
+    }
+
+    /// Makes the inactive or swapped out page at `vaddr` present again with
+    /// `flags`.
+    ///
+    /// Returns `None` if the page is neither inactive nor swapped out, or
+    /// whether it succeeds otherwise.
+    pub fn fault_in(
+        &mut self,
+        pt: &mut PageTable,
+        vaddr: VirtAddr,
+        flags: MappingFlags,
+    ) -> Option<bool> {
+        let vaddr = vaddr.align_down_4k();
+        if let Ok((frame, pte_flags, PageSize::Size4K)) = pt.query(vaddr) {
+            if pte_flags.is_empty() && frame.as_usize() != 0 {
+                // inactive, still in the frame
+                return Some(
+                    pt.remap(vaddr, frame, flags)
+                        .map(|(_, tlb)| tlb.flush())
+                        .is_ok(),
+                );
+            }
+        }
+        let slot = *self.slots.get(&vaddr)?;
+        let Some(frame) = alloc_frame(false) else {
+            return Some(false);
+        };
+        if let Err(e) = swap_read(slot, frame) {
+            warn!("failed to swap in {:#x}: {:?}", vaddr, e);
+            put_frame(frame);
+            return Some(false);
+        }
+        self.slots.remove(&vaddr);
+        Some(
+            pt.remap(vaddr, frame, flags)
+                .map(|(_, tlb)| tlb.flush())
+                .is_ok(),
+        )
+    }
+
+    /// Frees the slots of the pages in `[start, end)`.
+    pub fn discard(&mut self, start: VirtAddr, end: VirtAddr) {
+        let pages: Vec<_> = self.slots.range(start..end).map(|(&v, _)| v).collect();
+        for vaddr in pages {
+            swap_free(self.slots.remove(&vaddr).unwrap());
+        }
+    }
+}
+
+impl Drop for SwapState {
+    fn drop(&mut self) {
+        for &slot in self.slots.values() {
+            swap_free(slot);
+        }
+    }
+}
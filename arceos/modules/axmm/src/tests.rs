@@ -0,0 +1,467 @@
+use std::alloc::Layout;
+use std::sync::{Mutex, Once};
+
+use axalloc::OomPolicy;
+use axerrno::AxError;
+use axhal::paging::{MappingFlags, PageSize};
+use memory_addr::{va, PageIter4K, VirtAddr, PAGE_SIZE_4K};
+
+use crate::{AddrSpace, MemoryAdvice};
+
+static INIT: Once = Once::new();
+static SERIAL: Mutex<()> = Mutex::new(());
+
+/// The memory given to the frame allocator.
+const HEAP_SIZE: usize = 64 * 1024 * 1024;
+
+const BASE: VirtAddr = va!(0x1000_0000);
+const RW: MappingFlags = MappingFlags::READ
+    .union(MappingFlags::WRITE)
+    .union(MappingFlags::USER);
+
+fn init() {
+    INIT.call_once(|| {
+        // The linear mapping is the identity on the host, so the frames are
+        // accessed where they are allocated.
+        let layout = Layout::from_size_align(HEAP_SIZE, 0x20_0000).unwrap();
+        let heap = unsafe { std::alloc::alloc(layout) };
+        axalloc::global_init(heap as usize, HEAP_SIZE);
+    });
+}
+
+fn new_aspace() -> AddrSpace {
+    AddrSpace::new_empty(va!(0), 0x40_0000_0000).unwrap()
+}
+
+fn used_pages() -> usize {
+    axalloc::global_allocator().used_pages()
+}
+
+#[test]
+fn test_madvise_dontneed() {
+    let _lock = SERIAL.lock();
+    init();
+
+    const SIZE: usize = 16 * PAGE_SIZE_4K;
+    let mut aspace = new_aspace();
+    aspace.map_alloc(BASE, SIZE, RW, false).unwrap();
+    aspace
+        .fault_in_user(BASE, SIZE, MappingFlags::WRITE)
+        .unwrap();
+    aspace.write(BASE, &[0xaa; SIZE]).unwrap();
+    assert_eq!(aspace.rss(), SIZE);
+
+    let used = used_pages();
+    aspace
+        .madvise(BASE, SIZE / 2, MemoryAdvice::DontNeed)
+        .unwrap();
+    assert_eq!(aspace.rss(), SIZE / 2);
+    assert!(used_pages() <= used - SIZE / 2 / PAGE_SIZE_4K);
+
+    // Zeroed on the next touch, and the rest is kept.
+    aspace
+        .fault_in_user(BASE, SIZE, MappingFlags::READ)
+        .unwrap();
+    let mut buf = [0xff; SIZE];
+    aspace.read(BASE, &mut buf).unwrap();
+    assert!(buf[..SIZE / 2].iter().all(|&b| b == 0));
+    assert!(buf[SIZE / 2..].iter().all(|&b| b == 0xaa));
+
+    // Locked pages cannot be dropped.
+    aspace.mlock(BASE, SIZE).unwrap();
+    assert!(aspace.madvise(BASE, SIZE, MemoryAdvice::Free).is_err());
+    aspace.munlock(BASE, SIZE).unwrap();
+    aspace.madvise(BASE, SIZE, MemoryAdvice::Free).unwrap();
+    assert_eq!(aspace.rss(), 0);
+}
+
+/// Fills the pages of `[start, start + size)` with their page index.
+fn fill_pages(aspace: &mut AddrSpace, start: VirtAddr, size: usize) {
+    aspace
+        .fault_in_user(start, size, MappingFlags::WRITE)
+        .unwrap();
+    for (i, offset) in (0..size).step_by(PAGE_SIZE_4K).enumerate() {
+        aspace
+            .write(start + offset, &[i as u8; PAGE_SIZE_4K])
+            .unwrap();
+    }
+}
+
+/// Checks the pages filled by [`fill_pages`] at `start`.
+fn check_pages(aspace: &AddrSpace, start: VirtAddr, size: usize) {
+    let mut buf = [0; PAGE_SIZE_4K];
+    for (i, offset) in (0..size).step_by(PAGE_SIZE_4K).enumerate() {
+        aspace.read(start + offset, &mut buf).unwrap();
+        assert!(buf.iter().all(|&b| b == i as u8));
+    }
+}
+
+#[test]
+fn test_mremap_in_place() {
+    let _lock = SERIAL.lock();
+    init();
+
+    const SIZE: usize = 8 * PAGE_SIZE_4K;
+    let mut aspace = new_aspace();
+    aspace.map_alloc(BASE, SIZE, RW, true).unwrap();
+    fill_pages(&mut aspace, BASE, SIZE);
+
+    // shrink
+    let used = used_pages();
+    assert_eq!(aspace.mremap(BASE, SIZE, SIZE / 2, false, None), Ok(BASE));
+    assert!(!aspace.is_mapped(BASE + SIZE / 2, PAGE_SIZE_4K));
+    assert!(used_pages() <= used - SIZE / 2 / PAGE_SIZE_4K);
+    check_pages(&aspace, BASE, SIZE / 2);
+
+    // grow in place, the extension is populated like the rest
+    assert_eq!(aspace.mremap(BASE, SIZE / 2, SIZE, false, None), Ok(BASE));
+    assert!(aspace.is_mapped(BASE, SIZE));
+    assert_eq!(aspace.rss(), SIZE);
+    check_pages(&aspace, BASE, SIZE / 2);
+
+    // cannot grow in place without moving
+    aspace.map_alloc(BASE + SIZE, SIZE, RW, false).unwrap();
+    assert_eq!(
+        aspace.mremap(BASE, SIZE, 2 * SIZE, false, None),
+        Err(AxError::NoMemory)
+    );
+    assert!(aspace.is_mapped(BASE, 2 * SIZE));
+}
+
+#[test]
+fn test_mremap_move() {
+    let _lock = SERIAL.lock();
+    init();
+
+    const SIZE: usize = 8 * PAGE_SIZE_4K;
+    let mut aspace = new_aspace();
+    aspace.map_alloc(BASE, SIZE, RW, false).unwrap();
+    aspace.map_alloc(BASE + SIZE, SIZE, RW, false).unwrap();
+    fill_pages(&mut aspace, BASE, SIZE / 2); // half of it is populated
+
+    // MREMAP_MAYMOVE: moved without copying
+    let used = used_pages();
+    let new_start = aspace.mremap(BASE, SIZE, 2 * SIZE, true, None).unwrap();
+    assert_ne!(new_start, BASE);
+    assert!(!aspace.is_mapped(BASE, SIZE));
+    assert!(aspace.is_mapped(new_start, 2 * SIZE));
+    assert_eq!(aspace.rss(), SIZE / 2);
+    check_pages(&aspace, new_start, SIZE / 2);
+    assert!(used_pages() < used + SIZE / 2 / PAGE_SIZE_4K); // page tables only
+
+    // MREMAP_FIXED: replaces the mapping at the destination
+    aspace
+        .fault_in_user(BASE + SIZE, SIZE, MappingFlags::WRITE)
+        .unwrap();
+    aspace.write(BASE + SIZE, &[0xee; SIZE]).unwrap();
+    assert_eq!(
+        aspace.mremap(new_start, SIZE, SIZE, true, Some(BASE + SIZE)),
+        Ok(BASE + SIZE)
+    );
+    check_pages(&aspace, BASE + SIZE, SIZE / 2);
+    let mut buf = [0xff; PAGE_SIZE_4K];
+    let unpopulated = BASE + SIZE + SIZE / 2;
+    aspace
+        .fault_in_user(unpopulated, PAGE_SIZE_4K, MappingFlags::READ)
+        .unwrap();
+    aspace.read(unpopulated, &mut buf).unwrap();
+    assert!(buf.iter().all(|&b| b == 0)); // not populated in the source
+    assert!(aspace.is_mapped(new_start + SIZE, SIZE)); // the rest is kept
+
+    // The destination is kept if it fails.
+    assert_eq!(
+        aspace.mremap(BASE, SIZE, SIZE, true, Some(BASE + SIZE)),
+        Err(AxError::BadAddress)
+    );
+    assert_eq!(
+        aspace.mremap(
+            BASE + SIZE,
+            SIZE,
+            SIZE,
+            true,
+            Some(aspace.end() - PAGE_SIZE_4K)
+        ),
+        Err(AxError::InvalidInput)
+    );
+    check_pages(&aspace, BASE + SIZE, SIZE / 2);
+}
+
+const SIZE_2M: usize = 0x20_0000;
+
+fn page_size_at(aspace: &AddrSpace, vaddr: VirtAddr) -> PageSize {
+    aspace.page_table().query(vaddr).unwrap().2
+}
+
+#[test]
+fn test_huge_page_split() {
+    let _lock = SERIAL.lock();
+    init();
+
+    let mut aspace = new_aspace();
+    aspace.map_alloc(BASE, 2 * SIZE_2M, RW, true).unwrap();
+    assert_eq!(page_size_at(&aspace, BASE), PageSize::Size2M);
+    fill_pages(&mut aspace, BASE, 2 * SIZE_2M);
+    let frame = aspace.page_table().query(BASE).unwrap().0;
+
+    // Only the huge page across the range is split, into the same frames.
+    let mid = BASE + SIZE_2M / 2;
+    aspace
+        .protect(mid, PAGE_SIZE_4K, MappingFlags::READ | MappingFlags::USER)
+        .unwrap();
+    assert_eq!(page_size_at(&aspace, BASE), PageSize::Size4K);
+    assert_eq!(page_size_at(&aspace, BASE + SIZE_2M), PageSize::Size2M);
+    let (paddr, flags, _) = aspace.page_table().query(mid).unwrap();
+    assert_eq!(paddr, frame + SIZE_2M / 2);
+    assert!(!flags.contains(MappingFlags::WRITE));
+    let (paddr, flags, _) = aspace.page_table().query(mid + PAGE_SIZE_4K).unwrap();
+    assert_eq!(paddr, frame + SIZE_2M / 2 + PAGE_SIZE_4K);
+    assert!(flags.contains(MappingFlags::WRITE));
+
+    check_pages(&aspace, BASE, 2 * SIZE_2M);
+    assert_eq!(aspace.rss(), 2 * SIZE_2M);
+
+    let used = used_pages();
+    aspace.unmap(BASE, 2 * SIZE_2M).unwrap();
+    assert!(used_pages() <= used - 2 * SIZE_2M / PAGE_SIZE_4K);
+}
+
+#[test]
+fn test_huge_frame_fallback() {
+    let _lock = SERIAL.lock();
+    init();
+
+    // Leave only scattered 4K frames.
+    let allocator = axalloc::global_allocator();
+    let mut pages = Vec::new();
+    while let Ok(page) = allocator.try_alloc_pages(1, PAGE_SIZE_4K) {
+        pages.push(page);
+    }
+    let kept: Vec<_> = pages.iter().copied().skip(1).step_by(2).collect();
+    for &page in pages.iter().step_by(2) {
+        allocator.dealloc_pages(page, 1);
+    }
+
+    // The failed huge frames fall back to 4K frames, without applying the OOM
+    // policy.
+    axalloc::oom::set_oom_policy(OomPolicy::Panic);
+    let mut aspace = new_aspace();
+    aspace.map_alloc(BASE, SIZE_2M, RW, true).unwrap();
+    aspace
+        .map_alloc_huge(BASE + SIZE_2M, SIZE_2M, RW, PageSize::Size2M, false)
+        .unwrap();
+    fill_pages(&mut aspace, BASE, 2 * SIZE_2M);
+    axalloc::oom::set_oom_policy(OomPolicy::Fail);
+
+    assert_eq!(page_size_at(&aspace, BASE), PageSize::Size4K);
+    assert_eq!(page_size_at(&aspace, BASE + SIZE_2M), PageSize::Size4K);
+    check_pages(&aspace, BASE, 2 * SIZE_2M);
+    assert_eq!(aspace.rss(), 2 * SIZE_2M);
+
+    drop(aspace);
+    for page in kept {
+        allocator.dealloc_pages(page, 1);
+    }
+}
+
+/// A swap device in the memory.
+#[cfg(feature = "swap")]
+struct MemSwap(Mutex<Vec<[u8; PAGE_SIZE_4K]>>);
+
+#[cfg(feature = "swap")]
+impl crate::swap::SwapDevice for MemSwap {
+    fn num_slots(&self) -> usize {
+        self.0.lock().unwrap().len()
+    }
+
+    fn read_slot(&self, slot: usize, buf: &mut [u8]) -> axerrno::AxResult {
+        buf.copy_from_slice(&self.0.lock().unwrap()[slot]);
+        Ok(())
+    }
+
+    fn write_slot(&self, slot: usize, buf: &[u8]) -> axerrno::AxResult {
+        self.0.lock().unwrap()[slot].copy_from_slice(buf);
+        Ok(())
+    }
+}
+
+#[cfg(feature = "swap")]
+#[test]
+fn test_swap_round_trip() {
+    use std::boxed::Box;
+    use std::sync::Arc;
+
+    use crate::swap::{reclaim, register_aspace, set_swap_device, swap_usage};
+
+    let _lock = SERIAL.lock();
+    init();
+
+    const NUM_SLOTS: usize = 64;
+    const SIZE: usize = 16 * PAGE_SIZE_4K;
+    const NR_PAGES: usize = SIZE / PAGE_SIZE_4K;
+    let dev = MemSwap(Mutex::new(vec![[0; PAGE_SIZE_4K]; NUM_SLOTS]));
+    set_swap_device(Box::new(dev)).unwrap();
+    assert!(set_swap_device(Box::new(MemSwap(Mutex::new(Vec::new())))).is_err());
+
+    // Swapped out by the address space itself.
+    let mut aspace = new_aspace();
+    aspace.map_alloc(BASE, SIZE, RW, false).unwrap();
+    fill_pages(&mut aspace, BASE, SIZE);
+    let used = used_pages();
+    assert_eq!(aspace.swap_out(NR_PAGES), NR_PAGES);
+    assert_eq!(swap_usage(), (NR_PAGES, NUM_SLOTS));
+    assert_eq!(aspace.rss(), 0);
+    assert!(used_pages() <= used - NR_PAGES);
+
+    // Read back on the next access.
+    aspace
+        .fault_in_user(BASE, SIZE, MappingFlags::READ)
+        .unwrap();
+    check_pages(&aspace, BASE, SIZE);
+    assert_eq!(swap_usage(), (0, NUM_SLOTS));
+    assert_eq!(aspace.rss(), SIZE);
+
+    // Swapped out by the global reclaim, but not while it is locked.
+    let aspace = Arc::new(axsync::Mutex::new(aspace));
+    register_aspace(&aspace);
+    let guard = aspace.lock();
+    assert_eq!(reclaim(NR_PAGES / 2), 0);
+    drop(guard);
+    assert_eq!(reclaim(NR_PAGES / 2), NR_PAGES / 2);
+    assert_eq!(swap_usage(), (NR_PAGES / 2, NUM_SLOTS));
+
+    // The slots are freed with the address space.
+    let mut aspace = Arc::into_inner(aspace).unwrap().into_inner();
+    aspace
+        .fault_in_user(BASE, SIZE, MappingFlags::READ)
+        .unwrap();
+    check_pages(&aspace, BASE, SIZE);
+    assert_eq!(aspace.swap_out(NR_PAGES), NR_PAGES);
+    drop(aspace);
+    assert_eq!(swap_usage(), (0, NUM_SLOTS));
+    assert_eq!(reclaim(NR_PAGES), 0);
+}
+
+#[cfg(feature = "fs")]
+#[test]
+fn test_page_cache() {
+    use crate::frame::{frame_ref_count, put_frame};
+    use crate::page_cache::PageCache;
+
+    let _lock = SERIAL.lock();
+    init();
+
+    let used = used_pages();
+    let cache = PageCache::new();
+    let mut fills = 0;
+    let frame = cache
+        .get_page(0, |frame| {
+            fills += 1;
+            unsafe { core::ptr::write_bytes(frame.as_usize() as *mut u8, 0xaa, PAGE_SIZE_4K) };
+            Ok(())
+        })
+        .unwrap();
+    // Filled once, and shared by the later mappings.
+    assert_eq!(cache.get_page(0, |_| unreachable!()), Some(frame));
+    assert_eq!(fills, 1);
+    assert_eq!(frame_ref_count(frame), 3);
+    assert_eq!(unsafe { *(frame.as_usize() as *const u8) }, 0xaa);
+
+    // The dirty state belongs to the page, whoever maps it.
+    cache.set_dirty(0);
+    assert!(cache.is_dirty(0));
+
+    // Neither mapped nor dirty pages are dropped.
+    assert_eq!(cache.shrink(1), 0);
+    put_frame(frame);
+    put_frame(frame);
+    assert_eq!(cache.shrink(1), 0);
+    assert!(cache.take_dirty(0));
+    assert!(!cache.take_dirty(0));
+    assert_eq!(cache.shrink(1), 1);
+    assert_eq!(cache.len(), 0);
+
+    // Nothing is cached if the page cannot be read.
+    assert_eq!(cache.get_page(1, |_| Err(AxError::Io)), None);
+    assert_eq!(cache.len(), 0);
+    assert_eq!(used_pages(), used);
+}
+
+#[test]
+fn test_frame_refs() {
+    use crate::frame::{alloc_frame, frame_ref_count, get_frame, put_frame, unshare_frame};
+
+    let _lock = SERIAL.lock();
+    init();
+
+    let used = used_pages();
+    let frame = alloc_frame(false).unwrap();
+    unsafe { core::ptr::write_bytes(frame.as_usize() as *mut u8, 0x5a, PAGE_SIZE_4K) };
+    assert_eq!(frame_ref_count(frame), 1);
+    // The only owner writes in place.
+    assert_eq!(unshare_frame(frame), Some(frame));
+
+    get_frame(frame);
+    get_frame(frame);
+    assert_eq!(frame_ref_count(frame), 3);
+    let copy = unshare_frame(frame).unwrap();
+    assert_ne!(copy, frame);
+    assert_eq!(frame_ref_count(frame), 2);
+    assert_eq!(frame_ref_count(copy), 1);
+    let buf = unsafe { core::slice::from_raw_parts(copy.as_usize() as *const u8, PAGE_SIZE_4K) };
+    assert!(buf.iter().all(|&b| b == 0x5a));
+
+    // Freed with the last reference.
+    put_frame(frame);
+    assert_eq!(frame_ref_count(frame), 1);
+    assert_eq!(unshare_frame(frame), Some(frame));
+    put_frame(frame);
+    put_frame(copy);
+    assert_eq!(used_pages(), used);
+}
+
+fn frame_at(aspace: &AddrSpace, vaddr: VirtAddr) -> memory_addr::PhysAddr {
+    aspace.page_table().query(vaddr).unwrap().0
+}
+
+#[test]
+fn test_clone_cow() {
+    use crate::frame::frame_ref_count;
+
+    let _lock = SERIAL.lock();
+    init();
+
+    const SIZE: usize = 4 * PAGE_SIZE_4K;
+    let mut parent = new_aspace();
+    parent.map_alloc(BASE, SIZE, RW, false).unwrap();
+    fill_pages(&mut parent, BASE, SIZE);
+
+    // The frames are shared read-only after the fork.
+    let mut child = parent.clone_cow().unwrap();
+    check_pages(&child, BASE, SIZE);
+    for vaddr in PageIter4K::new(BASE, BASE + SIZE).unwrap() {
+        assert_eq!(frame_at(&parent, vaddr), frame_at(&child, vaddr));
+        assert_eq!(frame_ref_count(frame_at(&parent, vaddr)), 2);
+        let flags = parent.page_table().query(vaddr).unwrap().1;
+        assert!(!flags.contains(MappingFlags::WRITE));
+    }
+
+    // A write by the child copies the page.
+    let shared = frame_at(&parent, BASE);
+    child.write(BASE, &[0xff; 16]).unwrap();
+    assert_ne!(frame_at(&child, BASE), shared);
+    assert_eq!(frame_ref_count(shared), 1);
+    check_pages(&parent, BASE, SIZE);
+
+    // Then the parent owns it, and writes in place.
+    parent.write(BASE, &[0xee; 16]).unwrap();
+    assert_eq!(frame_at(&parent, BASE), shared);
+    let mut buf = [0; 16];
+    child.read(BASE, &mut buf).unwrap();
+    assert_eq!(buf, [0xff; 16]);
+
+    // The rest are owned by the parent alone once the child unmaps them.
+    let second = frame_at(&parent, BASE + PAGE_SIZE_4K);
+    child.unmap(BASE, SIZE).unwrap();
+    assert_eq!(frame_ref_count(second), 1);
+    check_pages(&parent, BASE + PAGE_SIZE_4K, SIZE - PAGE_SIZE_4K);
+}
@@ -0,0 +1,188 @@
+//! Access to the user memory from the kernel, e.g., in the syscall handlers.
+//!
+//! [`UserPtr`] and [`UserSlice`] check the user addresses against the
+//! [`AddrSpace`], handle the page faults of the lazy mappings in advance, and
+//! then copy with [`axhal::uaccess::copy_user`]. A bad pointer results in
+//! [`AxError::BadAddress`] (`EFAULT`) instead of a kernel panic.
+//!
+//! The given address space must be the one of the current task, whose page
+//! table is in use.
+//!
+//! [`AxError::BadAddress`]: axerrno::AxError::BadAddress
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::marker::PhantomData;
+use core::mem::{size_of, MaybeUninit};
+
+use axerrno::{ax_err, AxResult};
+use axhal::paging::MappingFlags;
+use memory_addr::{MemoryAddr, VirtAddr, PAGE_SIZE_4K};
+
+use crate::AddrSpace;
+
+fn copy_from_user(aspace: &mut AddrSpace, dst: &mut [u8], src: VirtAddr) -> AxResult {
+    aspace.fault_in_user(src, dst.len(), MappingFlags::READ)?;
+    if unsafe { axhal::uaccess::copy_user(dst.as_mut_ptr(), src.as_ptr(), dst.len()) } != 0 {
+        return ax_err!(BadAddress, "fault in reading the user memory");
+    }
+    Ok(())
+}
+
+fn copy_to_user(aspace: &mut AddrSpace, dst: VirtAddr, src: &[u8]) -> AxResult {
+    aspace.fault_in_user(dst, src.len(), MappingFlags::WRITE)?;
+    if unsafe { axhal::uaccess::copy_user(dst.as_mut_ptr(), src.as_ptr(), src.len()) } != 0 {
+        return ax_err!(BadAddress, "fault in writing the user memory");
+    }
+    Ok(())
+}
+
+/// A pointer to a `T` in the user memory.
+///
+/// `T` should be plain data that any bit pattern is valid for, e.g.,
+/// integers and `#[repr(C)]` structures of them.
+pub struct UserPtr<T> {
+    addr: VirtAddr,
+    _phantom: PhantomData<T>,
+}
+
+impl<T> UserPtr<T> {
+    /// Creates a pointer to the user address `addr`.
+    pub const fn new(addr: usize) -> Self {
+        Self {
+            addr: VirtAddr::from_usize(addr),
+            _phantom: PhantomData,
+        }
+    }
+
+    /// Returns the user address.
+    pub const fn addr(&self) -> VirtAddr {
+        self.addr
+    }
+
+    /// Whether the pointer is null.
+    pub fn is_null(&self) -> bool {
+        self.addr.as_usize() == 0
+    }
+
+    /// Returns the pointer to the `count`-th `T` after this one.
+    pub fn add(&self, count: usize) -> Self {
+        Self::new(self.addr.as_usize() + count * size_of::<T>())
+    }
+}
+
+impl<T: Copy> UserPtr<T> {
+    /// Reads the value from the user memory.
+    pub fn read(&self, aspace: &mut AddrSpace) -> AxResult<T> {
+        let mut value = MaybeUninit::<T>::uninit();
+        let buf = unsafe {
+            core::slice::from_raw_parts_mut(value.as_mut_ptr() as *mut u8, size_of::<T>())
+        };
+        copy_from_user(aspace, buf, self.addr)?;
+        Ok(unsafe { value.assume_init() })
+    }
+
+    /// Writes the value to the user memory.
+    pub fn write(&self, aspace: &mut AddrSpace, value: T) -> AxResult {
+        let buf =
+            unsafe { core::slice::from_raw_parts(&value as *const T as *const u8, size_of::<T>()) };
+        copy_to_user(aspace, self.addr, buf)
+    }
+}
+
+impl UserPtr<u8> {
+    /// Reads the NUL-terminated string, of at most `max_len` bytes without
+    /// the NUL.
+    ///
+    /// Returns [`AxError::InvalidInput`] if it is longer, or
+    /// [`AxError::InvalidData`] if it is not valid UTF-8.
+    ///
+    /// [`AxError::InvalidInput`]: axerrno::AxError::InvalidInput
+    /// [`AxError::InvalidData`]: axerrno::AxError::InvalidData
+    pub fn read_cstr(&self, aspace: &mut AddrSpace, max_len: usize) -> AxResult<String> {
+        let mut bytes = Vec::new();
+        let mut addr = self.addr;
+        // Page by page, as the pages after the NUL may be not mapped.
+        while bytes.len() <= max_len {
+            let chunk = (PAGE_SIZE_4K - addr.align_offset_4k()).min(max_len + 1 - bytes.len());
+            let start = bytes.len();
+            bytes.resize(start + chunk, 0);
+            copy_from_user(aspace, &mut bytes[start..], addr)?;
+            if let Some(pos) = bytes[start..].iter().position(|&b| b == 0) {
+                bytes.truncate(start + pos);
+                return String::from_utf8(bytes).or_else(|_| ax_err!(InvalidData));
+            }
+            addr += chunk;
+        }
+        ax_err!(InvalidInput, "string too long")
+    }
+}
+
+impl<T> Clone for UserPtr<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for UserPtr<T> {}
+
+impl<T> core::fmt::Debug for UserPtr<T> {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        write!(f, "UserPtr({:#x})", self.addr)
+    }
+}
+
+/// A byte slice in the user memory.
+#[derive(Debug, Clone, Copy)]
+pub struct UserSlice {
+    addr: VirtAddr,
+    len: usize,
+}
+
+impl UserSlice {
+    /// Creates a slice of `len` bytes at the user address `addr`.
+    pub const fn new(addr: usize, len: usize) -> Self {
+        Self {
+            addr: VirtAddr::from_usize(addr),
+            len,
+        }
+    }
+
+    /// Returns the user address.
+    pub const fn addr(&self) -> VirtAddr {
+        self.addr
+    }
+
+    /// Returns the length in bytes.
+    pub const fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Whether the slice is empty.
+    pub const fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Reads the first `buf.len()` bytes of the slice into `buf`.
+    pub fn read(&self, aspace: &mut AddrSpace, buf: &mut [u8]) -> AxResult {
+        if buf.len() > self.len {
+            return ax_err!(InvalidInput, "buffer larger than the user slice");
+        }
+        copy_from_user(aspace, buf, self.addr)
+    }
+
+    /// Reads the whole slice into a new vector.
+    pub fn read_to_vec(&self, aspace: &mut AddrSpace) -> AxResult<Vec<u8>> {
+        let mut buf = alloc::vec![0; self.len];
+        copy_from_user(aspace, &mut buf, self.addr)?;
+        Ok(buf)
+    }
+
+    /// Writes `data` to the start of the slice.
+    pub fn write(&self, aspace: &mut AddrSpace, data: &[u8]) -> AxResult {
+        if data.len() > self.len {
+            return ax_err!(InvalidInput, "data larger than the user slice");
+        }
+        copy_to_user(aspace, self.addr, data)
+    }
+}
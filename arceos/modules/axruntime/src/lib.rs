@@ -168,6 +168,10 @@ pub extern "C" fn rust_main(cpu_id: usize, dtb: usize) -> ! {
         axdisplay::init_display(all_devices.display);
     }
 
+    // Before the other CPUs and interrupts are started.
+    #[cfg(feature = "alloc")]
+    handoff_allocator();
+
     #[cfg(feature = "smp")]
     self::mp::start_secondary_cpus(cpu_id);
 
@@ -177,6 +181,22 @@ pub extern "C" fn rust_main(cpu_id: usize, dtb: usize) -> ! {
         init_interrupt();
     }
 
+    #[cfg(feature = "watchdog")]
+    {
+        info!("Start the watchdog...");
+        axtask::start_watchdog();
+    }
+
+    #[cfg(feature = "oom")]
+    {
+        info!("Start the OOM reaper...");
+        // Memory is low below 1/32 of the free pages at boot, and normal again
+        // above 1/16.
+        let free_pages = axalloc::global_allocator().available_pages();
+        axalloc::oom::set_watermarks(free_pages / 32, free_pages / 16);
+        axtask::start_oom_reaper();
+    }
+
     #[cfg(all(feature = "tls", not(feature = "multitask")))]
     {
         info!("Initialize thread local storage...");
@@ -206,22 +226,35 @@ fn init_allocator() {
     use axhal::mem::{memory_regions, phys_to_virt, MemRegionFlags};
 
     info!("Initialize global memory allocator...");
+    let byte_allocator = axconfig::BYTE_ALLOCATOR;
+    if !byte_allocator.is_empty()
+        && axalloc::global_allocator()
+            .select_byte_allocator(byte_allocator)
+            .is_err()
+    {
+        warn!("  {:?} allocator is not available.", byte_allocator);
+    }
     info!("  use {} allocator.", axalloc::global_allocator().name());
 
-    let mut max_region_size = 0;
-    let mut max_region_paddr = 0.into();
-    for r in memory_regions() {
-        if r.flags.contains(MemRegionFlags::FREE) && r.size > max_region_size {
-            max_region_size = r.size;
-            max_region_paddr = r.paddr;
-        }
-    }
+    // The boot-time allocations are served by the early allocator from the
+    // largest region, until `handoff_allocator`.
+    let max_region_paddr = max_free_region_paddr();
     for r in memory_regions() {
         if r.flags.contains(MemRegionFlags::FREE) && r.paddr == max_region_paddr {
-            axalloc::global_init(phys_to_virt(r.paddr).as_usize(), r.size);
+            axalloc::global_init_early(phys_to_virt(r.paddr).as_usize(), r.size);
             break;
         }
     }
+}
+
+#[cfg(feature = "alloc")]
+fn handoff_allocator() {
+    use axhal::mem::{memory_regions, phys_to_virt, MemRegionFlags};
+
+    info!("Hand off the early allocations to the global allocator...");
+    axalloc::global_handoff();
+
+    let max_region_paddr = max_free_region_paddr();
     for r in memory_regions() {
         if r.flags.contains(MemRegionFlags::FREE) && r.paddr != max_region_paddr {
             axalloc::global_add_memory(phys_to_virt(r.paddr).as_usize(), r.size)
@@ -230,6 +263,21 @@ fn init_allocator() {
     }
 }
 
+#[cfg(feature = "alloc")]
+fn max_free_region_paddr() -> axhal::mem::PhysAddr {
+    use axhal::mem::{memory_regions, MemRegionFlags};
+
+    let mut max_region_size = 0;
+    let mut max_region_paddr = 0.into();
+    for r in memory_regions() {
+        if r.flags.contains(MemRegionFlags::FREE) && r.size > max_region_size {
+            max_region_size = r.size;
+            max_region_paddr = r.paddr;
+        }
+    }
+    max_region_paddr
+}
+
 #[cfg(feature = "alt_alloc")]
 fn init_allocator() {
     use axhal::mem::{memory_regions, phys_to_virt, MemRegionFlags};
@@ -287,6 +335,10 @@ fn init_interrupt() {
         axtask::on_timer_tick();
     });
 
+    // Setup the handler of IPIs, which other CPUs send to wake up tasks here
+    #[cfg(all(feature = "smp", feature = "multitask"))]
+    axhal::irq::register_handler(axhal::irq::IPI_IRQ_NUM, axtask::on_ipi);
+
     // Enable IRQs before starting app
     axhal::arch::enable_irqs();
 }
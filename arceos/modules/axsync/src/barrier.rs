@@ -0,0 +1,132 @@
+//! A barrier enabling multiple tasks to synchronize the beginning of some
+//! computation.
+
+use core::fmt;
+
+use crate::{Condvar, Mutex};
+
+/// A barrier, similar to
+/// [`std::sync::Barrier`](https://doc.rust-lang.org/std/sync/struct.Barrier.html).
+///
+/// It enables multiple tasks to synchronize the beginning of some
+/// computation.
+pub struct Barrier {
+    lock: Mutex<BarrierState>,
+    cvar: Condvar,
+    num_tasks: usize,
+}
+
+// The inner state of a double barrier
+struct BarrierState {
+    count: usize,
+    generation_id: usize,
+}
+
+/// A `BarrierWaitResult` is returned by [`Barrier::wait()`] when all tasks
+/// in the [`Barrier`] have rendezvoused.
+pub struct BarrierWaitResult(bool);
+
+impl Barrier {
+    /// Creates a new barrier that can block a given number of tasks.
+    ///
+    /// A barrier will block `n`-1 tasks which call [`wait()`] and then wake
+    /// up all tasks at once when the `n`th task calls [`wait()`].
+    ///
+    /// [`wait()`]: Barrier::wait
+    pub const fn new(n: usize) -> Self {
+        Self {
+            lock: Mutex::new(BarrierState {
+                count: 0,
+                generation_id: 0,
+            }),
+            cvar: Condvar::new(),
+            num_tasks: n,
+        }
+    }
+
+    /// Blocks the current task until all tasks have rendezvoused here.
+    ///
+    /// Barriers are re-usable after all tasks have rendezvoused once, and can
+    /// be used continuously.
+    ///
+    /// A single (arbitrary) task will receive a [`BarrierWaitResult`] that
+    /// returns `true` from [`BarrierWaitResult::is_leader()`] when returning
+    /// from this function, and all other tasks will receive a result that
+    /// will return `false` from [`BarrierWaitResult::is_leader()`].
+    pub fn wait(&self) -> BarrierWaitResult {
+        let mut lock = self.lock.lock();
+        let local_gen = lock.generation_id;
+        lock.count += 1;
+        if lock.count < self.num_tasks {
+            let _lock = self
+                .cvar
+                .wait_while(lock, |state| local_gen == state.generation_id);
+            BarrierWaitResult(false)
+        } else {
+            lock.count = 0;
+            lock.generation_id = lock.generation_id.wrapping_add(1);
+            self.cvar.notify_all();
+            BarrierWaitResult(true)
+        }
+    }
+}
+
+impl fmt::Debug for Barrier {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Barrier").finish_non_exhaustive()
+    }
+}
+
+impl BarrierWaitResult {
+    /// Returns `true` if this task is the "leader task" for the call to
+    /// [`Barrier::wait()`].
+    ///
+    /// Only one task will have `true` returned from their result, all other
+    /// tasks will have `false` returned.
+    #[must_use]
+    pub fn is_leader(&self) -> bool {
+        self.0
+    }
+}
+
+impl fmt::Debug for BarrierWaitResult {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("BarrierWaitResult")
+            .field("is_leader", &self.is_leader())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::mutex::tests::{INIT, SERIAL};
+    use crate::Barrier;
+    use axtask as thread;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn barrier_leader() {
+        let _lock = SERIAL.lock();
+        INIT.call_once(thread::init_scheduler);
+
+        const NUM_TASKS: usize = 5;
+        static BARRIER: Barrier = Barrier::new(NUM_TASKS);
+        static LEADERS: AtomicUsize = AtomicUsize::new(0);
+
+        let tasks: Vec<_> = (0..NUM_TASKS)
+            .map(|_| {
+                thread::spawn(|| {
+                    for _ in 0..3 {
+                        if BARRIER.wait().is_leader() {
+                            LEADERS.fetch_add(1, Ordering::Relaxed);
+                        }
+                    }
+                })
+            })
+            .collect();
+        for t in tasks {
+            t.join();
+        }
+        assert_eq!(LEADERS.load(Ordering::Relaxed), 3);
+    }
+}
@@ -0,0 +1,290 @@
+//! A condition variable working with [`Mutex`].
+
+use core::fmt;
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use axtask::{Cancelled, WaitQueue};
+
+use crate::{Mutex, MutexGuard};
+
+/// A type indicating whether a timed wait on a condition variable returned
+/// due to a time out or not.
+///
+/// It is returned by the [`Condvar::wait_timeout`] method.
+#[cfg(feature = "irq")]
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub struct WaitTimeoutResult(bool);
+
+#[cfg(feature = "irq")]
+impl WaitTimeoutResult {
+    /// Returns `true` if the wait was known to have timed out.
+    #[must_use]
+    pub fn timed_out(&self) -> bool {
+        self.0
+    }
+}
+
+/// A Condition Variable, similar to
+/// [`std::sync::Condvar`](https://doc.rust-lang.org/std/sync/struct.Condvar.html).
+///
+/// Condition variables represent the ability to block a task such that it
+/// consumes no CPU time while waiting for an event to occur. It is always used
+/// together with a [`Mutex`].
+///
+/// Like the one in `std`, it may wake up spuriously, so the condition should
+/// be checked in a loop, or use [`wait_while`](Self::wait_while) instead.
+pub struct Condvar {
+    wq: WaitQueue,
+    /// Incremented on every notification, so that a waiter can tell whether
+    /// it has been notified since it released the mutex.
+    seq: AtomicU32,
+}
+
+impl Condvar {
+    /// Creates a new condition variable which is ready to be waited on and
+    /// notified.
+    pub const fn new() -> Self {
+        Self {
+            wq: WaitQueue::new(),
+            seq: AtomicU32::new(0),
+        }
+    }
+
+    /// Blocks the current task until this condition variable receives a
+    /// notification.
+    ///
+    /// This function will atomically unlock the mutex specified (represented
+    /// by `guard`) and block the current task. When this function call
+    /// returns, the lock specified will have been re-acquired.
+    pub fn wait<'a, T>(&self, guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+        let mutex = MutexGuard::mutex(&guard);
+        self.wait_unlocked(|| drop(guard));
+        mutex.lock()
+    }
+
+    /// Releases a lock by `unlock`, and blocks the current task until this
+    /// condition variable receives a notification.
+    ///
+    /// It's how [`wait`](Self::wait) works with locks other than [`Mutex`],
+    /// which the caller re-acquires after it returns.
+    pub fn wait_unlocked(&self, unlock: impl FnOnce()) {
+        let seq = self.seq.load(Ordering::Acquire);
+        unlock();
+        self.wq
+            .wait_until(|| self.seq.load(Ordering::Acquire) != seq);
+    }
+
+    /// Blocks the current task until the provided condition becomes false.
+    ///
+    /// `condition` is checked immediately; if not met (returns `true`), this
+    /// will [`wait`](Self::wait) for the next notification then check again.
+    /// This repeats until `condition` returns `false`.
+    pub fn wait_while<'a, T, F>(
+        &self,
+        mut guard: MutexGuard<'a, T>,
+        mut condition: F,
+    ) -> MutexGuard<'a, T>
+    where
+        F: FnMut(&mut T) -> bool,
+    {
+        while condition(&mut *guard) {
+            guard = self.wait(guard);
+        }
+        guard
+    }
+
+    /// Like [`wait`](Self::wait), but returns [`Cancelled`] if the current
+    /// task is cancelled while waiting, see [`axtask::cancel`].
+    ///
+    /// On cancellation, the mutex is left unlocked.
+    pub fn wait_cancellable<'a, T>(
+        &self,
+        guard: MutexGuard<'a, T>,
+    ) -> Result<MutexGuard<'a, T>, Cancelled> {
+        let seq = self.seq.load(Ordering::Acquire);
+        let mutex = unlock(guard);
+        if let Err(e) = self
+            .wq
+            .wait_until_cancellable(|| self.seq.load(Ordering::Acquire) != seq)
+        {
+            // We may have been chosen by `notify_one()`, pass the wakeup on
+            // to another waiter.
+            self.wq.notify_one(true);
+            return Err(e);
+        }
+        mutex.lock_cancellable()
+    }
+
+    /// Like [`wait_while`](Self::wait_while), but returns [`Cancelled`] if the
+    /// current task is cancelled while waiting, see [`axtask::cancel`].
+    ///
+    /// On cancellation, the mutex is left unlocked.
+    pub fn wait_while_cancellable<'a, T, F>(
+        &self,
+        mut guard: MutexGuard<'a, T>,
+        mut condition: F,
+    ) -> Result<MutexGuard<'a, T>, Cancelled>
+    where
+        F: FnMut(&mut T) -> bool,
+    {
+        while condition(&mut *guard) {
+            guard = self.wait_cancellable(guard)?;
+        }
+        Ok(guard)
+    }
+
+    /// Waits on this condition variable for a notification, timing out after
+    /// the specified duration.
+    ///
+    /// The returned [`WaitTimeoutResult`] indicates whether the timeout is
+    /// known to have elapsed.
+    #[cfg(feature = "irq")]
+    pub fn wait_timeout<'a, T>(
+        &self,
+        guard: MutexGuard<'a, T>,
+        dur: core::time::Duration,
+    ) -> (MutexGuard<'a, T>, WaitTimeoutResult) {
+        let mutex = MutexGuard::mutex(&guard);
+        let timeout = self.wait_timeout_unlocked(dur, || drop(guard));
+        (mutex.lock(), WaitTimeoutResult(timeout))
+    }
+
+    /// Like [`wait_unlocked`](Self::wait_unlocked), but times out after the
+    /// specified duration.
+    ///
+    /// Returns `true` if the timeout is known to have elapsed.
+    #[cfg(feature = "irq")]
+    pub fn wait_timeout_unlocked(&self, dur: core::time::Duration, unlock: impl FnOnce()) -> bool {
+        let seq = self.seq.load(Ordering::Acquire);
+        unlock();
+        self.wq
+            .wait_timeout_until(dur, || self.seq.load(Ordering::Acquire) != seq)
+    }
+
+    /// Waits on this condition variable for a notification, timing out after
+    /// the specified duration, until the provided condition becomes false.
+    ///
+    /// The returned [`WaitTimeoutResult`] indicates whether the timeout
+    /// elapsed while the condition is still true.
+    #[cfg(feature = "irq")]
+    pub fn wait_timeout_while<'a, T, F>(
+        &self,
+        mut guard: MutexGuard<'a, T>,
+        dur: core::time::Duration,
+        mut condition: F,
+    ) -> (MutexGuard<'a, T>, WaitTimeoutResult)
+    where
+        F: FnMut(&mut T) -> bool,
+    {
+        let deadline = axhal::time::wall_time() + dur;
+        while condition(&mut *guard) {
+            let now = axhal::time::wall_time();
+            if now >= deadline {
+                return (guard, WaitTimeoutResult(true));
+            }
+            guard = self.wait_timeout(guard, deadline - now).0;
+        }
+        (guard, WaitTimeoutResult(false))
+    }
+
+    /// Wakes up one blocked task on this condvar.
+    pub fn notify_one(&self) {
+        self.seq.fetch_add(1, Ordering::Release);
+        self.wq.notify_one(true);
+    }
+
+    /// Wakes up all blocked tasks on this condvar.
+    pub fn notify_all(&self) {
+        self.seq.fetch_add(1, Ordering::Release);
+        self.wq.notify_all(true);
+    }
+}
+
+impl Default for Condvar {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Debug for Condvar {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Condvar").finish_non_exhaustive()
+    }
+}
+
+/// Releases the lock and returns the mutex to re-acquire it later.
+fn unlock<'a, T>(guard: MutexGuard<'a, T>) -> &'a Mutex<T> {
+    let mutex = MutexGuard::mutex(&guard);
+    drop(guard);
+    mutex
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::mutex::tests::{INIT, SERIAL};
+    use crate::{Condvar, Mutex, RwLock};
+    use axtask as thread;
+
+    #[test]
+    fn notify_all() {
+        let _lock = SERIAL.lock();
+        INIT.call_once(thread::init_scheduler);
+
+        const NUM_TASKS: usize = 10;
+        static M: Mutex<usize> = Mutex::new(0);
+        static CV: Condvar = Condvar::new();
+
+        for _ in 0..NUM_TASKS {
+            thread::spawn(|| {
+                let mut guard = CV.wait_while(M.lock(), |started| *started == 0);
+                *guard += 1;
+                drop(guard);
+                CV.notify_all();
+            });
+        }
+
+        thread::yield_now(); // let some tasks wait on the condvar
+        *M.lock() = 1;
+        CV.notify_all();
+        let guard = CV.wait_while(M.lock(), |count| *count < NUM_TASKS + 1);
+        assert_eq!(*guard, NUM_TASKS + 1);
+    }
+
+    #[test]
+    fn wait_unlocked() {
+        let _lock = SERIAL.lock();
+        INIT.call_once(thread::init_scheduler);
+
+        // works with other locks, e.g., an `RwLock`
+        static LOCK: RwLock<bool> = RwLock::new(false);
+        static CV: Condvar = Condvar::new();
+
+        thread::spawn(|| {
+            *LOCK.write() = true;
+            CV.notify_all();
+        });
+        let mut guard = LOCK.read();
+        while !*guard {
+            CV.wait_unlocked(|| drop(guard));
+            guard = LOCK.read();
+        }
+    }
+
+    #[test]
+    fn cancel_wait() {
+        let _lock = SERIAL.lock();
+        INIT.call_once(thread::init_scheduler);
+
+        static M: Mutex<bool> = Mutex::new(false);
+        static CV: Condvar = Condvar::new();
+
+        let task = thread::spawn(|| {
+            let res = CV.wait_while_cancellable(M.lock(), |ready| !*ready);
+            assert!(matches!(res, Err(thread::Cancelled)));
+        });
+        thread::yield_now(); // let the task wait on the condvar
+        assert!(thread::cancel(&task));
+        assert_eq!(task.join(), Some(thread::EXIT_CANCELLED));
+        assert!(!M.is_locked()); // left unlocked by the cancelled task
+    }
+}
@@ -0,0 +1,175 @@
+//! A futex word to build custom blocking primitives on.
+
+use core::fmt;
+use core::ops::Deref;
+use core::sync::atomic::{AtomicU32, Ordering};
+
+pub use axtask::FutexError;
+
+/// A 32-bit atomic word that tasks can wait on.
+///
+/// It dereferences to [`AtomicU32`], and waiters are kept in the futex table
+/// of [`axtask`] instead of a [`WaitQueue`](axtask::WaitQueue) of its own,
+/// so it is only 4 bytes. The same table backs the `futex` syscall, so a
+/// kernel-side task and a user program can wait on the same word.
+///
+/// # Examples
+///
+/// ```ignore
+/// use axsync::Futex;
+/// use core::sync::atomic::Ordering;
+///
+/// static READY: Futex = Futex::new(0);
+///
+/// axtask::spawn(|| {
+///     READY.store(1, Ordering::Release);
+///     READY.wake_all();
+/// });
+/// while READY.load(Ordering::Acquire) == 0 {
+///     let _ = READY.wait(0);
+/// }
+/// ```
+#[repr(transparent)]
+pub struct Futex(AtomicU32);
+
+impl Futex {
+    /// Creates a new futex word with the initial value.
+    pub const fn new(value: u32) -> Self {
+        Self(AtomicU32::new(value))
+    }
+
+    /// Blocks the current task if the value is `expected`, until it is woken
+    /// up by [`wake`](Self::wake) or [`requeue`](Self::requeue).
+    ///
+    /// Returns [`FutexError::WouldBlock`] immediately if the value is not
+    /// `expected`. Wakeups may be spurious, so the caller should check the
+    /// value again.
+    pub fn wait(&self, expected: u32) -> Result<(), FutexError> {
+        axtask::futex_wait(&self.0, expected)
+    }
+
+    /// Like [`wait`](Self::wait), but also returns [`FutexError::TimedOut`]
+    /// when the given duration has elapsed.
+    #[cfg(feature = "irq")]
+    pub fn wait_timeout(&self, expected: u32, dur: core::time::Duration) -> Result<(), FutexError> {
+        axtask::futex_wait_timeout(&self.0, expected, dur)
+    }
+
+    /// Wakes up at most `count` tasks waiting on this futex.
+    ///
+    /// Returns the number of tasks woken up.
+    pub fn wake(&self, count: usize) -> usize {
+        axtask::futex_wake(&self.0, count)
+    }
+
+    /// Wakes up all tasks waiting on this futex.
+    pub fn wake_all(&self) -> usize {
+        self.wake(usize::MAX)
+    }
+
+    /// Wakes up at most `nr_wake` tasks waiting on this futex, and moves at
+    /// most `nr_requeue` of the others to wait on `to`.
+    ///
+    /// Returns the number of tasks woken up plus the number of tasks requeued.
+    pub fn requeue(&self, to: &Futex, nr_wake: usize, nr_requeue: usize) -> usize {
+        // Never fails without the expected value.
+        axtask::futex_requeue(&self.0, &to.0, nr_wake, nr_requeue, None).unwrap_or(0)
+    }
+}
+
+impl Deref for Futex {
+    type Target = AtomicU32;
+
+    fn deref(&self) -> &AtomicU32 {
+        &self.0
+    }
+}
+
+impl fmt::Debug for Futex {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("Futex")
+            .field(&self.0.load(Ordering::Relaxed))
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::mutex::tests::{INIT, SERIAL};
+    use crate::Futex;
+    use axtask as thread;
+    use core::cell::UnsafeCell;
+    use core::sync::atomic::Ordering;
+
+    /// A mutex with 3 states: 0 (unlocked), 1 (locked), 2 (locked with
+    /// waiters).
+    struct FutexMutex<T> {
+        state: Futex,
+        data: UnsafeCell<T>,
+    }
+
+    unsafe impl<T: Send> Sync for FutexMutex<T> {}
+
+    impl<T> FutexMutex<T> {
+        const fn new(data: T) -> Self {
+            Self {
+                state: Futex::new(0),
+                data: UnsafeCell::new(data),
+            }
+        }
+
+        fn lock(&self) {
+            if self
+                .state
+                .compare_exchange(0, 1, Ordering::Acquire, Ordering::Relaxed)
+                .is_ok()
+            {
+                return;
+            }
+            while self.state.swap(2, Ordering::Acquire) != 0 {
+                let _ = self.state.wait(2);
+            }
+        }
+
+        fn unlock(&self) {
+            if self.state.swap(0, Ordering::Release) == 2 {
+                self.state.wake(1);
+            }
+        }
+    }
+
+    #[test]
+    fn futex_mutex() {
+        let _lock = SERIAL.lock();
+        INIT.call_once(thread::init_scheduler);
+
+        const NUM_TASKS: u32 = 10;
+        const NUM_ITERS: u32 = 1_000;
+        static M: FutexMutex<u32> = FutexMutex::new(0);
+
+        fn inc(delta: u32) {
+            for _ in 0..NUM_ITERS {
+                M.lock();
+                let val = unsafe { *M.data.get() };
+                if rand::random::<u32>() % 3 == 0 {
+                    thread::yield_now();
+                }
+                unsafe { *M.data.get() = val + delta };
+                M.unlock();
+            }
+        }
+
+        let tasks: Vec<_> = (0..NUM_TASKS)
+            .map(|_| {
+                thread::spawn(|| {
+                    inc(1);
+                    inc(2);
+                })
+            })
+            .collect();
+        for task in tasks {
+            task.join();
+        }
+        assert_eq!(unsafe { *M.data.get() }, NUM_ITERS * NUM_TASKS * 3);
+    }
+}
@@ -3,26 +3,71 @@
 //! Currently supported primitives:
 //!
 //! - [`Mutex`]: A mutual exclusion primitive.
+//! - [`PiMutex`]: A mutual exclusion primitive with priority inheritance.
+//! - [`Condvar`]: A condition variable working with [`Mutex`].
+//! - [`RwLock`]: A writer-preferring reader-writer lock.
+//! - [`Semaphore`]: A counting semaphore.
+//! - [`Barrier`]: A barrier to synchronize multiple tasks.
+//! - [`Futex`]: A 32-bit word to build custom blocking primitives on.
 //! - mod [`spin`]: spinlocks imported from the [`kspin`] crate.
 //!
 //! # Cargo Features
 //!
 //! - `multitask`: For use in the multi-threaded environments. If the feature is
 //!   not enabled, [`Mutex`] will be an alias of [`spin::SpinNoIrq`]. This
-//!   feature is enabled by default.
+//!   feature is enabled by default. All primitives except [`Mutex`] and
+//!   [`spin`] require it.
+//! - `irq`: Interrupts are enabled, timed waits such as
+//!   [`Condvar::wait_timeout`] can be used.
 
 #![cfg_attr(not(test), no_std)]
 #![feature(doc_cfg)]
 
+#[cfg(feature = "multitask")]
+#[macro_use]
+extern crate log;
+#[cfg(feature = "multitask")]
+extern crate alloc;
+
 pub use kspin as spin;
 
+#[cfg(feature = "multitask")]
+mod barrier;
+#[cfg(feature = "multitask")]
+mod condvar;
+#[cfg(feature = "multitask")]
+mod futex;
 #[cfg(feature = "multitask")]
 mod mutex;
+#[cfg(feature = "multitask")]
+mod pi_mutex;
+#[cfg(feature = "multitask")]
+mod rwlock;
+#[cfg(feature = "multitask")]
+mod semaphore;
 
+#[cfg(all(feature = "multitask", feature = "irq"))]
+#[doc(cfg(all(feature = "multitask", feature = "irq")))]
+pub use self::condvar::WaitTimeoutResult;
 #[cfg(feature = "multitask")]
 #[doc(cfg(feature = "multitask"))]
 pub use self::mutex::{Mutex, MutexGuard};
+#[cfg(feature = "multitask")]
+#[doc(cfg(feature = "multitask"))]
+pub use self::pi_mutex::{PiMutex, PiMutexGuard};
+#[cfg(feature = "multitask")]
+#[doc(cfg(feature = "multitask"))]
+pub use self::{
+    barrier::{Barrier, BarrierWaitResult},
+    condvar::Condvar,
+    futex::{Futex, FutexError},
+    rwlock::{RwLock, RwLockReadGuard, RwLockWriteGuard},
+    semaphore::{Semaphore, SemaphoreGuard},
+};
 
 #[cfg(not(feature = "multitask"))]
 #[doc(cfg(not(feature = "multitask")))]
 pub use kspin::{SpinNoIrq as Mutex, SpinNoIrqGuard as MutexGuard};
+#[cfg(not(feature = "multitask"))]
+#[doc(cfg(not(feature = "multitask")))]
+pub use kspin::{SpinNoIrq as PiMutex, SpinNoIrqGuard as PiMutexGuard};
@@ -5,7 +5,7 @@ use core::fmt;
 use core::ops::{Deref, DerefMut};
 use core::sync::atomic::{AtomicU64, Ordering};
 
-use axtask::{current, WaitQueue};
+use axtask::{current, Cancelled, WaitQueue};
 
 /// A mutual exclusion primitive useful for protecting shared data, similar to
 /// [`std::sync::Mutex`](https://doc.rust-lang.org/std/sync/struct.Mutex.html).
@@ -98,6 +98,40 @@ impl<T: ?Sized> Mutex<T> {
         }
     }
 
+    /// Like [`lock`](Self::lock), but returns [`Cancelled`] if the current
+    /// task is cancelled while waiting for the lock, see [`axtask::cancel`].
+    pub fn lock_cancellable(&self) -> Result<MutexGuard<T>, Cancelled> {
+        let current_id = current().id().as_u64();
+        loop {
+            match self.owner_id.compare_exchange_weak(
+                0,
+                current_id,
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => break,
+                Err(owner_id) => {
+                    assert_ne!(
+                        owner_id,
+                        current_id,
+                        "{} tried to acquire mutex it already owns.",
+                        current().id_name()
+                    );
+                    if let Err(e) = self.wq.wait_until_cancellable(|| !self.is_locked()) {
+                        // We may have been chosen by `force_unlock()`, pass
+                        // the wakeup on to another waiter.
+                        self.wq.notify_one(true);
+                        return Err(e);
+                    }
+                }
+            }
+        }
+        Ok(MutexGuard {
+            lock: self,
+            data: unsafe { &mut *self.data.get() },
+        })
+    }
+
     /// Try to lock this [`Mutex`], returning a lock guard if successful.
     #[inline(always)]
     pub fn try_lock(&self) -> Option<MutexGuard<T>> {
@@ -167,6 +201,13 @@ impl<T: ?Sized + fmt::Debug> fmt::Debug for Mutex<T> {
     }
 }
 
+impl<'a, T: ?Sized> MutexGuard<'a, T> {
+    /// Returns the mutex that the guard is created from.
+    pub(crate) fn mutex(this: &Self) -> &'a Mutex<T> {
+        this.lock
+    }
+}
+
 impl<'a, T: ?Sized> Deref for MutexGuard<'a, T> {
     type Target = T;
     #[inline(always)]
@@ -198,12 +239,13 @@ impl<'a, T: ?Sized> Drop for MutexGuard<'a, T> {
 }
 
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use crate::Mutex;
     use axtask as thread;
-    use std::sync::Once;
+    use std::sync::{Mutex as StdMutex, Once};
 
-    static INIT: Once = Once::new();
+    pub(crate) static INIT: Once = Once::new();
+    pub(crate) static SERIAL: StdMutex<()> = StdMutex::new(());
 
     fn may_interrupt() {
         // simulate interrupts
@@ -214,6 +256,7 @@ mod tests {
 
     #[test]
     fn lots_and_lots() {
+        let _lock = SERIAL.lock();
         INIT.call_once(thread::init_scheduler);
 
         const NUM_TASKS: u32 = 10;
@@ -249,4 +292,23 @@ mod tests {
         assert_eq!(*M.lock(), NUM_ITERS * NUM_TASKS * 3);
         println!("Mutex test OK");
     }
+
+    #[test]
+    fn cancel_lock() {
+        let _lock = SERIAL.lock();
+        INIT.call_once(thread::init_scheduler);
+
+        static M: Mutex<u32> = Mutex::new(0);
+
+        let guard = M.lock();
+        let task = thread::spawn(|| {
+            assert!(matches!(M.lock_cancellable(), Err(thread::Cancelled)));
+        });
+        thread::yield_now(); // let the task block on the mutex
+        assert!(thread::cancel(&task));
+        assert_eq!(task.join(), Some(thread::EXIT_CANCELLED));
+        drop(guard);
+        *M.lock() += 1; // still usable
+        assert_eq!(*M.lock(), 1);
+    }
 }
@@ -0,0 +1,426 @@
+//! A sleeping mutex with priority inheritance.
+
+use alloc::{sync::Arc, vec::Vec};
+use core::cell::UnsafeCell;
+use core::fmt;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use axtask::{current, AxTaskRef, TaskInner, WaitQueue};
+use kspin::SpinNoIrq;
+
+/// The tasks that are interested in a [`PiMutex`].
+struct PiState {
+    owner: Option<AxTaskRef>,
+    waiters: Vec<AxTaskRef>,
+    /// The state of the next lock held by the owner, see
+    /// [`TaskInner::held_pi_locks`].
+    next_held: usize,
+}
+
+impl PiState {
+    /// The highest priority (the smallest value) of all waiters.
+    fn top_waiter_priority(&self) -> Option<isize> {
+        self.waiters.iter().map(|t| t.priority()).min()
+    }
+
+    /// Raises the priority of the owner to the highest priority of waiters,
+    /// if it is lower than that.
+    fn boost_owner(&self) {
+        if let (Some(owner), Some(prio)) = (&self.owner, self.top_waiter_priority()) {
+            if prio < owner.priority() {
+                debug!("PiMutex: boost {} to priority {}", owner.id_name(), prio);
+                axtask::set_effective_priority(owner, prio);
+            }
+        }
+    }
+}
+
+/// Links the lock with the given state to the locks held by the current task.
+fn add_held_lock(curr: &TaskInner, state: &SpinNoIrq<PiState>) {
+    let head = curr.held_pi_locks();
+    state.lock().next_held = head.load(Ordering::Relaxed);
+    head.store(state as *const _ as usize, Ordering::Relaxed);
+}
+
+/// Unlinks the lock with the given state from the locks held by the current
+/// task, and returns the highest priority of the waiters of the others.
+fn remove_held_lock(curr: &TaskInner, state: &SpinNoIrq<PiState>) -> Option<isize> {
+    let head = curr.held_pi_locks();
+    let this = state as *const _ as usize;
+    let next = core::mem::take(&mut state.lock().next_held);
+    let mut prio = None;
+    let mut prev: Option<&SpinNoIrq<PiState>> = None;
+    let mut addr = head.load(Ordering::Relaxed);
+    while addr != 0 {
+        if addr == this {
+            match prev {
+                Some(prev) => prev.lock().next_held = next,
+                None => head.store(next, Ordering::Relaxed),
+            }
+            addr = next;
+            continue;
+        }
+        // Safety: the lock is held by the current task, so it is alive until
+        // the task unlocks it.
+        let other = unsafe { &*(addr as *const SpinNoIrq<PiState>) };
+        let other_state = other.lock();
+        prio = prio
+            .into_iter()
+            .chain(other_state.top_waiter_priority())
+            .min();
+        addr = other_state.next_held;
+        drop(other_state);
+        prev = Some(other);
+    }
+    prio
+}
+
+/// A mutual exclusion primitive with priority inheritance.
+///
+/// It works like [`Mutex`](crate::Mutex), except that while higher-priority
+/// tasks are waiting for the lock, the priority of the holder is raised to
+/// the highest one of them through the scheduler, so that a low-priority
+/// holder cannot block high-priority tasks indefinitely. On unlock, the
+/// holder drops back to its base priority, or the priority it still inherits
+/// from the other [`PiMutex`]es it holds.
+///
+/// The priority is only changed with schedulers that support priorities
+/// (e.g., `sched_cfs`), otherwise it behaves the same as a [`Mutex`].
+///
+/// Only the direct holder is boosted, i.e., the priority is not propagated
+/// along a chain of blocked lock holders.
+///
+/// [`Mutex`]: crate::Mutex
+pub struct PiMutex<T: ?Sized> {
+    wq: WaitQueue,
+    owner_id: AtomicU64,
+    state: SpinNoIrq<PiState>,
+    data: UnsafeCell<T>,
+}
+
+/// A guard that provides mutable data access.
+///
+/// When the guard falls out of scope it will release the lock.
+pub struct PiMutexGuard<'a, T: ?Sized + 'a> {
+    lock: &'a PiMutex<T>,
+    data: *mut T,
+}
+
+// Same unsafe impls as `std::sync::Mutex`
+unsafe impl<T: ?Sized + Send> Sync for PiMutex<T> {}
+unsafe impl<T: ?Sized + Send> Send for PiMutex<T> {}
+
+impl<T> PiMutex<T> {
+    /// Creates a new [`PiMutex`] wrapping the supplied data.
+    #[inline(always)]
+    pub const fn new(data: T) -> Self {
+        Self {
+            wq: WaitQueue::new(),
+            owner_id: AtomicU64::new(0),
+            state: SpinNoIrq::new(PiState {
+                owner: None,
+                waiters: Vec::new(),
+                next_held: 0,
+            }),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    /// Consumes this [`PiMutex`] and unwraps the underlying data.
+    #[inline(always)]
+    pub fn into_inner(self) -> T {
+        // We know statically that there are no outstanding references to
+        // `self` so there's no need to lock.
+        let PiMutex { data, .. } = self;
+        data.into_inner()
+    }
+}
+
+impl<T: ?Sized> PiMutex<T> {
+    /// Returns `true` if the lock is currently held.
+    ///
+    /// # Safety
+    ///
+    /// This function provides no synchronization guarantees and so its result should be considered 'out of date'
+    /// the instant it is called. Do not use it for synchronization purposes. However, it may be useful as a heuristic.
+    #[inline(always)]
+    pub fn is_locked(&self) -> bool {
+        self.owner_id.load(Ordering::Relaxed) != 0
+    }
+
+    /// Locks the [`PiMutex`] and returns a guard that permits access to the inner data.
+    ///
+    /// If the lock is held by a lower-priority task, its priority is raised to
+    /// the priority of the current task until it releases the lock.
+    pub fn lock(&self) -> PiMutexGuard<T> {
+        let curr = current();
+        let current_id = curr.id().as_u64();
+        let mut waiting = false;
+        loop {
+            match self.owner_id.compare_exchange_weak(
+                0,
+                current_id,
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => break,
+                Err(owner_id) => {
+                    assert_ne!(
+                        owner_id,
+                        current_id,
+                        "{} tried to acquire mutex it already owns.",
+                        curr.id_name()
+                    );
+                    if !waiting {
+                        waiting = true;
+                        let mut state = self.state.lock();
+                        state.waiters.push(curr.as_task_ref().clone());
+                        state.boost_owner();
+                    }
+                    // Wait until the lock looks unlocked before retrying
+                    self.wq.wait_until(|| !self.is_locked());
+                }
+            }
+        }
+
+        let mut state = self.state.lock();
+        if waiting {
+            state
+                .waiters
+                .retain(|t| !Arc::ptr_eq(curr.as_task_ref(), t));
+        }
+        state.owner = Some(curr.as_task_ref().clone());
+        // Waiters that came before we set the owner could not boost us.
+        state.boost_owner();
+        drop(state);
+        add_held_lock(&curr, &self.state);
+
+        PiMutexGuard {
+            lock: self,
+            data: unsafe { &mut *self.data.get() },
+        }
+    }
+
+    /// Try to lock this [`PiMutex`], returning a lock guard if successful.
+    #[inline(always)]
+    pub fn try_lock(&self) -> Option<PiMutexGuard<T>> {
+        let curr = current();
+        if self
+            .owner_id
+            .compare_exchange(0, curr.id().as_u64(), Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+        {
+            let mut state = self.state.lock();
+            state.owner = Some(curr.as_task_ref().clone());
+            state.boost_owner();
+            drop(state);
+            add_held_lock(&curr, &self.state);
+            Some(PiMutexGuard {
+                lock: self,
+                data: unsafe { &mut *self.data.get() },
+            })
+        } else {
+            None
+        }
+    }
+
+    /// Force unlock the [`PiMutex`], and drop the priority of the current task
+    /// back to its base priority, or the highest priority of the waiters of
+    /// the other [`PiMutex`]es it holds.
+    ///
+    /// # Safety
+    ///
+    /// This is *extremely* unsafe if the lock is not held by the current
+    /// thread. However, this can be useful in some instances for exposing
+    /// the lock to FFI that doesn’t know how to deal with RAII.
+    pub unsafe fn force_unlock(&self) {
+        let curr = current();
+        let owner = self.state.lock().owner.take();
+        if let Some(owner) = owner {
+            let inherited = remove_held_lock(&owner, &self.state);
+            let prio = inherited.map_or(owner.base_priority(), |prio| {
+                prio.min(owner.base_priority())
+            });
+            if owner.priority() != prio {
+                debug!("PiMutex: restore {} to priority {}", owner.id_name(), prio);
+                axtask::set_effective_priority(&owner, prio);
+            }
+        }
+        let owner_id = self.owner_id.swap(0, Ordering::Release);
+        assert_eq!(
+            owner_id,
+            curr.id().as_u64(),
+            "{} tried to release mutex it doesn't own",
+            curr.id_name()
+        );
+        self.wq.notify_one(true);
+    }
+
+    /// Returns a mutable reference to the underlying data.
+    ///
+    /// Since this call borrows the [`PiMutex`] mutably, and a mutable reference is guaranteed to be exclusive in
+    /// Rust, no actual locking needs to take place -- the mutable borrow statically guarantees no locks exist. As
+    /// such, this is a 'zero-cost' operation.
+    #[inline(always)]
+    pub fn get_mut(&mut self) -> &mut T {
+        // We know statically that there are no other references to `self`, so
+        // there's no need to lock the inner mutex.
+        unsafe { &mut *self.data.get() }
+    }
+}
+
+impl<T: ?Sized + Default> Default for PiMutex<T> {
+    #[inline(always)]
+    fn default() -> Self {
+        Self::new(Default::default())
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for PiMutex<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.try_lock() {
+            Some(guard) => write!(f, "PiMutex {{ data: ")
+                .and_then(|()| (*guard).fmt(f))
+                .and_then(|()| write!(f, "}}")),
+            None => write!(f, "PiMutex {{ <locked> }}"),
+        }
+    }
+}
+
+impl<'a, T: ?Sized> Deref for PiMutexGuard<'a, T> {
+    type Target = T;
+    #[inline(always)]
+    fn deref(&self) -> &T {
+        // We know statically that only we are referencing data
+        unsafe { &*self.data }
+    }
+}
+
+impl<'a, T: ?Sized> DerefMut for PiMutexGuard<'a, T> {
+    #[inline(always)]
+    fn deref_mut(&mut self) -> &mut T {
+        // We know statically that only we are referencing data
+        unsafe { &mut *self.data }
+    }
+}
+
+impl<'a, T: ?Sized + fmt::Debug> fmt::Debug for PiMutexGuard<'a, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl<'a, T: ?Sized> Drop for PiMutexGuard<'a, T> {
+    /// The dropping of the [`PiMutexGuard`] will release the lock it was created from.
+    fn drop(&mut self) {
+        unsafe { self.lock.force_unlock() }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::mutex::tests::{INIT, SERIAL};
+    use crate::PiMutex;
+    use axtask as thread;
+
+    #[test]
+    fn lots_and_lots() {
+        let _lock = SERIAL.lock();
+        INIT.call_once(thread::init_scheduler);
+
+        const NUM_TASKS: u32 = 10;
+        const NUM_ITERS: u32 = 1_000;
+        static M: PiMutex<u32> = PiMutex::new(0);
+
+        fn inc(delta: u32) {
+            for _ in 0..NUM_ITERS {
+                let mut val = M.lock();
+                *val += delta;
+                if rand::random::<u32>() % 3 == 0 {
+                    thread::yield_now();
+                }
+                drop(val);
+            }
+        }
+
+        let tasks: Vec<_> = (0..NUM_TASKS).map(|_| thread::spawn(|| inc(1))).collect();
+        for t in tasks {
+            t.join();
+        }
+
+        let curr = thread::current();
+        assert_eq!(curr.priority(), curr.base_priority());
+        assert_eq!(*M.lock(), NUM_ITERS * NUM_TASKS);
+        println!("PiMutex test OK");
+    }
+
+    #[test]
+    fn inherit_from_other_locks() {
+        use core::sync::atomic::{AtomicBool, Ordering};
+
+        let _lock = SERIAL.lock();
+        INIT.call_once(thread::init_scheduler);
+
+        static A: PiMutex<()> = PiMutex::new(());
+        static B: PiMutex<()> = PiMutex::new(());
+        static WAITING: AtomicBool = AtomicBool::new(false);
+
+        let curr = thread::current();
+        let a = A.lock();
+        let b = B.lock();
+        let waiter = thread::spawn(|| {
+            thread::set_priority(-5);
+            WAITING.store(true, Ordering::Release);
+            drop(A.lock());
+        });
+        while !WAITING.load(Ordering::Acquire) {
+            thread::yield_now();
+        }
+        let boosted = curr.priority();
+        drop(b);
+        assert_eq!(curr.priority(), boosted); // still inherits from `A`
+        drop(a);
+        waiter.join();
+        assert_eq!(curr.priority(), curr.base_priority());
+    }
+
+    #[test]
+    fn boost_low_priority_holder() {
+        use core::sync::atomic::{AtomicBool, Ordering};
+
+        let _lock = SERIAL.lock();
+        INIT.call_once(thread::init_scheduler);
+
+        static M: PiMutex<()> = PiMutex::new(());
+        static WAITING: AtomicBool = AtomicBool::new(false);
+
+        // Only with the schedulers that support priorities, e.g., with
+        // `--features axtask/sched_cfs`.
+        if !thread::set_priority(5) {
+            return;
+        }
+        let curr = thread::current();
+        let guard = M.lock();
+        let waiter = thread::spawn(|| {
+            thread::set_priority(-5);
+            WAITING.store(true, Ordering::Release);
+            drop(M.lock());
+        });
+        while !WAITING.load(Ordering::Acquire) {
+            thread::yield_now();
+        }
+        // The waiter boosts the holder once it blocks on the lock.
+        while M.state.lock().waiters.is_empty() {
+            thread::yield_now();
+        }
+        assert_eq!(curr.priority(), -5);
+        assert_eq!(curr.base_priority(), 5);
+
+        drop(guard);
+        assert_eq!(curr.priority(), 5);
+        waiter.join();
+        assert!(thread::set_priority(0));
+        assert_eq!(curr.priority(), 0);
+    }
+}
@@ -0,0 +1,341 @@
+//! A writer-preferring sleeping reader-writer lock.
+
+use core::cell::UnsafeCell;
+use core::fmt;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use axtask::{Cancelled, WaitQueue};
+
+/// The lock is held by a writer.
+const WRITER: usize = 1 << (usize::BITS - 1);
+
+/// A reader-writer lock, similar to
+/// [`std::sync::RwLock`](https://doc.rust-lang.org/std/sync/struct.RwLock.html).
+///
+/// It allows a number of readers or at most one writer at any point in time.
+/// The lock prefers writers: once a writer is waiting, new readers will block
+/// until all waiting writers have finished, so writers cannot be starved by a
+/// continuous stream of readers.
+///
+/// Because of this, a task that already holds a read lock must not try to
+/// acquire it again, which may deadlock if a writer is waiting in between.
+pub struct RwLock<T: ?Sized> {
+    /// The number of readers, or [`WRITER`] if held by a writer.
+    state: AtomicUsize,
+    writers_waiting: AtomicUsize,
+    read_wq: WaitQueue,
+    write_wq: WaitQueue,
+    data: UnsafeCell<T>,
+}
+
+/// A guard that provides immutable data access.
+///
+/// When the guard falls out of scope it will release the shared read access.
+pub struct RwLockReadGuard<'a, T: ?Sized + 'a> {
+    lock: &'a RwLock<T>,
+    data: *const T,
+}
+
+/// A guard that provides mutable data access.
+///
+/// When the guard falls out of scope it will release the exclusive write
+/// access.
+pub struct RwLockWriteGuard<'a, T: ?Sized + 'a> {
+    lock: &'a RwLock<T>,
+    data: *mut T,
+}
+
+// Same unsafe impls as `std::sync::RwLock`
+unsafe impl<T: ?Sized + Send> Send for RwLock<T> {}
+unsafe impl<T: ?Sized + Send + Sync> Sync for RwLock<T> {}
+
+impl<T> RwLock<T> {
+    /// Creates a new instance of an [`RwLock`] which is unlocked.
+    #[inline(always)]
+    pub const fn new(data: T) -> Self {
+        Self {
+            state: AtomicUsize::new(0),
+            writers_waiting: AtomicUsize::new(0),
+            read_wq: WaitQueue::new(),
+            write_wq: WaitQueue::new(),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    /// Consumes this [`RwLock`] and unwraps the underlying data.
+    #[inline(always)]
+    pub fn into_inner(self) -> T {
+        let RwLock { data, .. } = self;
+        data.into_inner()
+    }
+}
+
+impl<T: ?Sized> RwLock<T> {
+    fn can_read(&self) -> bool {
+        self.state.load(Ordering::Acquire) & WRITER == 0
+            && self.writers_waiting.load(Ordering::Acquire) == 0
+    }
+
+    /// Locks this [`RwLock`] with shared read access, blocking the current
+    /// task until it can be acquired.
+    pub fn read(&self) -> RwLockReadGuard<T> {
+        loop {
+            if let Some(guard) = self.try_read() {
+                return guard;
+            }
+            self.read_wq.wait_until(|| self.can_read());
+        }
+    }
+
+    /// Like [`read`](Self::read), but returns [`Cancelled`] if the current
+    /// task is cancelled while waiting, see [`axtask::cancel`].
+    pub fn read_cancellable(&self) -> Result<RwLockReadGuard<T>, Cancelled> {
+        loop {
+            if let Some(guard) = self.try_read() {
+                return Ok(guard);
+            }
+            self.read_wq.wait_until_cancellable(|| self.can_read())?;
+        }
+    }
+
+    /// Attempts to acquire this [`RwLock`] with shared read access.
+    ///
+    /// It fails if the lock is held by a writer or a writer is waiting.
+    pub fn try_read(&self) -> Option<RwLockReadGuard<T>> {
+        let mut state = self.state.load(Ordering::Relaxed);
+        while state & WRITER == 0 && self.writers_waiting.load(Ordering::Acquire) == 0 {
+            match self.state.compare_exchange_weak(
+                state,
+                state + 1,
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => {
+                    return Some(RwLockReadGuard {
+                        lock: self,
+                        data: self.data.get(),
+                    })
+                }
+                Err(s) => state = s,
+            }
+        }
+        None
+    }
+
+    /// Locks this [`RwLock`] with exclusive write access, blocking the current
+    /// task until it can be acquired.
+    pub fn write(&self) -> RwLockWriteGuard<T> {
+        self.writers_waiting.fetch_add(1, Ordering::AcqRel);
+        loop {
+            if self
+                .state
+                .compare_exchange(0, WRITER, Ordering::Acquire, Ordering::Relaxed)
+                .is_ok()
+            {
+                break;
+            }
+            self.write_wq
+                .wait_until(|| self.state.load(Ordering::Acquire) == 0);
+        }
+        self.writers_waiting.fetch_sub(1, Ordering::AcqRel);
+        RwLockWriteGuard {
+            lock: self,
+            data: self.data.get(),
+        }
+    }
+
+    /// Like [`write`](Self::write), but returns [`Cancelled`] if the current
+    /// task is cancelled while waiting, see [`axtask::cancel`].
+    pub fn write_cancellable(&self) -> Result<RwLockWriteGuard<T>, Cancelled> {
+        self.writers_waiting.fetch_add(1, Ordering::AcqRel);
+        loop {
+            if self
+                .state
+                .compare_exchange(0, WRITER, Ordering::Acquire, Ordering::Relaxed)
+                .is_ok()
+            {
+                break;
+            }
+            if let Err(e) = self
+                .write_wq
+                .wait_until_cancellable(|| self.state.load(Ordering::Acquire) == 0)
+            {
+                // Give up waiting: pass a possible wakeup on to another
+                // writer, or let the readers blocked by us in.
+                if self.writers_waiting.fetch_sub(1, Ordering::AcqRel) > 1 {
+                    self.write_wq.notify_one(true);
+                } else {
+                    self.read_wq.notify_all(true);
+                }
+                return Err(e);
+            }
+        }
+        self.writers_waiting.fetch_sub(1, Ordering::AcqRel);
+        Ok(RwLockWriteGuard {
+            lock: self,
+            data: self.data.get(),
+        })
+    }
+
+    /// Attempts to lock this [`RwLock`] with exclusive write access.
+    pub fn try_write(&self) -> Option<RwLockWriteGuard<T>> {
+        if self
+            .state
+            .compare_exchange(0, WRITER, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+        {
+            Some(RwLockWriteGuard {
+                lock: self,
+                data: self.data.get(),
+            })
+        } else {
+            None
+        }
+    }
+
+    /// Returns `true` if the lock is currently held by a writer or readers.
+    ///
+    /// # Safety
+    ///
+    /// This function provides no synchronization guarantees and so its result
+    /// should be considered 'out of date' the instant it is called.
+    #[inline(always)]
+    pub fn is_locked(&self) -> bool {
+        self.state.load(Ordering::Relaxed) != 0
+    }
+
+    /// Returns a mutable reference to the underlying data.
+    #[inline(always)]
+    pub fn get_mut(&mut self) -> &mut T {
+        // We know statically that there are no other references to `self`, so
+        // there's no need to lock.
+        unsafe { &mut *self.data.get() }
+    }
+
+    fn read_unlock(&self) {
+        if self.state.fetch_sub(1, Ordering::Release) == 1 {
+            // the last reader, let a waiting writer in.
+            self.write_wq.notify_one(true);
+        }
+    }
+
+    fn write_unlock(&self) {
+        self.state.store(0, Ordering::Release);
+        if self.writers_waiting.load(Ordering::Acquire) > 0 {
+            self.write_wq.notify_one(true);
+        } else {
+            self.read_wq.notify_all(true);
+        }
+    }
+}
+
+impl<T: ?Sized + Default> Default for RwLock<T> {
+    #[inline(always)]
+    fn default() -> Self {
+        Self::new(Default::default())
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for RwLock<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.try_read() {
+            Some(guard) => write!(f, "RwLock {{ data: ")
+                .and_then(|()| (*guard).fmt(f))
+                .and_then(|()| write!(f, "}}")),
+            None => write!(f, "RwLock {{ <locked> }}"),
+        }
+    }
+}
+
+impl<'a, T: ?Sized> Deref for RwLockReadGuard<'a, T> {
+    type Target = T;
+    #[inline(always)]
+    fn deref(&self) -> &T {
+        unsafe { &*self.data }
+    }
+}
+
+impl<'a, T: ?Sized> Deref for RwLockWriteGuard<'a, T> {
+    type Target = T;
+    #[inline(always)]
+    fn deref(&self) -> &T {
+        unsafe { &*self.data }
+    }
+}
+
+impl<'a, T: ?Sized> DerefMut for RwLockWriteGuard<'a, T> {
+    #[inline(always)]
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.data }
+    }
+}
+
+impl<'a, T: ?Sized + fmt::Debug> fmt::Debug for RwLockReadGuard<'a, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl<'a, T: ?Sized + fmt::Debug> fmt::Debug for RwLockWriteGuard<'a, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl<'a, T: ?Sized> Drop for RwLockReadGuard<'a, T> {
+    fn drop(&mut self) {
+        self.lock.read_unlock();
+    }
+}
+
+impl<'a, T: ?Sized> Drop for RwLockWriteGuard<'a, T> {
+    fn drop(&mut self) {
+        self.lock.write_unlock();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::mutex::tests::{INIT, SERIAL};
+    use crate::{RwLock, Semaphore};
+    use axtask as thread;
+
+    #[test]
+    fn readers_and_writers() {
+        let _lock = SERIAL.lock();
+        INIT.call_once(thread::init_scheduler);
+
+        const NUM_TASKS: usize = 10;
+        const NUM_ITERS: usize = 100;
+        static LOCK: RwLock<(usize, usize)> = RwLock::new((0, 0));
+        static DONE: Semaphore = Semaphore::new(0);
+
+        for i in 0..NUM_TASKS {
+            thread::spawn(move || {
+                for _ in 0..NUM_ITERS {
+                    if i % 2 == 0 {
+                        let mut val = LOCK.write();
+                        val.0 += 1;
+                        thread::yield_now();
+                        val.1 += 1;
+                    } else {
+                        let val = LOCK.read();
+                        thread::yield_now();
+                        assert_eq!(val.0, val.1);
+                    }
+                }
+                DONE.release();
+            });
+        }
+
+        for _ in 0..NUM_TASKS {
+            DONE.acquire();
+        }
+        assert!(!LOCK.is_locked());
+        assert_eq!(
+            *LOCK.read(),
+            (NUM_ITERS * NUM_TASKS / 2, NUM_ITERS * NUM_TASKS / 2)
+        );
+    }
+}
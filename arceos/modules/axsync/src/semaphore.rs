@@ -0,0 +1,131 @@
+//! A counting semaphore.
+
+use core::fmt;
+use core::sync::atomic::{AtomicIsize, Ordering};
+
+use axtask::{Cancelled, WaitQueue};
+
+/// A counting, blocking semaphore.
+///
+/// Semaphores are a form of atomic counter where access is only granted if
+/// the counter is a positive value. Each acquisition will block the current
+/// task until the counter is positive, and then decrement it. Each release
+/// will increment the counter and wake up a waiting task if any.
+pub struct Semaphore {
+    wq: WaitQueue,
+    count: AtomicIsize,
+}
+
+/// An RAII guard which will release a resource acquired from a semaphore when
+/// dropped.
+pub struct SemaphoreGuard<'a> {
+    sem: &'a Semaphore,
+}
+
+impl Semaphore {
+    /// Creates a new semaphore with the initial count specified.
+    ///
+    /// The count specified can be thought of as a number of resources, and a
+    /// call to [`acquire`](Self::acquire) or [`access`](Self::access) will
+    /// block until at least one resource is available.
+    pub const fn new(count: isize) -> Self {
+        Self {
+            wq: WaitQueue::new(),
+            count: AtomicIsize::new(count),
+        }
+    }
+
+    /// Returns the number of available resources.
+    pub fn available(&self) -> isize {
+        self.count.load(Ordering::Acquire)
+    }
+
+    /// Acquires a resource of this semaphore, blocking the current task until
+    /// it can do so.
+    pub fn acquire(&self) {
+        while !self.try_acquire() {
+            self.wq.wait_until(|| self.available() > 0);
+        }
+    }
+
+    /// Like [`acquire`](Self::acquire), but returns [`Cancelled`] if the
+    /// current task is cancelled while waiting, see [`axtask::cancel`].
+    pub fn acquire_cancellable(&self) -> Result<(), Cancelled> {
+        while !self.try_acquire() {
+            if let Err(e) = self.wq.wait_until_cancellable(|| self.available() > 0) {
+                // We may have been chosen by `release()`, pass the wakeup on
+                // to another waiter.
+                self.wq.notify_one(true);
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Tries to acquire a resource of this semaphore without blocking.
+    ///
+    /// Returns `true` if a resource is acquired.
+    pub fn try_acquire(&self) -> bool {
+        let mut count = self.count.load(Ordering::Relaxed);
+        while count > 0 {
+            match self.count.compare_exchange_weak(
+                count,
+                count - 1,
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return true,
+                Err(c) => count = c,
+            }
+        }
+        false
+    }
+
+    /// Acquires a resource of this semaphore, blocking the current task until
+    /// it can do so or the given duration has elapsed.
+    ///
+    /// Returns `true` if a resource is acquired.
+    #[cfg(feature = "irq")]
+    pub fn acquire_timeout(&self, dur: core::time::Duration) -> bool {
+        let deadline = axhal::time::wall_time() + dur;
+        while !self.try_acquire() {
+            let now = axhal::time::wall_time();
+            if now >= deadline {
+                return false;
+            }
+            self.wq
+                .wait_timeout_until(deadline - now, || self.available() > 0);
+        }
+        true
+    }
+
+    /// Releases a resource from this semaphore.
+    ///
+    /// This will increment the number of resources and wake up a task waiting
+    /// for it.
+    pub fn release(&self) {
+        self.count.fetch_add(1, Ordering::Release);
+        self.wq.notify_one(true);
+    }
+
+    /// Acquires a resource of this semaphore, returning an RAII guard to
+    /// release the semaphore when dropped.
+    pub fn access(&self) -> SemaphoreGuard<'_> {
+        self.acquire();
+        SemaphoreGuard { sem: self }
+    }
+}
+
+impl fmt::Debug for Semaphore {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Semaphore")
+            .field("count", &self.available())
+            .finish()
+    }
+}
+
+impl Drop for SemaphoreGuard<'_> {
+    fn drop(&mut self) {
+        self.sem.release();
+    }
+}
@@ -2,14 +2,45 @@
 
 use alloc::{string::String, sync::Arc};
 
-pub(crate) use crate::run_queue::{AxRunQueue, RUN_QUEUE};
+pub(crate) use crate::run_queue::{current_run_queue, AxRunQueue};
 
 #[doc(cfg(feature = "multitask"))]
-pub use crate::task::{CurrentTask, TaskId, TaskInner};
+pub use crate::cpumask::AxCpuMask;
+#[doc(cfg(feature = "multitask"))]
+pub use crate::cputime::{ps, total_cpu_time, TaskCpuTime, TaskListing};
+#[cfg(feature = "irq")]
+#[doc(cfg(feature = "irq"))]
+pub use crate::futex::futex_wait_timeout;
+#[doc(cfg(feature = "multitask"))]
+pub use crate::futex::{futex_requeue, futex_wait, futex_wake, FutexError};
+#[cfg(feature = "oom")]
+#[doc(cfg(feature = "oom"))]
+pub use crate::oom::{
+    default_oom_score, set_oom_score_fn, start_oom_reaper, wait_memory_pressure, OomScoreFn,
+    OOM_GRACE_PERIOD,
+};
+#[cfg(feature = "sched_edf")]
+#[doc(cfg(feature = "sched_edf"))]
+pub use crate::sched_edf::{DeadlineOverrunFn, DeadlineParams, MAX_BANDWIDTH_PERCENT};
+#[doc(cfg(feature = "multitask"))]
+pub use crate::task::{Cancelled, CurrentTask, TaskId, TaskInner, EXIT_CANCELLED};
 #[doc(cfg(feature = "multitask"))]
 pub use crate::task_ext::{TaskExtMut, TaskExtRef};
 #[doc(cfg(feature = "multitask"))]
 pub use crate::wait_queue::WaitQueue;
+#[cfg(feature = "watchdog")]
+#[doc(cfg(feature = "watchdog"))]
+pub use crate::watchdog::{
+    set_hung_task_timeout, set_softlockup_threshold, start_watchdog, DEFAULT_HUNG_TASK_TIMEOUT,
+    DEFAULT_SOFTLOCKUP_THRESHOLD,
+};
+#[cfg(feature = "irq")]
+#[doc(cfg(feature = "irq"))]
+pub use crate::workqueue::queue_delayed_work;
+#[doc(cfg(feature = "multitask"))]
+pub use crate::workqueue::{
+    cancel_work, cancel_work_sync, flush_work, queue_work, queue_work_on, Work,
+};
 
 /// The reference type of a task.
 pub type AxTaskRef = Arc<AxTask>;
@@ -18,14 +49,22 @@ cfg_if::cfg_if! {
     if #[cfg(feature = "sched_rr")] {
         const MAX_TIME_SLICE: usize = 5;
         pub(crate) type AxTask = scheduler::RRTask<TaskInner, MAX_TIME_SLICE>;
-        pub(crate) type Scheduler = scheduler::RRScheduler<TaskInner, MAX_TIME_SLICE>;
+        pub(crate) type NormalScheduler = scheduler::RRScheduler<TaskInner, MAX_TIME_SLICE>;
     } else if #[cfg(feature = "sched_cfs")] {
         pub(crate) type AxTask = scheduler::CFSTask<TaskInner>;
-        pub(crate) type Scheduler = scheduler::CFScheduler<TaskInner>;
+        pub(crate) type NormalScheduler = scheduler::CFScheduler<TaskInner>;
     } else {
         // If no scheduler features are set, use FIFO as the default.
         pub(crate) type AxTask = scheduler::FifoTask<TaskInner>;
-        pub(crate) type Scheduler = scheduler::FifoScheduler<TaskInner>;
+        pub(crate) type NormalScheduler = scheduler::FifoScheduler<TaskInner>;
+    }
+}
+
+cfg_if::cfg_if! {
+    if #[cfg(feature = "sched_edf")] {
+        pub(crate) type Scheduler = crate::sched_edf::EdfScheduler;
+    } else {
+        pub(crate) type Scheduler = NormalScheduler;
     }
 }
 
@@ -63,6 +102,17 @@ pub fn current() -> CurrentTask {
     CurrentTask::get()
 }
 
+/// Returns all tasks that have not been dropped (including the exited ones
+/// that are still referenced), ordered by the task ID.
+pub fn all_tasks() -> alloc::vec::Vec<AxTaskRef> {
+    crate::task::all_tasks()
+}
+
+/// Finds a task that has not been dropped by its ID.
+pub fn find_task(id: u64) -> Option<AxTaskRef> {
+    crate::task::find_task(id)
+}
+
 /// Initializes the task scheduler (for the primary CPU).
 pub fn init_scheduler() {
     info!("Initialize scheduling...");
@@ -72,11 +122,18 @@ pub fn init_scheduler() {
     crate::timers::init();
 
     info!("  use {} scheduler.", Scheduler::scheduler_name());
+    #[cfg(feature = "sched_edf")]
+    info!("  EDF scheduling class enabled.");
+
+    crate::workqueue::init_worker();
 }
 
 /// Initializes the task scheduler for secondary CPUs.
 pub fn init_scheduler_secondary() {
     crate::run_queue::init_secondary();
+    #[cfg(feature = "irq")]
+    crate::timers::init();
+    crate::workqueue::init_worker();
 }
 
 /// Handles periodic timer ticks for the task manager.
@@ -85,14 +142,28 @@ pub fn init_scheduler_secondary() {
 #[cfg(feature = "irq")]
 #[doc(cfg(feature = "irq"))]
 pub fn on_timer_tick() {
+    #[cfg(feature = "watchdog")]
+    crate::watchdog::check_softlockup();
     crate::timers::check_events();
-    RUN_QUEUE.lock().scheduler_timer_tick();
+    #[cfg(feature = "oom")]
+    crate::oom::check_events();
+    current_run_queue().scheduler_timer_tick();
+}
+
+/// Handles the inter-processor interrupts for the task manager.
+///
+/// They are sent when tasks are woken up or spawned on this CPU by other
+/// CPUs, to let it reschedule at once.
+#[cfg(feature = "irq")]
+#[doc(cfg(feature = "irq"))]
+pub fn on_ipi() {
+    current_run_queue().resched_ipi();
 }
 
 /// Adds the given task to the run queue, returns the task reference.
 pub fn spawn_task(task: TaskInner) -> AxTaskRef {
     let task_ref = task.into_arc();
-    RUN_QUEUE.lock().add_task(task_ref.clone());
+    crate::run_queue::add_new_task(task_ref.clone());
     task_ref
 }
 
@@ -129,35 +200,138 @@ where
 ///
 /// [CFS]: https://en.wikipedia.org/wiki/Completely_Fair_Scheduler
 pub fn set_priority(prio: isize) -> bool {
-    RUN_QUEUE.lock().set_current_priority(prio)
+    current_run_queue().set_current_priority(prio)
+}
+
+/// Set the priority in effect for the given task, without changing its base
+/// priority.
+///
+/// It is used by priority-inheritance locks to temporarily raise the priority
+/// of the lock holder, and to drop it back (to [`TaskInner::base_priority`])
+/// on unlock.
+///
+/// It is changed in the run queue of the CPU that the task is on, which may
+/// not be the current one.
+///
+/// Returns `true` if the priority is set successfully.
+pub fn set_effective_priority(task: &AxTaskRef, prio: isize) -> bool {
+    crate::run_queue::task_run_queue(task).set_task_effective_priority(task, prio)
+}
+
+/// Turns the current task into a periodic deadline task, which is scheduled
+/// by the EDF class before all other tasks.
+///
+/// `on_overrun` is called when the task uses up its runtime or misses its
+/// deadline in a period, see [`DeadlineOverrunFn`].
+///
+/// The task is pinned to the current CPU until [`clear_deadline`] is called.
+///
+/// Returns `false` if the parameters are invalid, or the total bandwidth of
+/// the deadline tasks on the current CPU would exceed
+/// [`MAX_BANDWIDTH_PERCENT`].
+#[cfg(feature = "sched_edf")]
+pub fn set_deadline(params: DeadlineParams, on_overrun: Option<DeadlineOverrunFn>) -> bool {
+    current_run_queue().set_current_deadline(Some(params), on_overrun)
+}
+
+/// Turns the current deadline task back into a normal task, and releases its
+/// reserved bandwidth.
+#[cfg(feature = "sched_edf")]
+pub fn clear_deadline() {
+    current_run_queue().set_current_deadline(None, None);
+}
+
+/// The current deadline task finishes its job in this period, and sleeps
+/// until the next period begins.
+///
+/// For normal tasks, it is the same as [`yield_now`].
+#[cfg(feature = "sched_edf")]
+pub fn wait_next_period() {
+    let mut rq = current_run_queue();
+    crate::sched_edf::yield_period(&current());
+    rq.yield_current();
+}
+
+/// Set the CPU affinity mask for current task.
+///
+/// If the current CPU is not in the mask, the current task is migrated to one
+/// of the allowed CPUs immediately.
+///
+/// Returns `false` if the mask contains no online CPU, or the current task is
+/// a deadline task, which is pinned to its CPU.
+pub fn set_affinity(cpumask: AxCpuMask) -> bool {
+    if !crate::run_queue::has_online_cpu(&cpumask) {
+        return false;
+    }
+    #[cfg(feature = "sched_edf")]
+    if crate::sched_edf::is_deadline_task(&current()) {
+        return false;
+    }
+    current().set_cpumask(cpumask);
+    current_run_queue().migrate_current();
+    true
+}
+
+/// Cancels the given task.
+///
+/// The task is marked as cancelled, and woken up from any [`WaitQueue`] or
+/// timer it is blocked on. A cancellable wait (e.g.,
+/// [`WaitQueue::wait_cancellable`] or [`sleep`]) returns [`Cancelled`], and
+/// later cancellable waits of the task also return [`Cancelled`] immediately.
+/// The blocking primitives in `axsync` have cancellable variants built on
+/// them, such as `Mutex::lock_cancellable` and `Condvar::wait_cancellable`.
+///
+/// Other waits return early once, like a spurious wakeup: [`WaitQueue::wait`]
+/// and [`WaitQueue::wait_timeout`] return, while the waits for a condition
+/// (e.g., [`WaitQueue::wait_until`]) check it again and keep blocking. After
+/// such a wait, the task should check [`TaskInner::is_cancelled`] by itself.
+///
+/// Once the task exits, [`TaskInner::join`] returns [`EXIT_CANCELLED`].
+///
+/// Returns `false` if the task is the idle task, or has exited.
+pub fn cancel(task: &AxTaskRef) -> bool {
+    current_run_queue().cancel_task(task)
+}
+
+/// Cancels the task with the given ID, see [`cancel`].
+///
+/// Returns `false` if the task is not found, is the idle task, or has exited.
+pub fn kill(id: u64) -> bool {
+    find_task(id).is_some_and(|task| cancel(&task))
 }
 
 /// Current task gives up the CPU time voluntarily, and switches to another
 /// ready task.
 pub fn yield_now() {
-    RUN_QUEUE.lock().yield_current();
+    current_run_queue().yield_current();
 }
 
 /// Current task is going to sleep for the given duration.
 ///
-/// If the feature `irq` is not enabled, it uses busy-wait instead.
+/// It returns early if the current task is cancelled, see [`cancel`].
+///
+/// If the feature `irq` is not enabled, it uses busy-wait instead, which
+/// cannot be cancelled.
 pub fn sleep(dur: core::time::Duration) {
     sleep_until(axhal::time::wall_time() + dur);
 }
 
 /// Current task is going to sleep, it will be woken up at the given deadline.
 ///
-/// If the feature `irq` is not enabled, it uses busy-wait instead.
+/// It returns early if the current task is cancelled, see [`cancel`].
+///
+/// If the feature `irq` is not enabled, it uses busy-wait instead, which
+/// cannot be cancelled.
 pub fn sleep_until(deadline: axhal::time::TimeValue) {
     #[cfg(feature = "irq")]
-    RUN_QUEUE.lock().sleep_until(deadline);
+    current_run_queue().sleep_until(deadline);
     #[cfg(not(feature = "irq"))]
     axhal::time::busy_wait_until(deadline);
 }
 
 /// Exits the current task.
 pub fn exit(exit_code: i32) -> ! {
-    RUN_QUEUE.lock().exit_current(exit_code)
+    current_run_queue().exit_current(exit_code)
 }
 
 /// The idle task routine.
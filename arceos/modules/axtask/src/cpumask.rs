@@ -0,0 +1,105 @@
+use core::fmt;
+
+const SMP: usize = axconfig::SMP;
+const BITS_PER_WORD: usize = usize::BITS as usize;
+const NR_WORDS: usize = SMP.div_ceil(BITS_PER_WORD);
+
+/// A set of CPUs that a task is allowed to run on.
+///
+/// Bit `i` of the mask corresponds to the CPU with ID `i`. Only the first
+/// [`axconfig::SMP`] bits are meaningful.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct AxCpuMask {
+    bits: [usize; NR_WORDS],
+}
+
+impl AxCpuMask {
+    /// Creates an empty mask.
+    pub const fn new() -> Self {
+        Self {
+            bits: [0; NR_WORDS],
+        }
+    }
+
+    /// Creates a mask with all CPUs set.
+    pub const fn full() -> Self {
+        let mut mask = Self::new();
+        let mut i = 0;
+        while i < SMP {
+            mask.bits[i / BITS_PER_WORD] |= 1 << (i % BITS_PER_WORD);
+            i += 1;
+        }
+        mask
+    }
+
+    /// Creates a mask with only the given CPU set.
+    pub const fn one_shot(cpu_id: usize) -> Self {
+        let mut mask = Self::new();
+        if cpu_id < SMP {
+            mask.bits[cpu_id / BITS_PER_WORD] = 1 << (cpu_id % BITS_PER_WORD);
+        }
+        mask
+    }
+
+    /// Creates a mask from raw bit words, in the same layout as `cpu_set_t`.
+    ///
+    /// Bits beyond [`axconfig::SMP`] are ignored.
+    pub fn from_raw_words(words: &[usize]) -> Self {
+        let mut mask = Self::new();
+        for (dst, src) in mask.bits.iter_mut().zip(words) {
+            *dst = *src;
+        }
+        mask.bits[NR_WORDS - 1] &= Self::full().bits[NR_WORDS - 1];
+        mask
+    }
+
+    /// Returns the raw bit words of the mask.
+    pub const fn as_raw_words(&self) -> &[usize] {
+        &self.bits
+    }
+
+    /// Whether the given CPU is in the mask.
+    pub const fn get(&self, cpu_id: usize) -> bool {
+        cpu_id < SMP && self.bits[cpu_id / BITS_PER_WORD] & (1 << (cpu_id % BITS_PER_WORD)) != 0
+    }
+
+    /// Adds or removes the given CPU to/from the mask.
+    pub fn set(&mut self, cpu_id: usize, value: bool) {
+        if cpu_id >= SMP {
+            return;
+        }
+        let bit = 1 << (cpu_id % BITS_PER_WORD);
+        if value {
+            self.bits[cpu_id / BITS_PER_WORD] |= bit;
+        } else {
+            self.bits[cpu_id / BITS_PER_WORD] &= !bit;
+        }
+    }
+
+    /// Whether no CPU is in the mask.
+    pub fn is_empty(&self) -> bool {
+        self.bits.iter().all(|&w| w == 0)
+    }
+
+    /// Whether all CPUs are in the mask.
+    pub fn is_full(&self) -> bool {
+        *self == Self::full()
+    }
+
+    /// Returns an iterator over the IDs of the CPUs in the mask.
+    pub fn iter(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..SMP).filter(|&i| self.get(i))
+    }
+}
+
+impl Default for AxCpuMask {
+    fn default() -> Self {
+        Self::full()
+    }
+}
+
+impl fmt::Debug for AxCpuMask {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_set().entries(self.iter()).finish()
+    }
+}
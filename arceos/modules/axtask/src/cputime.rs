@@ -0,0 +1,224 @@
+//! Per-task CPU time accounting.
+//!
+//! The time is charged to the task switched out on every context switch, and
+//! on every transition between user and kernel mode (reported by `axhal`
+//! through the [`USER_KERNEL_SWITCH`] trap handler).
+//!
+//! [`USER_KERNEL_SWITCH`]: axhal::trap::USER_KERNEL_SWITCH
+
+use core::fmt;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use core::time::Duration;
+
+use axhal::time::monotonic_time_nanos;
+use axhal::trap::{register_trap_handler, USER_KERNEL_SWITCH};
+use kspin::SpinNoIrq;
+
+use crate::task::TaskState;
+use crate::{AxTaskRef, TaskInner};
+
+/// The CPU time statistics of a task.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct TaskCpuTime {
+    /// The time spent in user mode.
+    pub utime: Duration,
+    /// The time spent in kernel mode.
+    pub stime: Duration,
+    /// The number of voluntary context switches, i.e., the task blocked or
+    /// exited.
+    pub nvcsw: u64,
+    /// The number of involuntary context switches, i.e., the task was
+    /// preempted or yielded.
+    pub nivcsw: u64,
+}
+
+impl TaskCpuTime {
+    /// The total CPU time, i.e., `utime + stime`.
+    pub fn total(&self) -> Duration {
+        self.utime + self.stime
+    }
+}
+
+impl core::ops::AddAssign for TaskCpuTime {
+    fn add_assign(&mut self, rhs: Self) {
+        self.utime += rhs.utime;
+        self.stime += rhs.stime;
+        self.nvcsw += rhs.nvcsw;
+        self.nivcsw += rhs.nivcsw;
+    }
+}
+
+/// The accounting state embedded in each task.
+///
+/// It is only updated by the CPU that the task is running on, with IRQs
+/// disabled, but may be read by any CPU.
+pub(crate) struct CpuAccounting {
+    utime_ns: AtomicU64,
+    stime_ns: AtomicU64,
+    nvcsw: AtomicU64,
+    nivcsw: AtomicU64,
+    /// When the current accounting period begins, in nanoseconds.
+    stamp: AtomicU64,
+    /// Whether the current accounting period is in user mode.
+    in_user: AtomicBool,
+}
+
+impl CpuAccounting {
+    pub const fn new() -> Self {
+        Self {
+            utime_ns: AtomicU64::new(0),
+            stime_ns: AtomicU64::new(0),
+            nvcsw: AtomicU64::new(0),
+            nivcsw: AtomicU64::new(0),
+            stamp: AtomicU64::new(0),
+            in_user: AtomicBool::new(false),
+        }
+    }
+
+    /// Charges the time since the last stamp, and starts a new period in
+    /// user mode or kernel mode.
+    fn charge(&self, now: u64, to_user: bool) {
+        let delta = now.saturating_sub(self.stamp.swap(now, Ordering::Relaxed));
+        if self.in_user.swap(to_user, Ordering::Relaxed) {
+            self.utime_ns.fetch_add(delta, Ordering::Relaxed);
+        } else {
+            self.stime_ns.fetch_add(delta, Ordering::Relaxed);
+        }
+    }
+
+    /// Returns the statistics, `running` tells whether the time since the
+    /// last stamp should be included.
+    fn snapshot(&self, running: bool) -> TaskCpuTime {
+        let mut utime = self.utime_ns.load(Ordering::Relaxed);
+        let mut stime = self.stime_ns.load(Ordering::Relaxed);
+        if running {
+            let stamp = self.stamp.load(Ordering::Relaxed);
+            let delta = monotonic_time_nanos().saturating_sub(stamp);
+            if self.in_user.load(Ordering::Relaxed) {
+                utime += delta;
+            } else {
+                stime += delta;
+            }
+        }
+        TaskCpuTime {
+            utime: Duration::from_nanos(utime),
+            stime: Duration::from_nanos(stime),
+            nvcsw: self.nvcsw.load(Ordering::Relaxed),
+            nivcsw: self.nivcsw.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// The accumulated statistics of all dropped tasks.
+static EXITED: SpinNoIrq<TaskCpuTime> = SpinNoIrq::new(TaskCpuTime {
+    utime: Duration::ZERO,
+    stime: Duration::ZERO,
+    nvcsw: 0,
+    nivcsw: 0,
+});
+
+impl TaskInner {
+    /// Gets the CPU time statistics of the task.
+    ///
+    /// If the task is running, the time of the current period is included.
+    pub fn cpu_time(&self) -> TaskCpuTime {
+        self.cpu_accounting().snapshot(self.is_running())
+    }
+}
+
+/// Called on the context switch from `prev` to `next`, `involuntary` tells
+/// whether `prev` is still runnable.
+pub(crate) fn on_switch(prev: &TaskInner, next: &TaskInner, involuntary: bool) {
+    let now = monotonic_time_nanos();
+    let prev_acct = prev.cpu_accounting();
+    prev_acct.charge(now, false);
+    if involuntary {
+        prev_acct.nivcsw.fetch_add(1, Ordering::Relaxed);
+    } else {
+        prev_acct.nvcsw.fetch_add(1, Ordering::Relaxed);
+    }
+    // `next` always resumes in kernel mode.
+    let next_acct = next.cpu_accounting();
+    next_acct.stamp.store(now, Ordering::Relaxed);
+    next_acct.in_user.store(false, Ordering::Relaxed);
+}
+
+/// Starts the accounting of a task that becomes the current task without a
+/// context switch (i.e., the init tasks).
+pub(crate) fn on_init(task: &TaskInner) {
+    task.cpu_accounting()
+        .stamp
+        .store(monotonic_time_nanos(), Ordering::Relaxed);
+}
+
+/// Saves the statistics of a task being dropped, so that they are still
+/// counted in [`total_cpu_time`].
+pub(crate) fn on_drop(task: &TaskInner) {
+    *EXITED.lock() += task.cpu_accounting().snapshot(false);
+}
+
+/// Returns the sum of the CPU time statistics of all tasks, including the
+/// ones that have exited.
+pub fn total_cpu_time() -> TaskCpuTime {
+    let mut total = *EXITED.lock();
+    for task in crate::all_tasks() {
+        total += task.cpu_time();
+    }
+    total
+}
+
+#[register_trap_handler(USER_KERNEL_SWITCH)]
+fn user_kernel_switch(to_kernel: bool) {
+    if let Some(curr) = crate::current_may_uninit() {
+        curr.cpu_accounting()
+            .charge(monotonic_time_nanos(), !to_kernel);
+    }
+}
+
+/// A `ps`-style listing of tasks, returned by [`ps`].
+pub struct TaskListing(alloc::vec::Vec<AxTaskRef>);
+
+/// Returns a `ps`-style listing of all alive tasks, which can be printed by
+/// the [`Display`](fmt::Display) implementation.
+///
+/// # Examples
+///
+/// ```ignore
+/// println!("{}", axtask::ps());
+/// ```
+pub fn ps() -> TaskListing {
+    TaskListing(crate::all_tasks())
+}
+
+impl fmt::Display for TaskListing {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "{:>5} {:>3} {:<7} {:>4} {:>10} {:>10} {:>7} {:>7} NAME",
+            "TID", "CPU", "STATE", "PRIO", "UTIME(ms)", "STIME(ms)", "NVCSW", "NIVCSW"
+        )?;
+        for task in self.0.iter() {
+            let time = task.cpu_time();
+            let state = match task.state() {
+                TaskState::Running => "running",
+                TaskState::Ready => "ready",
+                TaskState::Blocked | TaskState::BlockedCancellable => "blocked",
+                TaskState::Exited => "exited",
+            };
+            writeln!(
+                f,
+                "{:>5} {:>3} {:<7} {:>4} {:>10} {:>10} {:>7} {:>7} {}",
+                task.id().as_u64(),
+                task.cpu_id(),
+                state,
+                task.priority(),
+                time.utime.as_millis(),
+                time.stime.as_millis(),
+                time.nvcsw,
+                time.nivcsw,
+                task.name(),
+            )?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,212 @@
+//! Fast user-space mutex (futex) primitives.
+//!
+//! A futex is a 32-bit word that tasks can wait on, as long as it holds an
+//! expected value. Waiters are kept in a global table keyed by the address of
+//! the word, so that a lock only needs the word itself, without its own
+//! [`WaitQueue`](crate::WaitQueue).
+//!
+//! The address is the virtual address seen by the caller, so futexes in
+//! different address spaces that happen to have the same address share the
+//! same waiters. It is harmless (at most a spurious wakeup) since the waiters
+//! must check the word again.
+
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use axhal::time::TimeValue;
+use kspin::SpinNoIrq;
+
+use crate::{current_run_queue, AxTaskRef};
+
+/// The waiters of all futexes, keyed by the address of the futex word.
+static FUTEX_TABLE: SpinNoIrq<BTreeMap<usize, VecDeque<AxTaskRef>>> =
+    SpinNoIrq::new(BTreeMap::new());
+
+/// The error type of futex operations.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FutexError {
+    /// The value of the futex word is not the expected one.
+    WouldBlock,
+    /// The wait timed out.
+    TimedOut,
+    /// The waiting task is cancelled, see [`cancel`](crate::cancel).
+    Interrupted,
+}
+
+#[inline]
+fn futex_key(futex: &AtomicU32) -> usize {
+    futex as *const _ as usize
+}
+
+#[cfg_attr(not(feature = "irq"), allow(unused_variables))]
+fn futex_wait_inner(
+    futex: &AtomicU32,
+    expected: u32,
+    deadline: Option<TimeValue>,
+) -> Result<(), FutexError> {
+    let key = futex_key(futex);
+    let curr = crate::current();
+    #[cfg(feature = "watchdog")]
+    crate::watchdog::clear_wait_site(&curr);
+
+    let mut rq = current_run_queue();
+    // Hold the table lock while checking the value, so that a waker that
+    // changes the value cannot slip in before we are enqueued.
+    let mut table = FUTEX_TABLE.lock();
+    if futex.load(Ordering::SeqCst) != expected {
+        return Err(FutexError::WouldBlock);
+    }
+    let res = rq.block_current_cancellable(move |task| {
+        task.set_in_wait_queue(true);
+        table.entry(key).or_default().push_back(task.clone());
+        #[cfg(feature = "irq")]
+        if let Some(deadline) = deadline {
+            // Set the alarm after the task is blocked, see `WaitQueue::wait_timeout`.
+            crate::timers::set_alarm_wakeup(deadline, task);
+        }
+    });
+    drop(rq);
+
+    // Woken up by `futex_wake()` if it has been removed from the table, which
+    // is always done with the table locked.
+    let woken = {
+        let mut table = FUTEX_TABLE.lock();
+        if curr.in_wait_queue() {
+            remove_waiter(&mut table, curr.as_task_ref());
+            curr.set_in_wait_queue(false);
+            false
+        } else {
+            true
+        }
+    };
+    #[cfg(feature = "irq")]
+    if curr.in_timer_list() {
+        crate::timers::cancel_alarm(curr.as_task_ref());
+    }
+
+    match res {
+        // Cancelled before blocked, or both cancelled and woken up.
+        Err(_) => Err(FutexError::Interrupted),
+        Ok(()) if woken => Ok(()),
+        Ok(()) => Err(FutexError::TimedOut),
+    }
+}
+
+/// Removes a task from the table, whichever futex it waits on (it may have
+/// been requeued).
+fn remove_waiter(table: &mut BTreeMap<usize, VecDeque<AxTaskRef>>, task: &AxTaskRef) {
+    let mut empty_key = None;
+    for (key, waiters) in table.iter_mut() {
+        if let Some(idx) = waiters.iter().position(|t| Arc::ptr_eq(t, task)) {
+            waiters.remove(idx);
+            if waiters.is_empty() {
+                empty_key = Some(*key);
+            }
+            break;
+        }
+    }
+    if let Some(key) = empty_key {
+        table.remove(&key);
+    }
+}
+
+/// Blocks the current task on the futex, if its value is `expected`, until
+/// [`futex_wake`] is called on it.
+///
+/// Returns [`FutexError::WouldBlock`] if the value is not `expected`, or
+/// [`FutexError::Interrupted`] if the current task is cancelled before or
+/// during the wait.
+pub fn futex_wait(futex: &AtomicU32, expected: u32) -> Result<(), FutexError> {
+    futex_wait_inner(futex, expected, None)
+}
+
+/// Like [`futex_wait`], but also returns [`FutexError::TimedOut`] when the
+/// given duration has elapsed.
+#[cfg(feature = "irq")]
+pub fn futex_wait_timeout(
+    futex: &AtomicU32,
+    expected: u32,
+    dur: core::time::Duration,
+) -> Result<(), FutexError> {
+    futex_wait_inner(futex, expected, Some(axhal::time::wall_time() + dur))
+}
+
+/// Wakes up at most `count` tasks waiting on the futex, in FIFO order.
+///
+/// Returns the number of tasks woken up.
+pub fn futex_wake(futex: &AtomicU32, count: usize) -> usize {
+    let key = futex_key(futex);
+    let mut rq = current_run_queue();
+    let woken = {
+        let mut table = FUTEX_TABLE.lock();
+        let Some(waiters) = table.get_mut(&key) else {
+            return 0;
+        };
+        let n = count.min(waiters.len());
+        let woken: Vec<_> = waiters.drain(..n).collect();
+        if waiters.is_empty() {
+            table.remove(&key);
+        }
+        for task in woken.iter() {
+            task.set_in_wait_queue(false);
+        }
+        woken
+    };
+    let n = woken.len();
+    for task in woken {
+        rq.unblock_task(task, false);
+    }
+    n
+}
+
+/// Wakes up at most `nr_wake` tasks waiting on the futex `from`, and moves at
+/// most `nr_requeue` of the remaining waiters to the futex `to`.
+///
+/// If `expected` is given, the value of `from` is checked first like in
+/// [`futex_wait`], and [`FutexError::WouldBlock`] is returned on mismatch.
+///
+/// Returns the number of tasks woken up plus the number of tasks requeued.
+pub fn futex_requeue(
+    from: &AtomicU32,
+    to: &AtomicU32,
+    nr_wake: usize,
+    nr_requeue: usize,
+    expected: Option<u32>,
+) -> Result<usize, FutexError> {
+    let (from_key, to_key) = (futex_key(from), futex_key(to));
+    let mut rq = current_run_queue();
+    let (woken, nr_requeued) = {
+        let mut table = FUTEX_TABLE.lock();
+        if expected.is_some_and(|v| from.load(Ordering::SeqCst) != v) {
+            return Err(FutexError::WouldBlock);
+        }
+        let Some(mut waiters) = table.remove(&from_key) else {
+            return Ok(0);
+        };
+        let n = nr_wake.min(waiters.len());
+        let woken: Vec<_> = waiters.drain(..n).collect();
+        for task in woken.iter() {
+            task.set_in_wait_queue(false);
+        }
+        let n = nr_requeue.min(waiters.len());
+        if from_key == to_key {
+            table.insert(from_key, waiters);
+        } else {
+            if n > 0 {
+                let requeued = waiters.drain(..n);
+                table.entry(to_key).or_default().extend(requeued);
+            }
+            if !waiters.is_empty() {
+                table.insert(from_key, waiters);
+            }
+        }
+        (woken, n)
+    };
+    let n = woken.len() + nr_requeued;
+    for task in woken {
+        rq.unblock_task(task, false);
+    }
+    Ok(n)
+}
@@ -4,6 +4,24 @@
 //! creation, scheduling, sleeping, termination, etc. The scheduler algorithm
 //! is configurable by cargo features.
 //!
+//! Each CPU has its own run queue. Newly spawned tasks are distributed to all
+//! CPUs, woken tasks go back to the CPU they ran on last time, and an idle CPU
+//! steals ready tasks from the others. A task can be restricted to a subset
+//! of CPUs with an [`AxCpuMask`].
+//!
+//! The CPU time spent by each task in user and kernel mode is accounted on
+//! context switches and mode transitions, see [`TaskInner::cpu_time`] and
+//! [`ps`].
+//!
+//! A task can be stopped by others with [`cancel`] or [`kill`], which wakes it
+//! up from cancellable waits so that it can clean up and exit.
+//!
+//! User-style locks can be built on futexes, see [`futex_wait`] and
+//! [`futex_wake`].
+//!
+//! Interrupt handlers can defer work to the per-CPU worker tasks with
+//! [`queue_work`] or [`queue_delayed_work`], see [`Work`].
+//!
 //! # Cargo Features
 //!
 //! - `multitask`: Enable multi-task support. If it's enabled, complex task
@@ -20,6 +38,16 @@
 //!   the `multitask` and `preempt` features if it is enabled.
 //! - `sched_cfs`: Use the [Completely Fair Scheduler][3]. It also enables the
 //!   the `multitask` and `preempt` features if it is enabled.
+//! - `sched_edf`: Add an earliest-deadline-first class for periodic real-time
+//!   tasks on top of the above scheduler, see [`set_deadline`]. It also
+//!   enables the `multitask` and `preempt` features if it is enabled.
+//! - `watchdog`: Report tasks blocked on a [`WaitQueue`] for too long, and CPUs
+//!   that do not reschedule for too many timer ticks, see [`start_watchdog`].
+//!   It also enables the `multitask` and `irq` features if it is enabled.
+//! - `oom`: Wake up the tasks waiting for the memory pressure to change, and
+//!   kill a task when the allocator runs out of memory, see
+//!   [`wait_memory_pressure`] and [`start_oom_reaper`]. It also enables the
+//!   `multitask` and `irq` features if it is enabled.
 //!
 //! [1]: scheduler::FifoScheduler
 //! [2]: scheduler::RRScheduler
@@ -42,14 +70,24 @@ cfg_if::cfg_if! {
         extern crate log;
         extern crate alloc;
 
+        mod cpumask;
+        mod cputime;
+        mod futex;
         mod run_queue;
         mod task;
         mod task_ext;
         mod api;
         mod wait_queue;
+        mod workqueue;
 
         #[cfg(feature = "irq")]
         mod timers;
+        #[cfg(feature = "oom")]
+        mod oom;
+        #[cfg(feature = "sched_edf")]
+        mod sched_edf;
+        #[cfg(feature = "watchdog")]
+        mod watchdog;
 
         #[doc(cfg(feature = "multitask"))]
         pub use self::api::*;
@@ -0,0 +1,138 @@
+//! Memory pressure notifications and the OOM reaper.
+//!
+//! The allocator only records the events, as it cannot wake up or kill tasks
+//! in the middle of an allocation. They are checked on every timer tick:
+//!
+//! - The tasks blocked in [`wait_memory_pressure`] are woken up when the
+//!   memory pressure of [`axalloc`] changes.
+//! - A kill requested by [`OomPolicy::KillLargest`] is passed to the
+//!   `oom_reaper` task started by [`start_oom_reaper`], which cancels the task
+//!   with the highest score, see [`set_oom_score_fn`] and
+//!   [`default_oom_score`].
+//!
+//! A cancelled task only exits at its next cancellable point, so the victim
+//! is given [`OOM_GRACE_PERIOD`] to exit and free its memory. The requests in
+//! the meantime are ignored, and the task with the next highest score is
+//! killed by the first request after that.
+//!
+//! [`OomPolicy::KillLargest`]: axalloc::OomPolicy::KillLargest
+
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use core::time::Duration;
+
+use axalloc::oom::{memory_pressure, pressure_events, take_kill_request};
+use axalloc::MemoryPressure;
+use axhal::time::{monotonic_time, TimeValue};
+use kspin::SpinNoIrq;
+
+use crate::{AxTaskRef, TaskInner, WaitQueue};
+
+/// The function to compute the OOM score of a task, see [`set_oom_score_fn`].
+pub type OomScoreFn = fn(&TaskInner) -> usize;
+
+/// The time given to a killed task to exit, before another task is killed.
+pub const OOM_GRACE_PERIOD: Duration = Duration::from_secs(1);
+
+static PRESSURE_WQ: WaitQueue = WaitQueue::new();
+/// The pressure events seen by the last timer tick.
+static SEEN_EVENTS: AtomicUsize = AtomicUsize::new(0);
+
+static REAPER_WQ: WaitQueue = WaitQueue::new();
+static KILL_PENDING: AtomicBool = AtomicBool::new(false);
+static SCORE_FN: SpinNoIrq<Option<OomScoreFn>> = SpinNoIrq::new(None);
+
+/// Blocks the current task until the memory pressure changes, and returns
+/// the new one.
+pub fn wait_memory_pressure() -> MemoryPressure {
+    let events = pressure_events();
+    PRESSURE_WQ.wait_until(|| pressure_events() != events);
+    memory_pressure()
+}
+
+/// Sets the function to compute the OOM score of a task, e.g., the size of
+/// its address space. The task with the highest non-zero score is killed by
+/// the OOM reaper.
+///
+/// The memory used by a task is not tracked by `axtask`, so the default,
+/// [`default_oom_score`], only counts the kernel stack.
+pub fn set_oom_score_fn(score_fn: OomScoreFn) {
+    *SCORE_FN.lock() = Some(score_fn);
+}
+
+/// The default OOM score of a task: the size of its kernel stack, the only
+/// memory that `axtask` knows the task holds.
+pub fn default_oom_score(task: &TaskInner) -> usize {
+    task.kernel_stack_size()
+}
+
+/// Called on every timer tick.
+pub(crate) fn check_events() {
+    let events = pressure_events();
+    if SEEN_EVENTS.swap(events, Ordering::Relaxed) != events {
+        PRESSURE_WQ.notify_all(false);
+    }
+    if take_kill_request() {
+        KILL_PENDING.store(true, Ordering::Release);
+        REAPER_WQ.notify_one(false);
+    }
+}
+
+/// Finds the task with the highest non-zero score to be killed, except the
+/// tasks already cancelled.
+fn select_victim() -> Option<(AxTaskRef, usize)> {
+    let score_fn = (*SCORE_FN.lock()).unwrap_or(default_oom_score);
+    let curr = crate::current();
+    let mut victim: Option<(AxTaskRef, usize)> = None;
+    crate::task::for_each_task(|task| {
+        if task.is_init()
+            || task.is_idle()
+            || task.is_exited()
+            || task.is_cancelled()
+            || curr.ptr_eq(task)
+        {
+            return;
+        }
+        let score = score_fn(task);
+        if score > victim.as_ref().map_or(0, |v| v.1) {
+            victim = Some((task.clone(), score));
+        }
+    });
+    victim
+}
+
+/// Kills a task, unless the last victim is still in its grace period.
+fn reap(last_victim: &mut Option<(AxTaskRef, TimeValue)>) {
+    if let Some((task, killed_at)) = last_victim {
+        if !task.is_exited() {
+            if monotonic_time() < *killed_at + OOM_GRACE_PERIOD {
+                return;
+            }
+            warn!("oom_reaper: {} did not exit in time", task.id_name());
+        }
+        *last_victim = None;
+    }
+    match select_victim() {
+        Some((task, score)) => {
+            error!("oom_reaper: killing {}, score {}", task.id_name(), score);
+            crate::cancel(&task);
+            *last_victim = Some((task, monotonic_time()));
+        }
+        None => warn!("oom_reaper: no task to kill"),
+    }
+}
+
+/// Spawns the `oom_reaper` task that kills a task when requested by the
+/// allocator.
+pub fn start_oom_reaper() {
+    crate::spawn_raw(
+        || {
+            let mut last_victim = None;
+            while !crate::current().is_cancelled() {
+                REAPER_WQ.wait_until(|| KILL_PENDING.swap(false, Ordering::Acquire));
+                reap(&mut last_victim);
+            }
+        },
+        "oom_reaper".into(),
+        axconfig::TASK_STACK_SIZE,
+    );
+}
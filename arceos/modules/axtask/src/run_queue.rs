@@ -1,43 +1,165 @@
 use alloc::collections::VecDeque;
 use alloc::sync::Arc;
-use kspin::SpinNoIrq;
+use alloc::vec::Vec;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{fence, AtomicBool, AtomicUsize, Ordering};
+
+use axhal::cpu::this_cpu_id;
+use kernel_guard::NoPreemptIrqSave;
+use kspin::{SpinNoIrq, SpinRaw, SpinRawGuard};
 use lazyinit::LazyInit;
 use scheduler::BaseScheduler;
 
 use crate::task::{CurrentTask, TaskState};
-use crate::{AxTaskRef, Scheduler, TaskInner, WaitQueue};
+use crate::{AxCpuMask, AxTaskRef, Cancelled, Scheduler, TaskInner, WaitQueue, EXIT_CANCELLED};
+
+const SMP: usize = axconfig::SMP;
+
+/// The run queues of all CPUs, indexed by the CPU ID.
+static RUN_QUEUES: [LazyInit<SpinRaw<AxRunQueue>>; SMP] = [const { LazyInit::new() }; SMP];
 
-// TODO: per-CPU
-pub(crate) static RUN_QUEUE: LazyInit<SpinNoIrq<AxRunQueue>> = LazyInit::new();
+/// Tasks woken up by other CPUs, they will be moved into the run queue of the
+/// target CPU on its next scheduling point.
+static REMOTE_WAKEUPS: [SpinNoIrq<VecDeque<AxTaskRef>>; SMP] =
+    [const { SpinNoIrq::new(VecDeque::new()) }; SMP];
 
-// TODO: per-CPU
-static EXITED_TASKS: SpinNoIrq<VecDeque<AxTaskRef>> = SpinNoIrq::new(VecDeque::new());
+static EXITED_TASKS: [SpinNoIrq<VecDeque<AxTaskRef>>; SMP] =
+    [const { SpinNoIrq::new(VecDeque::new()) }; SMP];
 
-static WAIT_FOR_EXIT: WaitQueue = WaitQueue::new();
+static WAIT_FOR_EXIT: [WaitQueue; SMP] = [const { WaitQueue::new() }; SMP];
 
 #[percpu::def_percpu]
 static IDLE_TASK: LazyInit<AxTaskRef> = LazyInit::new();
 
+/// The `on_cpu` flag of the task that was switched out last on this CPU. It
+/// is cleared by the next task once the context switch is completed.
+#[percpu::def_percpu]
+static PREV_TASK_ON_CPU: usize = 0;
+
 pub(crate) struct AxRunQueue {
+    cpu_id: usize,
     scheduler: Scheduler,
 }
 
+/// A locked reference to the run queue of a CPU, usually the current one.
+///
+/// IRQs and preemption are disabled while it is alive, so the current task
+/// cannot be migrated to another CPU unless it reschedules by itself.
+pub(crate) struct AxRunQueueRef {
+    inner: SpinRawGuard<'static, AxRunQueue>,
+    _guard: NoPreemptIrqSave,
+}
+
+impl Deref for AxRunQueueRef {
+    type Target = AxRunQueue;
+    fn deref(&self) -> &Self::Target {
+        &self.inner
+    }
+}
+
+impl DerefMut for AxRunQueueRef {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.inner
+    }
+}
+
+/// Locks and returns the run queue of the current CPU.
+pub(crate) fn current_run_queue() -> AxRunQueueRef {
+    // IRQs and preemption must be disabled before we read the CPU ID.
+    let guard = NoPreemptIrqSave::new();
+    AxRunQueueRef {
+        inner: RUN_QUEUES[this_cpu_id()].lock(),
+        _guard: guard,
+    }
+}
+
+/// Locks and returns the run queue of the CPU that the given task is on, i.e.,
+/// the CPU it is running on, or is ready to run on.
+pub(crate) fn task_run_queue(task: &AxTaskRef) -> AxRunQueueRef {
+    let guard = NoPreemptIrqSave::new();
+    loop {
+        let cpu_id = task.cpu_id();
+        let rq = RUN_QUEUES[cpu_id].lock();
+        // It may be migrated to another CPU before we get the lock.
+        if cpu_id == task.cpu_id() {
+            return AxRunQueueRef {
+                inner: rq,
+                _guard: guard,
+            };
+        }
+    }
+}
+
+/// Whether any CPU in the mask has been brought up.
+pub(crate) fn has_online_cpu(cpumask: &AxCpuMask) -> bool {
+    cpumask.iter().any(|cpu_id| RUN_QUEUES[cpu_id].is_inited())
+}
+
+/// Selects the CPU to run the task on, `preferred` is used if it is allowed.
+fn select_cpu(task: &AxTaskRef, preferred: usize) -> usize {
+    let cpumask = task.cpumask();
+    if cpumask.get(preferred) && RUN_QUEUES[preferred].is_inited() {
+        return preferred;
+    }
+    cpumask
+        .iter()
+        .find(|&cpu_id| RUN_QUEUES[cpu_id].is_inited())
+        .unwrap_or(preferred)
+}
+
+/// Hands a ready task over to another CPU, which will move it into its run
+/// queue on its next scheduling point.
+///
+/// The target CPU is interrupted to reschedule at once, instead of waiting
+/// for its next timer tick.
+pub(crate) fn hand_over(cpu_id: usize, task: AxTaskRef) {
+    REMOTE_WAKEUPS[cpu_id].lock().push_back(task);
+    #[cfg(feature = "irq")]
+    axhal::irq::send_ipi(cpu_id);
+}
+
+/// Adds a newly spawned task to one of the run queues.
+///
+/// New tasks are distributed to all allowed CPUs in a round-robin manner.
+pub(crate) fn add_new_task(task: AxTaskRef) {
+    static NEXT_CPU: AtomicUsize = AtomicUsize::new(0);
+
+    let _guard = NoPreemptIrqSave::new();
+    let cpu_id = select_cpu(&task, NEXT_CPU.fetch_add(1, Ordering::Relaxed) % SMP);
+    task.set_cpu_id(cpu_id);
+    if cpu_id == this_cpu_id() {
+        RUN_QUEUES[cpu_id].lock().add_task(task);
+    } else {
+        // Hand the task over to the target CPU instead of contending for the
+        // lock of its run queue.
+        hand_over(cpu_id, task);
+    }
+}
+
 impl AxRunQueue {
-    pub fn new() -> SpinNoIrq<Self> {
-        let gc_task = TaskInner::new(gc_entry, "gc".into(), axconfig::TASK_STACK_SIZE).into_arc();
+    pub fn new(cpu_id: usize) -> SpinRaw<Self> {
+        let gc_task = TaskInner::new(
+            move || gc_entry(cpu_id),
+            "gc".into(),
+            axconfig::TASK_STACK_SIZE,
+        );
+        // It recycles the tasks exited on this CPU, do not let others steal it.
+        gc_task.set_cpumask(AxCpuMask::one_shot(cpu_id));
+        let gc_task = gc_task.into_arc();
         let mut scheduler = Scheduler::new();
         scheduler.add_task(gc_task);
-        SpinNoIrq::new(Self { scheduler })
+        SpinRaw::new(Self { cpu_id, scheduler })
     }
 
     pub fn add_task(&mut self, task: AxTaskRef) {
-        debug!("task spawn: {}", task.id_name());
+        debug!("task spawn: {} on CPU {}", task.id_name(), self.cpu_id);
         assert!(task.is_ready());
         self.scheduler.add_task(task);
     }
 
     #[cfg(feature = "irq")]
     pub fn scheduler_timer_tick(&mut self) {
+        self.pull_remote_wakeups();
         let curr = crate::current();
         if !curr.is_idle() && self.scheduler.task_tick(curr.as_task_ref()) {
             #[cfg(feature = "preempt")]
@@ -45,6 +167,160 @@ impl AxRunQueue {
         }
     }
 
+    /// Handles the IPI sent by [`hand_over`] on other CPUs.
+    #[cfg(feature = "irq")]
+    pub fn resched_ipi(&mut self) {
+        if REMOTE_WAKEUPS[self.cpu_id].lock().is_empty() {
+            return;
+        }
+        self.pull_remote_wakeups();
+        // Let the woken up tasks compete with the current one.
+        #[cfg(feature = "preempt")]
+        crate::current().set_preempt_pending(true);
+    }
+
+    pub fn set_current_priority(&mut self, prio: isize) -> bool {
+        let curr = crate::current();
+        let boosted = curr.priority() < curr.base_priority();
+        if !self.scheduler.set_priority(curr.as_task_ref(), prio) {
+            return false;
+        }
+        curr.set_base_priority(prio);
+        if boosted && curr.priority() < prio {
+            // keep the inherited priority until the lock is released.
+            self.scheduler
+                .set_priority(curr.as_task_ref(), curr.priority());
+        } else {
+            curr.set_effective_priority(prio);
+        }
+        true
+    }
+
+    #[cfg(feature = "sched_edf")]
+    pub fn set_current_deadline(
+        &mut self,
+        params: Option<crate::DeadlineParams>,
+        on_overrun: Option<crate::DeadlineOverrunFn>,
+    ) -> bool {
+        let curr = crate::current();
+        if !crate::sched_edf::set_task_deadline(&curr, self.cpu_id, params, on_overrun) {
+            return false;
+        }
+        // Let the deadline tasks run in EDF order.
+        #[cfg(feature = "preempt")]
+        curr.set_preempt_pending(true);
+        true
+    }
+
+    pub fn set_task_effective_priority(&mut self, task: &AxTaskRef, prio: isize) -> bool {
+        if self.scheduler.set_priority(task, prio) {
+            task.set_effective_priority(prio);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Marks the current task as blocked and cancellable.
+    ///
+    /// Returns `false` (and keeps it running) if the task has been cancelled.
+    fn set_current_blocked_cancellable(curr: &CurrentTask) -> bool {
+        curr.set_state(TaskState::BlockedCancellable);
+        // Pairs with the fence in `cancel_task()`: either we see the
+        // cancellation here, or the canceller sees the task blocked.
+        fence(Ordering::SeqCst);
+        if curr.is_cancelled() {
+            // If it fails, the canceller is waking us up already, go on to
+            // reschedule and it will be put back to a run queue.
+            return !curr.transition_state(TaskState::BlockedCancellable, TaskState::Running);
+        }
+        true
+    }
+
+    /// Cancels the given task, and wakes it up if it is blocked in a
+    /// cancellable wait.
+    ///
+    /// Returns `false` if the task is the idle task, or has exited.
+    pub fn cancel_task(&mut self, task: &AxTaskRef) -> bool {
+        if task.is_idle() || task.state() == TaskState::Exited {
+            return false;
+        }
+        if task.set_cancelled() {
+            debug!("task cancel: {}", task.id_name());
+            fence(Ordering::SeqCst);
+            // Wake it from any wait. A plain wait returns early once, like a
+            // spurious wakeup, the later ones block as usual.
+            if task.transition_state(TaskState::BlockedCancellable, TaskState::Ready)
+                || task.transition_state(TaskState::Blocked, TaskState::Ready)
+            {
+                self.enqueue_woken_task(task.clone(), true);
+            }
+        }
+        true
+    }
+
+    /// Wakes up the given blocked task.
+    ///
+    /// The task is put back to the CPU it ran last time, or one of its allowed
+    /// CPUs if its affinity has changed since then. If it is not the current
+    /// CPU, the task is handed over to that CPU, which will pick it up on its
+    /// next scheduling point.
+    pub fn unblock_task(&mut self, task: AxTaskRef, resched: bool) {
+        debug!("task unblock: {}", task.id_name());
+        // Other CPUs may try to wake up the same task at the same time (e.g.,
+        // by a timer and `notify()`), only one of them can succeed.
+        if task.transition_state(TaskState::Blocked, TaskState::Ready)
+            || task.transition_state(TaskState::BlockedCancellable, TaskState::Ready)
+        {
+            self.enqueue_woken_task(task, resched);
+        }
+    }
+
+    /// Puts a task that has just transitioned from blocked to ready into a
+    /// run queue.
+    fn enqueue_woken_task(&mut self, task: AxTaskRef, resched: bool) {
+        // The task may be still switching out on another CPU, wait for its
+        // context to be saved before it can be picked up again.
+        while task.on_cpu() {
+            core::hint::spin_loop();
+        }
+
+        let cpu_id = select_cpu(&task, task.cpu_id());
+        if cpu_id == self.cpu_id {
+            self.scheduler.add_task(task); // TODO: priority
+            if resched {
+                #[cfg(feature = "preempt")]
+                crate::current().set_preempt_pending(true);
+            }
+        } else {
+            hand_over(cpu_id, task);
+        }
+    }
+}
+
+/// The operations that reschedule the current task.
+///
+/// They are done on the locked reference, as the current task may come back
+/// on another CPU, whose run queue is then locked instead.
+impl AxRunQueueRef {
+    /// Common reschedule subroutine, see [`AxRunQueue::resched`].
+    ///
+    /// If the current task is migrated while it is switched out, the lock of
+    /// the old run queue has been released by the next task there, while the
+    /// lock of the new one is held by the task that switched back to it. The
+    /// latter is taken over.
+    fn resched(&mut self, preempt: bool) {
+        self.inner.resched(preempt);
+        let cpu_id = this_cpu_id();
+        if cpu_id != self.cpu_id {
+            let inner = unsafe {
+                RUN_QUEUES[cpu_id].force_unlock();
+                RUN_QUEUES[cpu_id].lock()
+            };
+            core::mem::forget(core::mem::replace(&mut self.inner, inner));
+        }
+    }
+
     pub fn yield_current(&mut self) {
         let curr = crate::current();
         trace!("task yield: {}", curr.id_name());
@@ -52,9 +328,14 @@ impl AxRunQueue {
         self.resched(false);
     }
 
-    pub fn set_current_priority(&mut self, prio: isize) -> bool {
-        self.scheduler
-            .set_priority(crate::current().as_task_ref(), prio)
+    /// Moves the current task to another CPU if the current CPU is no longer
+    /// allowed by its affinity mask.
+    pub fn migrate_current(&mut self) {
+        let curr = crate::current();
+        if !curr.cpumask().get(self.cpu_id) {
+            debug!("task migrate: {} from CPU {}", curr.id_name(), self.cpu_id);
+            self.resched(false);
+        }
     }
 
     #[cfg(feature = "preempt")]
@@ -87,13 +368,22 @@ impl AxRunQueue {
         assert!(curr.is_running());
         assert!(!curr.is_idle());
         if curr.is_init() {
-            EXITED_TASKS.lock().clear();
+            for exited in EXITED_TASKS.iter() {
+                exited.lock().clear();
+            }
             axhal::misc::terminate();
         } else {
+            let exit_code = if curr.is_cancelled() {
+                EXIT_CANCELLED
+            } else {
+                exit_code
+            };
             curr.set_state(TaskState::Exited);
+            #[cfg(feature = "sched_edf")]
+            crate::sched_edf::task_exit(&curr);
             curr.notify_exit(exit_code, self);
-            EXITED_TASKS.lock().push_back(curr.clone());
-            WAIT_FOR_EXIT.notify_one_locked(false, self);
+            EXITED_TASKS[self.cpu_id].lock().push_back(curr.clone());
+            WAIT_FOR_EXIT[self.cpu_id].notify_one_locked(false, self);
             self.resched(false);
         }
         unreachable!("task exited!");
@@ -117,15 +407,32 @@ impl AxRunQueue {
         self.resched(false);
     }
 
-    pub fn unblock_task(&mut self, task: AxTaskRef, resched: bool) {
-        debug!("task unblock: {}", task.id_name());
-        if task.is_blocked() {
-            task.set_state(TaskState::Ready);
-            self.scheduler.add_task(task); // TODO: priority
-            if resched {
-                #[cfg(feature = "preempt")]
-                crate::current().set_preempt_pending(true);
-            }
+    /// Like [`block_current`](Self::block_current), but the task can also be
+    /// woken up by [`cancel_task`](AxRunQueue::cancel_task).
+    ///
+    /// Returns [`Cancelled`] if the task has been cancelled, either before or
+    /// during the wait.
+    pub fn block_current_cancellable<F>(&mut self, wait_queue_push: F) -> Result<(), Cancelled>
+    where
+        F: FnOnce(AxTaskRef),
+    {
+        let curr = crate::current();
+        debug!("task block (cancellable): {}", curr.id_name());
+        assert!(curr.is_running());
+        assert!(!curr.is_idle());
+
+        #[cfg(feature = "preempt")]
+        assert!(curr.can_preempt(1));
+
+        if !AxRunQueue::set_current_blocked_cancellable(&curr) {
+            return Err(Cancelled);
+        }
+        wait_queue_push(curr.clone());
+        self.resched(false);
+        if curr.is_cancelled() {
+            Err(Cancelled)
+        } else {
+            Ok(())
         }
     }
 
@@ -137,10 +444,15 @@ impl AxRunQueue {
         assert!(!curr.is_idle());
 
         let now = axhal::time::wall_time();
-        if now < deadline {
+        // The timer may expire on another CPU once it is set, so we must
+        // mark the current task as blocked first.
+        if now < deadline && AxRunQueue::set_current_blocked_cancellable(&curr) {
             crate::timers::set_alarm_wakeup(deadline, curr.clone());
-            curr.set_state(TaskState::Blocked);
             self.resched(false);
+            if curr.in_timer_list() {
+                // woken up by cancellation
+                crate::timers::cancel_alarm(curr.as_task_ref());
+            }
         }
     }
 }
@@ -149,21 +461,92 @@ impl AxRunQueue {
     /// Common reschedule subroutine. If `preempt`, keep current task's time
     /// slice, otherwise reset it.
     fn resched(&mut self, preempt: bool) {
+        #[cfg(feature = "watchdog")]
+        crate::watchdog::touch_softlockup();
         let prev = crate::current();
-        if prev.is_running() {
+        // The task is still runnable, so it is an involuntary switch.
+        let involuntary = prev.is_running();
+        if involuntary {
             prev.set_state(TaskState::Ready);
             if !prev.is_idle() {
-                self.scheduler.put_prev_task(prev.clone(), preempt);
+                let cpu_id = select_cpu(prev.as_task_ref(), self.cpu_id);
+                if cpu_id == self.cpu_id {
+                    self.scheduler.put_prev_task(prev.clone(), preempt);
+                } else {
+                    // The current CPU is not allowed any more, the target CPU
+                    // will wait for the context switch to be completed before
+                    // running it.
+                    hand_over(cpu_id, prev.clone());
+                }
+            }
+        }
+        self.pull_remote_wakeups();
+        let next = self
+            .scheduler
+            .pick_next_task()
+            .or_else(|| self.steal_task())
+            .unwrap_or_else(|| unsafe {
+                // Safety: IRQs must be disabled at this time.
+                IDLE_TASK.current_ref_raw().get_unchecked().clone()
+            });
+        self.switch_to(prev, next, involuntary);
+    }
+
+    /// Moves the tasks woken up by other CPUs into the local run queue.
+    fn pull_remote_wakeups(&mut self) {
+        let mut wakeups = REMOTE_WAKEUPS[self.cpu_id].lock();
+        while let Some(task) = wakeups.pop_front() {
+            self.scheduler.add_task(task);
+        }
+    }
+
+    /// Steals a ready task from other CPUs when there is nothing to run on
+    /// the current CPU.
+    ///
+    /// The run queues of other CPUs are only try-locked, as we already hold
+    /// the local one.
+    fn steal_task(&mut self) -> Option<AxTaskRef> {
+        for i in 1..SMP {
+            let victim = (self.cpu_id + i) % SMP;
+            let Some(rq) = RUN_QUEUES[victim].get() else {
+                continue;
+            };
+            if let Some(mut rq) = rq.try_lock() {
+                if let Some(task) = rq.take_task_for(self.cpu_id) {
+                    debug!(
+                        "CPU {} steals {} from CPU {}",
+                        self.cpu_id,
+                        task.id_name(),
+                        victim
+                    );
+                    return Some(task);
+                }
             }
         }
-        let next = self.scheduler.pick_next_task().unwrap_or_else(|| unsafe {
-            // Safety: IRQs must be disabled at this time.
-            IDLE_TASK.current_ref_raw().get_unchecked().clone()
-        });
-        self.switch_to(prev, next);
+        None
     }
 
-    fn switch_to(&mut self, prev_task: CurrentTask, next_task: AxTaskRef) {
+    /// Takes the first ready task whose affinity allows `cpu_id` out, for that
+    /// CPU to steal.
+    pub(crate) fn take_task_for(&mut self, cpu_id: usize) -> Option<AxTaskRef> {
+        // The scheduler can only be popped, so drain it and put the others
+        // back in the same order.
+        let mut found = None;
+        let mut others = Vec::new();
+        while let Some(task) = self.scheduler.pick_next_task() {
+            if found.is_none() && task.cpumask().get(cpu_id) {
+                found = Some(task);
+            } else {
+                others.push(task);
+            }
+        }
+        for task in others {
+            self.scheduler.put_prev_task(task, false);
+        }
+        found
+    }
+
+    fn switch_to(&mut self, prev_task: CurrentTask, next_task: AxTaskRef, involuntary: bool) {
         trace!(
             "context switch: {} -> {}",
             prev_task.id_name(),
@@ -175,6 +558,14 @@ impl AxRunQueue {
         if prev_task.ptr_eq(&next_task) {
             return;
         }
+        // The next task may have been running on another CPU just now, wait
+        // for its context to be saved.
+        while next_task.on_cpu() {
+            core::hint::spin_loop();
+        }
+        next_task.set_cpu_id(self.cpu_id);
+        next_task.set_on_cpu(true);
+        crate::cputime::on_switch(&prev_task, &next_task, involuntary);
 
         unsafe {
             let prev_ctx_ptr = prev_task.ctx_mut_ptr();
@@ -185,35 +576,67 @@ impl AxRunQueue {
             assert!(Arc::strong_count(prev_task.as_task_ref()) > 1);
             assert!(Arc::strong_count(&next_task) >= 1);
 
+            PREV_TASK_ON_CPU.write_current_raw(prev_task.on_cpu_ptr() as usize);
             CurrentTask::set_current(prev_task, next_task);
             (*prev_ctx_ptr).switch_to(&*next_ctx_ptr);
         }
+
+        // Now we are back to `prev_task`, which may have been stolen by
+        // another CPU while it was ready, see `AxRunQueueRef::resched()`.
+        clear_prev_task_on_cpu();
     }
 }
 
-fn gc_entry() {
+/// Clears the `on_cpu` flag of the task that was switched out last on the
+/// current CPU.
+///
+/// It must be called by the next task right after the context switch.
+pub(crate) fn clear_prev_task_on_cpu() {
+    unsafe {
+        let ptr = PREV_TASK_ON_CPU.read_current_raw() as *const AtomicBool;
+        if !ptr.is_null() {
+            PREV_TASK_ON_CPU.write_current_raw(0);
+            (*ptr).store(false, Ordering::Release);
+        }
+    }
+}
+
+/// Releases the lock of the current CPU's run queue that was implicitly held
+/// across the reschedule.
+///
+/// # Safety
+///
+/// It should only be called at the entry of a newly spawned task.
+pub(crate) unsafe fn force_unlock_current_run_queue() {
+    RUN_QUEUES[this_cpu_id()].force_unlock();
+}
+
+fn gc_entry(cpu_id: usize) {
+    let exited_tasks = &EXITED_TASKS[cpu_id];
     loop {
         // Drop all exited tasks and recycle resources.
-        let n = EXITED_TASKS.lock().len();
+        let n = exited_tasks.lock().len();
         for _ in 0..n {
             // Do not do the slow drops in the critical section.
-            let task = EXITED_TASKS.lock().pop_front();
+            let task = exited_tasks.lock().pop_front();
             if let Some(task) = task {
-                if Arc::strong_count(&task) == 1 {
+                if Arc::strong_count(&task) == 1 && !task.on_cpu() {
                     // If I'm the last holder of the task, drop it immediately.
                     drop(task);
                 } else {
                     // Otherwise (e.g, `switch_to` is not compeleted, held by the
                     // joiner, etc), push it back and wait for them to drop first.
-                    EXITED_TASKS.lock().push_back(task);
+                    exited_tasks.lock().push_back(task);
                 }
             }
         }
-        WAIT_FOR_EXIT.wait();
+        WAIT_FOR_EXIT[cpu_id].wait_idle();
     }
 }
 
 pub(crate) fn init() {
+    let cpu_id = this_cpu_id();
+
     // Create the `idle` task (not current task).
     const IDLE_TASK_STACK_SIZE: usize = 4096;
     let idle_task = TaskInner::new(|| crate::run_idle(), "idle".into(), IDLE_TASK_STACK_SIZE);
@@ -224,17 +647,21 @@ pub(crate) fn init() {
     // Put the subsequent execution into the `main` task.
     let main_task = TaskInner::new_init("main".into()).into_arc();
     main_task.set_state(TaskState::Running);
-    unsafe { CurrentTask::init_current(main_task) };
+    unsafe { CurrentTask::init_current(main_task, cpu_id) };
 
-    RUN_QUEUE.init_once(AxRunQueue::new());
+    RUN_QUEUES[cpu_id].init_once(AxRunQueue::new(cpu_id));
 }
 
 pub(crate) fn init_secondary() {
+    let cpu_id = this_cpu_id();
+
     // Put the subsequent execution into the `idle` task.
     let idle_task = TaskInner::new_init("idle".into()).into_arc();
     idle_task.set_state(TaskState::Running);
     IDLE_TASK.with_current(|i| {
         i.init_once(idle_task.clone());
     });
-    unsafe { CurrentTask::init_current(idle_task) }
+    unsafe { CurrentTask::init_current(idle_task, cpu_id) }
+
+    RUN_QUEUES[cpu_id].init_once(AxRunQueue::new(cpu_id));
 }
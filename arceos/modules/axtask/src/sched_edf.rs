@@ -0,0 +1,379 @@
+//! Earliest-deadline-first (EDF) scheduling class for periodic real-time
+//! tasks, similar to `SCHED_DEADLINE` in Linux.
+//!
+//! Each deadline task is declared with a `runtime`, a relative `deadline` and
+//! a `period`: in every period, it is guaranteed to receive `runtime` of CPU
+//! time before `deadline` elapsed since the beginning of the period. Deadline
+//! tasks always run before the tasks of the normal scheduling class (FIFO, RR
+//! or CFS), and among them the one with the earliest absolute deadline runs
+//! first.
+//!
+//! A task that uses up its runtime (or misses its deadline) in a period is
+//! throttled until the next period, and its overrun callback is invoked.
+//!
+//! Deadline tasks are pinned to the CPU they become deadline tasks on, as the
+//! run queues are per CPU. A new task is only admitted if the total bandwidth
+//! (`runtime / period`) of the deadline tasks on that CPU does not exceed
+//! [`MAX_BANDWIDTH_PERCENT`].
+
+use alloc::collections::BTreeMap;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+
+use axhal::time::monotonic_time_nanos;
+use scheduler::BaseScheduler;
+
+use crate::{AxCpuMask, AxTaskRef, NormalScheduler, TaskInner};
+
+/// The maximum percentage of the time of a CPU that can be reserved by
+/// deadline tasks, the rest is left for the normal tasks.
+pub const MAX_BANDWIDTH_PERCENT: u64 = 95;
+
+const BW_SHIFT: u32 = 20;
+const MAX_CPU_BW: u64 = (1 << BW_SHIFT) * MAX_BANDWIDTH_PERCENT / 100;
+
+/// The total bandwidth of the admitted deadline tasks on each CPU.
+static CPU_BW: [AtomicU64; axconfig::SMP] = [const { AtomicU64::new(0) }; axconfig::SMP];
+
+/// The function called when a deadline task overruns, i.e., it uses up its
+/// runtime or misses its deadline in a period.
+///
+/// It is called in the timer interrupt context with the run queue locked, so
+/// it must not block.
+pub type DeadlineOverrunFn = fn(&TaskInner);
+
+/// The scheduling parameters of a deadline task.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeadlineParams {
+    /// The CPU time reserved for the task in each period.
+    pub runtime: Duration,
+    /// The relative deadline to the beginning of each period.
+    pub deadline: Duration,
+    /// The period of the task.
+    pub period: Duration,
+}
+
+impl DeadlineParams {
+    /// Whether `0 < runtime <= deadline <= period`.
+    pub fn is_valid(&self) -> bool {
+        !self.runtime.is_zero() && self.runtime <= self.deadline && self.deadline <= self.period
+    }
+
+    fn bandwidth(&self) -> u64 {
+        ((self.runtime.as_nanos() << BW_SHIFT) / self.period.as_nanos()) as u64
+    }
+}
+
+/// The per-task state of the deadline class.
+pub(crate) struct DlEntity {
+    params: DeadlineParams,
+    on_overrun: Option<DeadlineOverrunFn>,
+    /// The CPU that the task is pinned to, and its bandwidth is reserved on.
+    cpu_id: usize,
+    /// The affinity of the task before it is pinned.
+    saved_cpumask: AxCpuMask,
+    /// The beginning of the current period, in nanoseconds.
+    release: u64,
+    /// The absolute deadline of the current period, in nanoseconds.
+    abs_deadline: u64,
+    /// The runtime left in the current period, in nanoseconds.
+    remaining: i64,
+    /// When the task starts running, or 0 if it is not running.
+    exec_start: u64,
+    /// The task is waiting for the next period.
+    throttled: bool,
+    /// The task gives up the rest of its runtime in the current period.
+    yielded: bool,
+}
+
+impl DlEntity {
+    fn new(
+        params: DeadlineParams,
+        on_overrun: Option<DeadlineOverrunFn>,
+        cpu_id: usize,
+        saved_cpumask: AxCpuMask,
+    ) -> Self {
+        let mut dl = Self {
+            params,
+            on_overrun,
+            cpu_id,
+            saved_cpumask,
+            release: 0,
+            abs_deadline: 0,
+            remaining: 0,
+            exec_start: 0,
+            throttled: false,
+            yielded: false,
+        };
+        dl.replenish(monotonic_time_nanos());
+        dl
+    }
+
+    /// Starts a new period at `now`.
+    fn replenish(&mut self, now: u64) {
+        self.release = now;
+        self.abs_deadline = now + self.params.deadline.as_nanos() as u64;
+        self.remaining = self.params.runtime.as_nanos() as i64;
+        self.throttled = false;
+        self.yielded = false;
+    }
+
+    /// The beginning of the next period.
+    fn next_release(&self) -> u64 {
+        self.release + self.params.period.as_nanos() as u64
+    }
+
+    /// Charges the time consumed since the last update.
+    fn update_curr(&mut self, now: u64) {
+        if self.exec_start != 0 {
+            self.remaining -= now.saturating_sub(self.exec_start) as i64;
+            self.exec_start = now;
+        }
+    }
+
+    fn is_overrun(&self, now: u64) -> bool {
+        self.remaining <= 0 || now > self.abs_deadline
+    }
+}
+
+/// Reserves `new_bw` instead of `old_bw` on the CPU, if the total does not
+/// exceed [`MAX_BANDWIDTH_PERCENT`].
+fn reserve_bandwidth(cpu_id: usize, old_bw: u64, new_bw: u64) -> bool {
+    CPU_BW[cpu_id]
+        .fetch_update(Ordering::AcqRel, Ordering::Acquire, |total| {
+            let total = total - old_bw + new_bw;
+            (total <= MAX_CPU_BW).then_some(total)
+        })
+        .is_ok()
+}
+
+/// Sets the deadline parameters of the task on `cpu_id`, or reverts it to the
+/// normal class if `params` is [`None`].
+///
+/// A new deadline task is pinned to `cpu_id` until it reverts, when its
+/// affinity is restored.
+///
+/// Returns `false` if the parameters are invalid or the admission control
+/// fails, i.e., the total bandwidth on the CPU would exceed
+/// [`MAX_BANDWIDTH_PERCENT`].
+///
+/// The task must not be in any run queue (e.g., it is the current task on
+/// `cpu_id`).
+pub(crate) fn set_task_deadline(
+    task: &TaskInner,
+    cpu_id: usize,
+    params: Option<DeadlineParams>,
+    on_overrun: Option<DeadlineOverrunFn>,
+) -> bool {
+    if params.is_some_and(|p| !p.is_valid()) {
+        return false;
+    }
+    let mut dl = task.dl_entity().lock();
+    let old_bw = dl.as_ref().map_or(0, |dl| dl.params.bandwidth());
+    let new_bw = params.map_or(0, |p| p.bandwidth());
+    if !reserve_bandwidth(cpu_id, old_bw, new_bw) {
+        return false;
+    }
+    let saved_cpumask = match dl.take() {
+        Some(old) => old.saved_cpumask,
+        None => task.cpumask(),
+    };
+    match params {
+        Some(params) => {
+            task.set_cpumask(AxCpuMask::one_shot(cpu_id));
+            *dl = Some(DlEntity::new(params, on_overrun, cpu_id, saved_cpumask));
+        }
+        None => task.set_cpumask(saved_cpumask),
+    }
+    true
+}
+
+/// Whether the task is a deadline task, which is pinned to its CPU.
+pub(crate) fn is_deadline_task(task: &TaskInner) -> bool {
+    task.dl_entity().lock().is_some()
+}
+
+/// Releases the bandwidth of an exited task.
+pub(crate) fn task_exit(task: &TaskInner) {
+    if let Some(dl) = task.dl_entity().lock().take() {
+        CPU_BW[dl.cpu_id].fetch_sub(dl.params.bandwidth(), Ordering::AcqRel);
+    }
+}
+
+/// Gives up the rest of the runtime of the current deadline task in this
+/// period, it will be throttled until the next period on the next reschedule.
+pub(crate) fn yield_period(task: &TaskInner) -> bool {
+    match task.dl_entity().lock().as_mut() {
+        Some(dl) => {
+            dl.yielded = true;
+            true
+        }
+        None => false,
+    }
+}
+
+/// A scheduler that puts the deadline class on top of the normal scheduler.
+pub(crate) struct EdfScheduler {
+    /// Ready deadline tasks, ordered by (absolute deadline, task ID).
+    ready: BTreeMap<(u64, u64), AxTaskRef>,
+    /// Throttled deadline tasks, ordered by (next release time, task ID).
+    throttled: BTreeMap<(u64, u64), AxTaskRef>,
+    normal: NormalScheduler,
+}
+
+impl EdfScheduler {
+    pub fn new() -> Self {
+        Self {
+            ready: BTreeMap::new(),
+            throttled: BTreeMap::new(),
+            normal: NormalScheduler::new(),
+        }
+    }
+
+    pub fn scheduler_name() -> &'static str {
+        NormalScheduler::scheduler_name()
+    }
+
+    fn enqueue_dl(&mut self, task: AxTaskRef, dl: &mut DlEntity) {
+        let id = task.id().as_u64();
+        if dl.throttled {
+            self.throttled.insert((dl.next_release(), id), task);
+        } else {
+            self.ready.insert((dl.abs_deadline, id), task);
+        }
+    }
+
+    /// Moves the throttled tasks whose next period has begun back to the
+    /// ready queue.
+    fn replenish(&mut self, now: u64) {
+        while let Some(entry) = self.throttled.first_entry() {
+            if entry.key().0 > now {
+                break;
+            }
+            let task = entry.remove();
+            let mut dl = task.dl_entity().lock();
+            if let Some(dl) = dl.as_mut() {
+                // Skip the periods that have been missed entirely.
+                let period = dl.params.period.as_nanos() as u64;
+                dl.replenish(now - (now - dl.next_release()) % period);
+                self.ready
+                    .insert((dl.abs_deadline, task.id().as_u64()), task.clone());
+            }
+        }
+    }
+}
+
+impl BaseScheduler for EdfScheduler {
+    type SchedItem = AxTaskRef;
+
+    fn init(&mut self) {
+        self.normal.init();
+    }
+
+    fn add_task(&mut self, task: Self::SchedItem) {
+        let mut guard = task.dl_entity().lock();
+        match guard.as_mut() {
+            Some(dl) => {
+                let now = monotonic_time_nanos();
+                // A task waking up after its deadline starts a new period.
+                if now >= dl.abs_deadline && (!dl.throttled || now >= dl.next_release()) {
+                    dl.replenish(now);
+                }
+                dl.exec_start = 0;
+                self.enqueue_dl(task.clone(), dl);
+            }
+            None => {
+                drop(guard);
+                self.normal.add_task(task);
+            }
+        }
+    }
+
+    fn remove_task(&mut self, task: &Self::SchedItem) -> Option<Self::SchedItem> {
+        let guard = task.dl_entity().lock();
+        match guard.as_ref() {
+            Some(dl) => {
+                let id = task.id().as_u64();
+                self.ready
+                    .remove(&(dl.abs_deadline, id))
+                    .or_else(|| self.throttled.remove(&(dl.next_release(), id)))
+            }
+            None => {
+                drop(guard);
+                self.normal.remove_task(task)
+            }
+        }
+    }
+
+    fn pick_next_task(&mut self) -> Option<Self::SchedItem> {
+        let now = monotonic_time_nanos();
+        self.replenish(now);
+        if let Some((_, task)) = self.ready.pop_first() {
+            if let Some(dl) = task.dl_entity().lock().as_mut() {
+                dl.exec_start = now;
+            }
+            return Some(task);
+        }
+        self.normal.pick_next_task()
+    }
+
+    fn put_prev_task(&mut self, prev: Self::SchedItem, preempt: bool) {
+        let mut guard = prev.dl_entity().lock();
+        match guard.as_mut() {
+            Some(dl) => {
+                let now = monotonic_time_nanos();
+                dl.update_curr(now);
+                dl.exec_start = 0;
+                if dl.yielded || dl.remaining <= 0 {
+                    dl.throttled = true;
+                    dl.yielded = false;
+                }
+                self.enqueue_dl(prev.clone(), dl);
+            }
+            None => {
+                drop(guard);
+                self.normal.put_prev_task(prev, preempt);
+            }
+        }
+    }
+
+    fn task_tick(&mut self, current: &Self::SchedItem) -> bool {
+        let now = monotonic_time_nanos();
+        self.replenish(now);
+        let mut guard = current.dl_entity().lock();
+        match guard.as_mut() {
+            Some(dl) => {
+                dl.update_curr(now);
+                if !dl.throttled && dl.is_overrun(now) {
+                    warn!(
+                        "deadline task overrun: {}, remaining={}ns",
+                        current.id_name(),
+                        dl.remaining
+                    );
+                    dl.throttled = true;
+                    if let Some(on_overrun) = dl.on_overrun {
+                        on_overrun(current);
+                    }
+                    return true;
+                }
+                // Preempted by a task with an earlier deadline.
+                self.ready
+                    .first_key_value()
+                    .is_some_and(|(&(deadline, _), _)| deadline < dl.abs_deadline)
+            }
+            None => {
+                drop(guard);
+                let resched = self.normal.task_tick(current);
+                resched || !self.ready.is_empty()
+            }
+        }
+    }
+
+    fn set_priority(&mut self, task: &Self::SchedItem, prio: isize) -> bool {
+        if task.dl_entity().lock().is_some() {
+            // The priority of deadline tasks is determined by their deadlines.
+            return false;
+        }
+        self.normal.set_priority(task, prio)
+    }
+}
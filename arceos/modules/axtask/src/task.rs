@@ -1,24 +1,41 @@
-use alloc::{boxed::Box, string::String, sync::Arc};
+use alloc::collections::BTreeMap;
+use alloc::sync::{Arc, Weak};
+use alloc::{boxed::Box, string::String, vec::Vec};
 use core::ops::Deref;
-use core::sync::atomic::{AtomicBool, AtomicI32, AtomicU64, AtomicU8, Ordering};
+use core::sync::atomic::{
+    AtomicBool, AtomicI32, AtomicIsize, AtomicU64, AtomicU8, AtomicUsize, Ordering,
+};
 use core::{alloc::Layout, cell::UnsafeCell, fmt, ptr::NonNull};
 
-#[cfg(feature = "preempt")]
-use core::sync::atomic::AtomicUsize;
-
 #[cfg(feature = "tls")]
 use axhal::tls::TlsArea;
 
 use axhal::arch::TaskContext;
+use kspin::SpinNoIrq;
 use memory_addr::{align_up_4k, VirtAddr};
 
+use crate::cputime::CpuAccounting;
 use crate::task_ext::AxTaskExt;
-use crate::{AxRunQueue, AxTask, AxTaskRef, WaitQueue};
+use crate::{AxCpuMask, AxRunQueue, AxTask, AxTaskRef, WaitQueue};
 
 /// A unique identifier for a thread.
 #[derive(Debug, Clone, Copy, Eq, PartialEq)]
 pub struct TaskId(u64);
 
+/// The error returned by a cancellable wait when the task is cancelled.
+///
+/// The task is expected to release its resources and exit as soon as
+/// possible after receiving it.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Cancelled;
+
+/// The exit code reported by [`TaskInner::join`] for a cancelled task,
+/// regardless of the code it exits with.
+pub const EXIT_CANCELLED: i32 = i32::MIN;
+
+/// All tasks that have not been dropped, indexed by the task ID.
+static TASK_REGISTRY: SpinNoIrq<BTreeMap<u64, Weak<AxTask>>> = SpinNoIrq::new(BTreeMap::new());
+
 /// The possible states of a task.
 #[repr(u8)]
 #[derive(Debug, Clone, Copy, Eq, PartialEq)]
@@ -27,6 +44,8 @@ pub(crate) enum TaskState {
     Ready = 2,
     Blocked = 3,
     Exited = 4,
+    /// Blocked, and can also be woken up by cancellation.
+    BlockedCancellable = 5,
 }
 
 /// The inner task structure.
@@ -39,9 +58,30 @@ pub struct TaskInner {
     entry: Option<*mut dyn FnOnce()>,
     state: AtomicU8,
 
+    /// The CPU that the task ran on last time.
+    cpu_id: AtomicUsize,
+    /// Whether the task is running on a CPU, or its context is being saved.
+    on_cpu: AtomicBool,
+    /// The CPUs that the task is allowed to run on.
+    cpumask: SpinNoIrq<AxCpuMask>,
+
+    /// The priority set by the task itself.
+    base_priority: AtomicIsize,
+    /// The priority in effect, which may be raised by priority inheritance.
+    priority: AtomicIsize,
+    /// The head of the list of the priority-inheritance locks held.
+    held_pi_locks: AtomicUsize,
+    #[cfg(feature = "sched_edf")]
+    dl_entity: SpinNoIrq<Option<crate::sched_edf::DlEntity>>,
+
+    cpu_accounting: CpuAccounting,
+
     in_wait_queue: AtomicBool,
     #[cfg(feature = "irq")]
     in_timer_list: AtomicBool,
+    cancelled: AtomicBool,
+    #[cfg(feature = "watchdog")]
+    wait_site: crate::watchdog::WaitSite,
 
     #[cfg(feature = "preempt")]
     need_resched: AtomicBool,
@@ -79,6 +119,7 @@ impl From<u8> for TaskState {
             2 => Self::Ready,
             3 => Self::Blocked,
             4 => Self::Exited,
+            5 => Self::BlockedCancellable,
             _ => unreachable!(),
         }
     }
@@ -126,9 +167,54 @@ impl TaskInner {
         alloc::format!("Task({}, {:?})", self.id.as_u64(), self.name)
     }
 
+    /// Gets the CPU affinity mask of the task.
+    pub fn cpumask(&self) -> AxCpuMask {
+        *self.cpumask.lock()
+    }
+
+    /// Sets the CPU affinity mask of the task.
+    ///
+    /// It takes effect the next time the task is put into a run queue. Use
+    /// [`set_affinity`](crate::set_affinity) to change the affinity of the
+    /// current task immediately.
+    pub fn set_cpumask(&self, cpumask: AxCpuMask) {
+        *self.cpumask.lock() = cpumask;
+    }
+
+    /// Gets the priority currently in effect.
+    ///
+    /// It is the same as [`base_priority`](Self::base_priority) unless the
+    /// task is boosted by a priority-inheritance lock. A smaller value means a
+    /// higher priority.
+    pub fn priority(&self) -> isize {
+        self.priority.load(Ordering::Acquire)
+    }
+
+    /// Gets the priority set by [`set_priority`](crate::set_priority).
+    pub fn base_priority(&self) -> isize {
+        self.base_priority.load(Ordering::Acquire)
+    }
+
+    /// The head of the list of the priority-inheritance locks held by the
+    /// task, which the locks link themselves into, so that no memory is
+    /// allocated to track them. It's only accessed by the task itself.
+    ///
+    /// It is 0 if no lock is held.
+    pub fn held_pi_locks(&self) -> &AtomicUsize {
+        &self.held_pi_locks
+    }
+
+    /// Whether the task has been cancelled by [`cancel`](crate::cancel) or
+    /// [`kill`](crate::kill).
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+
     /// Wait for the task to exit, and return the exit code.
     ///
     /// It will return immediately if the task has already exited (but not dropped).
+    /// If the task has been cancelled, the exit code is [`EXIT_CANCELLED`].
+    #[cfg_attr(feature = "watchdog", track_caller)]
     pub fn join(&self) -> Option<i32> {
         self.wait_for_exit
             .wait_until(|| self.state() == TaskState::Exited);
@@ -171,9 +257,21 @@ impl TaskInner {
             is_init: false,
             entry: None,
             state: AtomicU8::new(TaskState::Ready as u8),
+            cpu_id: AtomicUsize::new(0),
+            on_cpu: AtomicBool::new(false),
+            cpumask: SpinNoIrq::new(AxCpuMask::full()),
+            base_priority: AtomicIsize::new(0),
+            priority: AtomicIsize::new(0),
+            held_pi_locks: AtomicUsize::new(0),
+            #[cfg(feature = "sched_edf")]
+            dl_entity: SpinNoIrq::new(None),
+            cpu_accounting: CpuAccounting::new(),
             in_wait_queue: AtomicBool::new(false),
             #[cfg(feature = "irq")]
             in_timer_list: AtomicBool::new(false),
+            cancelled: AtomicBool::new(false),
+            #[cfg(feature = "watchdog")]
+            wait_site: crate::watchdog::WaitSite::new(),
             #[cfg(feature = "preempt")]
             need_resched: AtomicBool::new(false),
             #[cfg(feature = "preempt")]
@@ -206,7 +304,11 @@ impl TaskInner {
     }
 
     pub(crate) fn into_arc(self) -> AxTaskRef {
-        Arc::new(AxTask::new(self))
+        let task = Arc::new(AxTask::new(self));
+        TASK_REGISTRY
+            .lock()
+            .insert(task.id.as_u64(), Arc::downgrade(&task));
+        task
     }
 
     #[inline]
@@ -219,6 +321,21 @@ impl TaskInner {
         self.state.store(state as u8, Ordering::Release)
     }
 
+    /// Atomically transitions the task state from `current` to `new`.
+    ///
+    /// Returns `false` if the task is not in the `current` state.
+    #[inline]
+    pub(crate) fn transition_state(&self, current: TaskState, new: TaskState) -> bool {
+        self.state
+            .compare_exchange(
+                current as u8,
+                new as u8,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            )
+            .is_ok()
+    }
+
     #[inline]
     pub(crate) fn is_running(&self) -> bool {
         matches!(self.state(), TaskState::Running)
@@ -231,7 +348,15 @@ impl TaskInner {
 
     #[inline]
     pub(crate) fn is_blocked(&self) -> bool {
-        matches!(self.state(), TaskState::Blocked)
+        matches!(
+            self.state(),
+            TaskState::Blocked | TaskState::BlockedCancellable
+        )
+    }
+
+    #[inline]
+    pub(crate) fn is_exited(&self) -> bool {
+        matches!(self.state(), TaskState::Exited)
     }
 
     #[inline]
@@ -244,6 +369,53 @@ impl TaskInner {
         self.is_idle
     }
 
+    /// Returns the ID of the CPU that the task ran on last time.
+    #[inline]
+    pub fn cpu_id(&self) -> usize {
+        self.cpu_id.load(Ordering::Acquire)
+    }
+
+    #[inline]
+    pub(crate) fn set_cpu_id(&self, cpu_id: usize) {
+        self.cpu_id.store(cpu_id, Ordering::Release);
+    }
+
+    #[inline]
+    pub(crate) fn on_cpu(&self) -> bool {
+        self.on_cpu.load(Ordering::Acquire)
+    }
+
+    #[inline]
+    pub(crate) fn set_on_cpu(&self, on_cpu: bool) {
+        self.on_cpu.store(on_cpu, Ordering::Release);
+    }
+
+    #[inline]
+    pub(crate) fn on_cpu_ptr(&self) -> *const AtomicBool {
+        &self.on_cpu
+    }
+
+    #[inline]
+    pub(crate) fn set_base_priority(&self, prio: isize) {
+        self.base_priority.store(prio, Ordering::Release);
+    }
+
+    #[inline]
+    pub(crate) fn set_effective_priority(&self, prio: isize) {
+        self.priority.store(prio, Ordering::Release);
+    }
+
+    #[inline]
+    #[cfg(feature = "sched_edf")]
+    pub(crate) fn dl_entity(&self) -> &SpinNoIrq<Option<crate::sched_edf::DlEntity>> {
+        &self.dl_entity
+    }
+
+    #[inline]
+    pub(crate) fn cpu_accounting(&self) -> &CpuAccounting {
+        &self.cpu_accounting
+    }
+
     #[inline]
     pub(crate) fn in_wait_queue(&self) -> bool {
         self.in_wait_queue.load(Ordering::Acquire)
@@ -254,6 +426,12 @@ impl TaskInner {
         self.in_wait_queue.store(in_wait_queue, Ordering::Release);
     }
 
+    #[inline]
+    #[cfg(feature = "watchdog")]
+    pub(crate) fn wait_site(&self) -> &crate::watchdog::WaitSite {
+        &self.wait_site
+    }
+
     #[inline]
     #[cfg(feature = "irq")]
     pub(crate) fn in_timer_list(&self) -> bool {
@@ -266,6 +444,13 @@ impl TaskInner {
         self.in_timer_list.store(in_timer_list, Ordering::Release);
     }
 
+    /// Marks the task as cancelled, returns `false` if it is already
+    /// cancelled.
+    #[inline]
+    pub(crate) fn set_cancelled(&self) -> bool {
+        !self.cancelled.swap(true, Ordering::SeqCst)
+    }
+
     #[inline]
     #[cfg(feature = "preempt")]
     pub(crate) fn set_preempt_pending(&self, pending: bool) {
@@ -297,7 +482,7 @@ impl TaskInner {
     fn current_check_preempt_pending() {
         let curr = crate::current();
         if curr.need_resched.load(Ordering::Acquire) && curr.can_preempt(0) {
-            let mut rq = crate::RUN_QUEUE.lock();
+            let mut rq = crate::current_run_queue();
             if curr.need_resched.load(Ordering::Acquire) {
                 rq.preempt_resched();
             }
@@ -328,6 +513,16 @@ impl TaskInner {
             None => None,
         }
     }
+
+    /// Returns the size of the kernel stack, or 0 if the task runs on the
+    /// boot stack.
+    #[inline]
+    pub const fn kernel_stack_size(&self) -> usize {
+        match &self.kstack {
+            Some(s) => s.layout.size(),
+            None => 0,
+        }
+    }
 }
 
 impl fmt::Debug for TaskInner {
@@ -343,9 +538,49 @@ impl fmt::Debug for TaskInner {
 impl Drop for TaskInner {
     fn drop(&mut self) {
         debug!("task drop: {}", self.id_name());
+        TASK_REGISTRY.lock().remove(&self.id.as_u64());
+        crate::cputime::on_drop(self);
     }
 }
 
+/// Returns all tasks that have not been dropped, ordered by the task ID.
+pub(crate) fn all_tasks() -> Vec<AxTaskRef> {
+    TASK_REGISTRY
+        .lock()
+        .values()
+        .filter_map(Weak::upgrade)
+        .collect()
+}
+
+/// Calls `f` on each task that has not been dropped, ordered by the task ID.
+///
+/// Unlike [`all_tasks`], it does not allocate memory, so that it can be used
+/// when memory is short.
+#[cfg(feature = "oom")]
+pub(crate) fn for_each_task(mut f: impl FnMut(&AxTaskRef)) {
+    let mut next_id = 0;
+    loop {
+        // The registry is not locked when calling `f` and dropping the task,
+        // which may remove it from the registry.
+        let task = {
+            let registry = TASK_REGISTRY.lock();
+            let Some((&id, task)) = registry.range(next_id..).next() else {
+                break;
+            };
+            next_id = id + 1;
+            task.upgrade()
+        };
+        if let Some(task) = task {
+            f(&task);
+        }
+    }
+}
+
+/// Finds a task that has not been dropped by its ID.
+pub(crate) fn find_task(id: u64) -> Option<AxTaskRef> {
+    TASK_REGISTRY.lock().get(&id).and_then(Weak::upgrade)
+}
+
 struct TaskStack {
     ptr: NonNull<u8>,
     layout: Layout,
@@ -405,8 +640,11 @@ impl CurrentTask {
         Arc::ptr_eq(&self.0, other)
     }
 
-    pub(crate) unsafe fn init_current(init_task: AxTaskRef) {
+    pub(crate) unsafe fn init_current(init_task: AxTaskRef, cpu_id: usize) {
         assert!(init_task.is_init());
+        init_task.set_cpu_id(cpu_id);
+        init_task.set_on_cpu(true);
+        crate::cputime::on_init(&init_task);
         #[cfg(feature = "tls")]
         axhal::arch::write_thread_pointer(init_task.tls.tls_ptr() as usize);
         let ptr = Arc::into_raw(init_task);
@@ -429,8 +667,10 @@ impl Deref for CurrentTask {
 }
 
 extern "C" fn task_entry() -> ! {
+    // the previous task has been switched out completely
+    crate::run_queue::clear_prev_task_on_cpu();
     // release the lock that was implicitly held across the reschedule
-    unsafe { crate::RUN_QUEUE.force_unlock() };
+    unsafe { crate::run_queue::force_unlock_current_run_queue() };
     #[cfg(feature = "irq")]
     axhal::arch::enable_irqs();
     let task = crate::current();
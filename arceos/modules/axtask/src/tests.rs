@@ -128,3 +128,381 @@ fn test_task_join() {
         assert_eq!(tasks[i].join(), Some(i as _));
     }
 }
+
+#[test]
+fn test_set_affinity() {
+    let _lock = SERIAL.lock();
+    INIT.call_once(axtask::init_scheduler);
+
+    assert!(current().cpumask().is_full());
+    assert!(!axtask::set_affinity(axtask::AxCpuMask::new()));
+    assert!(axtask::set_affinity(axtask::AxCpuMask::one_shot(0)));
+    assert_eq!(current().cpumask(), axtask::AxCpuMask::one_shot(0));
+
+    let task = axtask::spawn(|| {
+        assert!(current().cpumask().is_full());
+        axtask::yield_now();
+    });
+    task.join();
+
+    assert!(axtask::set_affinity(axtask::AxCpuMask::full()));
+}
+
+#[test]
+fn test_per_cpu_queue() {
+    use crate::run_queue::hand_over;
+
+    let _lock = SERIAL.lock();
+    INIT.call_once(axtask::init_scheduler);
+
+    static WQ: WaitQueue = WaitQueue::new();
+    static STAGE: AtomicUsize = AtomicUsize::new(0);
+
+    let task = axtask::spawn(|| {
+        assert_eq!(current().cpu_id(), 0);
+        STAGE.fetch_add(1, Ordering::Relaxed);
+        WQ.wait_until(|| STAGE.load(Ordering::Relaxed) == 2);
+        assert_eq!(current().cpu_id(), 0);
+        STAGE.fetch_add(1, Ordering::Relaxed);
+    });
+    assert_eq!(task.cpu_id(), 0);
+    while STAGE.load(Ordering::Relaxed) < 1 {
+        axtask::yield_now();
+    }
+    STAGE.fetch_add(1, Ordering::Relaxed);
+    WQ.notify_one(true);
+    task.join();
+    assert_eq!(STAGE.load(Ordering::Relaxed), 3);
+
+    // A task handed over by another CPU runs on the next scheduling point.
+    static HANDED_OVER: AtomicUsize = AtomicUsize::new(0);
+    let task = crate::TaskInner::new(
+        || {
+            HANDED_OVER.fetch_add(1, Ordering::Relaxed);
+        },
+        "handed over".into(),
+        0x1000,
+    )
+    .into_arc();
+    hand_over(0, task.clone());
+    assert_eq!(HANDED_OVER.load(Ordering::Relaxed), 0);
+    task.join();
+    assert_eq!(HANDED_OVER.load(Ordering::Relaxed), 1);
+}
+
+#[test]
+fn test_steal_task() {
+    use crate::run_queue::AxRunQueue;
+    use crate::{AxCpuMask, TaskInner};
+
+    let _lock = SERIAL.lock();
+    INIT.call_once(axtask::init_scheduler);
+
+    let new_task = |name: &str, cpumask| {
+        let task = TaskInner::new(|| {}, name.into(), 0x1000);
+        task.set_cpumask(cpumask);
+        task.into_arc()
+    };
+
+    // The run queue of another CPU, whose tasks are never run here.
+    let victim = AxRunQueue::new(0);
+    let mut victim = victim.lock();
+    // Its gc task is pinned on CPU 0, take it out first.
+    assert_eq!(victim.take_task_for(0).unwrap().name(), "gc");
+
+    // The tasks behind a pinned one can still be stolen.
+    victim.add_task(new_task("pinned", AxCpuMask::new()));
+    victim.add_task(new_task("free", AxCpuMask::full()));
+    victim.add_task(new_task("free2", AxCpuMask::full()));
+    assert_eq!(victim.take_task_for(0).unwrap().name(), "free");
+    assert_eq!(victim.take_task_for(0).unwrap().name(), "free2");
+    // Not allowed to run here, it stays in the victim's queue.
+    assert!(victim.take_task_for(0).is_none());
+    assert!(victim.take_task_for(0).is_none());
+}
+
+#[test]
+fn test_cpu_time() {
+    let _lock = SERIAL.lock();
+    INIT.call_once(axtask::init_scheduler);
+
+    let before = axtask::total_cpu_time();
+    let task = axtask::spawn(|| {
+        axtask::yield_now(); // involuntary switch
+    }); // voluntary switch on exit
+    let id = task.id().as_u64();
+    assert!(axtask::find_task(id).is_some());
+    task.join();
+
+    let time = task.cpu_time();
+    assert_eq!(time.nivcsw, 1);
+    assert_eq!(time.nvcsw, 1);
+    assert!(axtask::ps().to_string().contains(&format!("{} ", id)));
+
+    drop(task);
+    while axtask::find_task(id).is_some() {
+        axtask::yield_now(); // wait for the gc task to drop it
+    }
+    let after = axtask::total_cpu_time();
+    assert!(after.nvcsw >= before.nvcsw + 1);
+    assert!(after.nivcsw >= before.nivcsw + 1);
+}
+
+#[test]
+fn test_cancel() {
+    let _lock = SERIAL.lock();
+    INIT.call_once(axtask::init_scheduler);
+
+    static WQ: WaitQueue = WaitQueue::new();
+    static STARTED: AtomicUsize = AtomicUsize::new(0);
+
+    let task = axtask::spawn(|| {
+        STARTED.fetch_add(1, Ordering::Relaxed);
+        assert_eq!(WQ.wait_cancellable(), Err(axtask::Cancelled));
+        assert!(current().is_cancelled());
+        // later cancellable waits return immediately
+        assert_eq!(WQ.wait_until_cancellable(|| false), Err(axtask::Cancelled));
+    });
+    while STARTED.load(Ordering::Relaxed) == 0 {
+        axtask::yield_now();
+    }
+    assert!(axtask::kill(task.id().as_u64()));
+    assert_eq!(task.join(), Some(axtask::EXIT_CANCELLED));
+    assert!(!axtask::cancel(&task)); // already exited
+
+    // not blocked in a cancellable wait, cancel it before it runs
+    let task = axtask::spawn(|| axtask::exit(1));
+    assert!(axtask::cancel(&task));
+    assert_eq!(task.join(), Some(axtask::EXIT_CANCELLED));
+}
+
+#[test]
+fn test_cancel_plain_wait() {
+    let _lock = SERIAL.lock();
+    INIT.call_once(axtask::init_scheduler);
+
+    static WQ: WaitQueue = WaitQueue::new();
+    static STARTED: AtomicUsize = AtomicUsize::new(0);
+
+    let task = axtask::spawn(|| {
+        STARTED.fetch_add(1, Ordering::Relaxed);
+        // not cancellable, but still woken up by the cancellation
+        WQ.wait();
+        assert!(current().is_cancelled());
+    });
+    while STARTED.load(Ordering::Relaxed) == 0 {
+        axtask::yield_now();
+    }
+    assert!(axtask::cancel(&task));
+    assert_eq!(task.join(), Some(axtask::EXIT_CANCELLED));
+    assert!(!WQ.notify_one(false)); // removed from the wait queue
+}
+
+#[cfg(feature = "sched_edf")]
+#[test]
+fn test_deadline_admission() {
+    use core::time::Duration;
+
+    let _lock = SERIAL.lock();
+    INIT.call_once(axtask::init_scheduler);
+
+    let params = |runtime_ms, period_ms| axtask::DeadlineParams {
+        runtime: Duration::from_millis(runtime_ms),
+        deadline: Duration::from_millis(period_ms),
+        period: Duration::from_millis(period_ms),
+    };
+    let curr = axtask::current();
+    let cpu_id = curr.cpu_id();
+    assert!(!axtask::set_deadline(params(20, 10), None)); // runtime > deadline
+    assert!(!axtask::set_deadline(params(100, 100), None)); // exceeds the bandwidth of a CPU
+    assert!(axtask::set_deadline(params(50, 100), None));
+    // Admitted on the current CPU, with the bandwidth of the old parameters
+    // given back.
+    assert!(axtask::set_deadline(params(90, 100), None));
+    assert!(!axtask::set_priority(0));
+
+    // Pinned to the CPU, whose bandwidth is reserved.
+    assert_eq!(curr.cpumask(), axtask::AxCpuMask::one_shot(cpu_id));
+    assert!(!axtask::set_affinity(axtask::AxCpuMask::full()));
+    axtask::wait_next_period();
+
+    // The affinity is restored, and the bandwidth released.
+    axtask::clear_deadline();
+    assert_eq!(curr.cpumask(), axtask::AxCpuMask::full());
+    assert!(axtask::set_deadline(params(90, 100), None));
+    axtask::clear_deadline();
+}
+
+#[test]
+fn test_workqueue() {
+    let _lock = SERIAL.lock();
+    INIT.call_once(axtask::init_scheduler);
+
+    static COUNT: AtomicUsize = AtomicUsize::new(0);
+
+    let work = axtask::Work::new(|| {
+        COUNT.fetch_add(1, Ordering::Relaxed);
+    });
+    assert!(axtask::queue_work(&work));
+    assert!(!axtask::queue_work(&work)); // already pending
+    assert!(work.is_pending());
+    axtask::flush_work(&work);
+    assert!(!work.is_pending());
+    assert_eq!(COUNT.load(Ordering::Relaxed), 1);
+
+    assert!(axtask::queue_work(&work));
+    assert!(axtask::cancel_work(&work));
+    assert!(!axtask::cancel_work(&work)); // not pending
+    axtask::flush_work(&work); // returns immediately
+    assert_eq!(COUNT.load(Ordering::Relaxed), 1);
+}
+
+#[test]
+fn test_workqueue_requeue_running() {
+    let _lock = SERIAL.lock();
+    INIT.call_once(axtask::init_scheduler);
+
+    static GATE: WaitQueue = WaitQueue::new();
+    static RELEASED: AtomicUsize = AtomicUsize::new(0);
+    static COUNT: AtomicUsize = AtomicUsize::new(0);
+    static RUNNING: AtomicUsize = AtomicUsize::new(0);
+
+    let work = axtask::Work::new(|| {
+        assert_eq!(RUNNING.fetch_add(1, Ordering::AcqRel), 0); // never concurrent
+        if COUNT.fetch_add(1, Ordering::Relaxed) == 0 {
+            // block in the first run, until requeued by the main task
+            GATE.wait_until(|| RELEASED.load(Ordering::Acquire) != 0);
+        }
+        RUNNING.fetch_sub(1, Ordering::AcqRel);
+    });
+    assert!(axtask::queue_work(&work));
+    while !work.is_running() {
+        axtask::yield_now();
+    }
+    assert!(!work.is_pending());
+
+    // requeue it to another CPU while it's running, it stays on its CPU
+    assert!(axtask::queue_work_on(axconfig::SMP - 1, &work));
+    assert!(!axtask::queue_work_on(0, &work)); // already pending
+    assert!(work.is_pending() && work.is_running());
+    assert_eq!(COUNT.load(Ordering::Relaxed), 1);
+
+    RELEASED.store(1, Ordering::Release);
+    GATE.notify_all(false);
+    axtask::flush_work(&work);
+    assert_eq!(COUNT.load(Ordering::Relaxed), 2);
+    assert!(!work.is_pending() && !work.is_running());
+}
+
+#[cfg(feature = "watchdog")]
+#[test]
+fn test_watchdog_hung_task() {
+    use crate::watchdog::check_hung_tasks;
+    use axhal::time::monotonic_time_nanos;
+
+    let _lock = SERIAL.lock();
+    INIT.call_once(axtask::init_scheduler);
+
+    static HUNG_WQ: WaitQueue = WaitQueue::new();
+    static BUSY_WQ: WaitQueue = WaitQueue::new();
+    static STOP: AtomicUsize = AtomicUsize::new(0);
+    const TIMEOUT_NS: u64 = 10_000_000;
+
+    let hung = axtask::spawn(|| HUNG_WQ.wait());
+    let busy = axtask::spawn(|| {
+        while STOP.load(Ordering::Acquire) == 0 {
+            BUSY_WQ.wait();
+        }
+    });
+    while !hung.is_blocked() || !busy.is_blocked() {
+        axtask::yield_now();
+    }
+
+    // Both have been blocked for the timeout, but the busy one makes progress
+    // just before the check.
+    let start = monotonic_time_nanos();
+    while monotonic_time_nanos() - start < TIMEOUT_NS {
+        core::hint::spin_loop();
+    }
+    assert!(BUSY_WQ.notify_one(false));
+    while !busy.is_blocked() {
+        axtask::yield_now();
+    }
+    assert!(check_hung_tasks(TIMEOUT_NS) >= 1);
+    assert!(hung.wait_site().is_reported());
+    assert!(!busy.wait_site().is_reported());
+    assert_eq!(check_hung_tasks(TIMEOUT_NS), 0); // reported once per hang
+
+    STOP.store(1, Ordering::Release);
+    BUSY_WQ.notify_one(false);
+    HUNG_WQ.notify_one(false);
+    busy.join();
+    hung.join();
+}
+
+#[cfg(feature = "watchdog")]
+#[test]
+fn test_watchdog_softlockup() {
+    use crate::watchdog::{check_softlockup, DEFAULT_SOFTLOCKUP_THRESHOLD};
+
+    let _lock = SERIAL.lock();
+    INIT.call_once(axtask::init_scheduler);
+
+    axtask::set_softlockup_threshold(3);
+    // Rescheduling between the ticks, never reported.
+    for _ in 0..10 {
+        assert!(!check_softlockup());
+        axtask::yield_now();
+    }
+    // Stuck for the threshold ticks, reported once.
+    assert!(!check_softlockup());
+    assert!(!check_softlockup());
+    assert!(check_softlockup());
+    assert!(!check_softlockup());
+    axtask::set_softlockup_threshold(DEFAULT_SOFTLOCKUP_THRESHOLD);
+}
+
+#[test]
+fn test_futex() {
+    use core::sync::atomic::AtomicU32;
+
+    let _lock = SERIAL.lock();
+    INIT.call_once(axtask::init_scheduler);
+
+    static FUTEX: AtomicU32 = AtomicU32::new(0);
+    static FUTEX2: AtomicU32 = AtomicU32::new(0);
+    static WOKEN: AtomicUsize = AtomicUsize::new(0);
+
+    assert_eq!(
+        axtask::futex_wait(&FUTEX, 1),
+        Err(axtask::FutexError::WouldBlock)
+    );
+    assert_eq!(axtask::futex_wake(&FUTEX, 1), 0);
+
+    const NUM_TASKS: usize = 3;
+    let tasks: Vec<_> = (0..NUM_TASKS)
+        .map(|_| {
+            axtask::spawn(|| {
+                assert_eq!(axtask::futex_wait(&FUTEX, 0), Ok(()));
+                WOKEN.fetch_add(1, Ordering::Relaxed);
+            })
+        })
+        .collect();
+    axtask::yield_now(); // let them wait
+
+    assert_eq!(
+        axtask::futex_requeue(&FUTEX, &FUTEX2, 1, 1, Some(1)),
+        Err(axtask::FutexError::WouldBlock)
+    );
+    // wake one, move one to `FUTEX2`, leave one on `FUTEX`
+    assert_eq!(axtask::futex_requeue(&FUTEX, &FUTEX2, 1, 1, Some(0)), Ok(2));
+    while WOKEN.load(Ordering::Relaxed) < 1 {
+        axtask::yield_now();
+    }
+    assert_eq!(axtask::futex_wake(&FUTEX2, usize::MAX), 1);
+    assert_eq!(axtask::futex_wake(&FUTEX, usize::MAX), 1);
+    for task in tasks {
+        task.join();
+    }
+    assert_eq!(WOKEN.load(Ordering::Relaxed), NUM_TASKS);
+}
@@ -1,40 +1,81 @@
 use alloc::sync::Arc;
+use axhal::cpu::this_cpu_id;
 use axhal::time::wall_time;
 use kspin::SpinNoIrq;
 use lazyinit::LazyInit;
 use timer_list::{TimeValue, TimerEvent, TimerList};
 
-use crate::{AxTaskRef, RUN_QUEUE};
+use crate::workqueue::Work;
+use crate::{current_run_queue, AxTaskRef};
 
-// TODO: per-CPU
-static TIMER_LIST: LazyInit<SpinNoIrq<TimerList<TaskWakeupEvent>>> = LazyInit::new();
+/// The timer lists of all CPUs, indexed by the CPU ID.
+///
+/// Events are set on the list of the current CPU and expire on its timer
+/// ticks, while they may be cancelled from any CPU.
+static TIMER_LISTS: [LazyInit<SpinNoIrq<TimerList<AxTimerEvent>>>; axconfig::SMP] =
+    [const { LazyInit::new() }; axconfig::SMP];
 
-struct TaskWakeupEvent(AxTaskRef);
+enum AxTimerEvent {
+    /// Wakes up a sleeping task.
+    TaskWakeup(AxTaskRef),
+    /// Queues a delayed work.
+    DelayedWork(Arc<Work>),
+}
 
-impl TimerEvent for TaskWakeupEvent {
+impl TimerEvent for AxTimerEvent {
     fn callback(self, _now: TimeValue) {
-        let mut rq = RUN_QUEUE.lock();
-        self.0.set_in_timer_list(false);
-        rq.unblock_task(self.0, true);
+        match self {
+            Self::TaskWakeup(task) => {
+                let mut rq = current_run_queue();
+                task.set_in_timer_list(false);
+                rq.unblock_task(task, true);
+            }
+            Self::DelayedWork(work) => crate::workqueue::delayed_work_expired(work),
+        }
+    }
+}
+
+fn local_timer_list() -> &'static SpinNoIrq<TimerList<AxTimerEvent>> {
+    // It does not matter if we are migrated after reading the CPU ID, the
+    // event will expire on that CPU instead.
+    &TIMER_LISTS[this_cpu_id()]
+}
+
+/// Cancels the events that match `cond` on all CPUs, as they may have been
+/// set on another CPU.
+fn cancel_events(cond: impl Fn(&AxTimerEvent) -> bool) {
+    for timers in TIMER_LISTS.iter().filter_map(|t| t.get()) {
+        timers.lock().cancel(&cond);
     }
 }
 
 pub fn set_alarm_wakeup(deadline: TimeValue, task: AxTaskRef) {
-    let mut timers = TIMER_LIST.lock();
+    let mut timers = local_timer_list().lock();
     task.set_in_timer_list(true);
-    timers.set(deadline, TaskWakeupEvent(task));
+    timers.set(deadline, AxTimerEvent::TaskWakeup(task));
 }
 
 pub fn cancel_alarm(task: &AxTaskRef) {
-    let mut timers = TIMER_LIST.lock();
     task.set_in_timer_list(false);
-    timers.cancel(|t| Arc::ptr_eq(&t.0, task));
+    cancel_events(|e| matches!(e, AxTimerEvent::TaskWakeup(t) if Arc::ptr_eq(t, task)));
+}
+
+pub fn set_work_timer(deadline: TimeValue, work: Arc<Work>) {
+    local_timer_list()
+        .lock()
+        .set(deadline, AxTimerEvent::DelayedWork(work));
+}
+
+pub fn cancel_work_timer(work: &Arc<Work>) {
+    cancel_events(|e| matches!(e, AxTimerEvent::DelayedWork(w) if Arc::ptr_eq(w, work)));
 }
 
+/// Handles the expired events on the current CPU.
 pub fn check_events() {
+    let timers = local_timer_list();
     loop {
         let now = wall_time();
-        let event = TIMER_LIST.lock().expire_one(now);
+        let event = timers.lock().expire_one(now);
         if let Some((_deadline, event)) = event {
             event.callback(now);
         } else {
@@ -43,6 +84,7 @@ pub fn check_events() {
     }
 }
 
+/// Initializes the timer list of the current CPU.
 pub fn init() {
-    TIMER_LIST.init_once(SpinNoIrq::new(TimerList::new()));
+    TIMER_LISTS[this_cpu_id()].init_once(SpinNoIrq::new(TimerList::new()));
 }
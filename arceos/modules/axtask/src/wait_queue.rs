@@ -2,7 +2,7 @@ use alloc::collections::VecDeque;
 use alloc::sync::Arc;
 use kspin::SpinRaw;
 
-use crate::{AxRunQueue, AxTaskRef, CurrentTask, RUN_QUEUE};
+use crate::{current_run_queue, AxRunQueue, AxTaskRef, Cancelled, CurrentTask};
 
 /// A queue to store sleeping tasks.
 ///
@@ -27,7 +27,7 @@ use crate::{AxRunQueue, AxTaskRef, CurrentTask, RUN_QUEUE};
 /// assert_eq!(VALUE.load(Ordering::Relaxed), 1);
 /// ```
 pub struct WaitQueue {
-    queue: SpinRaw<VecDeque<AxTaskRef>>, // we already disabled IRQs when lock the `RUN_QUEUE`
+    queue: SpinRaw<VecDeque<AxTaskRef>>, // we already disabled IRQs when lock the run queue
 }
 
 impl WaitQueue {
@@ -50,7 +50,7 @@ impl WaitQueue {
         // the event from another queue.
         if curr.in_wait_queue() {
             // wake up by timer (timeout).
-            // `RUN_QUEUE` is not locked here, so disable IRQs.
+            // The run queue is not locked here, so disable IRQs.
             let _guard = kernel_guard::IrqSave::new();
             self.queue.lock().retain(|t| !curr.ptr_eq(t));
             curr.set_in_wait_queue(false);
@@ -62,10 +62,41 @@ impl WaitQueue {
         }
     }
 
+    /// Records where the current task waits for the hung-task watchdog.
+    #[inline]
+    #[cfg_attr(feature = "watchdog", track_caller)]
+    fn record_wait_site(&self) {
+        #[cfg(feature = "watchdog")]
+        crate::watchdog::set_wait_site(
+            &crate::current(),
+            self,
+            Some(core::panic::Location::caller()),
+        );
+    }
+
+    /// Like [`wait`](Self::wait), but the wait is expected to be long and is
+    /// never reported by the hung-task watchdog.
+    pub(crate) fn wait_idle(&self) {
+        #[cfg(feature = "watchdog")]
+        crate::watchdog::set_wait_site(&crate::current(), self, None);
+        current_run_queue().block_current(|task| {
+            task.set_in_wait_queue(true);
+            self.queue.lock().push_back(task)
+        });
+        self.cancel_events(crate::current());
+    }
+
     /// Blocks the current task and put it into the wait queue, until other task
     /// notifies it.
+    ///
+    /// It also returns if the current task is cancelled during the wait (see
+    /// [`cancel`](crate::cancel)), check [`is_cancelled`] if it matters.
+    ///
+    /// [`is_cancelled`]: crate::TaskInner::is_cancelled
+    #[cfg_attr(feature = "watchdog", track_caller)]
     pub fn wait(&self) {
-        RUN_QUEUE.lock().block_current(|task| {
+        self.record_wait_site();
+        current_run_queue().block_current(|task| {
             task.set_in_wait_queue(true);
             self.queue.lock().push_back(task)
         });
@@ -77,18 +108,41 @@ impl WaitQueue {
     ///
     /// Note that even other tasks notify this task, it will not wake up until
     /// the condition becomes true.
+    #[cfg_attr(feature = "watchdog", track_caller)]
     pub fn wait_until<F>(&self, condition: F)
+    where
+        F: Fn() -> bool,
+    {
+        self.record_wait_site();
+        self.wait_until_inner(condition);
+    }
+
+    /// Like [`wait_until`](Self::wait_until), but the wait is expected to be
+    /// long and is never reported by the hung-task watchdog.
+    pub(crate) fn wait_until_idle<F>(&self, condition: F)
+    where
+        F: Fn() -> bool,
+    {
+        #[cfg(feature = "watchdog")]
+        crate::watchdog::set_wait_site(&crate::current(), self, None);
+        self.wait_until_inner(condition);
+    }
+
+    fn wait_until_inner<F>(&self, condition: F)
     where
         F: Fn() -> bool,
     {
         loop {
-            let mut rq = RUN_QUEUE.lock();
+            let mut rq = current_run_queue();
+            // Hold the wait queue lock while checking the condition, so that a
+            // notifier on another CPU cannot slip in before we are enqueued.
+            let mut wq = self.queue.lock();
             if condition() {
                 break;
             }
-            rq.block_current(|task| {
+            rq.block_current(move |task| {
                 task.set_in_wait_queue(true);
-                self.queue.lock().push_back(task);
+                wq.push_back(task);
             });
         }
         self.cancel_events(crate::current());
@@ -97,7 +151,9 @@ impl WaitQueue {
     /// Blocks the current task and put it into the wait queue, until other tasks
     /// notify it, or the given duration has elapsed.
     #[cfg(feature = "irq")]
+    #[cfg_attr(feature = "watchdog", track_caller)]
     pub fn wait_timeout(&self, dur: core::time::Duration) -> bool {
+        self.record_wait_site();
         let curr = crate::current();
         let deadline = axhal::time::wall_time() + dur;
         debug!(
@@ -105,11 +161,13 @@ impl WaitQueue {
             curr.id_name(),
             deadline
         );
-        crate::timers::set_alarm_wakeup(deadline, curr.clone());
 
-        RUN_QUEUE.lock().block_current(|task| {
+        current_run_queue().block_current(|task| {
             task.set_in_wait_queue(true);
-            self.queue.lock().push_back(task)
+            self.queue.lock().push_back(task.clone());
+            // Set the alarm after the task is blocked, otherwise the timer may
+            // expire on another CPU before that and the wakeup is lost.
+            crate::timers::set_alarm_wakeup(deadline, task);
         });
         let timeout = curr.in_wait_queue(); // still in the wait queue, must have timed out
         self.cancel_events(curr);
@@ -122,10 +180,12 @@ impl WaitQueue {
     /// Note that even other tasks notify this task, it will not wake up until
     /// the above conditions are met.
     #[cfg(feature = "irq")]
+    #[cfg_attr(feature = "watchdog", track_caller)]
     pub fn wait_timeout_until<F>(&self, dur: core::time::Duration, condition: F) -> bool
     where
         F: Fn() -> bool,
     {
+        self.record_wait_site();
         let curr = crate::current();
         let deadline = axhal::time::wall_time() + dur;
         debug!(
@@ -133,30 +193,125 @@ impl WaitQueue {
             curr.id_name(),
             deadline
         );
-        crate::timers::set_alarm_wakeup(deadline, curr.clone());
 
         let mut timeout = true;
         while axhal::time::wall_time() < deadline {
-            let mut rq = RUN_QUEUE.lock();
+            let mut rq = current_run_queue();
+            let mut wq = self.queue.lock();
             if condition() {
                 timeout = false;
                 break;
             }
-            rq.block_current(|task| {
+            rq.block_current(move |task| {
                 task.set_in_wait_queue(true);
-                self.queue.lock().push_back(task);
+                wq.push_back(task.clone());
+                if !task.in_timer_list() {
+                    crate::timers::set_alarm_wakeup(deadline, task);
+                }
             });
         }
         self.cancel_events(curr);
         timeout
     }
 
+    /// Blocks the current task and put it into the wait queue, until other task
+    /// notifies it, or the current task is cancelled.
+    ///
+    /// Returns [`Cancelled`] if the current task is cancelled before or during
+    /// the wait.
+    #[cfg_attr(feature = "watchdog", track_caller)]
+    pub fn wait_cancellable(&self) -> Result<(), Cancelled> {
+        self.record_wait_site();
+        let res = current_run_queue().block_current_cancellable(|task| {
+            task.set_in_wait_queue(true);
+            self.queue.lock().push_back(task)
+        });
+        self.cancel_events(crate::current());
+        res
+    }
+
+    /// Blocks the current task and put it into the wait queue, until the given
+    /// `condition` becomes true, or the current task is cancelled.
+    ///
+    /// Returns [`Cancelled`] if the current task is cancelled before or during
+    /// the wait.
+    #[cfg_attr(feature = "watchdog", track_caller)]
+    pub fn wait_until_cancellable<F>(&self, condition: F) -> Result<(), Cancelled>
+    where
+        F: Fn() -> bool,
+    {
+        self.record_wait_site();
+        let res = loop {
+            let mut rq = current_run_queue();
+            let mut wq = self.queue.lock();
+            if condition() {
+                break Ok(());
+            }
+            if let Err(e) = rq.block_current_cancellable(move |task| {
+                task.set_in_wait_queue(true);
+                wq.push_back(task);
+            }) {
+                break Err(e);
+            }
+        };
+        self.cancel_events(crate::current());
+        res
+    }
+
+    /// Blocks the current task and put it into the wait queue, until the given
+    /// `condition` becomes true, the given duration has elapsed, or the
+    /// current task is cancelled.
+    ///
+    /// Returns whether the wait timed out, or [`Cancelled`] if the current task
+    /// is cancelled before or during the wait.
+    #[cfg(feature = "irq")]
+    #[cfg_attr(feature = "watchdog", track_caller)]
+    pub fn wait_timeout_until_cancellable<F>(
+        &self,
+        dur: core::time::Duration,
+        condition: F,
+    ) -> Result<bool, Cancelled>
+    where
+        F: Fn() -> bool,
+    {
+        self.record_wait_site();
+        let curr = crate::current();
+        let deadline = axhal::time::wall_time() + dur;
+        debug!(
+            "task wait_timeout (cancellable): {}, deadline={:?}",
+            curr.id_name(),
+            deadline
+        );
+
+        let mut res = Ok(true);
+        while axhal::time::wall_time() < deadline {
+            let mut rq = current_run_queue();
+            let mut wq = self.queue.lock();
+            if condition() {
+                res = Ok(false);
+                break;
+            }
+            if let Err(e) = rq.block_current_cancellable(move |task| {
+                task.set_in_wait_queue(true);
+                wq.push_back(task.clone());
+                if !task.in_timer_list() {
+                    crate::timers::set_alarm_wakeup(deadline, task);
+                }
+            }) {
+                res = Err(e);
+                break;
+            }
+        }
+        self.cancel_events(curr);
+        res
+    }
+
     /// Wakes up one task in the wait queue, usually the first one.
     ///
     /// If `resched` is true, the current task will be preempted when the
     /// preemption is enabled.
     pub fn notify_one(&self, resched: bool) -> bool {
-        let mut rq = RUN_QUEUE.lock();
+        let mut rq = current_run_queue();
         if !self.queue.lock().is_empty() {
             self.notify_one_locked(resched, &mut rq)
         } else {
@@ -170,14 +325,14 @@ impl WaitQueue {
     /// preemption is enabled.
     pub fn notify_all(&self, resched: bool) {
         loop {
-            let mut rq = RUN_QUEUE.lock();
+            let mut rq = current_run_queue();
             if let Some(task) = self.queue.lock().pop_front() {
                 task.set_in_wait_queue(false);
                 rq.unblock_task(task, resched);
             } else {
                 break;
             }
-            drop(rq); // we must unlock `RUN_QUEUE` after unlocking `self.queue`.
+            drop(rq); // we must unlock the run queue after unlocking `self.queue`.
         }
     }
 
@@ -186,7 +341,7 @@ impl WaitQueue {
     /// If `resched` is true, the current task will be preempted when the
     /// preemption is enabled.
     pub fn notify_task(&mut self, resched: bool, task: &AxTaskRef) -> bool {
-        let mut rq = RUN_QUEUE.lock();
+        let mut rq = current_run_queue();
         let mut wq = self.queue.lock();
         if let Some(index) = wq.iter().position(|t| Arc::ptr_eq(t, task)) {
             task.set_in_wait_queue(false);
@@ -0,0 +1,193 @@
+//! Hung-task and soft-lockup detection.
+//!
+//! - A task is considered hung if it stays blocked on a [`WaitQueue`] for
+//!   longer than [`set_hung_task_timeout`] (timed waits are not counted). The
+//!   check runs in a `watchdog` task started by [`start_watchdog`].
+//! - A CPU is considered locked up if it does not pass through a scheduling
+//!   point for [`set_softlockup_threshold`] timer ticks. The check runs in
+//!   [`on_timer_tick`](crate::on_timer_tick).
+//!
+//! Both are reported with `error!` once per hang, naming the task and where it
+//! is blocked.
+//!
+//! [`WaitQueue`]: crate::WaitQueue
+
+use core::panic::Location;
+use core::ptr::null_mut;
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicU64, AtomicUsize, Ordering};
+use core::time::Duration;
+
+use axhal::time::monotonic_time_nanos;
+
+use crate::{TaskInner, WaitQueue};
+
+/// The default timeout for a task blocked on a wait queue to be reported.
+pub const DEFAULT_HUNG_TASK_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The default number of timer ticks without rescheduling for a CPU to be
+/// reported.
+pub const DEFAULT_SOFTLOCKUP_THRESHOLD: usize = axconfig::TICKS_PER_SEC * 10;
+
+static HUNG_TASK_TIMEOUT_NS: AtomicU64 =
+    AtomicU64::new(DEFAULT_HUNG_TASK_TIMEOUT.as_nanos() as u64);
+static SOFTLOCKUP_THRESHOLD: AtomicUsize = AtomicUsize::new(DEFAULT_SOFTLOCKUP_THRESHOLD);
+
+/// The number of timer ticks since the last scheduling point on this CPU.
+#[percpu::def_percpu]
+static TICKS_SINCE_RESCHED: usize = 0;
+
+/// Where a task is blocked, recorded on entry of the `WaitQueue` methods.
+pub(crate) struct WaitSite {
+    /// When the wait begins, in nanoseconds.
+    since: AtomicU64,
+    /// The address of the wait queue.
+    wq: AtomicUsize,
+    /// The caller of the wait method, null if the wait is not watched.
+    location: AtomicPtr<Location<'static>>,
+    /// The hang of this wait has been reported.
+    reported: AtomicBool,
+}
+
+impl WaitSite {
+    pub const fn new() -> Self {
+        Self {
+            since: AtomicU64::new(0),
+            wq: AtomicUsize::new(0),
+            location: AtomicPtr::new(null_mut()),
+            reported: AtomicBool::new(false),
+        }
+    }
+
+    /// Whether the hang of this wait has been reported.
+    #[cfg(test)]
+    pub fn is_reported(&self) -> bool {
+        self.reported.load(Ordering::Relaxed)
+    }
+}
+
+/// Records that the task is going to wait on `wq`, called from `location`.
+///
+/// If `location` is [`None`], the wait is never reported (e.g., the `gc`
+/// tasks that wait for exited tasks all the time).
+pub(crate) fn set_wait_site(
+    task: &TaskInner,
+    wq: &WaitQueue,
+    location: Option<&'static Location<'static>>,
+) {
+    let site = task.wait_site();
+    site.since.store(monotonic_time_nanos(), Ordering::Relaxed);
+    site.wq.store(wq as *const _ as usize, Ordering::Relaxed);
+    site.reported.store(false, Ordering::Relaxed);
+    site.location.store(
+        location.map_or(null_mut(), |l| l as *const _ as *mut _),
+        Ordering::Release,
+    );
+}
+
+/// Records that the task is going to wait somewhere that is never reported
+/// (e.g., on a futex).
+pub(crate) fn clear_wait_site(task: &TaskInner) {
+    task.wait_site()
+        .location
+        .store(null_mut(), Ordering::Release);
+}
+
+/// Sets the timeout for a task blocked on a wait queue to be reported, or
+/// disables the hung-task check if it is zero.
+pub fn set_hung_task_timeout(timeout: Duration) {
+    HUNG_TASK_TIMEOUT_NS.store(timeout.as_nanos() as u64, Ordering::Relaxed);
+}
+
+/// Sets the number of timer ticks without rescheduling for a CPU to be
+/// reported, or disables the soft-lockup check if it is zero.
+pub fn set_softlockup_threshold(ticks: usize) {
+    SOFTLOCKUP_THRESHOLD.store(ticks, Ordering::Relaxed);
+}
+
+/// Called at every scheduling point of the current CPU.
+pub(crate) fn touch_softlockup() {
+    // Safety: IRQs are disabled while the run queue is locked.
+    unsafe { TICKS_SINCE_RESCHED.write_current_raw(0) };
+}
+
+/// Called on every timer tick of the current CPU.
+///
+/// Returns whether a soft lockup is reported on this tick.
+pub(crate) fn check_softlockup() -> bool {
+    // Safety: IRQs are disabled in the timer IRQ handler.
+    let ticks = unsafe { TICKS_SINCE_RESCHED.read_current_raw() } + 1;
+    unsafe { TICKS_SINCE_RESCHED.write_current_raw(ticks) };
+
+    let threshold = SOFTLOCKUP_THRESHOLD.load(Ordering::Relaxed);
+    if threshold != 0 && ticks == threshold {
+        let curr = crate::current();
+        error!(
+            "watchdog: soft lockup - CPU#{} stuck for {}ms! running {}",
+            axhal::cpu::this_cpu_id(),
+            ticks as u64 * 1000 / axconfig::TICKS_PER_SEC as u64,
+            curr.id_name(),
+        );
+        return true;
+    }
+    false
+}
+
+/// Reports the tasks blocked for `timeout_ns` that are not reported yet, and
+/// returns the number of them.
+pub(crate) fn check_hung_tasks(timeout_ns: u64) -> usize {
+    let now = monotonic_time_nanos();
+    let mut reported = 0;
+    for task in crate::all_tasks() {
+        // Only untimed waits on wait queues are counted.
+        if !task.is_blocked() || !task.in_wait_queue() || task.in_timer_list() {
+            continue;
+        }
+        let site = task.wait_site();
+        let location = site.location.load(Ordering::Acquire);
+        if location.is_null() || site.reported.load(Ordering::Relaxed) {
+            continue;
+        }
+        let blocked_ns = now.saturating_sub(site.since.load(Ordering::Relaxed));
+        if blocked_ns >= timeout_ns {
+            site.reported.store(true, Ordering::Relaxed);
+            // Safety: it is always set from a `&'static Location`.
+            let location = unsafe { &*location };
+            error!(
+                "watchdog: {} blocked for more than {}ms on WaitQueue@{:#x} at {}",
+                task.id_name(),
+                blocked_ns / 1_000_000,
+                site.wq.load(Ordering::Relaxed),
+                location,
+            );
+            reported += 1;
+        }
+    }
+    reported
+}
+
+/// Spawns the `watchdog` task that checks for hung tasks periodically.
+///
+/// The soft-lockup check does not need it, it works once the timer IRQ is
+/// enabled.
+pub fn start_watchdog() {
+    crate::spawn_raw(
+        || {
+            while !crate::current().is_cancelled() {
+                let timeout_ns = HUNG_TASK_TIMEOUT_NS.load(Ordering::Relaxed);
+                if timeout_ns != 0 {
+                    check_hung_tasks(timeout_ns);
+                }
+                // Check twice in a timeout, so a hang is reported in at most
+                // 1.5 times of the timeout.
+                let interval_ns = if timeout_ns != 0 {
+                    timeout_ns / 2
+                } else {
+                    DEFAULT_HUNG_TASK_TIMEOUT.as_nanos() as u64
+                };
+                crate::sleep(Duration::from_nanos(interval_ns));
+            }
+        },
+        "watchdog".into(),
+        axconfig::TASK_STACK_SIZE,
+    );
+}
@@ -0,0 +1,261 @@
+//! Workqueues for deferring work out of interrupt context.
+//!
+//! Each CPU has a worker task (`kworker/<cpu>`) bound to it, which runs the
+//! [`Work`] items queued on this CPU one by one in task context. Works can be
+//! queued from anywhere including IRQ handlers, optionally after a delay.
+//!
+//! A work is never queued twice: queueing a work that is already pending does
+//! nothing. But a running work can be queued again, and it will run once more.
+//! It's always queued on the CPU it is running on then, so that it never runs
+//! on two CPUs at the same time.
+
+use alloc::{boxed::Box, collections::VecDeque, format, sync::Arc};
+use core::fmt;
+use core::sync::atomic::{AtomicU8, AtomicUsize, Ordering};
+
+use axhal::cpu::this_cpu_id;
+use kernel_guard::NoPreemptIrqSave;
+use kspin::SpinNoIrq;
+
+use crate::{AxCpuMask, TaskInner, WaitQueue};
+
+const SMP: usize = axconfig::SMP;
+
+/// The work is queued or waiting for its timer.
+const PENDING: u8 = 1 << 0;
+/// The work is being executed by a worker.
+const RUNNING: u8 = 1 << 1;
+/// The work is waiting for its timer.
+#[cfg(feature = "irq")]
+const DELAYED: u8 = 1 << 2;
+
+/// Pending works of each CPU. The states of a work are only changed with the
+/// lock of the CPU it is queued on held, except for clearing [`RUNNING`].
+static WORK_QUEUES: [SpinNoIrq<VecDeque<Arc<Work>>>; SMP] =
+    [const { SpinNoIrq::new(VecDeque::new()) }; SMP];
+
+/// Where the worker of each CPU waits for new works.
+static WORKER_WQ: [WaitQueue; SMP] = [const { WaitQueue::new() }; SMP];
+
+/// Where tasks wait for works to complete.
+static FLUSH_WQ: WaitQueue = WaitQueue::new();
+
+/// A work item that can be deferred to a worker task.
+///
+/// # Examples
+///
+/// ```ignore
+/// static RX_WORK: LazyInit<Arc<Work>> = LazyInit::new();
+///
+/// RX_WORK.init_once(Work::new(|| process_packets()));
+/// // in the IRQ handler
+/// axtask::queue_work(&RX_WORK);
+/// ```
+pub struct Work {
+    func: Box<dyn Fn() + Send + Sync>,
+    state: AtomicU8,
+    /// The CPU that the work is queued on last time.
+    cpu_id: AtomicUsize,
+}
+
+impl Work {
+    /// Creates a new work that runs the given function.
+    pub fn new<F>(func: F) -> Arc<Self>
+    where
+        F: Fn() + Send + Sync + 'static,
+    {
+        Arc::new(Self {
+            func: Box::new(func),
+            state: AtomicU8::new(0),
+            cpu_id: AtomicUsize::new(0),
+        })
+    }
+
+    /// Whether the work is queued or waiting for its timer.
+    pub fn is_pending(&self) -> bool {
+        self.state.load(Ordering::Acquire) & PENDING != 0
+    }
+
+    /// Whether the work is being executed by a worker.
+    pub fn is_running(&self) -> bool {
+        self.state.load(Ordering::Acquire) & RUNNING != 0
+    }
+
+    fn is_idle(&self) -> bool {
+        self.state.load(Ordering::Acquire) & (PENDING | RUNNING) == 0
+    }
+
+    /// Locks the queue of the CPU that the work is queued on last time.
+    fn lock_queue(&self) -> kspin::SpinNoIrqGuard<'static, VecDeque<Arc<Work>>> {
+        loop {
+            let cpu_id = self.cpu_id.load(Ordering::Acquire);
+            let queue = WORK_QUEUES[cpu_id].lock();
+            // It may be queued on another CPU before we get the lock.
+            if cpu_id == self.cpu_id.load(Ordering::Acquire) {
+                return queue;
+            }
+        }
+    }
+}
+
+impl fmt::Debug for Work {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Work")
+            .field("pending", &self.is_pending())
+            .field("running", &self.is_running())
+            .finish()
+    }
+}
+
+/// Queues the work on the current CPU.
+///
+/// It can be called in IRQ context. Returns `false` if the work is already
+/// pending.
+pub fn queue_work(work: &Arc<Work>) -> bool {
+    let _guard = NoPreemptIrqSave::new();
+    queue_work_on(this_cpu_id(), work)
+}
+
+/// Queues the work on the given CPU.
+///
+/// It can be called in IRQ context. Returns `false` if the work is already
+/// pending. If the work is running, it's queued on the CPU it is running on
+/// instead.
+pub fn queue_work_on(cpu_id: usize, work: &Arc<Work>) -> bool {
+    assert!(cpu_id < SMP);
+    let mut queue = work.lock_queue();
+    if work.state.fetch_or(PENDING, Ordering::AcqRel) & PENDING != 0 {
+        return false;
+    }
+    let last_cpu = work.cpu_id.load(Ordering::Acquire);
+    let target_cpu = if work.is_running() || cpu_id == last_cpu {
+        queue.push_back(work.clone());
+        last_cpu
+    } else {
+        drop(queue);
+        // Nobody else can touch a pending work that is in no queue.
+        let mut queue = WORK_QUEUES[cpu_id].lock();
+        work.cpu_id.store(cpu_id, Ordering::Release);
+        queue.push_back(work.clone());
+        cpu_id
+    };
+    // Wake the worker of the CPU it is actually queued on.
+    WORKER_WQ[target_cpu].notify_one(false);
+    true
+}
+
+/// Queues the work on the current CPU after the given delay.
+///
+/// It can be called in IRQ context. Returns `false` if the work is already
+/// pending.
+#[cfg(feature = "irq")]
+pub fn queue_delayed_work(work: &Arc<Work>, delay: core::time::Duration) -> bool {
+    if delay.is_zero() {
+        return queue_work(work);
+    }
+    let _guard = NoPreemptIrqSave::new();
+    let cpu_id = this_cpu_id();
+    let _queue = work.lock_queue();
+    if work.state.fetch_or(PENDING, Ordering::AcqRel) & PENDING != 0 {
+        return false;
+    }
+    // A running work stays on its CPU, see `queue_work_on`.
+    if !work.is_running() {
+        work.cpu_id.store(cpu_id, Ordering::Release);
+    }
+    work.state.fetch_or(DELAYED, Ordering::AcqRel);
+    crate::timers::set_work_timer(axhal::time::wall_time() + delay, work.clone());
+    true
+}
+
+/// Called by the timer when a delayed work expires.
+#[cfg(feature = "irq")]
+pub(crate) fn delayed_work_expired(work: Arc<Work>) {
+    let mut queue = work.lock_queue();
+    // It may have been cancelled after the timer expired.
+    if work.state.fetch_and(!DELAYED, Ordering::AcqRel) & DELAYED != 0 {
+        let cpu_id = work.cpu_id.load(Ordering::Acquire);
+        queue.push_back(work);
+        drop(queue);
+        WORKER_WQ[cpu_id].notify_one(false);
+    }
+}
+
+/// Cancels a pending work, without waiting for it to complete if it is
+/// running.
+///
+/// It can be called in IRQ context. Returns `true` if the work was pending.
+pub fn cancel_work(work: &Arc<Work>) -> bool {
+    let mut queue = work.lock_queue();
+    #[cfg(feature = "irq")]
+    if work.state.fetch_and(!DELAYED, Ordering::AcqRel) & DELAYED != 0 {
+        crate::timers::cancel_work_timer(work);
+        work.state.fetch_and(!PENDING, Ordering::AcqRel);
+        drop(queue);
+        FLUSH_WQ.notify_all(false);
+        return true;
+    }
+    if let Some(idx) = queue.iter().position(|w| Arc::ptr_eq(w, work)) {
+        queue.remove(idx);
+        work.state.fetch_and(!PENDING, Ordering::AcqRel);
+        drop(queue);
+        FLUSH_WQ.notify_all(false);
+        return true;
+    }
+    false
+}
+
+/// Cancels a pending work, and waits for it to complete if it is running.
+///
+/// Returns `true` if the work was pending. It must not be called in IRQ
+/// context, or by the work itself.
+pub fn cancel_work_sync(work: &Arc<Work>) -> bool {
+    let pending = cancel_work(work);
+    FLUSH_WQ.wait_until(|| !work.is_running());
+    pending
+}
+
+/// Waits for the work to complete, including the pending execution.
+///
+/// It must not be called in IRQ context, or by a work queued on the same
+/// CPU, otherwise it will deadlock.
+pub fn flush_work(work: &Arc<Work>) {
+    FLUSH_WQ.wait_until(|| work.is_idle());
+}
+
+fn worker_entry(cpu_id: usize) {
+    let queue = &WORK_QUEUES[cpu_id];
+    loop {
+        WORKER_WQ[cpu_id].wait_until_idle(|| !queue.lock().is_empty());
+        loop {
+            let work = {
+                let mut queue = queue.lock();
+                let work = queue.pop_front();
+                if let Some(work) = &work {
+                    // PENDING -> RUNNING, never idle in between for `flush_work`.
+                    work.state.fetch_or(RUNNING, Ordering::AcqRel);
+                    work.state.fetch_and(!PENDING, Ordering::AcqRel);
+                }
+                work
+            };
+            let Some(work) = work else {
+                break;
+            };
+            (work.func)();
+            work.state.fetch_and(!RUNNING, Ordering::AcqRel);
+            FLUSH_WQ.notify_all(false);
+        }
+    }
+}
+
+/// Spawns the worker task of the current CPU.
+pub(crate) fn init_worker() {
+    let cpu_id = this_cpu_id();
+    let worker = TaskInner::new(
+        move || worker_entry(cpu_id),
+        format!("kworker/{}", cpu_id),
+        axconfig::TASK_STACK_SIZE,
+    );
+    worker.set_cpumask(AxCpuMask::one_shot(cpu_id));
+    crate::spawn_task(worker);
+}
@@ -46,6 +46,16 @@ impl<const PAGE_SIZE: usize> EarlyAllocator<PAGE_SIZE> {
             page_count: 0,
         }
     }
+
+    /// Returns the end of the byte allocations, `b_pos`.
+    pub const fn bytes_end(&self) -> usize {
+        self.b_pos
+    }
+
+    /// Returns the start of the page allocations, `p_pos`.
+    pub const fn pages_start(&self) -> usize {
+        self.p_pos
+    }
 }
 
 impl<const PAGE_SIZE: usize> EarlyAllocator<PAGE_SIZE> {
@@ -18,6 +18,10 @@ build_args := \
   $(verbose)
 
 RUSTFLAGS := -C link-arg=-T$(LD_SCRIPT) -C link-arg=-no-pie -C link-arg=-znostart-stop-gc
+ifneq ($(filter alloc-trace,$(FEATURES) $(APP_FEAT)),)
+  # The callers of the allocations are found by the frame pointers.
+  RUSTFLAGS += -C force-frame-pointers=yes
+endif
 RUSTDOCFLAGS := -Z unstable-options --enable-index-page -D rustdoc::broken_intra_doc_links
 
 ifeq ($(MAKECMDGOALS), doc_check_missing)
@@ -2,9 +2,14 @@
 
 use axhal::arch::TrapFrame;
 use axhal::trap::{register_trap_handler, SYSCALL};
-use axerrno::LinuxError;
+use axerrno::{AxResult, LinuxError};
+use axmm::AddrSpace;
+use axtask::current;
+use axtask::TaskExtRef;
+use arceos_posix_api as api;
 
 const SYS_EXIT: usize = 93;
+const SYS_FUTEX: usize = 98;
 
 #[register_trap_handler(SYSCALL)]
 fn handle_syscall(tf: &TrapFrame, syscall_num: usize) -> isize {
@@ -14,6 +19,16 @@ fn handle_syscall(tf: &TrapFrame, syscall_num: usize) -> isize {
             ax_println!("[SYS_EXIT]: system is exiting ..");
             axtask::exit(tf.arg0() as _)
         },
+        SYS_FUTEX => unsafe {
+            api::sys_futex(
+                tf.arg0() as _,
+                tf.arg1() as _,
+                tf.arg2() as _,
+                tf.arg3() as _,
+                tf.arg4() as _,
+                tf.arg5() as _,
+            ) as _
+        },
         _ => {
             ax_println!("Unimplemented syscall: {}", syscall_num);
             -LinuxError::ENOSYS.code() as _
@@ -21,3 +36,10 @@ fn handle_syscall(tf: &TrapFrame, syscall_num: usize) -> isize {
     };
     ret
 }
+
+/// Runs the calls of `arceos_posix_api` that access the user memory (e.g.,
+/// the futex words) on the address space of the current process.
+#[linkme::distributed_slice(api::CURRENT_ASPACE)]
+fn with_current_aspace(f: &mut dyn FnMut(&mut AddrSpace) -> AxResult) -> AxResult {
+    f(&mut current().task_ext().aspace.lock())
+}
@@ -3,7 +3,10 @@
 use core::ffi::c_void;
 use axhal::arch::TrapFrame;
 use axhal::trap::{register_trap_handler, SYSCALL};
-use axerrno::LinuxError;
+use axerrno::{AxResult, LinuxError};
+use axmm::AddrSpace;
+use axmm::uaccess::{UserPtr, UserSlice};
+use alloc::vec;
 use axtask::current;
 use axtask::TaskExtRef;
 use arceos_posix_api as api;
@@ -13,6 +16,12 @@ const SYS_WRITEV: usize = 66;
 const SYS_EXIT: usize = 93;
 const SYS_EXIT_GROUP: usize = 94;
 const SYS_SET_TID_ADDRESS: usize = 96;
+const SYS_FUTEX: usize = 98;
+
+const IOV_MAX: i32 = 1024;
+
+/// The maximum size of a kernel buffer for writing files.
+const MAX_IO_SIZE: usize = 0x10000;
 
 #[register_trap_handler(SYSCALL)]
 fn handle_syscall(tf: &TrapFrame, syscall_num: usize) -> isize {
@@ -29,6 +38,16 @@ fn handle_syscall(tf: &TrapFrame, syscall_num: usize) -> isize {
             ax_println!("[SYS_EXIT]: system is exiting ..");
             axtask::exit(tf.arg0() as _)
         },
+        SYS_FUTEX => unsafe {
+            api::sys_futex(
+                tf.arg0() as _,
+                tf.arg1() as _,
+                tf.arg2() as _,
+                tf.arg3() as _,
+                tf.arg4() as _,
+                tf.arg5() as _,
+            ) as _
+        },
         _ => {
             ax_println!("Unimplemented syscall: {}", syscall_num);
             -LinuxError::ENOSYS.code() as _
@@ -37,8 +56,55 @@ fn handle_syscall(tf: &TrapFrame, syscall_num: usize) -> isize {
     ret
 }
 
+/// Writes `count` bytes at the user address `buf` to the file, through a
+/// kernel buffer.
+fn write_user(fd: i32, buf: usize, count: usize) -> isize {
+    let task = current();
+    let mut kbuf = vec![0u8; count.min(MAX_IO_SIZE)];
+    let mut written = 0;
+    while written < count {
+        let chunk = (count - written).min(kbuf.len());
+        let ubuf = UserSlice::new(buf + written, chunk);
+        if let Err(e) = ubuf.read(&mut task.task_ext().aspace.lock(), &mut kbuf[..chunk]) {
+            return match written {
+                0 => -LinuxError::from(e).code() as isize,
+                _ => written as isize,
+            };
+        }
+        let n = api::sys_write(fd, kbuf.as_ptr() as _, chunk);
+        if n < 0 {
+            return if written == 0 { n } else { written as isize };
+        }
+        written += n as usize;
+        if (n as usize) < chunk {
+            break;
+        }
+    }
+    written as isize
+}
+
 fn sys_writev(fd: i32, iov: *const api::ctypes::iovec, iocnt: i32) -> isize {
-    unsafe { api::sys_writev(fd, iov, iocnt) }
+    if !(0..=IOV_MAX).contains(&iocnt) {
+        return -LinuxError::EINVAL.code() as isize;
+    }
+    let task = current();
+    let iov = UserPtr::<api::ctypes::iovec>::new(iov as usize);
+    let mut written = 0;
+    for i in 0..iocnt as usize {
+        let ent = match iov.add(i).read(&mut task.task_ext().aspace.lock()) {
+            Ok(ent) => ent,
+            Err(e) => return -LinuxError::from(e).code() as isize,
+        };
+        let n = write_user(fd, ent.iov_base as usize, ent.iov_len);
+        if n < 0 {
+            return if written == 0 { n } else { written };
+        }
+        written += n;
+        if (n as usize) < ent.iov_len {
+            break;
+        }
+    }
+    written
 }
 
 pub(crate) fn sys_set_tid_address(tid_ptd: *const i32) -> isize {
@@ -51,3 +117,10 @@ fn sys_ioctl(_fd: i32, _op: usize, _argp: *mut c_void) -> i32 {
     ax_println!("Unimplemented syscall: SYS_IOCTL");
     0
 }
+
+/// Runs the calls of `arceos_posix_api` that access the user memory (e.g.,
+/// the futex words) on the address space of the current process.
+#[linkme::distributed_slice(api::CURRENT_ASPACE)]
+fn with_current_aspace(f: &mut dyn FnMut(&mut AddrSpace) -> AxResult) -> AxResult {
+    f(&mut current().task_ext().aspace.lock())
+}
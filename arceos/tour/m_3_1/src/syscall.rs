@@ -3,7 +3,11 @@
 use core::ffi::{c_void, c_char, c_int};
 use axhal::arch::TrapFrame;
 use axhal::trap::{register_trap_handler, SYSCALL};
-use axerrno::LinuxError;
+use axerrno::{AxResult, LinuxError};
+use axmm::AddrSpace;
+use axmm::uaccess::{UserPtr, UserSlice};
+use axhal::paging::MappingFlags;
+use alloc::vec;
 use axtask::current;
 use axtask::TaskExtRef;
 use arceos_posix_api as api;
@@ -17,8 +21,14 @@ const SYS_WRITEV: usize = 66;
 const SYS_EXIT: usize = 93;
 const SYS_EXIT_GROUP: usize = 94;
 const SYS_SET_TID_ADDRESS: usize = 96;
+const SYS_FUTEX: usize = 98;
 
 const AT_FDCWD: i32 = -100;
+const PATH_MAX: usize = 4096;
+const IOV_MAX: i32 = 1024;
+
+/// The maximum size of a kernel buffer for reading or writing files.
+const MAX_IO_SIZE: usize = 0x10000;
 
 #[register_trap_handler(SYSCALL)]
 fn handle_syscall(tf: &TrapFrame, syscall_num: usize) -> isize {
@@ -39,6 +49,16 @@ fn handle_syscall(tf: &TrapFrame, syscall_num: usize) -> isize {
             ax_println!("[SYS_EXIT]: system is exiting ..");
             axtask::exit(tf.arg0() as _)
         },
+        SYS_FUTEX => unsafe {
+            api::sys_futex(
+                tf.arg0() as _,
+                tf.arg1() as _,
+                tf.arg2() as _,
+                tf.arg3() as _,
+                tf.arg4() as _,
+                tf.arg5() as _,
+            ) as _
+        },
         _ => {
             ax_println!("Unimplemented syscall: {}", syscall_num);
             -LinuxError::ENOSYS.code() as _
@@ -49,7 +69,16 @@ fn handle_syscall(tf: &TrapFrame, syscall_num: usize) -> isize {
 
 fn sys_openat(dfd: c_int, fname: *const c_char, flags: c_int, mode: api::ctypes::mode_t) -> isize {
     assert_eq!(dfd, AT_FDCWD);
-    api::sys_open(fname, flags, mode) as isize
+    let task = current();
+    let mut uspace = task.task_ext().aspace.lock();
+    let path = UserPtr::<u8>::new(fname as usize).read_cstr(&mut uspace, PATH_MAX);
+    drop(uspace);
+    let mut path = match path {
+        Ok(path) => path.into_bytes(),
+        Err(e) => return -LinuxError::from(e).code() as isize,
+    };
+    path.push(0);
+    api::sys_open(path.as_ptr() as _, flags, mode) as isize
 }
 
 fn sys_close(fd: i32) -> isize {
@@ -57,15 +86,80 @@ fn sys_close(fd: i32) -> isize {
 }
 
 fn sys_read(fd: i32, buf: *mut c_void, count: usize) -> isize {
-    api::sys_read(fd, buf, count)
+    let task = current();
+    let aspace = &task.task_ext().aspace;
+    let ubuf = UserSlice::new(buf as usize, count.min(MAX_IO_SIZE));
+    // Check the buffer first, not to lose the data read.
+    let res = aspace
+        .lock()
+        .fault_in_user(ubuf.addr(), ubuf.len(), MappingFlags::WRITE);
+    if let Err(e) = res {
+        return -LinuxError::from(e).code() as isize;
+    }
+    let mut kbuf = vec![0u8; ubuf.len()];
+    let n = api::sys_read(fd, kbuf.as_mut_ptr() as _, kbuf.len());
+    if n <= 0 {
+        return n;
+    }
+    match ubuf.write(&mut aspace.lock(), &kbuf[..n as usize]) {
+        Ok(()) => n,
+        Err(e) => -LinuxError::from(e).code() as isize,
+    }
+}
+
+/// Writes `count` bytes at the user address `buf` to the file, through a
+/// kernel buffer.
+fn write_user(fd: i32, buf: usize, count: usize) -> isize {
+    let task = current();
+    let mut kbuf = vec![0u8; count.min(MAX_IO_SIZE)];
+    let mut written = 0;
+    while written < count {
+        let chunk = (count - written).min(kbuf.len());
+        let ubuf = UserSlice::new(buf + written, chunk);
+        if let Err(e) = ubuf.read(&mut task.task_ext().aspace.lock(), &mut kbuf[..chunk]) {
+            return match written {
+                0 => -LinuxError::from(e).code() as isize,
+                _ => written as isize,
+            };
+        }
+        let n = api::sys_write(fd, kbuf.as_ptr() as _, chunk);
+        if n < 0 {
+            return if written == 0 { n } else { written as isize };
+        }
+        written += n as usize;
+        if (n as usize) < chunk {
+            break;
+        }
+    }
+    written as isize
 }
 
 fn sys_write(fd: i32, buf: *const c_void, count: usize) -> isize {
-    api::sys_write(fd, buf, count)
+    write_user(fd, buf as usize, count)
 }
 
 fn sys_writev(fd: i32, iov: *const api::ctypes::iovec, iocnt: i32) -> isize {
-    unsafe { api::sys_writev(fd, iov, iocnt) }
+    if !(0..=IOV_MAX).contains(&iocnt) {
+        return -LinuxError::EINVAL.code() as isize;
+    }
+    let task = current();
+    let iov = UserPtr::<api::ctypes::iovec>::new(iov as usize);
+    let mut written = 0;
+    for i in 0..iocnt as usize {
+        let ent = match iov.add(i).read(&mut task.task_ext().aspace.lock()) {
+            Ok(ent) => ent,
+            Err(e) => return -LinuxError::from(e).code() as isize,
+        };
+        let n = write_user(fd, ent.iov_base as usize, ent.iov_len);
+        if n < 0 {
+            return if written == 0 { n } else { written };
+        }
+        written += n;
+        if (n as usize) < ent.iov_len {
+            break;
+        }
+    }
+    written
 }
 
 fn sys_set_tid_address(tid_ptd: *const i32) -> isize {
@@ -78,3 +172,10 @@ fn sys_ioctl(_fd: i32, _op: usize, _argp: *mut c_void) -> i32 {
     ax_println!("Ignore SYS_IOCTL");
     0
 }
+
+/// Runs the calls of `arceos_posix_api` that access the user memory (e.g.,
+/// the futex words) on the address space of the current process.
+#[linkme::distributed_slice(api::CURRENT_ASPACE)]
+fn with_current_aspace(f: &mut dyn FnMut(&mut AddrSpace) -> AxResult) -> AxResult {
+    f(&mut current().task_ext().aspace.lock())
+}
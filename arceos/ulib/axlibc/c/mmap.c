@@ -31,9 +31,13 @@ int mprotect(void *addr, size_t len, int prot)
     return 0;
 }
 
+#ifndef AX_CONFIG_MMAN
+
 // TODO
 int madvise(void *addr, size_t len, int advice)
 {
     unimplemented();
     return 0;
 }
+
+#endif // AX_CONFIG_MMAN
@@ -10,6 +10,7 @@
 //! - Memory
 //!     - `alloc`: Enable dynamic memory allocation.
 //!     - `tls`: Enable thread-local storage.
+//!     - `mman`: Enable memory advice and locking ([madvise], [mlock]).
 //! - Task management
 //!     - `multitask`: Enable multi-threading support.
 //! - Upperlayer stacks
@@ -24,6 +25,8 @@
 //! [ArceOS]: https://github.com/arceos-org/arceos
 //! [select]: https://man7.org/linux/man-pages/man2/select.2.html
 //! [epoll]: https://man7.org/linux/man-pages/man7/epoll.7.html
+//! [madvise]: https://man7.org/linux/man-pages/man2/madvise.2.html
+//! [mlock]: https://man7.org/linux/man-pages/man2/mlock.2.html
 
 #![cfg_attr(all(not(test), not(doc)), no_std)]
 #![feature(doc_cfg)]
@@ -57,6 +60,8 @@ mod fs;
 mod io_mpx;
 #[cfg(feature = "alloc")]
 mod malloc;
+#[cfg(feature = "mman")]
+mod mman;
 #[cfg(feature = "net")]
 mod net;
 #[cfg(feature = "pipe")]
@@ -73,6 +78,7 @@ mod io;
 mod mktime;
 mod rand;
 mod resource;
+mod sched;
 mod setjmp;
 mod sys;
 mod time;
@@ -85,10 +91,11 @@ pub use self::io::{read, writev};
 pub use self::errno::strerror;
 pub use self::mktime::mktime;
 pub use self::rand::{rand, random, srand};
-pub use self::resource::{getrlimit, setrlimit};
+pub use self::resource::{getrlimit, getrusage, setrlimit};
+pub use self::sched::{sched_getaffinity, sched_setaffinity};
 pub use self::setjmp::{longjmp, setjmp};
 pub use self::sys::sysconf;
-pub use self::time::{clock_gettime, nanosleep};
+pub use self::time::{clock_gettime, nanosleep, times};
 pub use self::unistd::{abort, exit, getpid};
 
 #[cfg(feature = "alloc")]
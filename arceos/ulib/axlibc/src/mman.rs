@@ -0,0 +1,23 @@
+use core::ffi::{c_int, c_void};
+
+use arceos_posix_api::{sys_madvise, sys_mlock, sys_munlock};
+
+use crate::utils::e;
+
+/// Give advice about the use of memory
+#[no_mangle]
+pub unsafe extern "C" fn madvise(addr: *mut c_void, len: usize, advice: c_int) -> c_int {
+    e(sys_madvise(addr, len as _, advice))
+}
+
+/// Lock the pages in memory
+#[no_mangle]
+pub unsafe extern "C" fn mlock(addr: *const c_void, len: usize) -> c_int {
+    e(sys_mlock(addr, len as _))
+}
+
+/// Unlock the pages locked by `mlock`
+#[no_mangle]
+pub unsafe extern "C" fn munlock(addr: *const c_void, len: usize) -> c_int {
+    e(sys_munlock(addr, len as _))
+}
@@ -0,0 +1,25 @@
+use core::ffi::c_int;
+
+use arceos_posix_api::{sys_sched_getaffinity, sys_sched_setaffinity};
+
+use crate::{ctypes, utils::e};
+
+/// Set the CPU affinity mask of a thread.
+#[no_mangle]
+pub unsafe extern "C" fn sched_setaffinity(
+    pid: c_int,
+    cpusetsize: usize,
+    mask: *const ctypes::cpu_set_t,
+) -> c_int {
+    e(sys_sched_setaffinity(pid, cpusetsize, mask))
+}
+
+/// Get the CPU affinity mask of a thread.
+#[no_mangle]
+pub unsafe extern "C" fn sched_getaffinity(
+    pid: c_int,
+    cpusetsize: usize,
+    mask: *mut ctypes::cpu_set_t,
+) -> c_int {
+    e(sys_sched_getaffinity(pid, cpusetsize, mask))
+}
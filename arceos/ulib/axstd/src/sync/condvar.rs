@@ -0,0 +1,144 @@
+//! A condition variable working with [`Mutex`], built on the one of `axsync`.
+//!
+//! [`Mutex`]: super::Mutex
+
+use core::fmt;
+
+use arceos_api::modules::axsync;
+
+use super::MutexGuard;
+
+/// A type indicating whether a timed wait on a condition variable returned
+/// due to a time out or not.
+///
+/// It is returned by the [`Condvar::wait_timeout`] method.
+#[cfg(feature = "irq")]
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub struct WaitTimeoutResult(bool);
+
+#[cfg(feature = "irq")]
+impl WaitTimeoutResult {
+    /// Returns `true` if the wait was known to have timed out.
+    #[must_use]
+    pub fn timed_out(&self) -> bool {
+        self.0
+    }
+}
+
+/// A Condition Variable, similar to
+/// [`std::sync::Condvar`](https://doc.rust-lang.org/std/sync/struct.Condvar.html).
+///
+/// Condition variables represent the ability to block a task such that it
+/// consumes no CPU time while waiting for an event to occur. It is always used
+/// together with a [`Mutex`](super::Mutex).
+///
+/// Like the one in `std`, it may wake up spuriously, so the condition should
+/// be checked in a loop, or use [`wait_while`](Self::wait_while) instead.
+pub struct Condvar {
+    inner: axsync::Condvar,
+}
+
+impl Condvar {
+    /// Creates a new condition variable which is ready to be waited on and
+    /// notified.
+    pub const fn new() -> Self {
+        Self {
+            inner: axsync::Condvar::new(),
+        }
+    }
+
+    /// Blocks the current task until this condition variable receives a
+    /// notification.
+    ///
+    /// This function will atomically unlock the mutex specified (represented
+    /// by `guard`) and block the current task. When this function call
+    /// returns, the lock specified will have been re-acquired.
+    pub fn wait<'a, T>(&self, guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+        let mutex = MutexGuard::mutex(&guard);
+        self.inner.wait_unlocked(|| drop(guard));
+        mutex.lock()
+    }
+
+    /// Blocks the current task until the provided condition becomes false.
+    ///
+    /// `condition` is checked immediately; if not met (returns `true`), this
+    /// will [`wait`](Self::wait) for the next notification then check again.
+    /// This repeats until `condition` returns `false`.
+    pub fn wait_while<'a, T, F>(
+        &self,
+        mut guard: MutexGuard<'a, T>,
+        mut condition: F,
+    ) -> MutexGuard<'a, T>
+    where
+        F: FnMut(&mut T) -> bool,
+    {
+        while condition(&mut *guard) {
+            guard = self.wait(guard);
+        }
+        guard
+    }
+
+    /// Waits on this condition variable for a notification, timing out after
+    /// the specified duration.
+    ///
+    /// The returned [`WaitTimeoutResult`] indicates whether the timeout is
+    /// known to have elapsed.
+    #[cfg(feature = "irq")]
+    pub fn wait_timeout<'a, T>(
+        &self,
+        guard: MutexGuard<'a, T>,
+        dur: core::time::Duration,
+    ) -> (MutexGuard<'a, T>, WaitTimeoutResult) {
+        let mutex = MutexGuard::mutex(&guard);
+        let timeout = self.inner.wait_timeout_unlocked(dur, || drop(guard));
+        (mutex.lock(), WaitTimeoutResult(timeout))
+    }
+
+    /// Waits on this condition variable for a notification, timing out after
+    /// the specified duration, until the provided condition becomes false.
+    ///
+    /// The returned [`WaitTimeoutResult`] indicates whether the timeout
+    /// elapsed while the condition is still true.
+    #[cfg(feature = "irq")]
+    pub fn wait_timeout_while<'a, T, F>(
+        &self,
+        mut guard: MutexGuard<'a, T>,
+        dur: core::time::Duration,
+        mut condition: F,
+    ) -> (MutexGuard<'a, T>, WaitTimeoutResult)
+    where
+        F: FnMut(&mut T) -> bool,
+    {
+        let deadline = crate::time::Instant::now() + dur;
+        while condition(&mut *guard) {
+            let remaining = deadline.duration_since(crate::time::Instant::now());
+            if remaining.is_zero() {
+                return (guard, WaitTimeoutResult(true));
+            }
+            guard = self.wait_timeout(guard, remaining).0;
+        }
+        (guard, WaitTimeoutResult(false))
+    }
+
+    /// Wakes up one blocked task on this condvar.
+    pub fn notify_one(&self) {
+        self.inner.notify_one();
+    }
+
+    /// Wakes up all blocked tasks on this condvar.
+    pub fn notify_all(&self) {
+        self.inner.notify_all();
+    }
+}
+
+impl Default for Condvar {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Debug for Condvar {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Condvar").finish_non_exhaustive()
+    }
+}
@@ -7,13 +7,29 @@ pub use core::sync::atomic;
 #[doc(no_inline)]
 pub use alloc::sync::{Arc, Weak};
 
+#[cfg(feature = "multitask")]
+mod condvar;
 #[cfg(feature = "multitask")]
 mod mutex;
 
+#[cfg(feature = "multitask")]
+#[doc(cfg(feature = "multitask"))]
+pub use self::condvar::Condvar;
 #[cfg(feature = "multitask")]
 #[doc(cfg(feature = "multitask"))]
 pub use self::mutex::{Mutex, MutexGuard};
 
+#[cfg(all(feature = "multitask", feature = "irq"))]
+#[doc(cfg(all(feature = "multitask", feature = "irq")))]
+pub use self::condvar::WaitTimeoutResult;
+
+#[cfg(feature = "multitask")]
+#[doc(cfg(feature = "multitask"))]
+pub use arceos_api::modules::axsync::{
+    Barrier, BarrierWaitResult, RwLock, RwLockReadGuard, RwLockWriteGuard, Semaphore,
+    SemaphoreGuard,
+};
+
 #[cfg(not(feature = "multitask"))]
 #[doc(cfg(not(feature = "multitask")))]
 pub use kspin::{SpinRaw as Mutex, SpinRawGuard as MutexGuard}; // never used in IRQ context
@@ -167,6 +167,13 @@ impl<T: ?Sized + fmt::Debug> fmt::Debug for Mutex<T> {
     }
 }
 
+impl<'a, T: ?Sized> MutexGuard<'a, T> {
+    /// Returns the mutex that the guard locks.
+    pub(super) fn mutex(guard: &Self) -> &'a Mutex<T> {
+        guard.lock
+    }
+}
+
 impl<'a, T: ?Sized> Deref for MutexGuard<'a, T> {
     type Target = T;
     #[inline(always)]